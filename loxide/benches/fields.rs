@@ -0,0 +1,128 @@
+//! Shapes against a table per instance, for storing fields.
+//!
+//! Times both storages on the same made up reads and writes, then a few
+//! scripts with the storage the VM was built with. Run it with and without
+//! `--features table_fields` to compare the scripts:
+//!
+//! ```text
+//! cargo bench --bench fields
+//! cargo bench --bench fields --features table_fields
+//! ```
+//!
+//! `make compare-fields` runs the benchmark suite with both instead.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use loxide::{
+    fields::{FieldStorage, Shape, ShapedFields, TableFields},
+    interpret,
+    mem::Mem,
+    value::Value,
+    vm::VM,
+};
+
+const ROUNDS: u32 = 1_000_000;
+const RUNS: u32 = 5;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "points",
+        "class Point { init(x, y) { this.x = x; this.y = y; } }
+         var total = 0;
+         for (var i = 0; i < 300000; i = i + 1) {
+           var p = Point(i, i + 1);
+           total = total + p.x + p.y;
+         }",
+    ),
+    (
+        "wide",
+        "class Wide {
+           init() {
+             this.a = 1; this.b = 2; this.c = 3; this.d = 4;
+             this.e = 5; this.f = 6; this.g = 7; this.h = 8;
+           }
+         }
+         var w = Wide();
+         var total = 0;
+         for (var i = 0; i < 300000; i = i + 1) {
+           total = total + w.a + w.d + w.h;
+           w.e = i;
+         }",
+    ),
+    (
+        "methods",
+        "class Counter {
+           init() { this.n = 0; }
+           inc() { this.n = this.n + 1; return this; }
+         }
+         var c = Counter();
+         for (var i = 0; i < 500000; i = i + 1) c.inc();",
+    ),
+];
+
+fn main() {
+    println!("field operations, {ROUNDS} rounds, best of {RUNS}");
+    let shaped = best(churn::<ShapedFields>);
+    let table = best(churn::<TableFields>);
+    println!("{:<10} {:>10.1?}", "table", table);
+    println!(
+        "{:<10} {:>10.1?} {:+.1}%",
+        "shapes",
+        shaped,
+        (shaped.as_secs_f64() / table.as_secs_f64() - 1.0) * 100.0
+    );
+
+    let storage = match cfg!(feature = "table_fields") {
+        true => "tables",
+        false => "shapes",
+    };
+    println!("\nscripts on {storage}, best of {RUNS}");
+    for &(name, src) in SCRIPTS {
+        let time = best(|| {
+            let mut vm = VM::new();
+            let start = Instant::now();
+            interpret(&mut vm, src).unwrap();
+            start.elapsed()
+        });
+        println!("{name:<10} {time:>10.1?}");
+    }
+}
+
+fn best(mut run: impl FnMut() -> Duration) -> Duration {
+    (0..RUNS).map(|_| run()).min().unwrap()
+}
+
+/// Roughly what a method does to `this`: a few fields set when it's made,
+/// then mostly read, sometimes written
+fn churn<F: FieldStorage>() -> Duration {
+    let mut mem = Mem::new();
+    let names: Vec<_> = ["x", "y", "z", "w"]
+        .iter()
+        .map(|name| mem.copy_string(name).as_non_null_ptr())
+        .collect();
+    let root = Shape::root();
+
+    let start = Instant::now();
+    let mut fields = F::new(root);
+    for &name in &names {
+        fields.set(name, Value::Number(0.0));
+    }
+    for round in 0..ROUNDS {
+        let name = names[round as usize % names.len()];
+        if let Some(Value::Number(n)) = fields.get(black_box(name)) {
+            fields.set(
+                names[(round as usize + 1) % names.len()],
+                Value::Number(n + 1.0),
+            );
+        }
+    }
+    let elapsed = start.elapsed();
+
+    black_box(fields.len());
+    fields.free();
+    unsafe { Shape::free(root) };
+    elapsed
+}
@@ -0,0 +1,104 @@
+//! What bounds checking the value stack costs.
+//!
+//! Times both stacks on the same made up sequence of operations, then a few
+//! scripts on the stack the VM was built with. Run it with and without
+//! `--features checked_stack` to compare the scripts:
+//!
+//! ```text
+//! cargo bench --bench stack
+//! cargo bench --bench stack --features checked_stack
+//! ```
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use loxide::{
+    interpret,
+    stack::{CheckedStack, UncheckedStack, ValueStack},
+    value::Value,
+    vm::VM,
+};
+
+const ROUNDS: u32 = 10_000_000;
+const RUNS: u32 = 5;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+         fib(27);",
+    ),
+    (
+        "locals",
+        "var total = 0;
+         for (var i = 0; i < 2000000; i = i + 1) {
+           var a = i; var b = a + 1; var c = b * 2;
+           total = total + c - a - b;
+         }",
+    ),
+    (
+        "closures",
+        "fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+         var inc = counter();
+         for (var i = 0; i < 1000000; i = i + 1) inc();",
+    ),
+];
+
+fn main() {
+    println!("stack operations, {ROUNDS} rounds, best of {RUNS}");
+    let unchecked = best(churn::<UncheckedStack>);
+    let checked = best(churn::<CheckedStack>);
+    println!("{:<10} {:>10.1?}", "unchecked", unchecked);
+    println!(
+        "{:<10} {:>10.1?} {:+.1}%",
+        "checked",
+        checked,
+        (checked.as_secs_f64() / unchecked.as_secs_f64() - 1.0) * 100.0
+    );
+
+    let stack = match cfg!(feature = "checked_stack") {
+        true => "checked",
+        false => "unchecked",
+    };
+    println!("\nscripts on the {stack} stack, best of {RUNS}");
+    for &(name, src) in SCRIPTS {
+        let time = best(|| {
+            let mut vm = VM::new();
+            let start = Instant::now();
+            interpret(&mut vm, src).unwrap();
+            start.elapsed()
+        });
+        println!("{name:<10} {time:>10.1?}");
+    }
+}
+
+fn best(mut run: impl FnMut() -> Duration) -> Duration {
+    (0..RUNS).map(|_| run()).min().unwrap()
+}
+
+/// Roughly what running a function does to the stack: locals read and
+/// written through its frame, operands pushed, combined and popped
+fn churn<S: ValueStack>() -> Duration {
+    let mut stack = S::with_capacity(256);
+    let start = Instant::now();
+    for _ in 0..4 {
+        stack.push(Value::Number(1.0));
+    }
+    let slots = stack.base();
+    for round in 0..ROUNDS {
+        stack.push(unsafe { stack.slot(slots, (round % 4) as usize) });
+        stack.push(Value::Number(round as f64));
+        let b = stack.pop();
+        let a = stack.peek(0);
+        if let (Value::Number(a), Value::Number(b)) = (a, b) {
+            stack.set(0, Value::Number(a + b));
+        }
+        let sum = stack.pop();
+        unsafe { stack.set_slot(slots, ((round + 1) % 4) as usize, black_box(sum)) };
+    }
+    let elapsed = start.elapsed();
+    black_box(stack.values());
+    elapsed
+}
@@ -0,0 +1,379 @@
+//! A trace of every object allocated and freed, for tuning the GC, written
+//! by `loxide --trace-allocs file` and read by `loxide trace-report file`.
+//!
+//! How long objects live decides how the collector should be paced, and
+//! where a generational one would draw the line between young and old. The
+//! report answers that with histograms of how many allocations and how many
+//! collections each object lived through.
+//!
+//! The file starts with `LOXT` and a little-endian u16 version. Each event
+//! after that is a byte with the event in its high four bits and the
+//! object's [`ObjKind`] in the low four, then unsigned LEB128 numbers:
+//!
+//! - allocation: the object's size, then how many objects are live with it
+//! - free: its size, how many are live without it, how many objects were
+//!   allocated after it, and how many collections it survived
+//! - collection, when one finishes: how many objects are live
+//!
+//! Objects still live when the trace ends have no free event.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::obj::ObjKind;
+
+pub const TRACE_MAGIC: &[u8; 4] = b"LOXT";
+pub const TRACE_VERSION: u16 = 1;
+
+const ALLOC: u8 = 0;
+const FREE: u8 = 1;
+const COLLECTION: u8 = 2;
+
+/// One thing the trace recorded, see the [module](self) docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceEvent {
+    Alloc {
+        kind: ObjKind,
+        size: u64,
+        live: u64,
+    },
+    Free {
+        kind: ObjKind,
+        size: u64,
+        live: u64,
+        /// Objects allocated between this one and it being freed
+        age: u64,
+        /// Collections that finished between the two
+        collections: u64,
+    },
+    Collection {
+        live: u64,
+    },
+}
+
+/// Writes the trace as [`Mem`](crate::mem::Mem) allocates and frees
+pub struct AllocTrace {
+    out: Box<dyn Write>,
+    /// The allocation count and collection count when each live object was
+    /// allocated, by address
+    born: HashMap<usize, (u64, u64)>,
+    allocations: u64,
+    collections: u64,
+    /// The first error writing, later events are dropped after one
+    error: Option<io::Error>,
+}
+
+impl AllocTrace {
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<Self> {
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        Ok(Self {
+            out,
+            born: HashMap::new(),
+            allocations: 0,
+            collections: 0,
+            error: None,
+        })
+    }
+
+    pub fn alloc(&mut self, addr: usize, kind: ObjKind, size: usize) {
+        self.born.insert(addr, (self.allocations, self.collections));
+        self.allocations += 1;
+        let live = self.born.len() as u64;
+        self.write(ALLOC, kind, &[size as u64, live]);
+    }
+
+    pub fn free(&mut self, addr: usize, kind: ObjKind, size: usize) {
+        // Allocated before the trace started
+        let Some((allocations, collections)) = self.born.remove(&addr) else {
+            return;
+        };
+        let live = self.born.len() as u64;
+        let age = self.allocations - allocations - 1;
+        let survived = self.collections - collections;
+        self.write(FREE, kind, &[size as u64, live, age, survived]);
+    }
+
+    pub fn collection(&mut self) {
+        self.collections += 1;
+        let live = self.born.len() as u64;
+        self.write(COLLECTION, ObjKind::ALL[0], &[live]);
+    }
+
+    /// Flush what's been written, or report the first write that failed
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+
+    fn write(&mut self, event: u8, kind: ObjKind, numbers: &[u64]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut bytes = vec![event << 4 | kind as u8];
+        for &n in numbers {
+            write_leb128(&mut bytes, n);
+        }
+        if let Err(err) = self.out.write_all(&bytes) {
+            self.error = Some(err);
+        }
+    }
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Every event in a trace written by [`AllocTrace`]
+pub fn read_trace(bytes: &[u8]) -> io::Result<Vec<TraceEvent>> {
+    if bytes.len() < 6 || &bytes[..4] != TRACE_MAGIC {
+        return Err(invalid("Not an allocation trace."));
+    }
+    if u16::from_le_bytes([bytes[4], bytes[5]]) != TRACE_VERSION {
+        return Err(invalid("Unsupported allocation trace version."));
+    }
+
+    let mut events = vec![];
+    let mut rest = &bytes[6..];
+    while let Some((&tag, after)) = rest.split_first() {
+        rest = after;
+        let mut number = || read_leb128(&mut rest);
+        let kind = *ObjKind::ALL
+            .get((tag & 0xf) as usize)
+            .ok_or_else(|| invalid("Unknown object kind in trace."))?;
+        let event = match tag >> 4 {
+            ALLOC => TraceEvent::Alloc {
+                kind,
+                size: number()?,
+                live: number()?,
+            },
+            FREE => TraceEvent::Free {
+                kind,
+                size: number()?,
+                live: number()?,
+                age: number()?,
+                collections: number()?,
+            },
+            COLLECTION => TraceEvent::Collection { live: number()? },
+            _ => return Err(invalid("Unknown event in trace.")),
+        };
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn read_leb128(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| invalid("Allocation trace ends in the middle of an event."))?;
+        *bytes = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("Number too large in allocation trace."))
+}
+
+/// How one kind of object fared
+#[derive(Debug, Clone, PartialEq)]
+pub struct KindSurvival {
+    pub kind: ObjKind,
+    pub allocated: u64,
+    pub bytes: u64,
+    pub freed: u64,
+    /// Freed before surviving a single collection
+    pub died_young: u64,
+}
+
+/// What a trace says about how long objects live
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceReport {
+    pub allocated: u64,
+    pub bytes: u64,
+    pub freed: u64,
+    pub collections: u64,
+    pub peak_live: u64,
+    /// Freed objects by how many allocations they lived through, in powers
+    /// of two: 0, 1, 2-3, 4-7 and so on
+    pub by_age: Vec<u64>,
+    /// The same for how many collections they survived
+    pub by_collections: Vec<u64>,
+    /// In the order each kind was first allocated
+    pub kinds: Vec<KindSurvival>,
+}
+
+/// The bucket of `n` in [`TraceReport::by_age`] and `by_collections`
+fn bucket(n: u64) -> usize {
+    (u64::BITS - n.leading_zeros()) as usize
+}
+
+fn bucket_label(bucket: usize) -> String {
+    match bucket {
+        0 => "0".to_string(),
+        1 => "1".to_string(),
+        _ => format!("{}-{}", 1u64 << (bucket - 1), (1u64 << bucket) - 1),
+    }
+}
+
+fn count(buckets: &mut Vec<u64>, n: u64) {
+    let bucket = bucket(n);
+    if buckets.len() <= bucket {
+        buckets.resize(bucket + 1, 0);
+    }
+    buckets[bucket] += 1;
+}
+
+impl TraceReport {
+    pub fn new(events: &[TraceEvent]) -> Self {
+        let mut report = TraceReport {
+            allocated: 0,
+            bytes: 0,
+            freed: 0,
+            collections: 0,
+            peak_live: 0,
+            by_age: vec![],
+            by_collections: vec![],
+            kinds: vec![],
+        };
+        for event in events {
+            match *event {
+                TraceEvent::Alloc { kind, size, live } => {
+                    report.allocated += 1;
+                    report.bytes += size;
+                    report.peak_live = report.peak_live.max(live);
+                    let stats = report.kind(kind);
+                    stats.allocated += 1;
+                    stats.bytes += size;
+                }
+                TraceEvent::Free {
+                    kind,
+                    age,
+                    collections,
+                    ..
+                } => {
+                    report.freed += 1;
+                    count(&mut report.by_age, age);
+                    count(&mut report.by_collections, collections);
+                    let stats = report.kind(kind);
+                    stats.freed += 1;
+                    if collections == 0 {
+                        stats.died_young += 1;
+                    }
+                }
+                TraceEvent::Collection { .. } => report.collections += 1,
+            }
+        }
+        report
+    }
+
+    fn kind(&mut self, kind: ObjKind) -> &mut KindSurvival {
+        let index = match self.kinds.iter().position(|stats| stats.kind == kind) {
+            Some(index) => index,
+            None => {
+                self.kinds.push(KindSurvival {
+                    kind,
+                    allocated: 0,
+                    bytes: 0,
+                    freed: 0,
+                    died_young: 0,
+                });
+                self.kinds.len() - 1
+            }
+        };
+        &mut self.kinds[index]
+    }
+
+    /// Objects that were never freed
+    pub fn live_at_exit(&self) -> u64 {
+        self.allocated - self.freed
+    }
+
+    /// A summary, both histograms, and a line per kind of object
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} objects allocated ({} bytes), {} freed, {} live at exit, {} collections, at most {} live",
+            self.allocated,
+            self.bytes,
+            self.freed,
+            self.live_at_exit(),
+            self.collections,
+            self.peak_live
+        )?;
+
+        for (title, buckets) in [
+            ("allocations lived through", &self.by_age),
+            ("collections survived", &self.by_collections),
+        ] {
+            writeln!(out, "\n{title}")?;
+            writeln!(
+                out,
+                "{:>13} {:>9} {:>7} {:>7}",
+                "", "objects", "%", "cum. %"
+            )?;
+            let mut cumulative = 0;
+            for (bucket, &objects) in buckets.iter().enumerate() {
+                cumulative += objects;
+                writeln!(
+                    out,
+                    "{:>13} {:>9} {:>6.1}% {:>6.1}%",
+                    bucket_label(bucket),
+                    objects,
+                    percent(objects, self.allocated),
+                    percent(cumulative, self.allocated),
+                )?;
+            }
+            writeln!(
+                out,
+                "{:>13} {:>9} {:>6.1}%",
+                "live at exit",
+                self.live_at_exit(),
+                percent(self.live_at_exit(), self.allocated)
+            )?;
+        }
+
+        writeln!(
+            out,
+            "\n{:<12} {:>9} {:>11} {:>9} {:>11}",
+            "kind", "objects", "bytes", "freed", "died young"
+        )?;
+        for stats in &self.kinds {
+            writeln!(
+                out,
+                "{:<12} {:>9} {:>11} {:>9} {:>10.1}%",
+                format!("{:?}", stats.kind),
+                stats.allocated,
+                stats.bytes,
+                stats.freed,
+                percent(stats.died_young, stats.allocated)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        _ => part as f64 * 100.0 / whole as f64,
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::obj::{ObjFunction, ObjKind};
+
+/// A place in the code that allocates objects, and what it allocated
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationSite {
+    /// The function allocating, `script` at the top level
+    pub function: String,
+    pub line: u32,
+    /// Where in the function's chunk the instruction allocating is
+    pub offset: u32,
+    pub count: u64,
+    /// The size of the objects themselves, not of what they own, like a
+    /// string's characters or a list's items
+    pub bytes: u64,
+    /// How many of each kind of object, in the order they were first seen
+    pub kinds: Vec<(ObjKind, u64)>,
+}
+
+/// Every object allocated while a script runs, by the instruction that
+/// allocated it. Natives are counted at the call that ran them.
+#[derive(Debug, Default)]
+pub struct Allocations {
+    /// By function and offset. A function that's freed might have its
+    /// address reused, so a site can end up shared by two functions, which
+    /// is fine for finding hot spots.
+    sites: HashMap<(*const ObjFunction, u32), AllocationSite>,
+}
+
+impl Allocations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, function: &ObjFunction, offset: u32, kind: ObjKind, bytes: usize) {
+        let site = self
+            .sites
+            .entry((function, offset))
+            .or_insert_with(|| AllocationSite {
+                function: function.name_str().to_owned(),
+                line: function.chunk.lines[offset as usize],
+                offset,
+                count: 0,
+                bytes: 0,
+                kinds: vec![],
+            });
+
+        site.count += 1;
+        site.bytes += bytes as u64;
+        match site.kinds.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, count)) => *count += 1,
+            None => site.kinds.push((kind, 1)),
+        }
+    }
+
+    /// Every site, the one that allocated the most objects first
+    pub fn sites(&self) -> Vec<&AllocationSite> {
+        let mut sites: Vec<_> = self.sites.values().collect();
+        sites.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.function.cmp(&b.function))
+                .then(a.offset.cmp(&b.offset))
+        });
+        sites
+    }
+
+    pub fn total(&self) -> u64 {
+        self.sites.values().map(|site| site.count).sum()
+    }
+
+    /// One line per site, busiest first, with the objects it allocated
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{:>9} {:>11}  site", "objects", "bytes")?;
+        for site in self.sites() {
+            let kinds: Vec<_> = site
+                .kinds
+                .iter()
+                .map(|(kind, count)| format!("{kind:?} {count}"))
+                .collect();
+            writeln!(
+                out,
+                "{:>9} {:>11}  {} [line {}] @{}: {}",
+                site.count,
+                site.bytes,
+                site.function,
+                site.line,
+                site.offset,
+                kinds.join(", ")
+            )?;
+        }
+        writeln!(out, "{:>9} total", self.total())
+    }
+}
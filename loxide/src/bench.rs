@@ -0,0 +1,435 @@
+//! Timing the compiler by phase, for `loxide bench-compile`, and the
+//! benchmark suite against other implementations, for `loxide bench`.
+//!
+//! The compiler parses and generates code in a single pass, so the two can't
+//! be timed apart directly. Code generation is timed by writing the bytecode
+//! the compiler produced into fresh chunks again, and parsing is what's left
+//! of the time compiling took once scanning and that are taken out.
+//!
+//! The suite runs each implementation as a separate process, so zlox and
+//! loxide are measured the same way: wall clock time from spawning it to
+//! reaping it, and the peak resident memory the OS reports for it. Each run
+//! is also handed a file in [`HEAP_STATS_VAR`] to write the most its own heap
+//! accounting had live to, which leaves out the allocator's overhead and
+//! everything that isn't a Lox object.
+
+use std::{
+    hint::black_box,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{chunk::Chunk, compile::Parser, mem::Mem, scanner::Scanner};
+
+/// How long each phase took over every iteration
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileTimings {
+    pub iterations: u32,
+    pub bytes: usize,
+    /// Including the final `Eof`
+    pub tokens: usize,
+    /// Turning the source into tokens
+    pub scan: Duration,
+    /// Everything else the compiler does: parsing, resolving names and
+    /// making constants
+    pub parse: Duration,
+    /// Writing instructions, with their lines and columns, and constants into
+    /// chunks
+    pub codegen: Duration,
+}
+
+impl CompileTimings {
+    pub fn total(&self) -> Duration {
+        self.scan + self.parse + self.codegen
+    }
+
+    /// Each phase's average time per iteration and its share of the total
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} iterations, {} bytes, {} tokens",
+            self.iterations, self.bytes, self.tokens
+        )?;
+        writeln!(out, "{:<8} {:>14} {:>7}", "phase", "per iteration", "share")?;
+        let total = self.total().as_secs_f64();
+        for (phase, time) in [
+            ("scan", self.scan),
+            ("parse", self.parse),
+            ("codegen", self.codegen),
+            ("total", self.total()),
+        ] {
+            let share = match total > 0.0 {
+                true => time.as_secs_f64() / total * 100.0,
+                false => 0.0,
+            };
+            writeln!(
+                out,
+                "{phase:<8} {:>14} {share:>6.1}%",
+                format!("{:.1?}", time / self.iterations)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compile `src` `iterations` times, each time from scratch, and time the
+/// phases. Fails with the first compile error if it doesn't compile.
+pub fn bench_compile(src: &str, iterations: u32) -> Result<CompileTimings, String> {
+    let mut timings = CompileTimings {
+        iterations: iterations.max(1),
+        bytes: src.len(),
+        tokens: 0,
+        scan: Duration::ZERO,
+        parse: Duration::ZERO,
+        codegen: Duration::ZERO,
+    };
+    let mut compile = Duration::ZERO;
+
+    for _ in 0..timings.iterations {
+        let start = Instant::now();
+        timings.tokens = black_box(Scanner::new(src).count());
+        timings.scan += start.elapsed();
+
+        let mut mem = Mem::new();
+        let start = Instant::now();
+        let mut parser = Parser::new(src, &mut mem);
+        parser.print_errors = false;
+        let compiled = parser.compile();
+        compile += start.elapsed();
+        if !compiled {
+            return Err(parser.diagnostics[0].to_string());
+        }
+
+        let script = parser.compiler.function;
+        let start = Instant::now();
+        for function in std::iter::once(script).chain(script.nested_functions()) {
+            black_box(rewrite(&function.chunk));
+        }
+        timings.codegen += start.elapsed();
+    }
+
+    // Compiling includes the other two, the parser pulls tokens and writes
+    // code as it goes
+    timings.parse = compile.saturating_sub(timings.scan + timings.codegen);
+    Ok(timings)
+}
+
+/// A copy of `chunk` written the way the compiler writes one
+fn rewrite(chunk: &Chunk) -> Chunk {
+    let mut copy = Chunk::new();
+    for (offset, &byte) in chunk.code.iter().enumerate() {
+        copy.write(byte, chunk.lines[offset], chunk.columns[offset]);
+    }
+    for &constant in chunk.constants.iter() {
+        copy.add_constant(constant);
+    }
+    for (&number, &value) in chunk.numbers.iter().zip(chunk.number_values.iter()) {
+        copy.add_number(number, value);
+    }
+    copy
+}
+
+/// The variable holding the path an interpreter writes its heap stats to
+/// when it exits, see [`write_heap_stats`]
+pub const HEAP_STATS_VAR: &str = "LOX_HEAP_STATS";
+
+/// Write the most bytes of objects that were live at once, as zlox does too
+pub fn write_heap_stats(path: &Path, peak_bytes: usize) -> io::Result<()> {
+    std::fs::write(path, format!("peak_heap_bytes {peak_bytes}\n"))
+}
+
+fn read_heap_stats(path: &Path) -> Option<u64> {
+    let stats = std::fs::read_to_string(path).ok()?;
+    stats
+        .lines()
+        .find_map(|line| line.strip_prefix("peak_heap_bytes ")?.parse().ok())
+}
+
+/// An interpreter to run the suite with, a command that takes the path of a
+/// script
+#[derive(Debug, Clone, PartialEq)]
+pub struct Implementation {
+    pub name: String,
+    pub command: PathBuf,
+}
+
+/// How one implementation did on one benchmark
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    /// Each run's time, warmups left out
+    pub times: Vec<Duration>,
+    /// The most any run had resident, in bytes, where the OS reports it
+    pub peak_rss: Option<u64>,
+    /// The most any run's own accounting had live, in bytes, where the
+    /// implementation writes its heap stats
+    pub peak_heap: Option<u64>,
+    /// Why a run failed, the rest aren't run then
+    pub error: Option<String>,
+}
+
+impl Measurement {
+    pub fn median(&self) -> Option<Duration> {
+        if self.error.is_some() || self.times.is_empty() {
+            return None;
+        }
+        let mut times = self.times.clone();
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+}
+
+/// The suite's results, a row per benchmark with a measurement for each
+/// implementation, in the order they were given
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub implementations: Vec<String>,
+    pub benchmarks: Vec<(String, Vec<Measurement>)>,
+}
+
+/// Run every script with every implementation, `warmup` times untimed and
+/// then `runs` times timed
+pub fn compare(
+    implementations: &[Implementation],
+    scripts: &[PathBuf],
+    warmup: u32,
+    runs: u32,
+) -> Comparison {
+    let benchmarks = scripts
+        .iter()
+        .map(|script| {
+            let name = script
+                .file_stem()
+                .unwrap_or(script.as_os_str())
+                .to_string_lossy()
+                .into_owned();
+            let measurements = implementations
+                .iter()
+                .map(|implementation| measure(&implementation.command, script, warmup, runs))
+                .collect();
+            (name, measurements)
+        })
+        .collect();
+
+    Comparison {
+        implementations: implementations.iter().map(|i| i.name.clone()).collect(),
+        benchmarks,
+    }
+}
+
+fn measure(command: &Path, script: &Path, warmup: u32, runs: u32) -> Measurement {
+    let mut measurement = Measurement {
+        times: vec![],
+        peak_rss: None,
+        peak_heap: None,
+        error: None,
+    };
+    for run in 0..warmup + runs.max(1) {
+        match run_once(command, script) {
+            Ok(ran) => {
+                if run >= warmup {
+                    measurement.times.push(ran.time);
+                }
+                measurement.peak_rss = measurement.peak_rss.max(ran.peak_rss);
+                measurement.peak_heap = measurement.peak_heap.max(ran.peak_heap);
+            }
+            Err(err) => {
+                measurement.error = Some(err.to_string());
+                break;
+            }
+        }
+    }
+    measurement
+}
+
+struct Run {
+    time: Duration,
+    peak_rss: Option<u64>,
+    peak_heap: Option<u64>,
+}
+
+/// How long `command script` took and how much memory it used
+fn run_once(command: &Path, script: &Path) -> io::Result<Run> {
+    let stats = std::env::temp_dir().join(format!("loxide-heap-{}", std::process::id()));
+    // Left over from a run that didn't write one, or from another process
+    let _ = std::fs::remove_file(&stats);
+
+    let start = Instant::now();
+    let child = Command::new(command)
+        .arg(script)
+        .env(HEAP_STATS_VAR, &stats)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let (success, peak_rss) = wait_with_rss(child)?;
+    let time = start.elapsed();
+
+    let peak_heap = read_heap_stats(&stats);
+    let _ = std::fs::remove_file(&stats);
+    match success {
+        true => Ok(Run {
+            time,
+            peak_rss,
+            peak_heap,
+        }),
+        false => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} failed on {}", command.display(), script.display()),
+        )),
+    }
+}
+
+/// Wait for `child` to exit, returning whether it succeeded and its peak
+/// resident memory in bytes
+#[cfg(unix)]
+fn wait_with_rss(child: Child) -> io::Result<(bool, Option<u64>)> {
+    let mut status = 0;
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // `Child::wait` would throw the usage away
+    let pid = unsafe {
+        libc::wait4(
+            child.id() as libc::pid_t,
+            &mut status,
+            0,
+            usage.as_mut_ptr(),
+        )
+    };
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let usage = unsafe { usage.assume_init() };
+    // Kilobytes everywhere but macOS, which counts bytes
+    let rss = match cfg!(target_os = "macos") {
+        true => usage.ru_maxrss as u64,
+        false => usage.ru_maxrss as u64 * 1024,
+    };
+    let success = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+    Ok((success, Some(rss)))
+}
+
+#[cfg(not(unix))]
+fn wait_with_rss(mut child: Child) -> io::Result<(bool, Option<u64>)> {
+    Ok((child.wait()?.success(), None))
+}
+
+impl Comparison {
+    /// How many times faster the first implementation was than `other`, by
+    /// their median times
+    pub fn speedup(&self, measurements: &[Measurement], other: usize) -> Option<f64> {
+        let first = measurements.first()?.median()?.as_secs_f64();
+        let other = measurements.get(other)?.median()?.as_secs_f64();
+        match first > 0.0 {
+            true => Some(other / first),
+            false => None,
+        }
+    }
+
+    /// A table with each implementation's median time, peak resident memory
+    /// and peak heap, and
+    /// the first one's speedup over each of the others
+    pub fn write_markdown(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut header = vec!["benchmark".to_string()];
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name} time")),
+        );
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name} RSS")),
+        );
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name} heap")),
+        );
+        header.extend(self.others().map(|other| format!("vs {other}")));
+        writeln!(out, "| {} |", header.join(" | "))?;
+        writeln!(out, "|{}", " --- |".repeat(header.len()))?;
+
+        for (name, measurements) in self.benchmarks.iter() {
+            let mut row = vec![name.clone()];
+            row.extend(measurements.iter().map(|m| match m.median() {
+                Some(median) => format!("{:.3} s", median.as_secs_f64()),
+                None => "failed".to_string(),
+            }));
+            row.extend(measurements.iter().map(|m| format_bytes(m.peak_rss)));
+            row.extend(measurements.iter().map(|m| format_bytes(m.peak_heap)));
+            row.extend((1..self.implementations.len()).map(|other| {
+                match self.speedup(measurements, other) {
+                    Some(speedup) => format!("{speedup:.2}x"),
+                    None => "-".to_string(),
+                }
+            }));
+            writeln!(out, "| {} |", row.join(" | "))?;
+        }
+        Ok(())
+    }
+
+    /// The same as [`write_markdown`](Comparison::write_markdown), in plain
+    /// units with empty cells for what's missing
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut header = vec!["benchmark".to_string()];
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name}_seconds")),
+        );
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name}_rss_bytes")),
+        );
+        header.extend(
+            self.implementations
+                .iter()
+                .map(|name| format!("{name}_heap_bytes")),
+        );
+        header.extend(self.others().map(|other| format!("speedup_vs_{other}")));
+        writeln!(out, "{}", header.join(","))?;
+
+        for (name, measurements) in self.benchmarks.iter() {
+            let mut row = vec![name.clone()];
+            row.extend(measurements.iter().map(|m| {
+                m.median()
+                    .map(|median| median.as_secs_f64().to_string())
+                    .unwrap_or_default()
+            }));
+            row.extend(
+                measurements
+                    .iter()
+                    .map(|m| m.peak_rss.map(|rss| rss.to_string()).unwrap_or_default()),
+            );
+            row.extend(
+                measurements
+                    .iter()
+                    .map(|m| m.peak_heap.map(|heap| heap.to_string()).unwrap_or_default()),
+            );
+            row.extend((1..self.implementations.len()).map(|other| {
+                self.speedup(measurements, other)
+                    .map(|speedup| speedup.to_string())
+                    .unwrap_or_default()
+            }));
+            writeln!(out, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn others(&self) -> impl Iterator<Item = &String> {
+        self.implementations.iter().skip(1)
+    }
+}
+
+/// In KiB or MiB, whichever reads better, or "-" if it's not known
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) if bytes >= 1024 * 1024 => {
+            format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+        }
+        Some(bytes) => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        None => "-".to_string(),
+    }
+}
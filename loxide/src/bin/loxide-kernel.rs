@@ -0,0 +1,355 @@
+//! A Jupyter kernel for Lox, built on [`VM::execute_statement`]: every cell
+//! runs against the same VM, so globals carry over, and the value of a
+//! trailing expression is shown as the cell's result.
+//!
+//! Start it the way Jupyter does, with the path to a connection file:
+//!
+//! ```text
+//! loxide-kernel /path/to/kernel-1234.json
+//! ```
+//!
+//! `kernel/kernel.json` is a kernelspec for installing it.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value as Json};
+use sha2::Sha256;
+use zeromq::{PubSocket, RepSocket, RouterSocket, Socket, SocketRecv, SocketSend, ZmqMessage};
+
+use loxide::{
+    value::Value,
+    vm::{InterpretError, VM},
+};
+
+const PROTOCOL_VERSION: &str = "5.3";
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// A decoded message from the wire
+struct Message {
+    /// Routing prefix, sent back unchanged with the reply
+    identities: Vec<Bytes>,
+    header: Json,
+    content: Json,
+}
+
+impl Message {
+    fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or_default()
+    }
+}
+
+struct Kernel {
+    vm: VM,
+    /// HMAC key from the connection file, empty if messages aren't signed
+    key: Vec<u8>,
+    session: String,
+    execution_count: u64,
+    iopub: PubSocket,
+}
+
+impl Kernel {
+    /// The HMAC of `parts`, None if messages aren't signed
+    fn mac(&self, parts: &[&[u8]]) -> Option<Hmac<Sha256>> {
+        if self.key.is_empty() {
+            return None;
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        Some(mac)
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> String {
+        match self.mac(parts) {
+            Some(mac) => hex::encode(mac.finalize().into_bytes()),
+            None => String::new(),
+        }
+    }
+
+    fn decode(&self, message: ZmqMessage) -> Option<Message> {
+        let mut frames = message.into_vec();
+        let delimiter = frames.iter().position(|frame| frame == DELIMITER)?;
+        let rest = frames.split_off(delimiter + 1);
+        frames.pop();
+
+        let [signature, header, parent_header, metadata, content, ..] = rest.as_slice() else {
+            return None;
+        };
+
+        // In constant time, so the signature can't be guessed a byte at a
+        // time from how long rejecting it takes
+        let valid = match self.mac(&[header, parent_header, metadata, content]) {
+            Some(mac) => hex::decode(signature)
+                .map_or(false, |signature| mac.verify_slice(&signature).is_ok()),
+            None => signature.is_empty(),
+        };
+        if !valid {
+            eprintln!("Dropping message with a bad signature.");
+            return None;
+        }
+
+        Some(Message {
+            identities: frames,
+            header: serde_json::from_slice(header).ok()?,
+            content: serde_json::from_slice(content).ok()?,
+        })
+    }
+
+    fn encode(
+        &self,
+        identities: Vec<Bytes>,
+        parent: &Message,
+        msg_type: &str,
+        content: Json,
+    ) -> ZmqMessage {
+        let header = json!({
+            "msg_id": uuid::Uuid::new_v4().to_string(),
+            "session": self.session,
+            "username": "kernel",
+            "date": chrono::Utc::now().to_rfc3339(),
+            "msg_type": msg_type,
+            "version": PROTOCOL_VERSION,
+        });
+
+        let [header, parent_header, metadata, content] =
+            [&header, &parent.header, &json!({}), &content]
+                .map(|part| part.to_string().into_bytes());
+        let signature = self.sign(&[&header, &parent_header, &metadata, &content]);
+
+        let mut frames = identities;
+        frames.push(Bytes::from_static(DELIMITER));
+        frames.extend(
+            [
+                signature.into_bytes(),
+                header,
+                parent_header,
+                metadata,
+                content,
+            ]
+            .map(Bytes::from),
+        );
+        ZmqMessage::try_from(frames).unwrap()
+    }
+
+    async fn reply(
+        &self,
+        socket: &mut RouterSocket,
+        parent: &Message,
+        msg_type: &str,
+        content: Json,
+    ) {
+        let message = self.encode(parent.identities.clone(), parent, msg_type, content);
+        if let Err(err) = socket.send(message).await {
+            eprintln!("Failed to send {msg_type}: {err}");
+        }
+    }
+
+    async fn publish(&mut self, parent: &Message, msg_type: &str, content: Json) {
+        let topic = Bytes::from(msg_type.to_owned());
+        let message = self.encode(vec![topic], parent, msg_type, content);
+        if let Err(err) = self.iopub.send(message).await {
+            eprintln!("Failed to publish {msg_type}: {err}");
+        }
+    }
+
+    /// Handle a request, returning `false` once the kernel has been asked to
+    /// shut down
+    async fn handle(&mut self, socket: &mut RouterSocket, request: Message) -> bool {
+        self.publish(&request, "status", json!({ "execution_state": "busy" }))
+            .await;
+
+        let mut running = true;
+        match request.msg_type() {
+            "kernel_info_request" => {
+                let content = json!({
+                    "status": "ok",
+                    "protocol_version": PROTOCOL_VERSION,
+                    "implementation": "loxide",
+                    "implementation_version": env!("CARGO_PKG_VERSION"),
+                    "language_info": {
+                        "name": "lox",
+                        "version": "",
+                        "mimetype": "text/x-lox",
+                        "file_extension": ".lox",
+                    },
+                    "banner": "loxide",
+                    "help_links": [],
+                });
+                self.reply(socket, &request, "kernel_info_reply", content)
+                    .await;
+            }
+            "execute_request" => {
+                let content = self.execute(&request).await;
+                self.reply(socket, &request, "execute_reply", content).await;
+            }
+            "is_complete_request" => {
+                let content = json!({ "status": "unknown" });
+                self.reply(socket, &request, "is_complete_reply", content)
+                    .await;
+            }
+            "comm_info_request" => {
+                let content = json!({ "status": "ok", "comms": {} });
+                self.reply(socket, &request, "comm_info_reply", content)
+                    .await;
+            }
+            "shutdown_request" => {
+                let restart = request.content["restart"].as_bool().unwrap_or(false);
+                let content = json!({ "status": "ok", "restart": restart });
+                self.reply(socket, &request, "shutdown_reply", content)
+                    .await;
+                running = false;
+            }
+            msg_type => eprintln!("Ignoring unsupported message {msg_type}."),
+        }
+
+        self.publish(&request, "status", json!({ "execution_state": "idle" }))
+            .await;
+        running
+    }
+
+    /// Run an `execute_request`, publishing its output and result, and return
+    /// the content of the reply
+    async fn execute(&mut self, request: &Message) -> Json {
+        let code = request.content["code"].as_str().unwrap_or_default();
+        let silent = request.content["silent"].as_bool().unwrap_or(false);
+
+        if !silent {
+            self.execution_count += 1;
+            let content = json!({ "code": code, "execution_count": self.execution_count });
+            self.publish(request, "execute_input", content).await;
+        }
+
+        let executed = match self.vm.execute_statement(code) {
+            Ok(executed) => executed,
+            Err(err) => {
+                // The message itself has already gone to stderr, a runtime
+                // error is named by its code
+                let (ename, evalue) = match (&err, &self.vm.last_error) {
+                    (InterpretError::RuntimeError | InterpretError::Interrupted, Some(last)) => {
+                        (last.code.to_string(), last.message.clone())
+                    }
+                    _ => (format!("{err:?}"), String::new()),
+                };
+                let error = json!({ "ename": ename, "evalue": evalue, "traceback": [] });
+                self.publish(request, "error", error.clone()).await;
+
+                let mut content =
+                    json!({ "status": "error", "execution_count": self.execution_count });
+                content
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(error.as_object().unwrap().clone());
+                return content;
+            }
+        };
+
+        if !executed.output.is_empty() {
+            let content = json!({ "name": "stdout", "text": executed.output });
+            self.publish(request, "stream", content).await;
+        }
+
+        if !silent && !matches!(executed.value, Value::Nil) {
+            let content = json!({
+                "execution_count": self.execution_count,
+                "data": { "text/plain": format!("{:?}", executed.value) },
+                "metadata": {},
+            });
+            self.publish(request, "execute_result", content).await;
+        }
+
+        json!({
+            "status": "ok",
+            "execution_count": self.execution_count,
+            "user_expressions": {},
+        })
+    }
+}
+
+fn endpoint(connection: &Json, port: &str) -> String {
+    format!(
+        "{}://{}:{}",
+        connection["transport"].as_str().unwrap_or("tcp"),
+        connection["ip"].as_str().unwrap_or("127.0.0.1"),
+        connection[port]
+            .as_u64()
+            .unwrap_or_else(|| panic!("Connection file has no {port}.")),
+    )
+}
+
+async fn bind<S: Socket>(mut socket: S, connection: &Json, port: &str) -> S {
+    let endpoint = endpoint(connection, port);
+    socket
+        .bind(&endpoint)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to bind {endpoint}: {err}"));
+    socket
+}
+
+#[tokio::main]
+async fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: loxide-kernel connection-file");
+            std::process::exit(64);
+        }
+    };
+
+    let connection: Json =
+        serde_json::from_slice(&std::fs::read(path).unwrap()).expect("Malformed connection file.");
+    let scheme = connection["signature_scheme"]
+        .as_str()
+        .unwrap_or("hmac-sha256");
+    assert_eq!(scheme, "hmac-sha256", "Unsupported signature scheme.");
+
+    let mut shell = bind(RouterSocket::new(), &connection, "shell_port").await;
+    let mut control = bind(RouterSocket::new(), &connection, "control_port").await;
+    // Nothing reads from stdin, but frontends expect something to be there
+    let _stdin = bind(RouterSocket::new(), &connection, "stdin_port").await;
+    let iopub = bind(PubSocket::new(), &connection, "iopub_port").await;
+    let mut heartbeat = bind(RepSocket::new(), &connection, "hb_port").await;
+
+    // On its own task, so long-running cells don't make the kernel look dead
+    tokio::spawn(async move {
+        while let Ok(ping) = heartbeat.recv().await {
+            if heartbeat.send(ping).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut kernel = Kernel {
+        vm: VM::new(),
+        key: connection["key"]
+            .as_str()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec(),
+        session: uuid::Uuid::new_v4().to_string(),
+        execution_count: 0,
+        iopub,
+    };
+
+    loop {
+        let (socket, message) = tokio::select! {
+            message = shell.recv() => (&mut shell, message),
+            message = control.recv() => (&mut control, message),
+        };
+
+        let request = match message.ok().and_then(|message| kernel.decode(message)) {
+            Some(request) => request,
+            None => continue,
+        };
+
+        if !kernel.handle(socket, request).await {
+            break;
+        }
+    }
+
+    // Give the last replies a chance to go out before the sockets close
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
@@ -0,0 +1,378 @@
+//! Which functions call which, worked out from the compiled bytecode without
+//! running it, written by `loxide callgraph file.lox` as a Graphviz dot
+//! graph.
+//!
+//! Each function's code is followed with a model of its stack that only
+//! knows where values came from. A call is in the graph when what it calls
+//! can be told from that: a function or class declared at the top level
+//! and called by its name, a function declared in the caller or one it's
+//! nested in, or a method invoked on `this` or `super`. A method on `this`
+//! is taken to be the one of the class it's written in, or of its closest
+//! superclass having it, so overrides in subclasses are missed. Calling a
+//! class calls its `init`. Calls of anything else, like a function passed
+//! as an argument, a method of another object or a native, aren't in it.
+//!
+//! Functions are named by where they're declared, like `fib`, `Point.init`,
+//! or `outer.helper` for a function declared inside `outer`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write},
+};
+
+use crate::{
+    chunk::{Instruction, Opcode},
+    compile::Parser,
+    mem::Mem,
+    obj::ObjFunction,
+};
+
+/// What a slot of the stack is known to hold
+#[derive(Debug, Clone, PartialEq)]
+enum Known {
+    /// Whatever the global with this name holds
+    Global(String),
+    Function(*const ObjFunction),
+    Class(String),
+    /// The instance a method of this class was called on
+    This(String),
+}
+
+/// What a call site calls, worked out once every global is known
+#[derive(Debug)]
+enum Callee {
+    Value(Known),
+    Method { class: String, name: String },
+}
+
+#[derive(Debug, Default)]
+struct Class {
+    superclass: Option<String>,
+    methods: HashMap<String, *const ObjFunction>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CallGraph {
+    /// Every function, the script first
+    pub functions: Vec<String>,
+    /// Caller and callee, as indices in `functions`, once each in the order
+    /// they're first found
+    pub calls: Vec<(usize, usize)>,
+}
+
+impl CallGraph {
+    /// The graph of `src`, or its first compile error
+    pub fn of_source(src: &str) -> Result<Self, String> {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        parser.print_errors = false;
+        if !parser.compile() {
+            return Err(parser.diagnostics[0].to_string());
+        }
+        let script = parser.compiler.function;
+        Ok(Self::of(script.as_ref()))
+    }
+
+    /// The graph of `script` and every function declared in it
+    pub fn of(script: &ObjFunction) -> Self {
+        Builder::default().build(script)
+    }
+
+    pub fn write_dot(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "digraph calls {{")?;
+        for name in &self.functions {
+            writeln!(out, "    {name:?};")?;
+        }
+        for &(caller, callee) in &self.calls {
+            writeln!(
+                out,
+                "    {:?} -> {:?};",
+                self.functions[caller], self.functions[callee]
+            )?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    /// Each function's index in [`CallGraph::functions`]
+    ids: HashMap<*const ObjFunction, usize>,
+    graph: CallGraph,
+    globals: HashMap<String, Known>,
+    classes: HashMap<String, Class>,
+    /// The class each method belongs to
+    method_classes: HashMap<*const ObjFunction, String>,
+    /// What's known of each function's upvalues, from where its closure
+    /// was made
+    upvalues: HashMap<*const ObjFunction, Vec<Option<Known>>>,
+    sites: Vec<(*const ObjFunction, Callee)>,
+}
+
+impl Builder {
+    fn build(mut self, script: &ObjFunction) -> CallGraph {
+        self.add(script, "script".to_string());
+        let mut queue = VecDeque::from([script as *const ObjFunction]);
+        while let Some(function) = queue.pop_front() {
+            // Safety: every function is held by the script's constants
+            let function = unsafe { &*function };
+            queue.extend(self.walk(function));
+        }
+
+        for (caller, callee) in std::mem::take(&mut self.sites) {
+            let callee = match callee {
+                Callee::Value(known) => self.resolve(&known),
+                Callee::Method { class, name } => self.method(&class, &name),
+            };
+            if let Some(callee) = callee {
+                let call = (self.ids[&caller], self.ids[&callee]);
+                if !self.graph.calls.contains(&call) {
+                    self.graph.calls.push(call);
+                }
+            }
+        }
+        self.graph
+    }
+
+    fn add(&mut self, function: *const ObjFunction, name: String) {
+        self.ids.insert(function, self.graph.functions.len());
+        self.graph.functions.push(name);
+    }
+
+    fn rename(&mut self, function: *const ObjFunction, name: String) {
+        if let Some(&id) = self.ids.get(&function) {
+            self.graph.functions[id] = name;
+        }
+    }
+
+    /// How names declared in `function` are prefixed
+    fn prefix(&self, function: *const ObjFunction) -> String {
+        match self.ids[&function] {
+            0 => String::new(),
+            id => format!("{}.", self.graph.functions[id]),
+        }
+    }
+
+    /// What `known` is, looking through globals set to other globals
+    fn follow<'a>(&'a self, mut known: &'a Known) -> &'a Known {
+        // Bounded, in case of globals set to each other
+        for _ in 0..=self.globals.len() {
+            match known {
+                Known::Global(name) => match self.globals.get(name) {
+                    Some(value) => known = value,
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+        known
+    }
+
+    /// The function `known` calls
+    fn resolve(&self, known: &Known) -> Option<*const ObjFunction> {
+        match self.follow(known) {
+            Known::Function(function) => Some(*function),
+            Known::Class(class) => self.method(class, "init"),
+            Known::Global(_) | Known::This(_) => None,
+        }
+    }
+
+    fn class_name<'a>(&'a self, known: &'a Known) -> Option<&'a str> {
+        match self.follow(known) {
+            Known::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    /// The method `name` of `class` or of its closest superclass with it
+    fn method(&self, class: &str, name: &str) -> Option<*const ObjFunction> {
+        let mut class = self.classes.get(class)?;
+        // Bounded, in case of a class inheriting from itself
+        for _ in 0..self.classes.len() {
+            if let Some(&method) = class.methods.get(name) {
+                return Some(method);
+            }
+            class = self.classes.get(class.superclass.as_ref()?)?;
+        }
+        None
+    }
+
+    /// Follow `function`'s code, returning the functions declared in it
+    fn walk(&mut self, function: &ObjFunction) -> Vec<*const ObjFunction> {
+        let this: *const ObjFunction = function;
+        let upvalues = self.upvalues.remove(&this).unwrap_or_default();
+        let mut stack: Vec<Option<Known>> =
+            vec![self.method_classes.get(&this).cloned().map(Known::This)];
+        let mut declared = vec![];
+
+        let chunk = &function.chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let byte = chunk.code[offset];
+            let Some(instruction) = chunk.disassemble_instruction(&mut offset) else {
+                break;
+            };
+            let Ok(op) = Opcode::try_from(byte) else {
+                break;
+            };
+
+            match (op, instruction) {
+                (Opcode::GetGlobal, Instruction::Constant(_, name)) => {
+                    stack.push(name.as_str().map(|name| Known::Global(name.to_string())))
+                }
+                (Opcode::DefineGlobal, Instruction::Constant(_, name)) => {
+                    if let (Some(known), Some(name)) = (pop(&mut stack, 1), name.as_str()) {
+                        self.globals.insert(name.to_string(), known);
+                    }
+                }
+                (Opcode::GetLocal, Instruction::Byte(_, slot)) => {
+                    stack.push(stack.get(slot as usize).cloned().flatten())
+                }
+                (Opcode::SetLocal, Instruction::Byte(_, slot)) => {
+                    let value = stack.last().cloned().flatten();
+                    if let Some(local) = stack.get_mut(slot as usize) {
+                        *local = value;
+                    }
+                }
+                (Opcode::GetUpvalue, Instruction::Byte(_, index)) => {
+                    stack.push(upvalues.get(index as usize).cloned().flatten())
+                }
+                (Opcode::Call, Instruction::Byte(_, args)) => {
+                    if let Some(callee) = pop(&mut stack, args as usize + 1) {
+                        self.sites.push((this, Callee::Value(callee)));
+                    }
+                    stack.push(None);
+                }
+                (Opcode::Invoke, Instruction::Invoke { method, arg_count }) => {
+                    if let (Some(Known::This(class)), Some(name)) =
+                        (pop(&mut stack, arg_count as usize + 1), method.as_str())
+                    {
+                        let name = name.to_string();
+                        self.sites.push((this, Callee::Method { class, name }));
+                    }
+                    stack.push(None);
+                }
+                (Opcode::SuperInvoke, Instruction::Invoke { method, arg_count }) => {
+                    let superclass = pop(&mut stack, 1);
+                    pop(&mut stack, arg_count as usize + 1);
+                    let class = superclass.as_ref().and_then(|known| self.class_name(known));
+                    if let (Some(class), Some(name)) = (class, method.as_str()) {
+                        let (class, name) = (class.to_string(), name.to_string());
+                        self.sites.push((this, Callee::Method { class, name }));
+                    }
+                    stack.push(None);
+                }
+                (
+                    Opcode::Closure,
+                    Instruction::Closure {
+                        function,
+                        upvalues: captured,
+                    },
+                ) => {
+                    let Some(closure) = function.as_fn() else {
+                        break;
+                    };
+                    let closure: *const ObjFunction = closure.as_ptr();
+                    let name = format!("{}{}", self.prefix(this), function_name(closure));
+                    self.add(closure, name);
+                    let known = captured
+                        .iter()
+                        .map(|upvalue| match upvalue.is_local {
+                            // A local function capturing itself
+                            true if upvalue.index as usize == stack.len() => {
+                                Some(Known::Function(closure))
+                            }
+                            true => stack.get(upvalue.index as usize).cloned().flatten(),
+                            false => upvalues.get(upvalue.index as usize).cloned().flatten(),
+                        })
+                        .collect();
+                    self.upvalues.insert(closure, known);
+                    declared.push(closure);
+                    stack.push(Some(Known::Function(closure)));
+                }
+                (Opcode::Class, Instruction::Constant(_, name)) => {
+                    let name = name.as_str().unwrap_or_default().to_string();
+                    self.classes.entry(name.clone()).or_default();
+                    stack.push(Some(Known::Class(name)));
+                }
+                (Opcode::Method, Instruction::Constant(_, name)) => {
+                    let method = pop(&mut stack, 1);
+                    let class = stack.last().cloned().flatten();
+                    let class = class.as_ref().and_then(|known| self.class_name(known));
+                    if let (Some(Known::Function(method)), Some(class), Some(name)) =
+                        (method, class, name.as_str())
+                    {
+                        let class = class.to_string();
+                        self.rename(method, format!("{}{class}.{name}", self.prefix(this)));
+                        if let Some(methods) = self.classes.get_mut(&class) {
+                            methods.methods.insert(name.to_string(), method);
+                        }
+                        self.method_classes.insert(method, class);
+                    }
+                }
+                (Opcode::Inherit, _) => {
+                    let subclass = pop(&mut stack, 1);
+                    let superclass = stack.last().cloned().flatten();
+                    let name = |known: Option<Known>| {
+                        known
+                            .as_ref()
+                            .and_then(|known| self.class_name(known))
+                            .map(str::to_string)
+                    };
+                    if let (Some(subclass), Some(superclass)) = (name(subclass), name(superclass)) {
+                        if let Some(class) = self.classes.get_mut(&subclass) {
+                            class.superclass = Some(superclass);
+                        }
+                    }
+                }
+                (Opcode::Unpack, Instruction::Byte(_, count)) => {
+                    pop(&mut stack, 1);
+                    stack.extend((0..count).map(|_| None));
+                }
+                (Opcode::BuildList, Instruction::Byte(_, count)) => {
+                    pop(&mut stack, count as usize);
+                    stack.push(None);
+                }
+                _ => {
+                    let (pops, pushes) = stack_effect(op);
+                    pop(&mut stack, pops);
+                    stack.extend((0..pushes).map(|_| None));
+                }
+            }
+        }
+        declared
+    }
+}
+
+/// Pop `n` values, returning what's known of the deepest of them
+fn pop(stack: &mut Vec<Option<Known>>, n: usize) -> Option<Known> {
+    let popped = stack.split_off(stack.len().saturating_sub(n));
+    popped.into_iter().next().flatten()
+}
+
+fn function_name(function: *const ObjFunction) -> String {
+    // Safety: every function is held by the script's constants
+    unsafe { &*function }.name_str().to_string()
+}
+
+/// How many values `op` pops, then how many it pushes, for the opcodes
+/// that don't need their operands to tell
+fn stack_effect(op: Opcode) -> (usize, usize) {
+    use Opcode::*;
+    match op {
+        Constant | Nil | True | False | Number | GetUpvalue | GetGlobal | Closure | Class
+        | GetLocal => (0, 1),
+        Pop | Print | CloseUpvalue | DefineGlobal | Return | TraceReturn => (1, 0),
+        Add | Subtract | Multiply | Divide | Equal | Greater | Less | IndexGet | Is
+        | SetProperty => (2, 1),
+        Negate | Not | GetProperty => (1, 1),
+        GetSuper => (2, 1),
+        IndexSet | Slice => (3, 1),
+        Method | Inherit => (1, 0),
+        SetGlobal | SetLocal | SetUpvalue | Jump | JumpIfFalse | JumpIfNil | Loop | TraceEnter => {
+            (0, 0)
+        }
+        // Handled with their operands
+        Call | Invoke | SuperInvoke | BuildList | Unpack => (0, 1),
+    }
+}
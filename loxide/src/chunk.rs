@@ -1,102 +1,265 @@
-use std::ops::Deref;
+use std::{
+    io::{self, Write},
+    ops::Deref,
+    rc::Rc,
+};
 
 use crate::{
+    color::{paint, Style},
     compile::Upvalue,
+    mem::{Gc, Mem, NumberPool},
+    obj::ObjFunction,
     value::{Value, ValueArray},
 };
 
+/// The instructions of a chunk. Their numbers are part of the `.loxb`
+/// format and of the public API, so they never change: a new opcode takes
+/// the next number, and a removed one leaves its number unused.
+#[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum Opcode {
     Return = 0,
-    Constant,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    DefineGlobal,
-    GetGlobal,
-    SetGlobal,
-    GetLocal,
-    SetLocal,
-    JumpIfFalse,
-    Jump,
-    Loop,
-    Call,
-    Closure,
-    GetUpvalue,
-    SetUpvalue,
-    CloseUpvalue,
-    Class,
-    GetProperty,
-    SetProperty,
-    Method,
-    Invoke,
-    Inherit,
-    GetSuper,
-    SuperInvoke,
+    Constant = 1,
+    Negate = 2,
+    Add = 3,
+    Subtract = 4,
+    Multiply = 5,
+    Divide = 6,
+    Nil = 7,
+    True = 8,
+    False = 9,
+    Not = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Print = 14,
+    Pop = 15,
+    DefineGlobal = 16,
+    GetGlobal = 17,
+    SetGlobal = 18,
+    GetLocal = 19,
+    SetLocal = 20,
+    JumpIfFalse = 21,
+    Jump = 22,
+    Loop = 23,
+    Call = 24,
+    Closure = 25,
+    GetUpvalue = 26,
+    SetUpvalue = 27,
+    CloseUpvalue = 28,
+    Class = 29,
+    GetProperty = 30,
+    SetProperty = 31,
+    Method = 32,
+    Invoke = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    SuperInvoke = 36,
+    BuildList = 37,
+    IndexGet = 38,
+    IndexSet = 39,
+    Number = 40,
+    Is = 41,
+    JumpIfNil = 42,
+    Unpack = 43,
+    Slice = 44,
+    /// Reports the call to [`VmEvents`](crate::events::VmEvents), only
+    /// put in by the [`CallTracing`](crate::passes::CallTracing) pass
+    TraceEnter = 45,
+    /// A `Return` reporting what it returns, from the same pass
+    TraceReturn = 46,
+}
+
+/// A byte that isn't any [`Opcode`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnknownOpcode(pub u8);
+
+impl std::fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown opcode {}", self.0)
+    }
 }
 
+impl std::error::Error for UnknownOpcode {}
+
 impl Opcode {
-    #[inline]
-    pub fn from_u8(val: u8) -> Option<Self> {
+    /// Every opcode, indexed by its number
+    pub const ALL: [Opcode; 47] = {
+        use Opcode::*;
+        [
+            Return,
+            Constant,
+            Negate,
+            Add,
+            Subtract,
+            Multiply,
+            Divide,
+            Nil,
+            True,
+            False,
+            Not,
+            Equal,
+            Greater,
+            Less,
+            Print,
+            Pop,
+            DefineGlobal,
+            GetGlobal,
+            SetGlobal,
+            GetLocal,
+            SetLocal,
+            JumpIfFalse,
+            Jump,
+            Loop,
+            Call,
+            Closure,
+            GetUpvalue,
+            SetUpvalue,
+            CloseUpvalue,
+            Class,
+            GetProperty,
+            SetProperty,
+            Method,
+            Invoke,
+            Inherit,
+            GetSuper,
+            SuperInvoke,
+            BuildList,
+            IndexGet,
+            IndexSet,
+            Number,
+            Is,
+            JumpIfNil,
+            Unpack,
+            Slice,
+            TraceEnter,
+            TraceReturn,
+        ]
+    };
+
+    /// Its name, as disassembly shows it
+    pub fn as_str(self) -> &'static str {
+        use Opcode::*;
+        match self {
+            Return => "Return",
+            Constant => "Constant",
+            Negate => "Negate",
+            Add => "Add",
+            Subtract => "Subtract",
+            Multiply => "Multiply",
+            Divide => "Divide",
+            Nil => "Nil",
+            True => "True",
+            False => "False",
+            Not => "Not",
+            Equal => "Equal",
+            Greater => "Greater",
+            Less => "Less",
+            Print => "Print",
+            Pop => "Pop",
+            DefineGlobal => "DefineGlobal",
+            GetGlobal => "GetGlobal",
+            SetGlobal => "SetGlobal",
+            GetLocal => "GetLocal",
+            SetLocal => "SetLocal",
+            JumpIfFalse => "JumpIfFalse",
+            Jump => "Jump",
+            Loop => "Loop",
+            Call => "Call",
+            Closure => "Closure",
+            GetUpvalue => "GetUpvalue",
+            SetUpvalue => "SetUpvalue",
+            CloseUpvalue => "CloseUpvalue",
+            Class => "Class",
+            GetProperty => "GetProperty",
+            SetProperty => "SetProperty",
+            Method => "Method",
+            Invoke => "Invoke",
+            Inherit => "Inherit",
+            GetSuper => "GetSuper",
+            SuperInvoke => "SuperInvoke",
+            BuildList => "BuildList",
+            IndexGet => "IndexGet",
+            IndexSet => "IndexSet",
+            Number => "Number",
+            Is => "Is",
+            JumpIfNil => "JumpIfNil",
+            Unpack => "Unpack",
+            Slice => "Slice",
+            TraceEnter => "TraceEnter",
+            TraceReturn => "TraceReturn",
+        }
+    }
+
+    /// How many bytes of operands follow it. `Closure` is followed by two
+    /// more for each of its function's upvalues.
+    pub fn operand_len(self) -> usize {
         use Opcode::*;
-        match val {
-            0 => Some(Return),
-            1 => Some(Constant),
-            2 => Some(Negate),
-            3 => Some(Add),
-            4 => Some(Subtract),
-            5 => Some(Multiply),
-            6 => Some(Divide),
-            7 => Some(Nil),
-            8 => Some(True),
-            9 => Some(False),
-            10 => Some(Not),
-            11 => Some(Equal),
-            12 => Some(Greater),
-            13 => Some(Less),
-            14 => Some(Print),
-            15 => Some(Pop),
-            16 => Some(DefineGlobal),
-            17 => Some(GetGlobal),
-            18 => Some(SetGlobal),
-            19 => Some(GetLocal),
-            20 => Some(SetLocal),
-            21 => Some(JumpIfFalse),
-            22 => Some(Jump),
-            23 => Some(Loop),
-            24 => Some(Call),
-            25 => Some(Closure),
-            26 => Some(GetUpvalue),
-            27 => Some(SetUpvalue),
-            28 => Some(CloseUpvalue),
-            29 => Some(Class),
-            30 => Some(GetProperty),
-            31 => Some(SetProperty),
-            32 => Some(Method),
-            33 => Some(Invoke),
-            34 => Some(Inherit),
-            35 => Some(GetSuper),
-            36 => Some(SuperInvoke),
-            _ => None,
+        match self {
+            CloseUpvalue | Pop | Print | Equal | Greater | Less | Not | True | False | Nil
+            | Add | Subtract | Multiply | Divide | Negate | Return | Inherit | IndexGet
+            | IndexSet | Slice | Is | TraceEnter | TraceReturn => 0,
+            Method | GetProperty | SetProperty | Class | Constant | DefineGlobal | GetGlobal
+            | SetGlobal | GetSuper | GetUpvalue | SetUpvalue | GetLocal | SetLocal | Call
+            | BuildList | Unpack | Number | Closure => 1,
+            Jump | JumpIfFalse | JumpIfNil | Loop | Invoke | SuperInvoke => 2,
         }
     }
 }
 
+impl TryFrom<u8> for Opcode {
+    type Error = UnknownOpcode;
+
+    #[inline]
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Opcode::ALL
+            .get(byte as usize)
+            .copied()
+            .ok_or(UnknownOpcode(byte))
+    }
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
+    /// Strings here are interned, so a name used all over a program is one
+    /// string object, and a chunk holds one constant per name it uses.
+    /// Closures share their function's chunk, so making them in a loop
+    /// doesn't copy any of this. Unlike numbers, names aren't pooled across
+    /// chunks: it would need wider operands to save one pointer per name per
+    /// function.
     pub constants: ValueArray,
+    /// Indices of the number literals in the [`NumberPool`](crate::mem::NumberPool),
+    /// by the operand of `Opcode::Number`
+    pub numbers: Vec<u32>,
+    /// The values of [`numbers`](Chunk::numbers), so `Opcode::Number` is a
+    /// single lookup
+    pub number_values: Vec<f64>,
     pub lines: Vec<u32>,
+    /// The column of each byte's token, or 0 where it isn't known
+    pub columns: Vec<u32>,
+    /// Names of the local variables, for the debugger
+    pub locals: Vec<LocalDebug>,
+    /// Names of the captured variables, by upvalue index
+    pub upvalue_names: Vec<String>,
+    /// The file it was compiled from, when it's one of several run in the
+    /// same VM
+    pub file: Option<Rc<str>>,
+    /// Compiled in strict mode, see [`Parser::strict`](crate::compile::Parser::strict).
+    /// `.loxb` files don't keep it.
+    pub strict: bool,
+}
+
+/// Where a local variable lives while it is in scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDebug {
+    pub name: String,
+    /// Relative to the call frame's slots
+    pub slot: u8,
+    /// The offset of the first instruction it is in scope for
+    pub start: u32,
+    /// The offset it goes out of scope at, `u32::MAX` if it lasts until the
+    /// function returns
+    pub end: u32,
 }
 
 impl Chunk {
@@ -104,8 +267,33 @@ impl Chunk {
         Self {
             code: vec![],
             constants: vec![],
+            numbers: vec![],
+            number_values: vec![],
             lines: vec![],
+            columns: vec![],
+            locals: vec![],
+            upvalue_names: vec![],
+            file: None,
+            strict: false,
+        }
+    }
+
+    /// The locals in scope at `offset`, innermost last. Shadowed ones are
+    /// left out.
+    pub fn locals_at(&self, offset: u32) -> Vec<&LocalDebug> {
+        let mut locals: Vec<&LocalDebug> = vec![];
+        for local in self.locals.iter() {
+            if local.start > offset || offset >= local.end {
+                continue;
+            }
+            match locals.iter_mut().find(|live| live.name == local.name) {
+                Some(live) if live.slot < local.slot => *live = local,
+                Some(_) => (),
+                None => locals.push(local),
+            }
         }
+        locals.sort_by_key(|local| local.slot);
+        locals
     }
 
     pub fn iter(&self) -> ChunkIter {
@@ -122,22 +310,88 @@ impl Chunk {
         })
     }
 
-    pub fn write(&mut self, op: u8, line: u32) {
+    pub fn write(&mut self, op: u8, line: u32, column: u32) {
         self.code.push(op);
         self.lines.push(line);
+        self.columns.push(column);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
-        let index = self.constants.len();
+    /// `[line 3:14]`, or just `[line 3]` without a column, for the
+    /// instruction at `offset`. `[main.lox:3:14]` when the chunk has a
+    /// [`file`](Chunk::file).
+    pub fn location(&self, offset: usize) -> String {
+        let line = self.lines[offset];
+        let column = self.columns.get(offset).copied().unwrap_or(0);
+        location(self.file.as_deref(), line, column)
+    }
+
+    /// Returns the constant's index, which may be too big for an operand
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
-        index.try_into().unwrap()
+        self.constants.len() - 1
+    }
+
+    /// Returns the operand that refers to the pooled number at `pool_index`,
+    /// whose value is `value`. Every use of a number in the chunk shares one.
+    pub fn add_number(&mut self, pool_index: u32, value: f64) -> usize {
+        if let Some(operand) = self.numbers.iter().position(|&i| i == pool_index) {
+            return operand;
+        }
+        self.numbers.push(pool_index);
+        self.number_values.push(value);
+        self.numbers.len() - 1
+    }
+
+    /// Write a listing of every instruction in the chunk, in the same
+    /// shape as clox's `disassembleChunk`
+    pub fn disassemble(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        self.disassemble_colored(name, out, false)
+    }
+
+    /// [`disassemble`](Chunk::disassemble), with the headers, offsets and
+    /// lines in color when `color` is set
+    pub fn disassemble_colored(
+        &self,
+        name: &str,
+        out: &mut dyn Write,
+        color: bool,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "{}",
+            paint(color, Style::Heading, &format!("== {name} =="))
+        )?;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let start = offset;
+            let line = self.lines[start];
+            let instr = match self.disassemble_instruction(&mut offset) {
+                Some(instr) => instr,
+                None => break,
+            };
+
+            let offset = paint(color, Style::Dim, &format!("{start:04}"));
+            if start > 0 && self.lines[start - 1] == line {
+                writeln!(out, "{offset}    | {instr:?}")?;
+            } else {
+                let line = paint(color, Style::Location, &format!("{line:4}"));
+                writeln!(out, "{offset} {line} {instr:?}")?;
+            }
+        }
+
+        writeln!(
+            out,
+            "{}",
+            paint(color, Style::Heading, &format!("== end {name} =="))
+        )
     }
 
     /// Dissamble instruction and increment offset to the start of
     /// the next one
     pub fn disassemble_instruction(&self, offset: &mut usize) -> Option<Instruction> {
         let instr = self.code[*offset];
-        let op = Opcode::from_u8(instr);
+        let op = Opcode::try_from(instr).ok();
         match op {
             Some(
                 Opcode::CloseUpvalue
@@ -156,7 +410,13 @@ impl Chunk {
                 | Opcode::Divide
                 | Opcode::Negate
                 | Opcode::Return
-                | Opcode::Inherit,
+                | Opcode::Inherit
+                | Opcode::IndexGet
+                | Opcode::IndexSet
+                | Opcode::Slice
+                | Opcode::Is
+                | Opcode::TraceEnter
+                | Opcode::TraceReturn,
             ) => {
                 *offset += 1;
                 Some(Instruction::Simple(op.unwrap()))
@@ -182,13 +442,16 @@ impl Chunk {
                 | Opcode::SetUpvalue
                 | Opcode::GetLocal
                 | Opcode::SetLocal
-                | Opcode::Call,
+                | Opcode::Call
+                | Opcode::BuildList
+                | Opcode::Unpack
+                | Opcode::Number,
             ) => {
                 let slot = self.code[*offset + 1];
                 *offset += 2;
                 Some(Instruction::Byte(op.unwrap(), slot))
             }
-            Some(Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop) => {
+            Some(Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::Loop) => {
                 let byte1 = self.code[*offset + 1];
                 let byte2 = self.code[*offset + 2];
                 *offset += 3;
@@ -228,6 +491,16 @@ impl Chunk {
     }
 }
 
+/// How [`Chunk::location`] and compile errors show where something is
+pub fn location(file: Option<&str>, line: u32, column: u32) -> String {
+    match (file, column) {
+        (None, 0) => format!("[line {line}]"),
+        (None, _) => format!("[line {line}:{column}]"),
+        (Some(file), 0) => format!("[{file}:{line}]"),
+        (Some(file), _) => format!("[{file}:{line}:{column}]"),
+    }
+}
+
 impl Deref for Chunk {
     type Target = Vec<u8>;
 
@@ -242,6 +515,105 @@ impl std::fmt::Debug for Chunk {
     }
 }
 
+/// A read-only look at a compiled function's bytecode, for tools that
+/// inspect it from outside the VM, see [`ObjFunction::chunk_view`]
+#[derive(Clone, Copy)]
+pub struct ChunkView<'a> {
+    function: &'a ObjFunction,
+}
+
+impl<'a> ChunkView<'a> {
+    pub fn new(function: &'a ObjFunction) -> Self {
+        Self { function }
+    }
+
+    /// `script` for the top level
+    pub fn name(&self) -> &'a str {
+        self.function.name_str()
+    }
+
+    pub fn arity(&self) -> u8 {
+        self.function.arity
+    }
+
+    pub fn upvalue_count(&self) -> u8 {
+        self.function.upvalue_count
+    }
+
+    pub fn code(&self) -> &'a [u8] {
+        &self.function.chunk.code
+    }
+
+    pub fn constants(&self) -> &'a [Value] {
+        &self.function.chunk.constants
+    }
+
+    /// The value of `Opcode::Number` with this operand, looked up in the
+    /// pool of the VM that compiled it
+    pub fn number(&self, operand: u8, numbers: &NumberPool) -> Option<f64> {
+        let &index = self.function.chunk.numbers.get(operand as usize)?;
+        Some(numbers.get(index))
+    }
+
+    /// The source line of each byte of code
+    pub fn lines(&self) -> &'a [u32] {
+        &self.function.chunk.lines
+    }
+
+    /// The line and column of the byte at `offset`, the column when it's
+    /// known
+    pub fn position(&self, offset: usize) -> Option<(u32, Option<u32>)> {
+        let chunk = &self.function.chunk;
+        let &line = chunk.lines.get(offset)?;
+        let column = chunk
+            .columns
+            .get(offset)
+            .copied()
+            .filter(|&column| column > 0);
+        Some((line, column))
+    }
+
+    pub fn locals(&self) -> &'a [LocalDebug] {
+        &self.function.chunk.locals
+    }
+
+    /// Every instruction and the offset it starts at
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, Instruction)> + 'a {
+        let chunk = &self.function.chunk;
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if offset >= chunk.code.len() {
+                return None;
+            }
+            let start = offset;
+            chunk
+                .disassemble_instruction(&mut offset)
+                .map(|instr| (start, instr))
+        })
+    }
+
+    /// The functions declared directly in this one, in the order of its
+    /// constants
+    pub fn functions(&self) -> impl Iterator<Item = ChunkView<'a>> + 'a {
+        self.constants().iter().filter_map(|constant| {
+            constant
+                .as_fn()
+                .map(|function| ChunkView::new(unsafe { &*function.as_ptr() }))
+        })
+    }
+}
+
+impl std::fmt::Debug for ChunkView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkView")
+            .field("name", &self.name())
+            .field("arity", &self.arity())
+            .field("code", &self.code().len())
+            .field("constants", &self.constants().len())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct InstructionDebug {
     pub line: u32,
@@ -329,3 +701,423 @@ impl<'a> Iterator for ChunkIterDebug<'a> {
         inner.map(|inner| InstructionDebug { inner, line })
     }
 }
+
+/// The start of a `.loxb` file: bytecode compiled by either loxide or zlox,
+/// that the other can load and run. Everything is little-endian:
+///
+/// ```text
+/// header    b"LOXB", u16 version, u16 flags
+/// function  the script, laid out as below
+///
+/// name      u32 length and utf-8 bytes, u32::MAX when it has none
+/// arity     u8
+/// upvalues  u8
+/// code      u32 length and bytes, jump operands big-endian as in clox
+/// lines     u32 for each byte of code
+/// columns   u32 for each byte of code, 0 where it isn't known
+/// constants u32 count, then each a tag and what it holds:
+///           0 nil, 1 false, 2 true, 3 f64 number, 4 string (as a name
+///           is), 5 function (as above)
+/// ```
+///
+/// Opcodes 0 to 36 are clox's, numbered as [`Opcode`] numbers them. Number
+/// literals are plain constants, the number pool only means something in
+/// the VM that made it.
+pub const LOXB_MAGIC: &[u8; 4] = b"LOXB";
+/// Bumped for any change an older reader would get wrong
+pub const LOXB_VERSION: u16 = 1;
+/// Set when the code uses `BuildList`, `IndexGet`, `IndexSet`, `Slice` or
+/// `Unpack`, which zlox doesn't have
+pub const LOXB_LISTS: u16 = 1 << 0;
+/// Set when the code uses `Is`, which zlox doesn't have either
+pub const LOXB_IS: u16 = 1 << 1;
+/// Set when the code uses `JumpIfNil`, for `?.` and `??`
+pub const LOXB_NIL_JUMPS: u16 = 1 << 2;
+
+const LOXB_SCRIPT_NAME: u32 = u32::MAX;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Write `script` and every function declared in it as a `.loxb` file.
+/// `numbers` is the pool its number literals were interned in.
+pub fn write_loxb(
+    script: &ObjFunction,
+    numbers: &NumberPool,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut body = vec![];
+    let mut flags = 0;
+    write_loxb_function(&mut body, script, numbers, &mut flags)?;
+
+    let mut buf = LOXB_MAGIC.to_vec();
+    buf.extend_from_slice(&LOXB_VERSION.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&body);
+    out.write_all(&buf)
+}
+
+fn write_loxb_function(
+    buf: &mut Vec<u8>,
+    function: &ObjFunction,
+    numbers: &NumberPool,
+    flags: &mut u16,
+) -> io::Result<()> {
+    match unsafe { function.name.as_ref() } {
+        Some(name) => put_loxb_bytes(buf, name.as_str().as_bytes()),
+        None => buf.extend_from_slice(&LOXB_SCRIPT_NAME.to_le_bytes()),
+    }
+    buf.push(function.arity);
+    buf.push(function.upvalue_count);
+
+    let chunk = &function.chunk;
+    let mut code = chunk.code.clone();
+    let mut constants = chunk.constants.clone();
+    let mut offset = 0;
+    while offset < code.len() {
+        let len = instruction_len(&code, offset, &constants, chunk.numbers.len())?;
+        match Opcode::try_from(code[offset]).ok() {
+            Some(
+                Opcode::BuildList
+                | Opcode::IndexGet
+                | Opcode::IndexSet
+                | Opcode::Slice
+                | Opcode::Unpack,
+            ) => *flags |= LOXB_LISTS,
+            Some(Opcode::Is) => *flags |= LOXB_IS,
+            Some(Opcode::JumpIfNil) => *flags |= LOXB_NIL_JUMPS,
+            Some(Opcode::Number) => {
+                let n = numbers.get(chunk.numbers[code[offset + 1] as usize]);
+                let index = match constants
+                    .iter()
+                    .position(|c| matches!(c, Value::Number(m) if m.to_bits() == n.to_bits()))
+                {
+                    Some(index) => index,
+                    None => {
+                        constants.push(Value::Number(n));
+                        constants.len() - 1
+                    }
+                };
+                code[offset] = Opcode::Constant as u8;
+                code[offset + 1] = u8::try_from(index).map_err(|_| {
+                    invalid("Too many constants and numbers in one chunk for .loxb")
+                })?;
+            }
+            _ => (),
+        }
+        offset += len;
+    }
+
+    put_loxb_bytes(buf, &code);
+    for &line in chunk.lines.iter() {
+        buf.extend_from_slice(&line.to_le_bytes());
+    }
+    for offset in 0..code.len() {
+        let column = chunk.columns.get(offset).copied().unwrap_or(0);
+        buf.extend_from_slice(&column.to_le_bytes());
+    }
+
+    buf.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+    for &constant in constants.iter() {
+        match constant {
+            Value::Nil => buf.push(0),
+            Value::Bool(false) => buf.push(1),
+            Value::Bool(true) => buf.push(2),
+            Value::Number(n) => {
+                buf.push(3);
+                buf.extend_from_slice(&n.to_le_bytes());
+            }
+            _ => match (constant.as_str(), constant.as_fn()) {
+                (Some(string), _) => {
+                    buf.push(4);
+                    put_loxb_bytes(buf, string.as_bytes());
+                }
+                (_, Some(nested)) => {
+                    buf.push(5);
+                    write_loxb_function(buf, &nested, numbers, flags)?;
+                }
+                _ => {
+                    return Err(invalid(
+                        "Only numbers, strings and functions can be .loxb constants",
+                    ))
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn put_loxb_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Load a `.loxb` file written by [`write_loxb`] or by zlox, returning the
+/// script. Its objects are allocated through `mem` without collecting, so
+/// nothing needs rooting until it runs.
+pub fn read_loxb(mem: &mut Mem, bytes: &[u8]) -> io::Result<Gc<ObjFunction>> {
+    let mut reader = LoxbReader { bytes, pos: 0 };
+    if reader.take(LOXB_MAGIC.len())? != LOXB_MAGIC {
+        return Err(invalid("Not a .loxb file"));
+    }
+    let version = reader.u16()?;
+    if version != LOXB_VERSION {
+        return Err(invalid(&format!(
+            "Unsupported .loxb version {version}, expected {LOXB_VERSION}"
+        )));
+    }
+    let flags = reader.u16()?;
+    if flags & !(LOXB_LISTS | LOXB_IS | LOXB_NIL_JUMPS) != 0 {
+        return Err(invalid("Unknown .loxb flags"));
+    }
+
+    let script = reader.function(mem)?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("Trailing bytes after the script"));
+    }
+    Ok(script)
+}
+
+struct LoxbReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LoxbReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(invalid(".loxb file is truncated"));
+        }
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self, len: u32) -> io::Result<&'a str> {
+        std::str::from_utf8(self.take(len as usize)?).map_err(|_| invalid("String isn't utf-8"))
+    }
+
+    fn function(&mut self, mem: &mut Mem) -> io::Result<Gc<ObjFunction>> {
+        let name = match self.u32()? {
+            LOXB_SCRIPT_NAME => std::ptr::null_mut(),
+            len => {
+                let name = self.str(len)?;
+                mem.copy_string(name).as_ptr()
+            }
+        };
+        let mut function = mem.alloc_obj(ObjFunction::new(name));
+        function.arity = self.u8()?;
+        function.upvalue_count = self.u8()?;
+
+        let len = self.u32()? as usize;
+        let code = self.take(len)?.to_vec();
+        let lines = (0..len).map(|_| self.u32()).collect::<io::Result<_>>()?;
+        let columns = (0..len).map(|_| self.u32()).collect::<io::Result<_>>()?;
+
+        let count = self.u32()?;
+        let mut constants = Vec::with_capacity(count.min(u8::MAX as u32 + 1) as usize);
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                0 => Value::Nil,
+                1 => Value::Bool(false),
+                2 => Value::Bool(true),
+                3 => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                4 => {
+                    let len = self.u32()?;
+                    let string = self.str(len)?;
+                    Value::Obj(mem.copy_string(string).cast())
+                }
+                5 => Value::Obj(self.function(mem)?.cast()),
+                _ => return Err(invalid("Unknown constant tag")),
+            };
+            constants.push(constant);
+        }
+
+        let mut offset = 0;
+        while offset < code.len() {
+            if code[offset] == Opcode::Number as u8 {
+                return Err(invalid("Number isn't a .loxb opcode"));
+            }
+            offset += instruction_len(&code, offset, &constants, 0)?;
+        }
+
+        function.chunk.code = code;
+        function.chunk.lines = lines;
+        function.chunk.columns = columns;
+        function.chunk.constants = constants;
+        verify(&function)?;
+        Ok(function)
+    }
+}
+
+/// Check that `function` can run without the VM reading or writing outside
+/// its frame, for code that didn't come from the compiler. Every path
+/// through the code is followed with the stack height it would have: no
+/// instruction may pop more than is there, reach a local that isn't, or
+/// push past the room a call makes for it, jumps have to land on an
+/// instruction, and paths have to agree on the height where they meet.
+///
+/// The functions among its constants are checked when they're loaded.
+pub(crate) fn verify(function: &ObjFunction) -> io::Result<()> {
+    let chunk = &function.chunk;
+    let code = &chunk.code[..];
+
+    // The length of the instruction starting at each offset
+    let mut lens = vec![0; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        lens[offset] = instruction_len(code, offset, &chunk.constants, chunk.numbers.len())?;
+        offset += lens[offset];
+    }
+
+    // Counting slot 0, which holds the function or `this`. A call leaves
+    // room for `U8_COUNT` values above the arguments.
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, function.arity as usize + 1)];
+    let frame_size = function.arity as usize + 1 + crate::vm::U8_COUNT;
+    while let Some((offset, height)) = pending.pop() {
+        if offset >= code.len() {
+            return Err(invalid("Code runs past its end"));
+        }
+        if lens[offset] == 0 {
+            return Err(invalid("Jump into the middle of an instruction"));
+        }
+        match heights[offset] {
+            Some(seen) if seen == height => continue,
+            Some(_) => return Err(invalid("Paths meet with different stack heights")),
+            None => heights[offset] = Some(height),
+        }
+
+        let op = Opcode::try_from(code[offset]).unwrap();
+        let operand = |i: usize| code[offset + i] as usize;
+        let next = offset + lens[offset];
+        let jump = || (operand(1) << 8) | operand(2);
+
+        use Opcode::*;
+        let (pops, pushes) = match op {
+            Constant | Number | Nil | True | False | GetGlobal | GetLocal | GetUpvalue | Class
+            | Closure => (0, 1),
+            Negate | Not | GetProperty => (1, 1),
+            Add | Subtract | Multiply | Divide | Equal | Greater | Less | Is | IndexGet
+            | SetProperty | GetSuper => (2, 1),
+            IndexSet | Slice => (3, 1),
+            Print | Pop | DefineGlobal | CloseUpvalue | Return | TraceReturn => (1, 0),
+            SetGlobal | SetLocal | SetUpvalue | JumpIfFalse | JumpIfNil => (1, 1),
+            Method | Inherit => (2, 1),
+            Call => (operand(1) + 1, 1),
+            Invoke => (operand(2) + 1, 1),
+            SuperInvoke => (operand(2) + 2, 1),
+            BuildList => (operand(1), 1),
+            Unpack => (1, operand(1)),
+            Jump | Loop | TraceEnter => (0, 0),
+        };
+        if pops > height {
+            return Err(invalid(&format!("Stack underflow at {offset}")));
+        }
+        let height = height - pops + pushes;
+        if height > frame_size {
+            return Err(invalid(&format!("Stack overflow at {offset}")));
+        }
+
+        let upvalue = |index: usize| match index < function.upvalue_count as usize {
+            true => Ok(()),
+            false => Err(invalid(&format!("Upvalue out of range at {offset}"))),
+        };
+        match op {
+            // `height` is after the push, so the new value doesn't count
+            GetLocal if operand(1) >= height - 1 => {
+                return Err(invalid(&format!("Local slot out of range at {offset}")))
+            }
+            SetLocal if operand(1) >= height => {
+                return Err(invalid(&format!("Local slot out of range at {offset}")))
+            }
+            GetUpvalue | SetUpvalue => upvalue(operand(1))?,
+            Closure => {
+                for capture in code[offset + 2..next].chunks(2) {
+                    match capture[0] {
+                        0 => upvalue(capture[1] as usize)?,
+                        // Before the closure is pushed
+                        1 if (capture[1] as usize) < height - 1 => (),
+                        _ => return Err(invalid(&format!("Bad capture at {offset}"))),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        match op {
+            Return | TraceReturn => (),
+            Jump => pending.push((next + jump(), height)),
+            Loop => match next.checked_sub(jump()) {
+                Some(target) => pending.push((target, height)),
+                None => return Err(invalid(&format!("Loop before the start at {offset}"))),
+            },
+            JumpIfFalse | JumpIfNil => {
+                pending.push((next + jump(), height));
+                pending.push((next, height));
+            }
+            _ => pending.push((next, height)),
+        }
+    }
+    Ok(())
+}
+
+/// The length of the instruction at `offset`, checking that its operands
+/// are there and that the constants and numbers they refer to exist
+fn instruction_len(
+    code: &[u8],
+    offset: usize,
+    constants: &[Value],
+    numbers: usize,
+) -> io::Result<usize> {
+    let operand = |i: usize| {
+        code.get(offset + i)
+            .copied()
+            .ok_or_else(|| invalid("Instruction is missing its operands"))
+    };
+    let constant = |i: usize| {
+        let index = operand(i)? as usize;
+        constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| invalid("Constant index out of range"))
+    };
+
+    let op = Opcode::try_from(code[offset]).map_err(|err| invalid(&err.to_string()))?;
+    let mut len = 1 + op.operand_len();
+    use Opcode::*;
+    match op {
+        Method | GetProperty | SetProperty | Class | Constant | DefineGlobal | GetGlobal
+        | SetGlobal | GetSuper | Invoke | SuperInvoke => {
+            constant(1)?;
+        }
+        Number => {
+            if operand(1)? as usize >= numbers {
+                return Err(invalid("Number index out of range"));
+            }
+        }
+        Closure => {
+            let function = constant(1)?
+                .as_fn()
+                .ok_or_else(|| invalid("Closure of a constant that isn't a function"))?;
+            len += 2 * function.upvalue_count as usize;
+        }
+        _ => (),
+    }
+    operand(len - 1)?;
+    Ok(len)
+}
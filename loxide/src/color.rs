@@ -0,0 +1,77 @@
+//! ANSI colors for compile errors, warnings, stack traces and bytecode
+//! listings. `loxide --color=auto`, the default, colors them when stderr is
+//! a terminal and `NO_COLOR` isn't set. Hosts embedding the VM get them
+//! uncolored unless they ask, with [`VmOptions::color`](crate::options::VmOptions::color).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// `auto`, `always` or `never`
+    pub fn parse(choice: &str) -> Option<Self> {
+        match choice {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to color what's written to stderr
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => stderr_is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn stderr_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+/// Without a way to tell, color only when asked to
+#[cfg(not(unix))]
+fn stderr_is_terminal() -> bool {
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Error,
+    Warning,
+    /// Where something is, like `[line 3:14]`
+    Location,
+    /// The part of a source line a diagnostic is about
+    Span,
+    /// What's there to give context, like the offsets in a listing
+    Dim,
+    Heading,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Error => "\x1b[1;31m",
+            Style::Warning => "\x1b[1;33m",
+            Style::Location => "\x1b[36m",
+            Style::Span => "\x1b[4;31m",
+            Style::Dim => "\x1b[2m",
+            Style::Heading => "\x1b[1m",
+        }
+    }
+}
+
+/// `text` in `style`, or as it is without `color`
+pub fn paint(color: bool, style: Style, text: &str) -> String {
+    match color {
+        true => format!("{}{text}\x1b[0m", style.code()),
+        false => text.to_string(),
+    }
+}
@@ -1,15 +1,29 @@
 use std::{
+    io,
     mem::MaybeUninit,
-    ptr::{addr_of_mut, null_mut, NonNull},
+    ptr::{self, addr_of_mut, null_mut, NonNull},
+    rc::Rc,
 };
 
+use fnv::{FnvHashMap, FnvHashSet};
+
 use crate::{
-    chunk::{Chunk, Opcode},
+    chunk::{location, Chunk, LocalDebug, Opcode},
+    color::{paint, Style},
+    errors::{json_string, ErrorCode, ErrorFormat},
     mem::{Gc, Mem},
+    native_fn,
     obj::ObjFunction,
+    passes::ChunkPass,
+    scanner::{number_value, Scanner, Span, Token, TokenKind},
     value::Value,
+    vm::U8_COUNT,
 };
 
+/// How deeply expressions can nest by default. Parsing them recurses, so
+/// this keeps something like ten thousand `(`s from overflowing the stack.
+pub const DEFAULT_MAX_EXPRESSION_DEPTH: usize = 256;
+
 #[derive(Debug, Clone, Copy)]
 struct ParseRuleCtx {
     can_assign: bool,
@@ -115,8 +129,8 @@ pub enum FunctionKindT<T> {
 }
 
 pub struct Locals<'src> {
-    stack: [MaybeUninit<Local<'src>>; u8::MAX as usize],
-    count: u8,
+    stack: [MaybeUninit<Local<'src>>; U8_COUNT],
+    count: usize,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -150,6 +164,10 @@ pub struct Compiler<'src> {
     locals: Locals<'src>,
     scope_depth: usize,
     upvalues: [MaybeUninit<Upvalue>; u8::MAX as usize],
+    /// The constant each name used so far was given, so a global referred to
+    /// over and over only takes up one. Names are interned, so they're keyed
+    /// by their address.
+    identifiers: FnvHashMap<*const str, u8>,
 }
 
 impl<'src> Compiler<'src> {
@@ -159,6 +177,8 @@ impl<'src> Compiler<'src> {
     pub fn new(
         function_kind: FunctionKindT<Token>,
         class_compiler: Option<Box<ClassCompiler>>,
+        // The interned name of `this`, for methods' first slot
+        this_name: &'src str,
         mem: &mut Mem,
     ) -> Self {
         let (function_name, function_kind) = match function_kind {
@@ -170,7 +190,9 @@ impl<'src> Compiler<'src> {
                 (mem.copy_string(prev_tok.msg).as_ptr(), FunctionKind::Method)
             }
             FunctionKindT::Script => (null_mut(), FunctionKind::Script),
-            FunctionKindT::Initializer => (null_mut(), FunctionKind::Initializer),
+            FunctionKindT::Initializer => {
+                (mem.copy_string("init").as_ptr(), FunctionKind::Initializer)
+            }
         };
 
         let function = mem.alloc_obj(ObjFunction::new(function_name));
@@ -181,11 +203,12 @@ impl<'src> Compiler<'src> {
             function,
             function_kind,
             locals: Locals {
-                stack: [Self::UNINTIALIZED_LOCAL; u8::MAX as usize],
+                stack: [Self::UNINTIALIZED_LOCAL; U8_COUNT],
                 count: 0,
             },
             scope_depth: 0,
             upvalues: [Self::UNINTIALIZED_UPVALUE; u8::MAX as usize],
+            identifiers: FnvHashMap::default(),
         };
 
         // Safety:
@@ -198,11 +221,13 @@ impl<'src> Compiler<'src> {
             (*local_ptr).name = Token {
                 kind: TokenKind::Nil,
                 line: 0,
+                column: 0,
                 msg: if function_kind != FunctionKind::Function {
-                    "this"
+                    this_name
                 } else {
                     ""
                 },
+                span: Span::EMPTY,
             };
         }
         this.locals.count += 1;
@@ -236,7 +261,7 @@ impl<'src> Compiler<'src> {
     }
 
     /// Add up value and return index in compiler's upvalue array
-    fn add_up_value(&mut self, index: u8, is_local: bool, errors: &mut Vec<&str>) -> u8 {
+    fn add_up_value(&mut self, index: u8, is_local: bool, errors: &mut Vec<ErrorCode>) -> u8 {
         unsafe {
             let upvalue_count = self.function.as_ref().upvalue_count;
 
@@ -255,7 +280,7 @@ impl<'src> Compiler<'src> {
             }
 
             if upvalue_count == u8::MAX {
-                errors.push("Too many closure variables in function.");
+                errors.push(ErrorCode::TooManyUpvalues);
                 return 0;
             }
 
@@ -273,40 +298,48 @@ impl<'src> Compiler<'src> {
     /// returns the index of the upvalue in its corresponding Compiler array
     ///
     /// this creates a chain of upvalues from this scope to the outer scope where the variable is
-    fn resolve_upvalue(&mut self, name: Token, errors: &mut Vec<&str>) -> Option<u8> {
+    fn resolve_upvalue(&mut self, name: Token, errors: &mut Vec<ErrorCode>) -> Option<u8> {
         let enclosing = match &mut self.enclosing {
             Some(enclosing) => enclosing,
             None => return None,
         };
 
-        match enclosing.resolve_local(name, errors) {
+        let upvalue = match enclosing.resolve_local(name, errors) {
             Some(local) => {
                 unsafe {
                     let local = enclosing.locals.stack[local as usize].assume_init_mut();
                     local.is_captured = true;
                 }
-                Some(self.add_up_value(local, true, errors))
+                self.add_up_value(local, true, errors)
             }
             // recurse
-            None => enclosing
-                .resolve_upvalue(name, errors)
-                .map(|index| self.add_up_value(index, false, errors)),
+            None => {
+                let index = enclosing.resolve_upvalue(name, errors)?;
+                self.add_up_value(index, false, errors)
+            }
+        };
+
+        let names = &mut self.current_chunk_mut().upvalue_names;
+        if names.len() == upvalue as usize {
+            names.push(name.msg.to_owned());
         }
+        Some(upvalue)
     }
 
-    fn resolve_local(&mut self, name: Token, errors: &mut Vec<&str>) -> Option<u8> {
+    fn resolve_local(&mut self, name: Token, errors: &mut Vec<ErrorCode>) -> Option<u8> {
         for (i, local) in self
             .locals
             .stack
             .iter()
             .enumerate()
-            .take(self.locals.count as usize)
+            .take(self.locals.count)
             .rev()
         {
             let local = unsafe { local.assume_init_ref() };
-            if local.name.msg == name.msg {
+            // The scanner interns names
+            if ptr::eq(local.name.msg, name.msg) {
                 if local.depth.is_none() {
-                    errors.push("Can't read local variable in its own initializer.");
+                    errors.push(ErrorCode::ReadInOwnInitializer);
                 }
                 return Some(i as u8);
             }
@@ -327,10 +360,160 @@ pub struct Parser<'a, 'src> {
 
     had_error: bool,
     panic_mode: bool,
+
+    /// Every compile error reported so far, in source order
+    pub diagnostics: Vec<Diagnostic>,
+    /// Warnings reported so far, they don't stop the script compiling
+    pub warnings: Vec<Diagnostic>,
+    /// Whether errors are also written to stderr as they are reported
+    pub print_errors: bool,
+    /// The file being compiled, for its errors and its functions'
+    /// [`Chunk::file`]
+    pub file: Option<Rc<str>>,
+    /// Whether errors are written in color, see [`Diagnostic::render`]
+    pub color: bool,
+    /// How errors are written, see [`Diagnostic::to_json`]
+    pub error_format: ErrorFormat,
+    /// Run over each function as it's finished, see [`passes`](crate::passes)
+    pub passes: Vec<Rc<dyn ChunkPass>>,
+    /// Warn about assignments to globals the program never declares, which
+    /// are most likely typos that only fail once the assignment runs
+    pub warn_undeclared: bool,
+    /// Names the program declares anywhere, interned by the scanner
+    declared: FnvHashSet<*const str>,
+    /// Every assignment to a global, checked once the whole program is seen
+    global_assignments: Vec<Token<'src>>,
+    /// Warn about functions and globals declared at the top level that
+    /// nothing the program runs ever reads, see [`Parser::check_unused`]
+    pub warn_unused: bool,
+    /// The names declared at the top level, with the warning for each if
+    /// it's unused
+    global_definitions: Vec<(Token<'src>, ErrorCode)>,
+    /// Every read of a global, with the top-level function or class whose
+    /// code it's in, or `None` for the script's own code
+    global_reads: Vec<(Option<*const str>, *const str)>,
+    /// The top-level function or class being compiled
+    global_owner: Option<*const str>,
+    /// Strict mode, for the whole VM or a file starting with a `//#strict`
+    /// directive: assignments to globals that are never declared are
+    /// errors, like every other warning, and the code's [`Chunk::strict`]
+    /// makes `==` and `!=` on different types and arithmetic that isn't
+    /// finite runtime errors
+    pub strict: bool,
+    /// Let a newline end a statement that's missing its `;`, if what's on
+    /// the next line can't continue it. A line starting with something
+    /// that can, like `(`, `[`, `-` or `.`, still does.
+    pub asi: bool,
+    /// Compile for a REPL: a trailing expression statement returns its value
+    /// from the script instead of discarding it, and may omit its `;`
+    pub repl: bool,
+    /// Names the next function compiled captures as its first upvalues, see
+    /// [`Parser::compile_reload`]
+    capture_first: Vec<&'src str>,
+    /// How deeply expressions can nest before it's a compile error
+    pub max_expression_depth: usize,
+    expression_depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// See [`Parser::file`]
+    pub file: Option<Rc<str>>,
+    pub line: u32,
+    /// Counting bytes from 1, like [`Token::column`]
+    pub column: u32,
+    /// The token the error is at
+    pub span: Span,
+    /// Where on the line the error is, e.g. " at end" or " at foo"
+    pub at: String,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:?}{}: {}",
+            location(self.file.as_deref(), self.line, self.column),
+            self.severity,
+            self.at,
+            self.message
+        )
+    }
+}
+
+impl Diagnostic {
+    /// How it's reported: as it displays, or in color with the line it's
+    /// on, `source_line`, underlined where it is
+    pub fn render(&self, source_line: Option<&str>, color: bool) -> String {
+        let (style, severity) = match self.severity {
+            Severity::Error => (Style::Error, "Error"),
+            Severity::Warning => (Style::Warning, "Warning"),
+        };
+        let mut out = format!(
+            "{} {}{}: {}",
+            paint(
+                color,
+                Style::Location,
+                &location(self.file.as_deref(), self.line, self.column)
+            ),
+            paint(color, style, severity),
+            self.at,
+            self.message
+        );
+        let Some(text) = source_line.filter(|_| color && self.column > 0) else {
+            return out;
+        };
+
+        let start = (self.column as usize - 1).min(text.len());
+        let end = (start + self.span.len() as usize).min(text.len());
+        let (before, rest) = text.split_at(start);
+        let (spanned, after) = rest.split_at(end - start);
+        let margin = format!("{:>5} | ", self.line);
+        out.push_str(&format!(
+            "\n{}{before}{}{after}",
+            paint(color, Style::Dim, &margin),
+            paint(color, Style::Span, spanned)
+        ));
+        let carets = "^".repeat((end - start).max(1));
+        out.push_str(&format!(
+            "\n{}{}{}",
+            paint(color, Style::Dim, &format!("{:>5} | ", "")),
+            " ".repeat(before.chars().count()),
+            paint(color, style, &carets)
+        ));
+        out
+    }
+
+    /// A line of JSON, see [`ErrorFormat::Json`]
+    pub fn to_json(&self) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        format!(
+            "{{\"severity\":\"{severity}\",\"code\":\"{}\",\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"span\":{{\"start\":{},\"end\":{}}}}}",
+            self.code.code(),
+            json_string(&self.message),
+            self.file.as_deref().map_or("null".to_string(), json_string),
+            self.line,
+            self.column,
+            self.span.start,
+            self.span.end
+        )
+    }
 }
 
 impl<'a, 'src: 'a> Parser<'a, 'src> {
-    pub const PARSE_RULES: [ParseRule<'a, 'src>; 40] = [
+    pub const PARSE_RULES: [ParseRule<'a, 'src>; 46] = [
         // left paren
         parse_rule!(pre = Parser::grouping, inf = Parser::call, Precedence::Call),
         // right paren
@@ -339,6 +522,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         none_prec!(),
         // right brace
         none_prec!(),
+        // left bracket
+        parse_rule!(pre = Parser::list, inf = Parser::index, Precedence::Call),
+        // right bracket
+        none_prec!(),
         // comma
         none_prec!(),
         // dot
@@ -353,14 +540,16 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(inf = Parser::binary, Precedence::Factor),
         // star
         parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // colon
+        none_prec!(),
         // bang
         parse_rule!(pre = Parser::unary, Precedence::None),
         // bangequal
         parse_rule!(inf = Parser::binary, Precedence::Equality),
         // equal
-        parse_rule!(inf = Parser::binary, Precedence::Equality),
+        none_prec!(),
         // equalequal
-        parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        parse_rule!(inf = Parser::binary, Precedence::Equality),
         // greater
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // greaterequal
@@ -369,6 +558,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // lessequal
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        // question dot
+        parse_rule!(inf = Parser::question_dot, Precedence::Call),
+        // question question
+        parse_rule!(inf = Parser::nil_coalesce, Precedence::Or),
         // identifier
         parse_rule!(pre = Parser::variable, Precedence::None),
         // string
@@ -389,12 +582,14 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         none_prec!(),
         // if
         none_prec!(),
+        // is
+        parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // nil
         parse_rule!(pre = Parser::literal, Precedence::None),
         // or
         parse_rule!(inf = Parser::or, Precedence::Or),
-        // print
-        none_prec!(),
+        // print, the native when it's used as a value
+        parse_rule!(pre = Parser::variable, Precedence::None),
         // return
         none_prec!(),
         // super
@@ -414,8 +609,13 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     ];
 
     pub fn new(src: &'src str, mem: &'a mut Mem) -> Self {
-        let scanner = Scanner::new(src);
-        let compiler = Box::new(Compiler::new(FunctionKindT::Script, None, mem));
+        Self::with_scanner(Scanner::new(src), mem)
+    }
+
+    pub fn with_scanner(scanner: Scanner<'src>, mem: &'a mut Mem) -> Self {
+        let mut scanner = scanner.interning();
+        let this = scanner.intern("this");
+        let compiler = Box::new(Compiler::new(FunctionKindT::Script, None, this, mem));
 
         Self {
             compiler,
@@ -425,7 +625,39 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             prev: MaybeUninit::uninit(),
             had_error: false,
             panic_mode: false,
-        }
+            diagnostics: vec![],
+            warnings: vec![],
+            print_errors: true,
+            file: None,
+            color: false,
+            error_format: ErrorFormat::Human,
+            passes: vec![],
+            warn_undeclared: false,
+            declared: FnvHashSet::default(),
+            global_assignments: vec![],
+            warn_unused: false,
+            global_definitions: vec![],
+            global_reads: vec![],
+            global_owner: None,
+            strict: false,
+            asi: false,
+            repl: false,
+            capture_first: vec![],
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            expression_depth: 0,
+        }
+    }
+
+    /// Why reading a streamed source failed, see [`Scanner::streaming`]. The
+    /// compile errors after it are from the source ending early.
+    pub fn read_error(&mut self) -> Option<io::Error> {
+        self.scanner.read_error()
+    }
+
+    /// A token for a name that isn't in the source, interned like the ones
+    /// that are
+    fn synthetic(&mut self, name: &'src str) -> Token<'src> {
+        Token::synthetic(self.scanner.intern(name))
     }
 
     #[inline]
@@ -439,16 +671,61 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     pub fn compile(&mut self) -> bool {
+        self.compiler.function.chunk.file = self.file.clone();
         self.advance();
+        self.strict |= self.scanner.strict;
+        self.compiler.function.chunk.strict = self.strict;
 
         while !self.match_tok(TokenKind::Eof) {
             self.declaration();
         }
 
+        if self.warn_undeclared || self.strict {
+            self.check_undeclared();
+        }
+        if self.warn_unused && !self.repl {
+            self.check_unused();
+        }
         self.end();
         !self.had_error
     }
 
+    /// Compile `src` as a single expression whose value the script returns,
+    /// with `locals` in scope as its first local slots, in order
+    pub fn compile_expression(&mut self, locals: &[&'src str]) -> bool {
+        self.compiler.scope_depth = 1;
+        for &name in locals {
+            let name = self.synthetic(name);
+            self.add_local(&name);
+            self.mark_initialized();
+        }
+
+        self.advance();
+        self.expression();
+        self.consume(TokenKind::Eof, ErrorCode::ExpectEndOfExpression);
+        self.emit_byte(Opcode::Return as u8);
+
+        self.end();
+        !self.had_error
+    }
+
+    /// Compile `src`, which declares a function, as if it were declared
+    /// where variables called `upvalues` are in scope. The first function in
+    /// it captures them as its first upvalues, in that order, whether it uses
+    /// them or not, so it can take the place of a function that captured
+    /// the same ones.
+    pub fn compile_reload(&mut self, upvalues: &[&'src str]) -> bool {
+        self.compiler.scope_depth = 1;
+        for &name in upvalues {
+            let name = self.synthetic(name);
+            self.add_local(&name);
+            self.mark_initialized();
+        }
+        self.capture_first = upvalues.to_vec();
+
+        self.compile()
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -467,7 +744,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
     }
 
-    fn handle_errors(&mut self, mut errors: Vec<&str>) {
+    fn handle_errors(&mut self, mut errors: Vec<ErrorCode>) {
         while let Some(err) = errors.pop() {
             self.error(err);
         }
@@ -491,7 +768,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         &Self::PARSE_RULES[kind as u8 as usize]
     }
 
-    fn named_variable(&mut self, name: Token, ctx: ParseRuleCtx) {
+    fn named_variable(&mut self, name: Token<'src>, ctx: ParseRuleCtx) {
         let (arg, get_op, set_op) = match self.resolve_local(name) {
             Some(arg) => (arg, Opcode::GetLocal as u8, Opcode::SetLocal as u8),
             None => self
@@ -507,9 +784,16 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         };
 
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            if set_op == Opcode::SetGlobal as u8 {
+                self.global_assignments.push(name);
+            }
             self.expression();
             self.emit_bytes(set_op, arg);
         } else {
+            if get_op == Opcode::GetGlobal as u8 {
+                self.global_reads
+                    .push((self.global_owner, name.msg as *const str));
+            }
             self.emit_bytes(get_op, arg);
         }
     }
@@ -531,28 +815,37 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn class_declaration(&mut self) {
-        self.consume(TokenKind::Identifier, "Expect class name.");
+        self.consume(TokenKind::Identifier, ErrorCode::ExpectClassName);
+        // `extension` is only special followed by a name, so it can still
+        // name a class
+        if self.prev().msg == "extension" && self.check(TokenKind::Identifier) {
+            self.class_extension();
+            return;
+        }
         let class_name = self.prev();
         let name_constant = self.identifier_constant(self.prev());
         self.declare_variable();
 
         self.emit_bytes(Opcode::Class as u8, name_constant);
         self.define_variable(name_constant);
+        // Its superclass is only used if it is
+        let owner = self.own_globals(class_name);
 
         self.compiler.class_compiler = Some(Box::new(ClassCompiler::new(
             self.compiler.class_compiler.take(),
         )));
 
         if self.match_tok(TokenKind::Less) {
-            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            self.consume(TokenKind::Identifier, ErrorCode::ExpectSuperclassName);
             self.variable(ParseRuleCtx { can_assign: false });
 
             if class_name.msg == self.prev().msg {
-                self.error("A class can't inherit from itself.");
+                self.error(ErrorCode::InheritFromSelf);
             }
 
             self.begin_scope();
-            self.add_local(&Token::synthetic("super"));
+            let super_ = self.synthetic("super");
+            self.add_local(&super_);
             self.define_variable(0);
 
             self.named_variable(class_name, ParseRuleCtx { can_assign: false });
@@ -566,12 +859,13 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         self.named_variable(class_name, ParseRuleCtx { can_assign: false });
 
-        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
+        self.consume(TokenKind::LeftBrace, ErrorCode::ExpectBraceBeforeClassBody);
         while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
             self.method();
         }
-        self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
+        self.consume(TokenKind::RightBrace, ErrorCode::ExpectBraceAfterClassBody);
         self.emit_byte(Opcode::Pop as u8);
+        self.global_owner = owner;
 
         if self
             .compiler
@@ -589,8 +883,39 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             .and_then(|cc| cc.enclosing);
     }
 
+    /// `class extension Name { ... }`, adding the methods to the existing
+    /// class `Name`, or replacing the ones it has with the same names.
+    /// Subclasses copied their methods when they were declared, so ones
+    /// declared before the extension don't see it.
+    fn class_extension(&mut self) {
+        self.consume(TokenKind::Identifier, ErrorCode::ExpectClassName);
+        let class_name = self.prev();
+        // Extending a class doesn't use it
+        let owner = self.own_globals(class_name);
+        self.named_variable(class_name, ParseRuleCtx { can_assign: false });
+
+        // The class's superclass isn't known here, so there's no `super`
+        self.compiler.class_compiler = Some(Box::new(ClassCompiler::new(
+            self.compiler.class_compiler.take(),
+        )));
+
+        self.consume(TokenKind::LeftBrace, ErrorCode::ExpectBraceBeforeClassBody);
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method();
+        }
+        self.consume(TokenKind::RightBrace, ErrorCode::ExpectBraceAfterClassBody);
+        self.emit_byte(Opcode::Pop as u8);
+        self.global_owner = owner;
+
+        self.compiler.class_compiler = self
+            .compiler
+            .class_compiler
+            .take()
+            .and_then(|cc| cc.enclosing);
+    }
+
     fn method(&mut self) {
-        self.consume(TokenKind::Identifier, "Expect method name.");
+        self.consume(TokenKind::Identifier, ErrorCode::ExpectMethodName);
         let constant = self.identifier_constant(self.prev());
 
         let mut kind = FunctionKind::Method;
@@ -602,9 +927,17 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn fn_declaration(&mut self) {
-        let global = self.parse_variable("Expect function name.");
+        let global = self.parse_variable(ErrorCode::ExpectFunctionName);
         self.mark_initialized();
+        let name = self.prev();
+        if self.compiler.scope_depth == 0 {
+            if let Some(definition) = self.global_definitions.last_mut() {
+                definition.1 = ErrorCode::UnusedFunction;
+            }
+        }
+        let owner = self.own_globals(name);
         self.function(FunctionKind::Function);
+        self.global_owner = owner;
         self.define_variable(global);
     }
 
@@ -616,7 +949,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn or(&mut self, _ctx: ParseRuleCtx) {
-        let else_jump = self.emit_jump(Opcode::Jump as u8);
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         let end_jump = self.emit_jump(Opcode::Jump as u8);
 
         self.patch_jump(else_jump);
@@ -628,8 +961,17 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn number(&mut self, _ctx: ParseRuleCtx) {
-        let value: f64 = self.prev().msg.parse().unwrap();
-        self.emit_constant(value.into())
+        let value = number_value(self.prev().msg);
+        let pool_index = self.mem.numbers.intern(value);
+        let operand = self
+            .compiler
+            .current_chunk_mut()
+            .add_number(pool_index, value);
+        if operand > u8::MAX as usize {
+            self.error(ErrorCode::TooManyNumbers);
+            return;
+        }
+        self.emit_bytes(Opcode::Number as u8, operand as u8)
     }
 
     fn string(&mut self, _ctx: ParseRuleCtx) {
@@ -652,7 +994,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn grouping(&mut self, _ctx: ParseRuleCtx) {
         self.expression();
-        self.consume(TokenKind::RightParen, "Expect ')' after expression.")
+        self.consume(TokenKind::RightParen, ErrorCode::ExpectParenAfterExpression)
     }
 
     fn call(&mut self, _ctx: ParseRuleCtx) {
@@ -662,26 +1004,125 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn argument_list(&mut self) -> u8 {
         let mut arg_count = 0;
-        if !self.check(TokenKind::RightParen) {
-            loop {
-                self.expression();
+        self.comma_list(
+            TokenKind::RightParen,
+            ErrorCode::ExpectParenAfterArguments,
+            |parser| {
+                parser.expression();
                 if arg_count == u8::MAX {
-                    self.error("Can't have more than 255 arguments");
+                    parser.error(ErrorCode::TooManyArguments);
+                } else {
+                    arg_count += 1;
                 }
-                arg_count += 1;
-                if !self.match_tok(TokenKind::Comma) {
+            },
+        );
+        arg_count
+    }
+
+    fn list(&mut self, _ctx: ParseRuleCtx) {
+        let mut item_count = 0;
+        self.comma_list(
+            TokenKind::RightBracket,
+            ErrorCode::ExpectBracketAfterListItems,
+            |parser| {
+                parser.expression();
+                if item_count == u8::MAX {
+                    parser.error(ErrorCode::TooManyListItems);
+                } else {
+                    item_count += 1;
+                }
+            },
+        );
+        self.emit_bytes(Opcode::BuildList as u8, item_count);
+    }
+
+    /// Items parsed by `item`, separated by commas and maybe followed by
+    /// one, then `close`, or `code` if something else follows an item.
+    /// After an error in an item the rest of it is skipped, so the ones
+    /// after it still get their errors reported.
+    fn comma_list(&mut self, close: TokenKind, code: ErrorCode, mut item: impl FnMut(&mut Self)) {
+        if !self.check(close) {
+            loop {
+                let start = self.cur().span.start;
+                item(self);
+                let prev = self.prev();
+                if self.panic_mode && prev.kind == TokenKind::Comma && prev.span.start >= start {
+                    // The error was at the comma ending the item, like in
+                    // `f(1 +, 2)`
+                    self.panic_mode = false;
+                } else {
+                    if !self.check(close) && !self.check(TokenKind::Comma) {
+                        self.error_at_current(code);
+                    }
+                    self.skip_bad_item(close);
+                    if !self.match_tok(TokenKind::Comma) {
+                        break;
+                    }
+                }
+                if self.check(close) {
                     break;
                 }
             }
         }
+        self.consume(close, code);
+    }
 
-        self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
+    /// Skip to the `,` or `close` ending a list item with an error in it and
+    /// stop panicking, unless the list isn't closed before the statement
+    /// ends, which [`Parser::synchronize`] recovers from instead
+    fn skip_bad_item(&mut self, close: TokenKind) {
+        if !self.panic_mode {
+            return;
+        }
+        let mut depth = 0usize;
+        loop {
+            use TokenKind::*;
+            match self.cur().kind {
+                kind if depth == 0 && (kind == close || kind == Comma) => break,
+                LeftParen | LeftBracket => depth += 1,
+                RightParen | RightBracket if depth > 0 => depth -= 1,
+                Semicolon | LeftBrace | RightBrace | Eof => return,
+                _ => (),
+            }
+            self.advance();
+        }
+        self.panic_mode = false;
+    }
 
-        arg_count
+    fn index(&mut self, ctx: ParseRuleCtx) {
+        // `xs[start:end]`, where either can be left out
+        if self.match_tok(TokenKind::Colon) {
+            self.emit_byte(Opcode::Nil as u8);
+            self.slice_end();
+            return;
+        }
+        self.expression();
+        if self.match_tok(TokenKind::Colon) {
+            self.slice_end();
+            return;
+        }
+        self.consume(TokenKind::RightBracket, ErrorCode::ExpectBracketAfterIndex);
+
+        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.expression();
+            self.emit_byte(Opcode::IndexSet as u8);
+        } else {
+            self.emit_byte(Opcode::IndexGet as u8);
+        }
+    }
+
+    fn slice_end(&mut self) {
+        if self.check(TokenKind::RightBracket) {
+            self.emit_byte(Opcode::Nil as u8);
+        } else {
+            self.expression();
+        }
+        self.consume(TokenKind::RightBracket, ErrorCode::ExpectBracketAfterIndex);
+        self.emit_byte(Opcode::Slice as u8);
     }
 
     fn dot(&mut self, ctx: ParseRuleCtx) {
-        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+        self.consume(TokenKind::Identifier, ErrorCode::ExpectPropertyName);
         let name = self.identifier_constant(self.prev());
 
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
@@ -696,6 +1137,34 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
     }
 
+    /// `a?.b`, which is nil if `a` is, skipping the rest of the chain after
+    /// it too, like `.c(d)[e]` in `a?.b.c(d)[e]`
+    fn question_dot(&mut self, _ctx: ParseRuleCtx) {
+        let ctx = ParseRuleCtx { can_assign: false };
+        let nil_jump = self.emit_jump(Opcode::JumpIfNil as u8);
+        self.dot(ctx);
+        while Precedence::Call as u8 <= Self::get_rule(self.cur().kind).precedence as u8 {
+            self.advance();
+            if let Some(infix_rule) = Self::get_rule(self.prev().kind).infix {
+                infix_rule(self, ctx);
+            }
+        }
+        self.patch_jump(nil_jump);
+    }
+
+    /// `a ?? b`, which is `b` only if `a` is nil
+    fn nil_coalesce(&mut self, _ctx: ParseRuleCtx) {
+        let else_jump = self.emit_jump(Opcode::JumpIfNil as u8);
+        let end_jump = self.emit_jump(Opcode::Jump as u8);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
     fn unary(&mut self, _ctx: ParseRuleCtx) {
         let op_kind = self.prev().kind;
 
@@ -713,12 +1182,27 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         let rule = Self::get_rule(op_kind);
         self.parse_precedence(Precedence::from_u8(rule.precedence as u8 + 1).unwrap());
 
+        // `a < b < c` is `(a < b) < c`, never what was meant
+        let is_comparison = |kind| {
+            matches!(
+                kind,
+                TokenKind::Greater
+                    | TokenKind::GreaterEqual
+                    | TokenKind::Less
+                    | TokenKind::LessEqual
+            )
+        };
+        if is_comparison(op_kind) && is_comparison(self.cur().kind) {
+            self.warning_at(self.cur(), ErrorCode::ChainedComparison);
+        }
+
         match op_kind {
             TokenKind::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
             TokenKind::EqualEqual => self.emit_byte(Opcode::Equal as u8),
             TokenKind::Greater => self.emit_byte(Opcode::Greater as u8),
             TokenKind::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
             TokenKind::Less => self.emit_byte(Opcode::Less as u8),
+            TokenKind::Is => self.emit_byte(Opcode::Is as u8),
             TokenKind::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
             TokenKind::Plus => self.emit_byte(Opcode::Add as u8),
             TokenKind::Minus => self.emit_byte(Opcode::Subtract as u8),
@@ -737,35 +1221,47 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         };
 
         let temp = self.compiler.class_compiler.take();
+        let this = self.scanner.intern("this");
         let temp_compiler = std::mem::replace(
             &mut self.compiler,
-            Box::new(Compiler::new(kindt, temp, self.mem)),
+            Box::new(Compiler::new(kindt, temp, this, self.mem)),
         );
         self.compiler.enclosing = Some(temp_compiler);
+        self.compiler.function.chunk.file = self.file.clone();
+        self.compiler.function.chunk.strict = self.strict;
 
         self.begin_scope();
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
-        if !self.check(TokenKind::RightParen) {
-            loop {
-                match self.compiler.current_fn().arity.checked_add(1) {
+        for name in std::mem::take(&mut self.capture_first) {
+            let name = self.synthetic(name);
+            self.resolve_upvalue(name);
+        }
+
+        self.consume(
+            TokenKind::LeftParen,
+            ErrorCode::ExpectParenAfterFunctionName,
+        );
+        self.comma_list(
+            TokenKind::RightParen,
+            ErrorCode::ExpectParenAfterParameters,
+            |parser| {
+                match parser.compiler.current_fn().arity.checked_add(1) {
                     Some(new_arity) => {
-                        self.compiler.current_fn_mut().arity = new_arity;
+                        parser.compiler.current_fn_mut().arity = new_arity;
                     }
                     None => {
-                        self.error_at_current("Can't have more than 255 parameters");
+                        parser.error_at_current(ErrorCode::TooManyParameters);
                     }
                 };
 
-                let constant = self.parse_variable("Expect parameter name.");
-                self.define_variable(constant);
-                if !self.match_tok(TokenKind::Comma) {
-                    break;
-                }
-            }
-        }
-        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
-        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+                let constant = parser.parse_variable(ErrorCode::ExpectParameterName);
+                parser.define_variable(constant);
+            },
+        );
+        self.consume(
+            TokenKind::LeftBrace,
+            ErrorCode::ExpectBraceBeforeFunctionBody,
+        );
 
         self.block();
 
@@ -797,7 +1293,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn var_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
+        if self.match_tok(TokenKind::LeftParen) {
+            self.destructuring_declaration();
+            return;
+        }
+        let global = self.parse_variable(ErrorCode::ExpectVariableName);
 
         if self.match_tok(TokenKind::Equal) {
             self.expression();
@@ -805,16 +1305,46 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.emit_byte(Opcode::Nil as u8);
         }
 
-        self.consume(
-            TokenKind::Semicolon,
-            "Expect ';' after variable declaration.",
-        );
+        self.consume_semicolon(ErrorCode::ExpectSemicolonAfterVariable);
 
         self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, err_msg: &str) -> u8 {
-        self.consume(TokenKind::Identifier, err_msg);
+    /// `var (a, b) = list;`, declaring a variable for each item of a list
+    /// with exactly that many, like the one `return a, b;` returns
+    fn destructuring_declaration(&mut self) {
+        let mut globals = vec![];
+        loop {
+            globals.push(self.parse_variable(ErrorCode::ExpectVariableName));
+            if !self.match_tok(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightParen, ErrorCode::ExpectParenAfterVariables);
+        self.consume(TokenKind::Equal, ErrorCode::ExpectEqualAfterVariables);
+        self.expression();
+        self.consume_semicolon(ErrorCode::ExpectSemicolonAfterVariable);
+
+        if globals.len() > u8::MAX as usize {
+            self.error(ErrorCode::TooManyLocals);
+            return;
+        }
+        // The items land in the locals' slots, in order
+        self.emit_bytes(Opcode::Unpack as u8, globals.len() as u8);
+        if self.compiler.scope_depth > 0 {
+            let count = self.compiler.locals.count;
+            for slot in count - globals.len().min(count)..count {
+                self.mark_slot_initialized(slot);
+            }
+            return;
+        }
+        for &global in globals.iter().rev() {
+            self.emit_bytes(Opcode::DefineGlobal as u8, global);
+        }
+    }
+
+    fn parse_variable(&mut self, code: ErrorCode) -> u8 {
+        self.consume(TokenKind::Identifier, code);
 
         self.declare_variable();
         if self.compiler.scope_depth > 0 {
@@ -829,7 +1359,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             .locals
             .stack
             .iter()
-            .take(self.compiler.locals.count as usize)
+            .take(self.compiler.locals.count)
             .rev()
         {
             let local = unsafe { local.assume_init_ref() };
@@ -845,14 +1375,17 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
 
         if had_error {
-            self.error("Already a variable with this name in this scope.");
+            self.error(ErrorCode::AlreadyDeclared);
         }
 
         self.identifier_constant(name)
     }
 
     fn declare_variable(&mut self) {
+        self.declared.insert(self.prev().msg);
         if self.compiler.scope_depth == 0 {
+            self.global_definitions
+                .push((self.prev(), ErrorCode::UnusedGlobal));
             return;
         }
 
@@ -860,12 +1393,12 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn add_local(&mut self, tok: &Token<'src>) {
-        if self.compiler.locals.count == u8::MAX {
-            self.error("Too many local variables in function.");
+        if self.compiler.locals.count == U8_COUNT {
+            self.error(ErrorCode::TooManyLocals);
             return;
         }
 
-        let local = self.compiler.locals.stack[self.compiler.locals.count as usize].as_mut_ptr();
+        let local = self.compiler.locals.stack[self.compiler.locals.count].as_mut_ptr();
         self.compiler.locals.count += 1;
 
         unsafe {
@@ -888,17 +1421,33 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         if self.compiler.scope_depth == 0 {
             return;
         }
+        self.mark_slot_initialized(self.compiler.locals.count - 1);
+    }
+
+    fn mark_slot_initialized(&mut self, slot: usize) {
         let scope_depth = self.compiler.scope_depth;
-        unsafe {
-            self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
-                .assume_init_mut()
-                .depth = Some(scope_depth as u32);
-        }
+        let local = unsafe { self.compiler.locals.stack[slot].assume_init_mut() };
+        local.depth = Some(scope_depth as u32);
+
+        let name = local.name.msg.to_owned();
+        let chunk = self.compiler.current_chunk_mut();
+        chunk.locals.push(LocalDebug {
+            name,
+            slot: slot as u8,
+            start: chunk.code.len() as u32,
+            end: u32::MAX,
+        });
     }
 
-    fn identifier_constant(&mut self, name: Token) -> u8 {
+    fn identifier_constant(&mut self, name: Token<'src>) -> u8 {
+        if let Some(&constant) = self.compiler.identifiers.get(&(name.msg as *const str)) {
+            return constant;
+        }
+
         let constant = Value::Obj(self.mem.copy_string(name.msg).cast());
-        self.make_constant(constant)
+        let constant = self.make_constant(constant);
+        self.compiler.identifiers.insert(name.msg, constant);
+        constant
     }
 
     fn statement(&mut self) {
@@ -926,7 +1475,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         let offset = self.compiler.current_chunk().len() - loop_start + 2;
         if offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+            self.error(ErrorCode::LoopTooLarge);
         }
 
         self.emit_byte(((offset as u16) >> 8) as u8);
@@ -936,7 +1485,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn for_statement(&mut self) {
         self.begin_scope();
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        self.consume(TokenKind::LeftParen, ErrorCode::ExpectParenAfterFor);
 
         // Handle the initializer caluse
         if self.match_tok(TokenKind::Semicolon) {
@@ -952,7 +1501,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         // Handle the loop condition
         let exit_jump = if !self.match_tok(TokenKind::Semicolon) {
             self.expression();
-            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.");
+            self.consume(
+                TokenKind::Semicolon,
+                ErrorCode::ExpectSemicolonAfterLoopCondition,
+            );
 
             let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
             self.emit_byte(Opcode::Pop as u8);
@@ -972,7 +1524,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             // discard value from increment caluse
             self.emit_byte(Opcode::Pop as u8);
 
-            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+            self.consume(TokenKind::RightParen, ErrorCode::ExpectParenAfterForClauses);
 
             self.emit_loop(loop_start);
             loop_start = increment_start;
@@ -993,9 +1545,9 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn while_statement(&mut self) {
         let loop_start = self.compiler.current_chunk().len();
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
+        self.consume(TokenKind::LeftParen, ErrorCode::ExpectParenAfterWhile);
         self.expression();
-        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+        self.consume(TokenKind::RightParen, ErrorCode::ExpectParenAfterCondition);
 
         let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit_byte(Opcode::Pop as u8);
@@ -1006,28 +1558,39 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.emit_byte(Opcode::Pop as u8);
     }
 
+    /// At the top level this ends the script, and the value is its exit
+    /// status
     fn return_statement(&mut self) {
-        if self.compiler.function_kind == FunctionKind::Script {
-            self.error("Can't return from top-level code.");
-        }
-
-        if self.match_tok(TokenKind::Semicolon) {
+        if self.match_tok(TokenKind::Semicolon) || self.semicolon_inserted() {
             self.emit_return();
         } else {
             if self.compiler.function_kind == FunctionKind::Initializer {
-                self.error("Can't return a value from an initializer.");
+                self.error(ErrorCode::ReturnFromInitializer);
             }
 
             self.expression();
-            self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+            // `return a, b;` returns the list `[a, b]`
+            let mut value_count = 1;
+            while self.match_tok(TokenKind::Comma) {
+                self.expression();
+                if value_count == u8::MAX {
+                    self.error(ErrorCode::TooManyReturnValues);
+                } else {
+                    value_count += 1;
+                }
+            }
+            if value_count > 1 {
+                self.emit_bytes(Opcode::BuildList as u8, value_count);
+            }
+            self.consume_semicolon(ErrorCode::ExpectSemicolonAfterReturnValue);
             self.emit_byte(Opcode::Return as u8);
         }
     }
 
     fn if_statement(&mut self) {
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
+        self.consume(TokenKind::LeftParen, ErrorCode::ExpectParenAfterIf);
         self.expression();
-        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+        self.consume(TokenKind::RightParen, ErrorCode::ExpectParenAfterCondition);
 
         // then_jump -> pop -> then stmt -> else_jump -> pop -> else
         let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
@@ -1064,7 +1627,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         let jump = self.compiler.current_chunk().len() as u32 - offset - 2;
 
         if jump > u16::MAX as u32 {
-            self.error("Too much code to jump over.");
+            self.error(ErrorCode::JumpTooLarge);
         }
 
         self.compiler.current_chunk_mut().code[offset as usize] = (jump >> 8) as u8;
@@ -1076,7 +1639,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.declaration()
         }
 
-        self.consume(TokenKind::RightBrace, "Expect '}' after block.")
+        self.consume(TokenKind::RightBrace, ErrorCode::ExpectBraceAfterBlock)
     }
 
     fn begin_scope(&mut self) {
@@ -1087,7 +1650,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         while self.compiler.locals.count > 0
             && unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+                self.compiler.locals.stack[self.compiler.locals.count - 1]
                     .assume_init_ref()
                     .depth
                     .map(|val| val as isize)
@@ -1095,7 +1658,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             } > self.compiler.scope_depth as isize
         {
             let is_captured = unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+                self.compiler.locals.stack[self.compiler.locals.count - 1]
                     .assume_init_ref()
                     .is_captured
             };
@@ -1105,6 +1668,18 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 Opcode::Pop as u8
             });
             self.compiler.locals.count -= 1;
+
+            let slot = self.compiler.locals.count as u8;
+            let chunk = self.compiler.current_chunk_mut();
+            let end = chunk.code.len() as u32;
+            if let Some(local) = chunk
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|local| local.slot == slot && local.end == u32::MAX)
+            {
+                local.end = end;
+            }
         }
     }
 
@@ -1119,14 +1694,42 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn print_statement(&mut self) {
         self.expression();
-        self.consume(TokenKind::Semicolon, "Expect ';' after value.");
+        self.consume_semicolon(ErrorCode::ExpectSemicolonAfterValue);
         self.emit_byte(Opcode::Print as u8)
     }
 
     fn expression_statement(&mut self) {
         self.expression();
-        self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(Opcode::Pop as u8)
+        self.end_expression_statement();
+    }
+
+    fn end_expression_statement(&mut self) {
+        if !(self.repl && self.check(TokenKind::Eof)) {
+            self.consume_semicolon(ErrorCode::ExpectSemicolonAfterExpression);
+        }
+
+        if self.repl && self.check(TokenKind::Eof) && self.compiler.enclosing.is_none() {
+            self.emit_byte(Opcode::Return as u8)
+        } else {
+            self.emit_byte(Opcode::Pop as u8)
+        }
+    }
+
+    /// The `;` ending a statement, which can be left out in [`Parser::asi`]
+    /// mode where [`Parser::semicolon_inserted`]
+    fn consume_semicolon(&mut self, code: ErrorCode) {
+        if !self.match_tok(TokenKind::Semicolon) && !self.semicolon_inserted() {
+            self.error_at_current(code);
+        }
+    }
+
+    /// Whether a statement can end here without a `;` in [`Parser::asi`]
+    /// mode: the next token is on a later line, or closes the block, or
+    /// there's nothing after it.
+    fn semicolon_inserted(&self) -> bool {
+        self.asi
+            && (self.cur().line > self.prev().line
+                || matches!(self.cur().kind, TokenKind::RightBrace | TokenKind::Eof))
     }
 
     fn check(&self, kind: TokenKind) -> bool {
@@ -1135,6 +1738,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn end(&mut self) {
         self.emit_return();
+        if !self.had_error {
+            for pass in &self.passes {
+                pass.run(self.compiler.current_chunk_mut());
+            }
+        }
         #[cfg(debug_assertions)]
         {
             if !self.had_error {
@@ -1180,7 +1788,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn emit_byte(&mut self, byte: u8) {
         self.compiler
             .current_chunk_mut()
-            .write(byte, self.prev().line)
+            .write(byte, self.prev().line, self.prev().column)
     }
 
     fn emit_bytes(&mut self, a: u8, b: u8) {
@@ -1195,21 +1803,21 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn make_constant(&mut self, value: Value) -> u8 {
         let constant_idx = self.compiler.current_chunk_mut().add_constant(value);
-        if constant_idx >= u8::MAX {
-            self.error("Too many constants in one chunk");
+        if constant_idx > u8::MAX as usize {
+            self.error(ErrorCode::TooManyConstants);
             return 0;
         }
 
-        constant_idx
+        constant_idx as u8
     }
 
-    fn consume(&mut self, kind: TokenKind, msg: &str) {
+    fn consume(&mut self, kind: TokenKind, code: ErrorCode) {
         if self.cur().kind == kind {
             self.advance();
             return;
         }
 
-        self.error_at_current(msg)
+        self.error_at_current(code)
     }
 
     fn advance(&mut self) {
@@ -1221,44 +1829,146 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 break;
             }
 
-            self.error_at_current(self.cur().msg)
+            let code = ErrorCode::of_scanned(self.cur().msg)
+                .expect("The scanner only reports errors from the catalog");
+            self.error_at_current(code)
         }
     }
 
-    fn error_at_current(&mut self, msg: &str) {
-        self.error_at(self.cur(), msg)
+    fn error_at_current(&mut self, code: ErrorCode) {
+        self.error_at(self.cur(), code)
     }
 
-    fn error(&mut self, msg: &str) {
-        self.error_at(self.prev(), msg)
+    fn error(&mut self, code: ErrorCode) {
+        self.error_at(self.prev(), code)
     }
 
-    fn error_at(&mut self, token: Token<'src>, msg: &str) {
+    fn error_at(&mut self, token: Token<'src>, code: ErrorCode) {
         if self.panic_mode {
             return;
         }
 
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        let diagnostic = self.diagnostic(Severity::Error, token, code);
+        self.diagnostics.push(diagnostic);
+        self.had_error = true;
+    }
 
-        if token.kind == TokenKind::Eof {
-            eprint!(" at end")
+    /// In strict mode, an error
+    fn warning_at(&mut self, token: Token<'src>, code: ErrorCode) {
+        if self.strict {
+            let diagnostic = self.diagnostic(Severity::Error, token, code);
+            self.diagnostics.push(diagnostic);
+            self.had_error = true;
+            return;
+        }
+        let diagnostic = self.diagnostic(Severity::Warning, token, code);
+        self.warnings.push(diagnostic);
+    }
+
+    /// Describe a problem at `token`, printing it if errors are printed
+    fn diagnostic(&self, severity: Severity, token: Token<'src>, code: ErrorCode) -> Diagnostic {
+        let at = if token.kind == TokenKind::Eof {
+            " at end".to_string()
         } else if token.kind == TokenKind::Error {
+            String::new()
         } else {
-            eprint!(" at {}", token.msg)
+            format!(" at {}", token.msg)
+        };
+
+        let diagnostic = Diagnostic {
+            severity,
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            at,
+            code,
+            message: code.message(&[]),
+        };
+        if self.print_errors {
+            let source_line = match token.kind {
+                TokenKind::Synthetic => None,
+                _ => self.scanner.line_text(token.span.start),
+            };
+            match self.error_format {
+                ErrorFormat::Human => eprintln!("{}", diagnostic.render(source_line, self.color)),
+                ErrorFormat::Json => eprintln!("{}", diagnostic.to_json()),
+            }
         }
+        diagnostic
+    }
 
-        eprintln!(": {msg}");
-        self.had_error = true;
+    /// Warn about each assignment to a global that isn't declared anywhere
+    /// in the program, or defined already, like a native or a global from
+    /// an earlier REPL line
+    fn check_undeclared(&mut self) {
+        for name in std::mem::take(&mut self.global_assignments) {
+            if self.declared.contains(&(name.msg as *const str)) {
+                continue;
+            }
+            let global = self.mem.copy_string(name.msg);
+            if !native_fn::is_builtin_global(name.msg)
+                && self.mem.globals.get(global.as_non_null_ptr()).is_none()
+            {
+                self.warning_at(name, ErrorCode::UndeclaredAssignment);
+            }
+        }
+    }
+
+    /// Count the globals read from here on as read by `name`'s code if it's
+    /// declared at the top level, returning the owner to restore after
+    fn own_globals(&mut self, name: Token<'src>) -> Option<*const str> {
+        let owner = self.global_owner;
+        if owner.is_none() && self.compiler.scope_depth == 0 {
+            self.global_owner = Some(name.msg);
+        }
+        owner
+    }
+
+    /// Warn about each function and global declared at the top level that
+    /// isn't read by the script's own code, or by a function or class it
+    /// reads, however indirectly. Assigning to a global doesn't use it.
+    ///
+    /// Lox has no modules, so nothing is exported, but a file run with
+    /// [`interpret_file`](crate::interpret_file) shares its globals with
+    /// the files run after it, so those aren't checked. Neither is a name
+    /// defined already, which something else may be using.
+    fn check_unused(&mut self) {
+        let mut used = FnvHashSet::default();
+        let mut owners = vec![None];
+        while let Some(owner) = owners.pop() {
+            for &(reader, name) in &self.global_reads {
+                if reader == owner && used.insert(name) {
+                    owners.push(Some(name));
+                }
+            }
+        }
+
+        let mut warned = FnvHashSet::default();
+        for (name, code) in std::mem::take(&mut self.global_definitions) {
+            if used.contains(&(name.msg as *const str)) || !warned.insert(name.msg as *const str) {
+                continue;
+            }
+            let global = self.mem.copy_string(name.msg);
+            if self.mem.globals.get(global.as_non_null_ptr()).is_none() {
+                self.warning_at(name, code);
+            }
+        }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if self.expression_depth == self.max_expression_depth {
+            self.error_at_current(ErrorCode::NestedTooDeeply);
+            return;
+        }
+
         self.advance();
         let rule = match Self::get_rule(self.prev().kind).prefix {
             Some(rule) => rule,
             None => {
-                self.error("Expect expression");
+                self.error(ErrorCode::ExpectExpression);
                 return;
             }
         };
@@ -1266,8 +1976,14 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         let ctx = ParseRuleCtx {
             can_assign: precedence as u8 <= Precedence::Assignment as u8,
         };
+        self.expression_depth += 1;
         rule(self, ctx);
+        self.parse_infix(precedence, ctx);
+        self.expression_depth -= 1;
+    }
 
+    /// The rest of `parse_precedence`, once the prefix has been parsed
+    fn parse_infix(&mut self, precedence: Precedence, ctx: ParseRuleCtx) {
         while precedence as u8 <= Self::get_rule(self.cur().kind).precedence as u8 {
             self.advance();
             let infix_rule = match Self::get_rule(self.prev().kind).infix {
@@ -1278,7 +1994,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
 
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
-            self.error("Invalid assignment target.");
+            self.error(ErrorCode::InvalidAssignmentTarget);
         }
     }
 
@@ -1292,7 +2008,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn this(&mut self, _ctx: ParseRuleCtx) {
         if self.compiler.class_compiler.is_none() {
-            self.error("Can't use 'this' outside of a class.");
+            self.error(ErrorCode::ThisOutsideClass);
             return;
         }
         self.variable(ParseRuleCtx { can_assign: false })
@@ -1300,360 +2016,32 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn super_(&mut self, ctx: ParseRuleCtx) {
         match self.compiler.class_compiler.as_ref() {
-            None => self.error("Can't use 'super' outside of a class."),
+            None => self.error(ErrorCode::SuperOutsideClass),
             Some(class_compiler) if !class_compiler.has_superclass => {
-                self.error("Can't use 'super' in a class with no superclass.")
+                self.error(ErrorCode::SuperWithoutSuperclass)
             }
             _ => (),
         }
 
-        self.consume(TokenKind::Dot, "Expect '.' after 'super'.");
-        self.consume(TokenKind::Identifier, "Expect superclass method name.");
+        self.consume(TokenKind::Dot, ErrorCode::ExpectDotAfterSuper);
+        self.consume(TokenKind::Identifier, ErrorCode::ExpectSuperclassMethodName);
         let name = self.identifier_constant(self.prev());
 
         let ctx = ParseRuleCtx { can_assign: false };
 
-        self.named_variable(Token::synthetic("this"), ctx);
+        let this = self.synthetic("this");
+        self.named_variable(this, ctx);
 
         if self.match_tok(TokenKind::LeftParen) {
             let arg_count = self.argument_list();
-            self.named_variable(Token::synthetic("super"), ctx);
+            let super_ = self.synthetic("super");
+            self.named_variable(super_, ctx);
             self.emit_bytes(Opcode::SuperInvoke as u8, name);
             self.emit_byte(arg_count);
         } else {
-            self.named_variable(Token::synthetic("super"), ctx);
+            let super_ = self.synthetic("super");
+            self.named_variable(super_, ctx);
             self.emit_bytes(Opcode::GetSuper as u8, name);
         }
     }
 }
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TokenKind {
-    // Single-character tokens.
-    LeftParen = 0,
-    RightParen,
-    LeftBrace,
-    RightBrace,
-    Comma,
-    Dot,
-    Minus,
-    Plus,
-    Semicolon,
-    Slash,
-    Star,
-
-    // One or two character tokens.
-    Bang,
-    BangEqual,
-    Equal,
-    EqualEqual,
-    Greater,
-    GreaterEqual,
-    Less,
-    LessEqual,
-
-    // Literals.
-    Identifier,
-    String,
-    Number,
-
-    // Keywords.
-    And,
-    Class,
-    Else,
-    False,
-    For,
-    Fun,
-    If,
-    Nil,
-    Or,
-    Print,
-    Return,
-    Super,
-    This,
-    True,
-    Var,
-    While,
-
-    Error,
-    Eof,
-
-    Synthetic,
-}
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub struct Token<'src> {
-    kind: TokenKind,
-    line: u32,
-    msg: &'src str,
-}
-
-impl<'src> Token<'src> {
-    fn synthetic(msg: &'src str) -> Self {
-        Self {
-            kind: TokenKind::Synthetic,
-            line: u32::MAX,
-            msg,
-        }
-    }
-}
-
-pub struct Scanner<'src> {
-    src: &'src [u8],
-    start: usize,
-    current: usize,
-    line: usize,
-}
-
-impl<'src> Scanner<'src> {
-    pub fn new(src: &'src str) -> Self {
-        Self {
-            src: src.as_bytes(),
-            start: 0,
-            current: 0,
-            line: 1,
-        }
-    }
-
-    fn advance(&mut self) -> u8 {
-        let ret = self.src[self.current];
-        self.current += 1;
-        ret
-    }
-
-    fn peek(&mut self) -> u8 {
-        self.src.get(self.current).cloned().unwrap_or(b'\0')
-    }
-
-    fn peek_next(&mut self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.src[self.current + 1]
-        }
-    }
-
-    fn skip_whitespace(&mut self) {
-        loop {
-            let c = self.peek();
-
-            match c {
-                b' ' | b'\r' | b'\t' => {
-                    self.advance();
-                }
-                b'\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    }
-                }
-                _ => return,
-            }
-        }
-    }
-
-    pub fn token(&mut self) -> Token<'src> {
-        self.skip_whitespace();
-        self.start = self.current;
-
-        if self.is_at_end() {
-            return self.make_token(TokenKind::Eof);
-        }
-
-        let c = self.advance();
-
-        if Self::is_alpha(c) {
-            return self.identifier();
-        }
-
-        if Self::is_digit(c) {
-            return self.number();
-        }
-
-        match c {
-            b'(' => return self.make_token(TokenKind::LeftParen),
-            b')' => return self.make_token(TokenKind::RightParen),
-            b'{' => return self.make_token(TokenKind::LeftBrace),
-            b'}' => return self.make_token(TokenKind::RightBrace),
-            b';' => return self.make_token(TokenKind::Semicolon),
-            b',' => return self.make_token(TokenKind::Comma),
-            b'.' => return self.make_token(TokenKind::Dot),
-            b'-' => return self.make_token(TokenKind::Minus),
-            b'+' => return self.make_token(TokenKind::Plus),
-            b'/' => return self.make_token(TokenKind::Slash),
-            b'*' => return self.make_token(TokenKind::Star),
-            b'!' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::BangEqual
-                } else {
-                    TokenKind::Bang
-                };
-                return self.make_token(kind);
-            }
-            b'=' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::EqualEqual
-                } else {
-                    TokenKind::Equal
-                };
-                return self.make_token(kind);
-            }
-            b'<' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::LessEqual
-                } else {
-                    TokenKind::Less
-                };
-                return self.make_token(kind);
-            }
-            b'>' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::GreaterEqual
-                } else {
-                    TokenKind::Greater
-                };
-                return self.make_token(kind);
-            }
-            b'"' => return self.string(),
-            _ => (),
-        }
-
-        self.error_token("Unexpected character.")
-    }
-
-    fn is_alpha(c: u8) -> bool {
-        (b'a'..=b'z').contains(&c) || (b'A'..=b'Z').contains(&c) || c == b'_'
-    }
-
-    fn identifier(&mut self) -> Token<'src> {
-        while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
-            self.advance();
-        }
-
-        self.make_token(self.identifier_kind())
-    }
-
-    fn identifier_kind(&self) -> TokenKind {
-        match self.src[self.start] {
-            b'a' => self.check_keyword(1, 2, "nd", TokenKind::And),
-            b'c' => self.check_keyword(1, 4, "lass", TokenKind::Class),
-            b'e' => self.check_keyword(1, 3, "lse", TokenKind::Else),
-            b'f' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
-                b'a' => self.check_keyword(2, 3, "lse", TokenKind::False),
-                b'o' => self.check_keyword(2, 1, "r", TokenKind::For),
-                b'u' => self.check_keyword(2, 1, "n", TokenKind::Fun),
-                _ => TokenKind::Identifier,
-            },
-            b'i' => self.check_keyword(1, 1, "f", TokenKind::If),
-            b'n' => self.check_keyword(1, 2, "il", TokenKind::Nil),
-            b'o' => self.check_keyword(1, 1, "r", TokenKind::Or),
-            b'p' => self.check_keyword(1, 4, "rint", TokenKind::Print),
-            b'r' => self.check_keyword(1, 5, "eturn", TokenKind::Return),
-            b's' => self.check_keyword(1, 4, "uper", TokenKind::Super),
-            b't' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
-                b'h' => self.check_keyword(2, 2, "is", TokenKind::This),
-                b'r' => self.check_keyword(2, 2, "ue", TokenKind::True),
-                _ => TokenKind::Identifier,
-            },
-            b'v' => self.check_keyword(1, 2, "ar", TokenKind::Var),
-            b'w' => self.check_keyword(1, 4, "hile", TokenKind::While),
-            _ => TokenKind::Identifier,
-        }
-    }
-
-    fn check_keyword(&self, start: usize, len: usize, rest: &str, kind: TokenKind) -> TokenKind {
-        if self.current - self.start == start + len
-            && &self.src[(self.start + start)..(self.start + start + len)] == rest.as_bytes()
-        {
-            return kind;
-        }
-
-        TokenKind::Identifier
-    }
-
-    fn string(&mut self) -> Token<'src> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
-            }
-
-            self.advance();
-        }
-
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
-        }
-
-        // closing quote
-        self.advance();
-        self.make_token(TokenKind::String)
-    }
-
-    fn is_digit(c: u8) -> bool {
-        match c {
-            b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7' | b'8' | b'9' => true,
-            _ => false,
-        }
-    }
-
-    fn number(&mut self) -> Token<'src> {
-        while Self::is_digit(self.peek()) {
-            self.advance();
-        }
-
-        if self.peek() == b'.' && Self::is_digit(self.peek_next()) {
-            // consume the '.'
-            self.advance();
-
-            while Self::is_digit(self.peek()) {
-                self.advance();
-            }
-        }
-
-        self.make_token(TokenKind::Number)
-    }
-
-    fn matches(&mut self, expected: u8) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.src[self.current] != expected {
-            return false;
-        }
-
-        self.current += 1;
-
-        true
-    }
-
-    fn is_at_end(&self) -> bool {
-        self.current == self.src.len()
-        // self.src[self.current] == b'\0'
-    }
-
-    fn make_token(&self, kind: TokenKind) -> Token<'src> {
-        Token {
-            kind,
-            // Safety:
-            // The input is guaranteed to be valid utf8 so this is safe
-            msg: unsafe { std::str::from_utf8_unchecked(&self.src[self.start..self.current]) },
-            line: self.line as u32,
-        }
-    }
-
-    fn error_token(&self, err: &'src str) -> Token<'src> {
-        Token {
-            kind: TokenKind::Error,
-            msg: err,
-            line: self.line as u32,
-        }
-    }
-}
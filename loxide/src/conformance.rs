@@ -0,0 +1,161 @@
+use crate::{interpret, mutate::Mutation, vm::VM};
+
+/// More than any case needs, so a mutant stuck in a loop fails instead of
+/// hanging the suite
+const STEP_LIMIT: u64 = 100_000;
+
+/// A program and the value it must leave in its `result` global, formatted
+/// with `{:?}`
+pub struct Case {
+    pub name: &'static str,
+    pub src: &'static str,
+    pub expected: &'static str,
+}
+
+pub const CASES: &[Case] = &[
+    Case {
+        name: "arithmetic",
+        src: "var result = (1 + 2) * 3 - 8 / 4;",
+        expected: "Number(7.0)",
+    },
+    Case {
+        name: "comparison",
+        src: r#"
+var one = 1;
+var two = 2;
+var result = 0;
+if (one < two) result = result + 1;
+if (two > one) result = result + 10;
+result = result + one * 100 + two * 1000;
+"#,
+        expected: "Number(2111.0)",
+    },
+    Case {
+        name: "booleans",
+        src: "var result = !true == false;",
+        expected: "Bool(true)",
+    },
+    Case {
+        name: "logic",
+        src: r#"var result = (true and 1) + (3 or "x") * 10;"#,
+        expected: "Number(31.0)",
+    },
+    Case {
+        name: "loops",
+        src: r#"
+var result = 1;
+for (var i = 0; i < 5; i = i + 1) {
+    result = result * 2 + i;
+}
+"#,
+        expected: "Number(58.0)",
+    },
+    Case {
+        name: "functions",
+        src: r#"
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+var result = fib(10);
+"#,
+        expected: "Number(55.0)",
+    },
+    Case {
+        name: "closures",
+        src: r#"
+fun makeCounter() {
+    var count = 0;
+    fun increment() {
+        count = count + 1;
+        return count;
+    }
+    return increment;
+}
+var counter = makeCounter();
+counter();
+var result = counter() * 10 + counter();
+"#,
+        expected: "Number(23.0)",
+    },
+    Case {
+        name: "classes",
+        src: r#"
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+    sum() {
+        return this.x + this.y;
+    }
+}
+class Point3 < Point {
+    init(x, y, z) {
+        super.init(x, y);
+        this.z = z;
+    }
+    sum() {
+        return super.sum() * this.z;
+    }
+}
+var result = Point3(1, 2, 3).sum();
+"#,
+        expected: "Number(9.0)",
+    },
+    Case {
+        name: "bound methods",
+        src: r#"
+class Scaler {
+    init(factor) {
+        this.factor = factor;
+    }
+    scale(n) {
+        return n * this.factor;
+    }
+}
+var scale = Scaler(3).scale;
+var result = scale(2) + 1;
+"#,
+        expected: "Number(7.0)",
+    },
+    Case {
+        name: "strings",
+        src: r#"var result = "con" + "cat";"#,
+        expected: r#""concat""#,
+    },
+];
+
+impl Case {
+    /// Run the case, with `mutation` applied to its bytecode if given, and
+    /// describe how it failed if it did
+    pub fn run(&self, mutation: Option<Mutation>) -> Result<(), String> {
+        let mut vm = VM::new();
+        vm.mutation = mutation;
+        vm.step_limit = Some(STEP_LIMIT);
+
+        interpret(&mut vm, self.src).map_err(|err| format!("{err:?}"))?;
+
+        let name = vm.mem.copy_string("result").as_non_null_ptr();
+        match vm.mem.globals.get(name) {
+            Some(value) if format!("{value:?}") == self.expected => Ok(()),
+            Some(value) => Err(format!("expected {}, got {value:?}", self.expected)),
+            None => Err("`result` is not defined".into()),
+        }
+    }
+
+    /// Every mutation of this case's bytecode that still passes it
+    pub fn surviving_mutants(&self) -> Vec<Mutation> {
+        let mut vm = VM::new();
+        let script = {
+            let mut parser = crate::compile::Parser::new(self.src, &mut vm.mem);
+            assert!(parser.compile(), "{} failed to compile", self.name);
+            parser.compiler.function
+        };
+
+        Mutation::all(script)
+            .into_iter()
+            .filter(|&mutation| self.run(Some(mutation)).is_ok())
+            .collect()
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+
+use crate::obj::ObjFunction;
+
+/// Which source lines executed, and how many instructions ran on each
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Instruction count per line, indexed by line number
+    pub hits: Vec<u64>,
+    /// Lines that have at least one instruction compiled for them
+    pub executable: BTreeSet<u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn record(&mut self, line: u32) {
+        let line = line as usize;
+        if line >= self.hits.len() {
+            self.hits.resize(line + 1, 0);
+        }
+        self.hits[line] += 1;
+    }
+
+    pub fn hit_count(&self, line: u32) -> u64 {
+        self.hits.get(line as usize).cloned().unwrap_or(0)
+    }
+
+    /// Mark every line `function` (and the functions nested in it) has code
+    /// for as executable, so lines that never ran show up in the report
+    pub fn add_function(&mut self, function: &ObjFunction) {
+        self.executable.extend(function.chunk.lines.iter().cloned());
+
+        for constant in function.chunk.constants.iter() {
+            if let Some(function) = constant.as_fn() {
+                self.add_function(function.as_ref());
+            }
+        }
+    }
+
+    /// Print `src` with the hit count of each line in the margin, and `#####`
+    /// for executable lines that never ran
+    pub fn write_annotated(&self, src: &str, out: &mut dyn Write) -> io::Result<()> {
+        for (i, text) in src.lines().enumerate() {
+            let line = i as u32 + 1;
+            let count = self.hit_count(line);
+            if count > 0 {
+                writeln!(out, "{count:>9} | {text}")?;
+            } else if self.executable.contains(&line) {
+                writeln!(out, "{:>9} | {text}", "#####")?;
+            } else {
+                writeln!(out, "{:>9} | {text}", "")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the report as an lcov tracefile, readable by genhtml and most
+    /// editor coverage plugins
+    pub fn write_lcov(&self, source_file: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{source_file}")?;
+
+        let mut lines_hit = 0;
+        for &line in self.executable.iter() {
+            let count = self.hit_count(line);
+            if count > 0 {
+                lines_hit += 1;
+            }
+            writeln!(out, "DA:{line},{count}")?;
+        }
+
+        writeln!(out, "LF:{}", self.executable.len())?;
+        writeln!(out, "LH:{lines_hit}")?;
+        writeln!(out, "end_of_record")
+    }
+}
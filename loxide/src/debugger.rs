@@ -0,0 +1,851 @@
+//! Pausing and rewinding a running script.
+//!
+//! With a [`Debugger`] attached, [`VM::run`] returns
+//! [`InterpretError::Paused`] whenever the debugger asks it to stop, leaving
+//! the VM exactly where it was, so it can be inspected and then resumed by
+//! calling `run` again.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    io::Write,
+    mem::MaybeUninit,
+    path::Path,
+    ptr::{addr_of_mut, null_mut, NonNull},
+    str::FromStr,
+};
+
+use crate::{
+    compile::Parser,
+    events::{LogLevel, VmEvents},
+    fields::{FieldStorage, Fields},
+    mem::{Gc, Greystack},
+    native_fn::NativeFnKind,
+    obj::{
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFloatArray, ObjInstance, ObjKind, ObjList,
+        ObjString, ObjUpvalue,
+    },
+    stack::ValueStack,
+    value::Value,
+    vm::{CallFrame, InterpretError, VM},
+};
+
+pub struct Debugger {
+    /// Instructions executed since the debugger was attached
+    step: u64,
+    /// Pause before executing this step
+    pub pause_at: Option<u64>,
+    history: Option<History>,
+    watches: Vec<Watch>,
+    /// The watchpoint that paused execution most recently
+    pub last_hit: Option<WatchHit>,
+    pub breakpoints: Vec<Breakpoint>,
+    /// The breakpoint that paused execution most recently
+    pub last_breakpoint: Option<Breakpoint>,
+    /// The path of the script being run, which breakpoints naming a file are
+    /// matched against. When unset they match any script.
+    pub script: Option<String>,
+    /// The frame depth and line of the last instruction, so breakpoints only
+    /// trigger when execution arrives at their line
+    last_line: Option<(u32, u32)>,
+    /// Set while the debugger runs code of its own, which it shouldn't
+    /// trace or stop in
+    pub(crate) suspended: bool,
+    /// Set while [`VM::step_back`] replays instructions that already ran,
+    /// which shouldn't stop anywhere but the target step
+    replaying: bool,
+}
+
+/// Pause on reaching a line, if `condition` (a Lox expression, which can use
+/// the paused function's locals) is truthy there. Written `foo.lox:10 if x >
+/// 100`, with the file and condition optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub file: Option<String>,
+    pub line: u32,
+    pub condition: Option<String>,
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (location, condition) = match spec.split_once(" if ") {
+            Some((location, condition)) => (location, Some(condition.trim().to_owned())),
+            None => (spec, None),
+        };
+
+        let (file, line) = match location.trim().rsplit_once(':') {
+            Some((file, line)) => (Some(file.to_owned()), line),
+            None => (None, location.trim()),
+        };
+
+        let line = line
+            .parse()
+            .map_err(|_| format!("Invalid line number `{line}`."))?;
+        Ok(Self {
+            file,
+            line,
+            condition,
+        })
+    }
+}
+
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}", self.line)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {condition}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Something to pause on whenever its value changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Watch {
+    Global(String),
+    /// The field with this name, on any instance
+    Field(String),
+}
+
+impl Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Global(name) => write!(f, "global `{name}`"),
+            Self::Field(name) => write!(f, "field `{name}`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub watch: Watch,
+    /// None if this assignment created the field
+    pub old: Option<Value>,
+    pub new: Value,
+    pub line: u32,
+    pub function: String,
+}
+
+/// One executed instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub step: u64,
+    /// How many call frames were active
+    pub depth: u32,
+    pub instr_offset: u32,
+    pub line: u32,
+    /// How much the instruction grew (or shrank) the stack by
+    pub stack_delta: i32,
+}
+
+/// Recent execution, for stepping backwards.
+///
+/// Rewinding restores the nearest full snapshot at or before the target step
+/// and replays from there. Snapshots cover the stack, call frames, globals,
+/// open upvalues and the contents of every object reachable from them, which
+/// are put back in place so references to those objects stay valid. Calls to
+/// natives that can't be repeated, like `exec` or `clock`, can't be replayed,
+/// so stepping back past one is refused.
+struct History {
+    /// How many steps back we can go
+    limit: usize,
+    snapshot_interval: u64,
+    trace: VecDeque<TraceEntry>,
+    snapshots: VecDeque<State>,
+    /// Stack length before the last traced step, to work out its delta. None
+    /// when that step's delta is already known.
+    stack_len: Option<usize>,
+    /// The steps that called a nondeterministic native, and its name
+    unrepeatable: VecDeque<(u64, String)>,
+}
+
+struct State {
+    step: u64,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Vec<(NonNull<ObjString>, Value)>,
+    /// Open upvalues from the head of the list, with the stack slot each
+    /// points to
+    open_upvalues: Vec<(Gc<ObjUpvalue>, usize)>,
+    objects: Vec<Contents>,
+}
+
+/// What a mutable object held when a [`State`] was saved
+enum Contents {
+    Fields(Gc<ObjInstance>, Vec<(NonNull<ObjString>, Value)>),
+    Items(Gc<ObjList>, Vec<Value>),
+    Floats(Gc<ObjFloatArray>, Vec<f64>),
+    /// Only for upvalues that were already closed
+    Closed(Gc<ObjUpvalue>, Value),
+    Methods(
+        Gc<ObjClass>,
+        Vec<(NonNull<ObjString>, Value)>,
+        Option<Gc<ObjClass>>,
+    ),
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            step: 0,
+            pause_at: None,
+            history: None,
+            watches: vec![],
+            last_hit: None,
+            breakpoints: vec![],
+            last_breakpoint: None,
+            script: None,
+            last_line: None,
+            suspended: false,
+            replaying: false,
+        }
+    }
+
+    /// Also record the last `limit` instructions, with a full snapshot every
+    /// `snapshot_interval` of them, so [`VM::step_back`] can rewind over them.
+    /// More frequent snapshots make stepping back cheaper and recording
+    /// dearer.
+    pub fn with_history(limit: usize, snapshot_interval: u64) -> Self {
+        assert!(snapshot_interval > 0);
+        Self {
+            history: Some(History {
+                limit,
+                snapshot_interval,
+                trace: VecDeque::new(),
+                snapshots: VecDeque::new(),
+                stack_len: None,
+                unrepeatable: VecDeque::new(),
+            }),
+            ..Self::new()
+        }
+    }
+
+    pub fn watch(&mut self, watch: Watch) {
+        if !self.watches.contains(&watch) {
+            self.watches.push(watch);
+        }
+    }
+
+    /// Returns false if `watch` wasn't set
+    pub fn unwatch(&mut self, watch: &Watch) -> bool {
+        let len = self.watches.len();
+        self.watches.retain(|set| set != watch);
+        self.watches.len() != len
+    }
+
+    /// The breakpoints that could trigger on arriving at `line`
+    fn breakpoints_at(&self, line: u32) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter().filter(move |breakpoint| {
+            let in_script = match (&breakpoint.file, &self.script) {
+                (Some(file), Some(script)) => Path::new(script).ends_with(file),
+                _ => true,
+            };
+            breakpoint.line == line && in_script
+        })
+    }
+
+    fn is_watched(&self, global: bool, name: &str) -> bool {
+        self.watches.iter().any(|watch| match watch {
+            Watch::Global(watched) => global && watched == name,
+            Watch::Field(watched) => !global && watched == name,
+        })
+    }
+
+    /// How many instructions have run
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// Called by `call_value` for every native call, to remember the ones
+    /// that stepping back would have to repeat but can't
+    pub(crate) fn called_native(&mut self, native: &NativeFnKind, values: &[Value]) {
+        if self.replaying || native.is_deterministic(values) {
+            return;
+        }
+        if let Some(history) = self.history.as_mut() {
+            // The hook already counted the instruction making the call
+            let step = self.step.saturating_sub(1);
+            history.unrepeatable.push_back((step, native.name()));
+        }
+    }
+
+    /// The recorded instructions, oldest first
+    pub fn trace(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.history.iter().flat_map(|history| history.trace.iter())
+    }
+
+    pub fn mark(&self, greystack: &mut Greystack) {
+        let history = match self.history.as_ref() {
+            Some(history) => history,
+            None => return,
+        };
+
+        for state in history.snapshots.iter() {
+            for value in state.stack.iter() {
+                value.mark(greystack);
+            }
+            for frame in state.frames.iter() {
+                Obj::mark(frame.closure.cast::<Obj>().as_ptr(), greystack);
+            }
+            for &(key, value) in state.globals.iter() {
+                Obj::mark(key.as_ptr().cast(), greystack);
+                value.mark(greystack);
+            }
+            for &(upvalue, _) in state.open_upvalues.iter() {
+                Obj::mark(upvalue.as_ptr().cast(), greystack);
+            }
+            for contents in state.objects.iter() {
+                contents.mark(greystack);
+            }
+        }
+    }
+}
+
+impl Contents {
+    fn mark(&self, greystack: &mut Greystack) {
+        match self {
+            Contents::Fields(instance, fields) => {
+                Obj::mark(instance.as_ptr().cast(), greystack);
+                for &(name, value) in fields {
+                    Obj::mark(name.as_ptr().cast(), greystack);
+                    value.mark(greystack);
+                }
+            }
+            Contents::Items(list, items) => {
+                Obj::mark(list.as_ptr().cast(), greystack);
+                for value in items {
+                    value.mark(greystack);
+                }
+            }
+            Contents::Floats(array, _) => Obj::mark(array.as_ptr().cast(), greystack),
+            Contents::Closed(upvalue, value) => {
+                Obj::mark(upvalue.as_ptr().cast(), greystack);
+                value.mark(greystack);
+            }
+            Contents::Methods(class, methods, superclass) => {
+                Obj::mark(class.as_ptr().cast(), greystack);
+                for &(name, value) in methods {
+                    Obj::mark(name.as_ptr().cast(), greystack);
+                    value.mark(greystack);
+                }
+                if let Some(superclass) = superclass {
+                    Obj::mark(superclass.as_ptr().cast(), greystack);
+                }
+            }
+        }
+    }
+}
+
+/// Swallows whatever a replay does, since it already happened once
+struct Muted;
+
+impl VmEvents for Muted {
+    fn log(&mut self, _level: LogLevel, _message: &str) {}
+}
+
+impl VM {
+    /// Called by `run` before each instruction while a debugger is attached.
+    /// Returns true if execution should pause.
+    pub(crate) fn debug_hook(&mut self) -> bool {
+        let state = match self.debugger.as_ref() {
+            Some(debugger) if debugger.suspended => return false,
+            Some(debugger) if debugger.pause_at == Some(debugger.step) => {
+                self.debugger.as_mut().unwrap().pause_at = None;
+                return true;
+            }
+            Some(Debugger {
+                history: Some(history),
+                step,
+                ..
+            }) if step % history.snapshot_interval == 0 => Some(self.save_state(*step)),
+            _ => None,
+        };
+
+        if self.check_breakpoints() {
+            return true;
+        }
+
+        let stack_len = self.stack_len();
+        let frame = self.top_call_frame();
+        let (depth, instr_offset) = (self.call_frame_count, frame.instr_offset);
+        let line = frame.function().chunk.lines[instr_offset as usize];
+
+        let debugger = self.debugger.as_mut().unwrap();
+        if let Some(history) = debugger.history.as_mut() {
+            if let (Some(last), Some(last_len)) = (history.trace.back_mut(), history.stack_len) {
+                last.stack_delta = stack_len as i32 - last_len as i32;
+            }
+            history.stack_len = Some(stack_len);
+
+            history.trace.push_back(TraceEntry {
+                step: debugger.step,
+                depth,
+                instr_offset,
+                line,
+                stack_delta: 0,
+            });
+            if history.trace.len() > history.limit {
+                history.trace.pop_front();
+            }
+
+            history.snapshots.extend(state);
+            // Keep the newest snapshot from before the oldest traced step, to
+            // replay up to it from
+            let oldest = history
+                .trace
+                .front()
+                .map_or(debugger.step, |entry| entry.step);
+            while history.snapshots.len() > 1 && history.snapshots[1].step <= oldest {
+                history.snapshots.pop_front();
+            }
+            let replayed_from = history.snapshots.front().map_or(oldest, |state| state.step);
+            while let Some(&(step, _)) = history.unrepeatable.front() {
+                if step >= replayed_from {
+                    break;
+                }
+                history.unrepeatable.pop_front();
+            }
+        }
+
+        debugger.step += 1;
+        false
+    }
+
+    /// Returns true if a breakpoint on the line execution just arrived at
+    /// triggers
+    fn check_breakpoints(&mut self) -> bool {
+        let frame = self.top_call_frame();
+        let line = frame.function().chunk.lines[frame.instr_offset as usize];
+        let position = Some((self.call_frame_count, line));
+
+        let debugger = self.debugger.as_mut().unwrap();
+        if debugger.replaying || debugger.last_line == position {
+            return false;
+        }
+        debugger.last_line = position;
+
+        let breakpoints: Vec<_> = debugger.breakpoints_at(line).cloned().collect();
+        for breakpoint in breakpoints {
+            let triggered = match &breakpoint.condition {
+                None => true,
+                Some(condition) => self.evaluate_condition(condition).unwrap_or_else(|err| {
+                    let _ = writeln!(self.debug_sink, "Breakpoint condition failed: {err}");
+                    true
+                }),
+            };
+
+            if triggered {
+                let function = self.top_call_frame().function().name_str().to_owned();
+                writeln!(self.debug_sink, "Breakpoint {breakpoint} hit in {function}")
+                    .expect("Failed to write breakpoint.");
+                self.debugger.as_mut().unwrap().last_breakpoint = Some(breakpoint);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Evaluate a Lox expression in the top frame: its locals are visible
+    /// (though assigning to them doesn't change the originals), and so is
+    /// everything global. Needs a debugger attached.
+    pub fn evaluate_condition(&mut self, condition: &str) -> Result<bool, String> {
+        if self.debugger.is_none() {
+            return Err("No debugger attached.".into());
+        }
+
+        let frame = *self.top_call_frame();
+        let chunk = &frame.function().chunk;
+        let locals: Vec<_> = chunk
+            .locals_at(frame.instr_offset)
+            .into_iter()
+            .map(|local| (local.name.clone(), local.slot))
+            .collect();
+        let names: Vec<_> = locals.iter().map(|(name, _)| name.as_str()).collect();
+
+        let function = {
+            let mut parser = Parser::new(condition, &mut self.mem);
+            parser.print_errors = false;
+            if !parser.compile_expression(&names) {
+                let errors: Vec<_> = parser.diagnostics.iter().map(ToString::to_string).collect();
+                return Err(errors.join(" "));
+            }
+            parser.compiler.function
+        };
+
+        if !self.has_room_for_frame() {
+            return Err("Stack overflow.".into());
+        }
+
+        // Whatever runs the condition doesn't count as the script's progress
+        self.debugger.as_mut().unwrap().suspended = true;
+        let base = self.stack.top();
+        let closure = self.mem.alloc_obj(ObjClosure::new(function));
+        self.push(Value::Obj(closure.cast()));
+        for &(_, slot) in locals.iter() {
+            self.push(frame.index(slot as usize));
+        }
+        self.call_frames[self.call_frame_count as usize] = MaybeUninit::new(CallFrame {
+            instr_offset: 0,
+            slots_ptr: base,
+            closure,
+        });
+
+        let base_frame = std::mem::replace(&mut self.base_frame, self.call_frame_count);
+        self.call_frame_count += 1;
+        let result = self.run();
+        self.call_frame_count = self.base_frame;
+        self.base_frame = base_frame;
+        self.stack.set_top(base);
+        self.debugger.as_mut().unwrap().suspended = false;
+
+        match result {
+            Ok(value) => Ok(!value.is_falsey()),
+            Err(_) => Err("Runtime error.".into()),
+        }
+    }
+
+    /// Called by `run` after assigning to a global (or a field, if `global`
+    /// is false) while a debugger is attached. Pauses before the next
+    /// instruction if the assignment changed a watched value.
+    pub(crate) fn check_watch(
+        &mut self,
+        global: bool,
+        name: &ObjString,
+        old: Option<Value>,
+        new: Value,
+    ) {
+        let debugger = self.debugger.as_ref().unwrap();
+        if debugger.suspended
+            || debugger.replaying
+            || old == Some(new)
+            || !debugger.is_watched(global, name.as_str())
+        {
+            return;
+        }
+
+        let watch = match global {
+            true => Watch::Global(name.as_str().to_owned()),
+            false => Watch::Field(name.as_str().to_owned()),
+        };
+        let frame = self.top_call_frame();
+        let function = frame.function();
+        // The instruction pointer is already past this instruction
+        let line = function.chunk.lines[frame.instr_offset as usize - 1];
+        let function = function.name_str().to_owned();
+
+        let old_str = old.map_or("<unset>".into(), |old| format!("{old:?}"));
+        writeln!(
+            self.debug_sink,
+            "Watchpoint {watch} changed: {old_str} -> {new:?} [line {line}] in {function}"
+        )
+        .expect("Failed to write watchpoint.");
+
+        let debugger = self.debugger.as_mut().unwrap();
+        debugger.pause_at = Some(debugger.step);
+        debugger.last_hit = Some(WatchHit {
+            watch,
+            old,
+            new,
+            line,
+            function,
+        });
+    }
+
+    /// Rewind the last `count` instructions, leaving the VM paused where it
+    /// was before running them. Returns the step it is now at.
+    pub fn step_back(&mut self, count: u64) -> Result<u64, String> {
+        let debugger = self.debugger.as_mut().ok_or("No debugger attached.")?;
+        let history = debugger
+            .history
+            .as_mut()
+            .ok_or("The debugger isn't recording history.")?;
+
+        let target = debugger
+            .step
+            .checked_sub(count)
+            .filter(|&target| {
+                history
+                    .trace
+                    .front()
+                    .map_or(false, |entry| entry.step <= target)
+            })
+            .ok_or_else(|| format!("Can't step back {count} instructions."))?;
+
+        let index = history
+            .snapshots
+            .iter()
+            .rposition(|state| state.step <= target)
+            .ok_or("No snapshot to rewind to.")?;
+        let from = history.snapshots[index].step;
+        if let Some((step, name)) = history
+            .unrepeatable
+            .iter()
+            .find(|&&(step, _)| from <= step && step < target)
+        {
+            return Err(format!(
+                "Can't step back past the call to `{name}` at step {step}, it can't be replayed."
+            ));
+        }
+        history.snapshots.truncate(index + 1);
+        let state = history.snapshots.pop_back().unwrap();
+        // These get recorded again by the replay
+        history.trace.retain(|entry| entry.step < state.step);
+        history.unrepeatable.retain(|&(step, _)| step < state.step);
+        history.stack_len = None;
+        let resume_at = debugger.pause_at.replace(target);
+        debugger.step = state.step;
+        debugger.replaying = true;
+
+        self.restore_state(state);
+
+        let captured_output = self.captured_output.replace(vec![]);
+        let events = std::mem::replace(&mut self.events, Box::new(Muted));
+        let result = self.run();
+        self.captured_output = captured_output;
+        self.events = events;
+
+        let debugger = self.debugger.as_mut().unwrap();
+        debugger.replaying = false;
+        debugger.pause_at = resume_at;
+        // Where the step before the target was, as if execution had arrived
+        // here without the replay
+        debugger.last_line = debugger
+            .trace()
+            .last()
+            .map(|entry| (entry.depth, entry.line));
+        match result {
+            Err(InterpretError::Paused) => Ok(debugger.step),
+            _ => Err("Replay diverged from the recorded history.".into()),
+        }
+    }
+
+    fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn save_state(&self, step: u64) -> State {
+        let stack = self.stack.values().to_vec();
+
+        let frames: Vec<CallFrame> = self.call_frames[..self.call_frame_count as usize]
+            .iter()
+            .map(|frame| unsafe { frame.assume_init() })
+            .collect();
+
+        let globals: Vec<_> = self
+            .mem
+            .globals
+            .iter()
+            .map(|entry| (NonNull::new(entry.key).unwrap(), entry.value))
+            .collect();
+
+        let mut open_upvalues = vec![];
+        let mut upvalue = self.open_upvalues;
+        while let Some(open) = NonNull::new(upvalue) {
+            let open = Gc::new(open);
+            let slot = unsafe { open.location.as_ptr().offset_from(self.stack.base()) as usize };
+            open_upvalues.push((open, slot));
+            upvalue = open.next;
+        }
+
+        let roots = stack
+            .iter()
+            .copied()
+            .chain(globals.iter().map(|&(_, value)| value))
+            .chain(frames.iter().map(|frame| Value::Obj(frame.closure.cast())));
+        let objects = save_objects(roots);
+
+        State {
+            step,
+            stack,
+            frames,
+            globals,
+            open_upvalues,
+            objects,
+        }
+    }
+
+    fn restore_state(&mut self, state: State) {
+        self.stack.set_top(self.stack.base());
+        for &value in state.stack.iter() {
+            self.stack.push(value);
+        }
+
+        for (slot, frame) in self.call_frames.iter_mut().zip(state.frames.iter()) {
+            slot.write(*frame);
+        }
+        self.call_frame_count = state.frames.len() as u32;
+
+        let keys: Vec<_> = self
+            .mem
+            .globals
+            .iter()
+            .map(|entry| NonNull::new(entry.key).unwrap())
+            .collect();
+        for key in keys {
+            self.mem.globals.delete(key);
+        }
+        for (key, value) in state.globals {
+            self.mem.globals.set(key, value);
+        }
+
+        // Reopen them, in case they were closed since
+        let mut next = null_mut();
+        for &(mut upvalue, slot) in state.open_upvalues.iter().rev() {
+            upvalue.location = unsafe { NonNull::new_unchecked(self.stack.base().add(slot)) };
+            upvalue.next = next;
+            next = upvalue.as_ptr();
+        }
+        self.open_upvalues = next;
+
+        // In place, so every reference to them sees the old contents
+        for contents in state.objects {
+            match contents {
+                Contents::Fields(mut instance, fields) => {
+                    let root = instance.class.shapes;
+                    instance.fields.free();
+                    instance.fields = Fields::new(root);
+                    for (name, value) in fields {
+                        self.write_barrier(Value::Obj(Gc::new(name).cast()));
+                        self.write_barrier(value);
+                        instance.fields.set(name, value);
+                    }
+                }
+                Contents::Items(mut list, items) => {
+                    for &value in items.iter() {
+                        self.write_barrier(value);
+                    }
+                    list.items = items;
+                }
+                Contents::Floats(mut array, values) => array.values = values,
+                Contents::Closed(upvalue, value) => {
+                    self.write_barrier(value);
+                    let upvalue = upvalue.as_ptr();
+                    unsafe {
+                        (*upvalue).closed = value;
+                        (*upvalue).location =
+                            NonNull::new_unchecked(addr_of_mut!((*upvalue).closed));
+                    }
+                }
+                Contents::Methods(mut class, methods, superclass) => {
+                    let names: Vec<_> = class
+                        .methods
+                        .iter()
+                        .map(|entry| NonNull::new(entry.key).unwrap())
+                        .collect();
+                    for name in names {
+                        class.methods.delete(name);
+                    }
+                    for (name, value) in methods {
+                        self.write_barrier(value);
+                        class.methods.set(name, value);
+                    }
+                    class.superclass = superclass;
+                }
+            }
+        }
+        // The inline caches may have seen methods that are gone again
+        self.cache_epoch = self.cache_epoch.wrapping_add(1);
+    }
+}
+
+/// The contents of every mutable object reachable from `roots`
+fn save_objects(roots: impl Iterator<Item = Value>) -> Vec<Contents> {
+    fn push(pending: &mut Vec<Gc<Obj>>, value: Value) {
+        if let Value::Obj(obj) = value {
+            pending.push(obj);
+        }
+    }
+
+    let mut pending = vec![];
+    for value in roots {
+        push(&mut pending, value);
+    }
+    let mut seen = HashSet::new();
+    let mut objects = vec![];
+    while let Some(obj) = pending.pop() {
+        if !seen.insert(obj.as_ptr()) {
+            continue;
+        }
+        match obj.kind {
+            ObjKind::Instance => {
+                let instance = obj.cast::<ObjInstance>();
+                let fields: Vec<_> = instance.fields.iter().collect();
+                push(&mut pending, Value::Obj(instance.class.cast()));
+                for &(_, value) in fields.iter() {
+                    push(&mut pending, value);
+                }
+                objects.push(Contents::Fields(instance, fields));
+            }
+            ObjKind::List => {
+                let list = obj.cast::<ObjList>();
+                for &value in list.items.iter() {
+                    push(&mut pending, value);
+                }
+                objects.push(Contents::Items(list, list.items.clone()));
+            }
+            ObjKind::FloatArray => {
+                let array = obj.cast::<ObjFloatArray>();
+                objects.push(Contents::Floats(array, array.values.clone()));
+            }
+            ObjKind::Upvalue => {
+                let upvalue = obj.cast::<ObjUpvalue>();
+                // Open ones point into the stack, which is saved already
+                let closed = unsafe { addr_of_mut!((*upvalue.as_ptr()).closed) };
+                if upvalue.location.as_ptr() == closed {
+                    push(&mut pending, upvalue.closed);
+                    objects.push(Contents::Closed(upvalue, upvalue.closed));
+                }
+            }
+            ObjKind::Closure => {
+                let closure = obj.cast::<ObjClosure>();
+                let upvalues = unsafe {
+                    std::slice::from_raw_parts(
+                        closure.upvalues.as_ptr(),
+                        closure.upvalue_count as usize,
+                    )
+                };
+                for &upvalue in upvalues {
+                    if let Some(upvalue) = NonNull::new(upvalue) {
+                        pending.push(Gc::new(upvalue.cast()));
+                    }
+                }
+            }
+            ObjKind::Class => {
+                let class = obj.cast::<ObjClass>();
+                let methods: Vec<_> = class
+                    .methods
+                    .iter()
+                    .map(|entry| (NonNull::new(entry.key).unwrap(), entry.value))
+                    .collect();
+                for &(_, value) in methods.iter() {
+                    push(&mut pending, value);
+                }
+                if let Some(superclass) = class.superclass {
+                    pending.push(superclass.cast());
+                }
+                objects.push(Contents::Methods(class, methods, class.superclass));
+            }
+            ObjKind::BoundMethod => {
+                let bound = obj.cast::<ObjBoundMethod>();
+                push(&mut pending, bound.receiver);
+                pending.push(bound.method.cast());
+            }
+            // Never change once made. Weak refs only lose their target,
+            // which stays alive while a snapshot references it.
+            ObjKind::Str | ObjKind::StrView | ObjKind::Fn | ObjKind::Native | ObjKind::WeakRef => {}
+        }
+    }
+    objects
+}
@@ -0,0 +1,283 @@
+//! Every error message the compiler and the VM report, with a stable code.
+//!
+//! Tools can match on an [`ErrorCode`] instead of the text, which may change
+//! or be translated. Compile errors carry theirs in
+//! [`Diagnostic::code`](crate::compile::Diagnostic::code), runtime errors in
+//! [`VM::last_error`](crate::vm::VM::last_error). Runtime errors are numbered
+//! from E0001, compile errors from E0100 and compile warnings from W0001.
+//! Natives fail with a message of their own, those are all
+//! [`ErrorCode::Native`].
+//!
+//! Messages are English unless a [`Translator`] is installed with
+//! [`set_translator`].
+//!
+//! With [`ErrorFormat::Json`] both are written to stderr as a JSON object
+//! per line instead, for editors and other tools to read.
+
+use std::{fmt::Display, sync::RwLock};
+
+macro_rules! catalog {
+    ($($(#[$doc:meta])* $name:ident = $code:literal, $template:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum ErrorCode {
+            $($(#[$doc])* $name,)*
+        }
+
+        impl ErrorCode {
+            /// Every code, in catalog order
+            pub const ALL: &'static [ErrorCode] = &[$(ErrorCode::$name,)*];
+
+            /// E.g. "E0001"
+            pub fn code(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$name => $code,)*
+                }
+            }
+
+            /// The English message, with a `{}` for each argument
+            pub fn template(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$name => $template,)*
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    UndefinedVariable = "E0001", "Undefined variable: {}";
+    UndefinedProperty = "E0002", "Undefined property {}";
+    OperandsMustBeNumbersOrStrings = "E0003", "Operands must be two numbers or two strings.";
+    OperandMustBeNumber = "E0004", "Operand must be a number.";
+    /// The expected count, then the count passed
+    WrongArity = "E0005", "Expected {} arguments but got {}.";
+    StackOverflow = "E0006", "Stack overflow.";
+    NotCallable = "E0007", "Can only call functions and classes.";
+    SuperclassNotClass = "E0008", "Superclass must be a class.";
+    PropertyOfNonInstance = "E0009", "Only instances have properties.";
+    FieldOfNonInstance = "E0010", "Only instances have fields.";
+    NotIndexable = "E0011", "Only lists, float arrays and strings can be indexed.";
+    InvalidIndex = "E0012", "Index must be an integer.";
+    /// The index, the kind of collection, then its length
+    IndexOutOfRange = "E0013", "Index {} is out of range for a {} of length {}.";
+    FloatArrayItemNotNumber = "E0014", "Float array items must be numbers.";
+    Interrupted = "E0015", "Interrupted.";
+    StepLimitExceeded = "E0016", "Step limit exceeded.";
+    InvalidBytecode = "E0017", "Invalid bytecode. {}";
+    /// A panic inside the VM, with its message
+    Internal = "E0018", "Internal error: {}";
+    ExtendNonClass = "E0019", "Can only extend a class.";
+    IsNotClass = "E0020", "Right operand of 'is' must be a class.";
+    /// The types of the two operands
+    StrictEquality = "E0021", "Can't compare a {} with a {} in strict equality mode.";
+    DestructureNonList = "E0022", "Can only destructure a list.";
+    /// How many variables, then how many items the list has
+    DestructureCount = "E0023", "Expected {} values to destructure but got {}.";
+    InvalidRepeatCount = "E0024", "Can only repeat a string or list a non-negative whole number of times.";
+    /// The kind of sequence, its length, then the limit
+    RepeatTooLong = "E0025", "Repeating would make a {} of length {}, longer than {}.";
+    NotSliceable = "E0026", "Only lists and strings can be sliced.";
+    InvalidSliceBound = "E0027", "Slice bounds must be integers or nil.";
+    /// The start and end as written, the kind of sequence, then its length
+    SliceOutOfRange = "E0028", "Slice {}:{} is out of range for a {} of length {}.";
+    /// The start and end as written
+    SliceSplitsCharacter = "E0029", "Slice {}:{} splits a character of the string.";
+    StringNotMutable = "E0030", "Strings can't be changed.";
+    /// The index
+    IndexSplitsCharacter = "E0031", "Index {} is in the middle of a character of the string.";
+    /// The native's name
+    Nondeterministic = "E0032", "Can't call {} in deterministic mode, its result isn't reproducible.";
+    /// The result
+    NotFinite = "E0033", "Arithmetic result {} isn't a finite number in strict mode.";
+
+    UnexpectedCharacter = "E0100", "Unexpected character.";
+    UnterminatedString = "E0101", "Unterminated string.";
+    ExpectExpression = "E0102", "Expect expression";
+    ExpectEndOfExpression = "E0103", "Expect end of expression.";
+    InvalidAssignmentTarget = "E0104", "Invalid assignment target.";
+    NestedTooDeeply = "E0105", "Expression too deeply nested.";
+    ExpectParenAfterExpression = "E0106", "Expect ')' after expression.";
+    TooManyArguments = "E0107", "Can't have more than 255 arguments.";
+    ExpectParenAfterArguments = "E0108", "Expect ')' after arguments.";
+    TooManyListItems = "E0109", "Can't have more than 255 items in a list literal.";
+    ExpectBracketAfterListItems = "E0110", "Expect ']' after list items.";
+    ExpectBracketAfterIndex = "E0111", "Expect ']' after index.";
+    ExpectPropertyName = "E0112", "Expect property name after '.'.";
+    ExpectSemicolonAfterValue = "E0113", "Expect ';' after value.";
+    ExpectSemicolonAfterExpression = "E0114", "Expect ';' after expression.";
+    ExpectVariableName = "E0115", "Expect variable name.";
+    ExpectSemicolonAfterVariable = "E0116", "Expect ';' after variable declaration.";
+    AlreadyDeclared = "E0117", "Already a variable with this name in this scope.";
+    ReadInOwnInitializer = "E0118", "Can't read local variable in its own initializer.";
+    TooManyLocals = "E0119", "Too many local variables in function.";
+    TooManyUpvalues = "E0120", "Too many closure variables in function.";
+    TooManyConstants = "E0121", "Too many constants in one chunk.";
+    TooManyNumbers = "E0122", "Too many numbers in one chunk.";
+    ExpectBraceAfterBlock = "E0123", "Expect '}' after block.";
+    ExpectParenAfterIf = "E0124", "Expect '(' after 'if'.";
+    ExpectParenAfterCondition = "E0125", "Expect ')' after condition.";
+    ExpectParenAfterWhile = "E0126", "Expect '(' after 'while'.";
+    ExpectParenAfterFor = "E0127", "Expect '(' after 'for'.";
+    ExpectSemicolonAfterLoopCondition = "E0128", "Expect ';' after loop condition.";
+    ExpectParenAfterForClauses = "E0129", "Expect ')' after for clauses.";
+    LoopTooLarge = "E0130", "Loop body too large.";
+    JumpTooLarge = "E0131", "Too much code to jump over.";
+    ExpectFunctionName = "E0132", "Expect function name.";
+    ExpectParenAfterFunctionName = "E0133", "Expect '(' after function name.";
+    ExpectParameterName = "E0134", "Expect parameter name.";
+    TooManyParameters = "E0135", "Can't have more than 255 parameters.";
+    ExpectParenAfterParameters = "E0136", "Expect ')' after parameters.";
+    ExpectBraceBeforeFunctionBody = "E0137", "Expect '{' before function body.";
+    ExpectSemicolonAfterReturnValue = "E0138", "Expect ';' after return value.";
+    ReturnFromInitializer = "E0139", "Can't return a value from an initializer.";
+    ExpectClassName = "E0140", "Expect class name.";
+    ExpectSuperclassName = "E0141", "Expect superclass name.";
+    InheritFromSelf = "E0142", "A class can't inherit from itself.";
+    ExpectBraceBeforeClassBody = "E0143", "Expect '{' before class body.";
+    ExpectBraceAfterClassBody = "E0144", "Expect '}' after class body.";
+    ExpectMethodName = "E0145", "Expect method name.";
+    ThisOutsideClass = "E0146", "Can't use 'this' outside of a class.";
+    SuperOutsideClass = "E0147", "Can't use 'super' outside of a class.";
+    SuperWithoutSuperclass = "E0148", "Can't use 'super' in a class with no superclass.";
+    ExpectDotAfterSuper = "E0149", "Expect '.' after 'super'.";
+    ExpectSuperclassMethodName = "E0150", "Expect superclass method name.";
+    ExpectParenAfterVariables = "E0151", "Expect ')' after variable names.";
+    ExpectEqualAfterVariables = "E0152", "Expect '=' after destructured variables.";
+    TooManyReturnValues = "E0153", "Can't return more than 255 values.";
+    MalformedNumber = "E0154", "Malformed number literal.";
+    UnterminatedComment = "E0155", "Unterminated block comment.";
+
+    UndeclaredAssignment = "W0001", "Assignment to a global that is never declared.";
+    ChainedComparison = "W0002", "Chained comparison compares a boolean to the next operand.";
+    UnusedFunction = "W0003", "Function is never used.";
+    UnusedGlobal = "W0004", "Global is never used.";
+
+    /// The message a native failed with
+    Native = "E0300", "{}";
+}
+
+/// Looks up the message for a code in another language, with a `{}` for
+/// each argument in the same order as the English one. `None` falls back to
+/// English.
+pub type Translator = fn(ErrorCode) -> Option<&'static str>;
+
+static TRANSLATOR: RwLock<Option<Translator>> = RwLock::new(None);
+
+/// Translate every message reported from now on, or go back to English with
+/// `None`
+pub fn set_translator(translator: Option<Translator>) {
+    *TRANSLATOR.write().unwrap_or_else(|err| err.into_inner()) = translator;
+}
+
+impl ErrorCode {
+    /// The message for this code, translated if there's a [`Translator`],
+    /// with each `{}` replaced by the next of `args`
+    pub fn message(self, args: &[&dyn Display]) -> String {
+        let translator = *TRANSLATOR.read().unwrap_or_else(|err| err.into_inner());
+        let template = translator
+            .and_then(|translate| translate(self))
+            .unwrap_or(self.template());
+
+        let mut message = String::with_capacity(template.len());
+        let mut args = args.iter();
+        let mut pieces = template.split("{}");
+        message.push_str(pieces.next().unwrap_or_default());
+        for piece in pieces {
+            if let Some(arg) = args.next() {
+                message.push_str(&arg.to_string());
+            }
+            message.push_str(piece);
+        }
+        message
+    }
+
+    /// An [`Error`] with this code and its [`message`](ErrorCode::message)
+    pub fn error(self, args: &[&dyn Display]) -> Error {
+        Error {
+            code: self,
+            message: self.message(args),
+            trace: vec![],
+            notes: vec![],
+        }
+    }
+
+    /// The code of an error token's message, see
+    /// [`Scanner`](crate::scanner::Scanner)
+    pub fn of_scanned(message: &str) -> Option<ErrorCode> {
+        [
+            ErrorCode::UnexpectedCharacter,
+            ErrorCode::UnterminatedString,
+            ErrorCode::MalformedNumber,
+            ErrorCode::UnterminatedComment,
+        ]
+        .into_iter()
+        .find(|code| code.template() == message)
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// A runtime error as it was reported
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Where it happened, a line per frame from the innermost out
+    pub trace: Vec<String>,
+    /// Reported after the stack trace, like which functions a stack
+    /// overflow blames
+    pub notes: Vec<String>,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// How compile errors and runtime errors are written to stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// As their message, after where they are
+    #[default]
+    Human,
+    /// As a JSON object per line, with the same fields for both:
+    /// `severity`, `code`, `message`, `file`, `line`, `column` and `span`,
+    /// the byte offsets of the token a compile error is at. Runtime errors
+    /// have no span, and add their `trace` and `notes`.
+    Json,
+}
+
+impl ErrorFormat {
+    /// `human` or `json`
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// `text` as a JSON string
+pub fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,131 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::obj::ObjKind;
+
+/// Hooks for embedders to observe a running script. Every method has a
+/// default, so implementors only override what they care about.
+pub trait VmEvents {
+    /// A script called `log(level, message)`
+    fn log(&mut self, level: LogLevel, message: &str) {
+        eprintln!("[{level}] {message}");
+    }
+
+    /// A collection cycle is starting, with `live_bytes` of objects
+    /// allocated and not freed yet
+    fn gc_start(&mut self, live_bytes: usize) {
+        let _ = live_bytes;
+    }
+
+    /// A collection cycle finished. Called from inside the collector, so it
+    /// can't run any Lox.
+    fn gc_end(&mut self, stats: &GcStats) {
+        let _ = stats;
+    }
+
+    /// A function was called, `depth` calls deep from the script, with
+    /// `args` as a stack trace shows them. Only reported for functions
+    /// compiled with the [`CallTracing`](crate::passes::CallTracing) pass.
+    fn function_entered(&mut self, depth: usize, name: &str, args: &str) {
+        let _ = (depth, name, args);
+    }
+
+    /// The function [`function_entered`](VmEvents::function_entered) at
+    /// the same depth returned `result`, as `inspect` shows it. Not
+    /// reported for a call a runtime error unwound.
+    fn function_returned(&mut self, depth: usize, name: &str, result: &str) {
+        let _ = (depth, name, result);
+    }
+}
+
+/// What one collection cycle did, for [`VmEvents::gc_end`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcStats {
+    /// Bytes of objects live when the cycle started, not counting what they
+    /// own like a string's characters
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    /// Time spent collecting. An incremental cycle only counts its steps,
+    /// not the script running between them.
+    pub duration: Duration,
+    /// Objects freed, indexed by [`ObjKind`]
+    pub freed: [u64; ObjKind::ALL.len()],
+}
+
+impl GcStats {
+    pub fn new(bytes_before: usize) -> Self {
+        Self {
+            bytes_before,
+            bytes_after: bytes_before,
+            duration: Duration::ZERO,
+            freed: [0; ObjKind::ALL.len()],
+        }
+    }
+
+    /// How many objects of `kind` were freed
+    pub fn freed(&self, kind: ObjKind) -> u64 {
+        self.freed[kind as usize]
+    }
+
+    pub fn total_freed(&self) -> u64 {
+        self.freed.iter().sum()
+    }
+}
+
+/// The default: logs go to stderr
+pub struct StderrEvents;
+
+impl VmEvents for StderrEvents {}
+
+/// Logs go to stderr, and so does a tree of the traced calls, each
+/// indented under the call that made it, see `loxide --trace-calls`
+pub struct CallTreeEvents;
+
+impl VmEvents for CallTreeEvents {
+    fn function_entered(&mut self, depth: usize, name: &str, args: &str) {
+        eprintln!("{:indent$}{name}({args})", "", indent = depth * 2);
+    }
+
+    fn function_returned(&mut self, depth: usize, name: &str, result: &str) {
+        eprintln!("{:indent$}{name} -> {result}", "", indent = depth * 2);
+    }
+}
+
+/// Levels accepted by the `log` native, named as in the `log` crate so
+/// bridging to it (or `tracing`) is a one-to-one mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "trace" => Some(Self::Trace),
+            "debug" => Some(Self::Debug),
+            "info" => Some(Self::Info),
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Trace => "trace",
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,279 @@
+//! How instances store their fields, in two versions with the same
+//! interface: [`ShapedFields`], which keeps values in slots laid out by a
+//! [`Shape`] shared with every instance of the class that got the same
+//! fields in the same order, and [`TableFields`], a hash table per instance.
+//! The `table_fields` feature makes instances use the table, and
+//! `cargo bench --bench fields` compares the two.
+//!
+//! Each class owns a tree of shapes, starting from the empty one its
+//! instances are created with. Adding a field moves an instance to the
+//! child shape for that name, making it the first time it's needed. An
+//! instance with [`MAX_SLOTS`] fields keeps any more in a table.
+
+use std::ptr::NonNull;
+
+use crate::{
+    mem::Greystack,
+    obj::{Obj, ObjString},
+    table::Table,
+    value::Value,
+};
+
+/// The most fields a shape lays out, the rest go in the instance's table
+pub const MAX_SLOTS: usize = 32;
+
+pub trait FieldStorage {
+    /// No fields yet, for an instance of the class whose shapes start at
+    /// `root`
+    fn new(root: NonNull<Shape>) -> Self;
+
+    fn get(&self, name: NonNull<ObjString>) -> Option<Value>;
+    /// Returns true if the instance didn't have the field
+    fn set(&mut self, name: NonNull<ObjString>, value: Value) -> bool;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The shape laying out the slots, if the fields are kept in any
+    fn shape(&self) -> Option<NonNull<Shape>>;
+    /// The value in `slot` of the [`shape`](FieldStorage::shape)
+    fn slot(&self, slot: usize) -> Value;
+
+    /// Every field and its value, in no particular order. Boxed, since
+    /// trait methods can't return `impl Iterator` before Rust 1.75.
+    fn iter(&self) -> Box<dyn Iterator<Item = (NonNull<ObjString>, Value)> + '_>;
+
+    /// Mark the values, the names are marked with the class's shapes
+    fn mark(&self, greystack: &mut Greystack);
+    fn free(&mut self);
+}
+
+/// The storage instances use, [`TableFields`] with the `table_fields`
+/// feature
+#[cfg(not(feature = "table_fields"))]
+pub type Fields = ShapedFields;
+#[cfg(feature = "table_fields")]
+pub type Fields = TableFields;
+
+/// The fields of an instance, in the order they were added
+pub struct Shape {
+    names: Vec<NonNull<ObjString>>,
+    /// The shape an instance moves to when it gets each new field
+    transitions: Vec<(NonNull<ObjString>, NonNull<Shape>)>,
+}
+
+impl Shape {
+    /// A tree with just the empty shape, freed with [`Shape::free`]
+    pub fn root() -> NonNull<Shape> {
+        Self::leak(vec![])
+    }
+
+    fn leak(names: Vec<NonNull<ObjString>>) -> NonNull<Shape> {
+        let shape = Box::new(Shape {
+            names,
+            transitions: vec![],
+        });
+        unsafe { NonNull::new_unchecked(Box::into_raw(shape)) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The slot holding `name`
+    #[inline]
+    pub fn slot(&self, name: NonNull<ObjString>) -> Option<usize> {
+        self.names.iter().position(|&n| n == name)
+    }
+
+    /// This shape with `name` added
+    fn with(&mut self, name: NonNull<ObjString>) -> NonNull<Shape> {
+        if let Some(&(_, shape)) = self.transitions.iter().find(|&&(n, _)| n == name) {
+            return shape;
+        }
+        let mut names = self.names.clone();
+        names.push(name);
+        let shape = Self::leak(names);
+        self.transitions.push((name, shape));
+        shape
+    }
+
+    /// Mark the names of this shape and of every shape after it
+    pub fn mark(&self, greystack: &mut Greystack) {
+        // A child's names start with its parent's
+        if self.transitions.is_empty() {
+            for name in &self.names {
+                Obj::mark(name.as_ptr().cast(), greystack);
+            }
+        }
+        for (_, shape) in &self.transitions {
+            unsafe { shape.as_ref() }.mark(greystack);
+        }
+    }
+
+    /// Free `root` and every shape after it
+    ///
+    /// # Safety
+    /// Nothing can use any of them afterwards, so the class owning them has
+    /// to be freed along with every instance of it
+    pub unsafe fn free(root: NonNull<Shape>) {
+        let shape = Box::from_raw(root.as_ptr());
+        for &(_, child) in &shape.transitions {
+            Shape::free(child);
+        }
+    }
+}
+
+/// Values in slots laid out by a [`Shape`], plus a table for the fields
+/// past [`MAX_SLOTS`]
+pub struct ShapedFields {
+    shape: NonNull<Shape>,
+    slots: Vec<Value>,
+    overflow: Table,
+}
+
+impl ShapedFields {
+    #[inline]
+    fn shape(&self) -> &Shape {
+        unsafe { self.shape.as_ref() }
+    }
+}
+
+impl FieldStorage for ShapedFields {
+    fn new(root: NonNull<Shape>) -> Self {
+        Self {
+            shape: root,
+            slots: vec![],
+            overflow: Table::new(),
+        }
+    }
+
+    #[inline]
+    fn get(&self, name: NonNull<ObjString>) -> Option<Value> {
+        match self.shape().slot(name) {
+            Some(slot) => Some(self.slots[slot]),
+            None if self.slots.len() == MAX_SLOTS => self.overflow.get(name),
+            None => None,
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, name: NonNull<ObjString>, value: Value) -> bool {
+        if let Some(slot) = self.shape().slot(name) {
+            self.slots[slot] = value;
+            return false;
+        }
+        if self.slots.len() == MAX_SLOTS {
+            return self.overflow.set(name, value);
+        }
+        self.shape = unsafe { self.shape.as_mut() }.with(name);
+        self.slots.push(value);
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() + self.overflow.iter().count()
+    }
+
+    #[inline]
+    fn shape(&self) -> Option<NonNull<Shape>> {
+        Some(self.shape)
+    }
+
+    #[inline]
+    fn slot(&self, slot: usize) -> Value {
+        self.slots[slot]
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (NonNull<ObjString>, Value)> + '_> {
+        let slots = self
+            .shape()
+            .names
+            .iter()
+            .copied()
+            .zip(self.slots.iter().copied());
+        let overflow = self
+            .overflow
+            .iter()
+            .map(|entry| (unsafe { NonNull::new_unchecked(entry.key) }, entry.value));
+        Box::new(slots.chain(overflow))
+    }
+
+    fn mark(&self, greystack: &mut Greystack) {
+        for value in &self.slots {
+            value.mark(greystack);
+        }
+        self.overflow.mark(greystack);
+    }
+
+    fn free(&mut self) {
+        Table::free(&mut self.overflow);
+    }
+}
+
+impl std::fmt::Debug for ShapedFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Not the values, an instance can hold itself
+        f.debug_struct("ShapedFields")
+            .field("shape", &self.shape)
+            .field("slots", &self.slots.len())
+            .field("overflow", &self.overflow)
+            .finish()
+    }
+}
+
+/// A hash table of fields per instance
+#[derive(Debug)]
+pub struct TableFields {
+    table: Table,
+}
+
+impl FieldStorage for TableFields {
+    fn new(_root: NonNull<Shape>) -> Self {
+        Self {
+            table: Table::new(),
+        }
+    }
+
+    #[inline]
+    fn get(&self, name: NonNull<ObjString>) -> Option<Value> {
+        self.table.get(name)
+    }
+
+    #[inline]
+    fn set(&mut self, name: NonNull<ObjString>, value: Value) -> bool {
+        self.table.set(name, value)
+    }
+
+    fn len(&self) -> usize {
+        self.table.iter().count()
+    }
+
+    fn shape(&self) -> Option<NonNull<Shape>> {
+        None
+    }
+
+    fn slot(&self, _slot: usize) -> Value {
+        unreachable!("Table fields have no slots")
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (NonNull<ObjString>, Value)> + '_> {
+        Box::new(
+            self.table
+                .iter()
+                .map(|entry| (unsafe { NonNull::new_unchecked(entry.key) }, entry.value)),
+        )
+    }
+
+    fn mark(&self, greystack: &mut Greystack) {
+        self.table.mark(greystack);
+    }
+
+    fn free(&mut self) {
+        Table::free(&mut self.table);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{mem::Greystack, value::Value};
+
+/// Values the host is holding on to outside of the VM, which the GC treats as
+/// roots. A `Value` (or `Gc`) kept in a Rust local is invisible to the
+/// collector, so anything that can allocate in between may free it; pin it
+/// with [`VM::pin`](crate::vm::VM::pin) and hold the [`Handle`] instead.
+#[derive(Clone, Default)]
+pub struct PinTable(Rc<RefCell<PinSlots>>);
+
+#[derive(Default)]
+struct PinSlots {
+    values: Vec<Option<Value>>,
+    free: Vec<usize>,
+}
+
+impl PinTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pin(&self, value: Value) -> Handle {
+        let mut slots = self.0.borrow_mut();
+        let slot = match slots.free.pop() {
+            Some(slot) => {
+                slots.values[slot] = Some(value);
+                slot
+            }
+            None => {
+                slots.values.push(Some(value));
+                slots.values.len() - 1
+            }
+        };
+
+        Handle {
+            value,
+            slot,
+            table: self.clone(),
+        }
+    }
+
+    /// How many values are currently pinned
+    pub fn len(&self) -> usize {
+        let slots = self.0.borrow();
+        slots.values.len() - slots.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn mark(&self, greystack: &mut Greystack) {
+        for value in self.0.borrow().values.iter().flatten() {
+            value.mark(greystack);
+        }
+    }
+
+    fn unpin(&self, slot: usize) {
+        let mut slots = self.0.borrow_mut();
+        slots.values[slot] = None;
+        slots.free.push(slot);
+    }
+}
+
+/// A pinned value, kept alive by the GC until the handle is dropped
+pub struct Handle {
+    value: Value,
+    slot: usize,
+    table: PinTable,
+}
+
+impl Handle {
+    #[inline]
+    pub fn get(&self) -> Value {
+        self.value
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        self.table.pin(self.value)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.table.unpin(self.slot);
+    }
+}
+
+impl std::fmt::Debug for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Handle").field(&self.value).finish()
+    }
+}
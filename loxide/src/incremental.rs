@@ -0,0 +1,276 @@
+use std::ops::Range;
+
+use crate::{
+    compile::{Diagnostic, Parser},
+    mem::Mem,
+    scanner::{Scanner, Span, TokenKind},
+};
+
+/// A token that doesn't borrow the source it was scanned from, so it can be
+/// kept around while the source is being edited
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CachedToken {
+    pub kind: TokenKind,
+    pub line: u32,
+    pub span: Span,
+}
+
+/// A single text replacement, in byte offsets: `start..old_end` in the old
+/// source was replaced by `start..new_end` in the new source
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Edit {
+    pub start: u32,
+    pub old_end: u32,
+    pub new_end: u32,
+}
+
+/// The tokens of a source file, kept up to date across edits by only
+/// rescanning the lines an edit touches
+#[derive(Debug, Default)]
+pub struct TokenCache {
+    pub tokens: Vec<CachedToken>,
+}
+
+impl TokenCache {
+    pub fn new(src: &str) -> Self {
+        Self {
+            tokens: Self::scan(Scanner::new(src)),
+        }
+    }
+
+    fn scan(scanner: Scanner) -> Vec<CachedToken> {
+        scanner
+            .map(|tok| CachedToken {
+                kind: tok.kind,
+                line: tok.line,
+                span: tok.span,
+            })
+            .collect()
+    }
+
+    /// Update the cache after `edit` was applied, `src` being the new source.
+    ///
+    /// Scanning restarts at the first token on the line before the edit and
+    /// stops as soon as it produces a token identical (after shifting) to one
+    /// from after the edit, since the rest of the source is unchanged from
+    /// there on. Returns the indices of the tokens that were rescanned.
+    pub fn relex(&mut self, src: &str, edit: Edit) -> Range<usize> {
+        let delta = edit.new_end as i64 - edit.old_end as i64;
+
+        // Tokens ending strictly before the edit can't have changed, but we
+        // rescan their whole line anyway so the restart point is a token start
+        let mut first = self
+            .tokens
+            .iter()
+            .rposition(|tok| tok.kind != TokenKind::Eof && tok.span.end < edit.start)
+            .unwrap_or(0);
+        while first > 0 && self.tokens[first - 1].line == self.tokens[first].line {
+            first -= 1;
+        }
+
+        let (offset, line) = match self.tokens.get(first) {
+            Some(tok) if tok.span.end < edit.start => (tok.span.start, tok.line),
+            _ => (0, 1),
+        };
+
+        let mut rescanned = vec![];
+        let mut old = first;
+        for tok in Scanner::starting_at(src, offset, line) {
+            // Skip over old tokens that are behind the scanner
+            while old < self.tokens.len()
+                && (self.tokens[old].span.start < edit.old_end
+                    || self.tokens[old].span.start as i64 + delta < tok.span.start as i64)
+            {
+                old += 1;
+            }
+
+            let resynced = match self.tokens.get(old) {
+                Some(old_tok) => {
+                    tok.kind != TokenKind::Eof
+                        && old_tok.kind == tok.kind
+                        && old_tok.span.start as i64 + delta == tok.span.start as i64
+                        && old_tok.span.end as i64 + delta == tok.span.end as i64
+                }
+                None => false,
+            };
+
+            if resynced {
+                let line_delta = tok.line as i64 - self.tokens[old].line as i64;
+                for old_tok in self.tokens[old..].iter_mut() {
+                    old_tok.line = (old_tok.line as i64 + line_delta) as u32;
+                    old_tok.span.start = (old_tok.span.start as i64 + delta) as u32;
+                    old_tok.span.end = (old_tok.span.end as i64 + delta) as u32;
+                }
+                break;
+            }
+
+            rescanned.push(CachedToken {
+                kind: tok.kind,
+                line: tok.line,
+                span: tok.span,
+            });
+
+            if tok.kind == TokenKind::Eof {
+                old = self.tokens.len();
+            }
+        }
+
+        let rescanned_len = rescanned.len();
+        self.tokens.splice(first..old, rescanned);
+
+        first..first + rescanned_len
+    }
+
+    /// Find the top-level `fun` or `class` declaration containing the token at
+    /// `index`, returning the range of its tokens
+    pub fn containing_declaration(&self, index: usize) -> Option<Range<usize>> {
+        let mut depth = 0u32;
+        let mut decl_start = None;
+
+        for (i, tok) in self.tokens.iter().enumerate() {
+            match tok.kind {
+                TokenKind::Fun | TokenKind::Class if depth == 0 => decl_start = Some(i),
+                TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightBrace => {
+                    depth = depth.saturating_sub(1);
+                    match decl_start {
+                        Some(start) if depth == 0 => {
+                            if (start..=i).contains(&index) {
+                                return Some(start..i + 1);
+                            }
+                            decl_start = None;
+                        }
+                        _ => (),
+                    }
+                }
+                // A statement at the top level ends a declaration that never
+                // opened its body
+                TokenKind::Semicolon if depth == 0 => decl_start = None,
+                _ => (),
+            }
+
+            if i >= index && decl_start.is_none() && depth == 0 {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+/// An open source file for editor tooling: its text, tokens and compile
+/// diagnostics, all updated incrementally on each edit
+pub struct Document {
+    pub src: String,
+    pub tokens: TokenCache,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    pub fn new(src: String) -> Self {
+        let tokens = TokenCache::new(&src);
+        let diagnostics = check(&src, 0, 1);
+
+        Self {
+            src,
+            tokens,
+            diagnostics,
+        }
+    }
+
+    /// Replace the bytes in `range` with `text`.
+    ///
+    /// If the edit stays inside a single top-level function or class (and
+    /// doesn't change where it ends), only that declaration is compiled
+    /// again, otherwise the whole document is.
+    pub fn edit(&mut self, range: Range<u32>, text: &str) {
+        let old_line_count = self.src[range.start as usize..range.end as usize]
+            .matches('\n')
+            .count() as i64;
+        let new_line_count = text.matches('\n').count() as i64;
+
+        let old_decl = self.declaration_at(range.start);
+
+        self.src
+            .replace_range(range.start as usize..range.end as usize, text);
+        let edit = Edit {
+            start: range.start,
+            old_end: range.end,
+            new_end: range.start + text.len() as u32,
+        };
+        self.tokens.relex(&self.src, edit);
+
+        let new_decl = self.declaration_at(range.start);
+
+        let (old_lines, new_span, new_lines) = match (old_decl, new_decl) {
+            (Some((old_span, old_lines)), Some((new_span, new_lines)))
+                if old_span.start == new_span.start
+                    && old_span.start <= range.start
+                    && range.end <= old_span.end
+                    && old_span.end as i64 + (edit.new_end as i64 - edit.old_end as i64)
+                        == new_span.end as i64 =>
+            {
+                (old_lines, new_span, new_lines)
+            }
+            _ => {
+                self.diagnostics = check(&self.src, 0, 1);
+                return;
+            }
+        };
+
+        let line_delta = new_line_count - old_line_count;
+        let delta = edit.new_end as i64 - edit.old_end as i64;
+        let mut diagnostics: Vec<Diagnostic> = std::mem::take(&mut self.diagnostics)
+            .into_iter()
+            .filter(|d| !old_lines.contains(&d.line))
+            .map(|mut d| {
+                if d.line >= old_lines.end {
+                    d.line = (d.line as i64 + line_delta) as u32;
+                    d.span.start = (d.span.start as i64 + delta) as u32;
+                    d.span.end = (d.span.end as i64 + delta) as u32;
+                }
+                d
+            })
+            .collect();
+        // Scanning the new declaration where it is keeps its diagnostics'
+        // spans and columns right
+        diagnostics.extend(check(
+            &self.src[..new_span.end as usize],
+            new_span.start,
+            new_lines.start,
+        ));
+        diagnostics.sort_by_key(|d| d.line);
+
+        self.diagnostics = diagnostics;
+    }
+
+    /// Byte span and lines of the top-level declaration containing `offset`
+    fn declaration_at(&self, offset: u32) -> Option<(Span, Range<u32>)> {
+        let index = self
+            .tokens
+            .tokens
+            .iter()
+            .position(|tok| tok.span.end >= offset)?;
+        let decl = self.tokens.containing_declaration(index)?;
+
+        let first = self.tokens.tokens[decl.start];
+        let last = self.tokens.tokens[decl.end - 1];
+        Some((
+            Span {
+                start: first.span.start,
+                end: last.span.end,
+            },
+            first.line..last.line + 1,
+        ))
+    }
+}
+
+/// Compile `src` from byte `offset`, which is on line `line`, only to
+/// collect its diagnostics
+fn check(src: &str, offset: u32, line: u32) -> Vec<Diagnostic> {
+    let mut mem = Mem::new();
+    let mut parser = Parser::with_scanner(Scanner::starting_at(src, offset, line), &mut mem);
+    parser.print_errors = false;
+    parser.compile();
+    parser.diagnostics
+}
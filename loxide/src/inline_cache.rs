@@ -0,0 +1,94 @@
+//! Monomorphic inline caches for `GetProperty` and `Invoke`.
+//!
+//! Each function keeps a side table with an entry per byte of its code,
+//! made the first time one of its property lookups is cached. The entry at
+//! the offset of an instruction remembers the [`Shape`] of the last instance
+//! it looked a name up on, and where it found it: in one of the instance's
+//! slots, or as a method of its class. Every class has shapes of its own,
+//! so the shape stands for the class too.
+//!
+//! Entries carry the VM's cache epoch from when they were made. It moves on
+//! whenever a class gets a method, so a cached method is never one that's
+//! been replaced, and whenever a class is freed, so a new shape at the
+//! address of a freed one isn't mistaken for it. Instances stored in tables,
+//! with the `table_fields` feature, have no shape and are never cached.
+
+use std::ptr::NonNull;
+
+use crate::{fields::Shape, value::Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheHit {
+    /// A field, in this slot
+    Slot(usize),
+    /// A method of the class, which the instance has no field shadowing
+    Method(Value),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InlineCache {
+    shape: *const Shape,
+    epoch: u32,
+    hit: CacheHit,
+}
+
+impl InlineCache {
+    const EMPTY: InlineCache = InlineCache {
+        shape: std::ptr::null(),
+        epoch: 0,
+        hit: CacheHit::Slot(0),
+    };
+}
+
+/// The caches of one function, see the [module](self) docs
+#[derive(Debug, Default)]
+pub struct InlineCaches {
+    entries: Vec<InlineCache>,
+}
+
+impl InlineCaches {
+    /// What the instruction at `offset` found last time, if it was on an
+    /// instance with this shape since the epoch last moved
+    #[inline]
+    pub fn get(&self, offset: usize, shape: NonNull<Shape>, epoch: u32) -> Option<CacheHit> {
+        let entry = self.entries.get(offset)?;
+        match entry.shape == shape.as_ptr() && entry.epoch == epoch {
+            true => Some(entry.hit),
+            false => None,
+        }
+    }
+
+    /// Remember `hit` for the instruction at `offset` of a function with
+    /// `code_len` bytes of code
+    pub fn set(
+        &mut self,
+        code_len: usize,
+        offset: usize,
+        shape: NonNull<Shape>,
+        epoch: u32,
+        hit: CacheHit,
+    ) {
+        if self.entries.is_empty() {
+            self.entries = vec![InlineCache::EMPTY; code_len];
+        }
+        if let Some(entry) = self.entries.get_mut(offset) {
+            *entry = InlineCache {
+                shape: shape.as_ptr(),
+                epoch,
+                hit,
+            };
+        }
+    }
+
+    /// How many instructions have something cached
+    pub fn len(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.shape.is_null())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
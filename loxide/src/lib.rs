@@ -0,0 +1,4228 @@
+#![feature(ptr_sub_ptr)]
+#![feature(allocator_api)]
+#![feature(slice_ptr_get)]
+#![feature(let_chains)]
+
+pub mod alloc_trace;
+pub mod allocations;
+pub mod bench;
+pub mod callgraph;
+pub mod chunk;
+pub mod color;
+pub mod compile;
+pub mod conformance;
+pub mod coverage;
+pub mod debugger;
+pub mod errors;
+pub mod events;
+pub mod fields;
+pub mod handle;
+pub mod incremental;
+pub mod inline_cache;
+pub mod matrix;
+pub mod mem;
+pub mod mutate;
+pub mod native_fn;
+pub mod net;
+pub mod obj;
+pub mod options;
+pub mod passes;
+pub mod profile;
+pub mod reload;
+pub mod scanner;
+pub mod snapshot;
+pub mod stack;
+pub mod table;
+pub mod time;
+pub mod value;
+pub mod vm;
+
+use std::{
+    io::{self, BufRead},
+    rc::Rc,
+};
+
+use compile::Parser;
+use scanner::{Lexemes, Scanner};
+
+use vm::{InterpretError, InterpretResult};
+
+use crate::{
+    mem::{Gc, Mem},
+    obj::ObjFunction,
+    value::Value,
+    vm::VM,
+};
+
+#[macro_export]
+macro_rules! debug_println {
+    () => {
+        #[cfg(debug_assertions)]
+        $std::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        #[cfg(debug_assertions)]
+        println!($($arg)*);
+    };
+}
+
+/// Compile and run `src`, producing what the script returned, nil if it
+/// didn't `return`. A bug in the interpreter that panics is reported as a
+/// runtime error instead.
+pub fn interpret(vm: &mut VM, src: &str) -> InterpretResult<Value> {
+    vm.catch_panics(|vm| compile_and_run(vm, src))
+}
+
+fn compile_and_run(vm: &mut VM, src: &str) -> InterpretResult<Value> {
+    load(vm, src)?;
+    vm.run()
+}
+
+/// Compile `src` and set the VM up to run it, without running anything
+/// yet, e.g. to run it a slice at a time with
+/// [`VM::run_bounded`](vm::VM::run_bounded)
+pub fn load(vm: &mut VM, src: &str) -> InterpretResult<()> {
+    load_scanned(vm, Scanner::new(src), None)
+}
+
+/// Like [`interpret`], but compiling the source as it's read from `reader`
+/// without holding all of it in memory, see [`Scanner::streaming`]
+pub fn interpret_reader(vm: &mut VM, reader: impl BufRead) -> InterpretResult<Value> {
+    vm.catch_panics(|vm| {
+        load_reader(vm, reader)?;
+        vm.run()
+    })
+}
+
+/// Like [`load`], for a source read from `reader`. Failing to read it is
+/// reported like a compile error.
+pub fn load_reader(vm: &mut VM, reader: impl BufRead) -> InterpretResult<()> {
+    let lexemes = Lexemes::default();
+    load_scanned(vm, Scanner::streaming(reader, &lexemes), None)
+}
+
+/// Like [`interpret_reader`], for one of several files run one after the
+/// other in the same VM, sharing its globals. Its compile errors and the
+/// stack traces through its functions say which file they're in.
+pub fn interpret_file(vm: &mut VM, file: &str, reader: impl BufRead) -> InterpretResult<Value> {
+    vm.catch_panics(|vm| {
+        let lexemes = Lexemes::default();
+        let scanner = Scanner::streaming(reader, &lexemes);
+        load_scanned(vm, scanner, Some(file.into()))?;
+        vm.run()
+    })
+}
+
+fn load_scanned(vm: &mut VM, scanner: Scanner, file: Option<Rc<str>>) -> InterpretResult<()> {
+    let function = {
+        let mut parser = Parser::with_scanner(scanner, &mut vm.mem);
+        parser.file = file;
+        parser.max_expression_depth = vm.max_expression_depth;
+        parser.warn_undeclared = vm.warn_undeclared;
+        parser.warn_unused = vm.warn_unused && parser.file.is_none();
+        parser.asi = vm.asi;
+        parser.strict = vm.strict;
+        parser.color = vm.color;
+        parser.error_format = vm.error_format;
+        parser.passes = vm.passes.clone();
+        let compiled = parser.compile();
+        if let Some(err) = parser.read_error() {
+            eprintln!("Failed to read the source: {err}");
+            return Err(InterpretError::CompileError);
+        }
+        if !compiled {
+            return Err(InterpretError::CompileError);
+        }
+        parser.compiler.function
+    };
+
+    if let Some(mutation) = vm.mutation.take() {
+        mutation.apply(function, &mut vm.mem);
+    }
+
+    start(vm, function);
+    Ok(())
+}
+
+/// Compile `src` to a `.loxb` file, see [`chunk::write_loxb`]. Fails with
+/// the first compile error if it doesn't compile.
+pub fn compile_loxb(src: &str) -> Result<Vec<u8>, String> {
+    let mut mem = Mem::new();
+    let mut parser = Parser::new(src, &mut mem);
+    parser.print_errors = false;
+    if !parser.compile() {
+        return Err(parser.diagnostics[0].to_string());
+    }
+    let script = parser.compiler.function;
+
+    let mut bytes = vec![];
+    chunk::write_loxb(&script, &mem.numbers, &mut bytes).map_err(|err| err.to_string())?;
+    Ok(bytes)
+}
+
+/// Load a `.loxb` file, from [`compile_loxb`] or zlox, and set the VM up to
+/// run it like [`load`] does
+pub fn load_loxb(vm: &mut VM, bytes: &[u8]) -> io::Result<()> {
+    let function = chunk::read_loxb(&mut vm.mem, bytes)?;
+    start(vm, function);
+    Ok(())
+}
+
+/// Run what [`load`] or [`load_loxb`] set up, reporting a panic as a
+/// runtime error like [`interpret`] does
+pub fn run(vm: &mut VM) -> InterpretResult<Value> {
+    vm.catch_panics(VM::run)
+}
+
+fn start(vm: &mut VM, function: Gc<ObjFunction>) {
+    if vm.dump_bytecode {
+        function
+            .as_ref()
+            .disassemble_all_colored(&mut vm.debug_sink, vm.color)
+            .expect("Failed to write bytecode listing.");
+    }
+
+    if let Some(coverage) = vm.coverage.as_mut() {
+        coverage.add_function(function.as_ref());
+    }
+
+    vm.init(function);
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{
+        cell::{RefCell, UnsafeCell},
+        collections::{HashMap, HashSet},
+        mem::MaybeUninit,
+        ptr::NonNull,
+        rc::Rc,
+        time::Duration,
+    };
+
+    use crate::{
+        alloc_trace::{self, AllocTrace, TraceEvent, TraceReport},
+        allocations::Allocations,
+        bench,
+        chunk::{Chunk, Instruction, Opcode, UnknownOpcode},
+        compile::Parser,
+        conformance::CASES,
+        coverage::Coverage,
+        debugger::{Breakpoint, Debugger, Watch, WatchHit},
+        errors::{self, ErrorCode},
+        events::{GcStats, LogLevel, VmEvents},
+        fields::{self, FieldStorage, Shape, ShapedFields, TableFields},
+        incremental::{Document, Edit, TokenCache},
+        interpret, interpret_file, interpret_reader, load_reader,
+        mem::{Gc, Mem},
+        native_fn::{self, Capabilities, NativeCtx},
+        obj::{ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjList, ObjString},
+        options::VmOptions,
+        profile::{self, LineProfile, OpcodeHistogram},
+        scanner::{self, Lexemes, Scanner, Token, TokenKind},
+        snapshot,
+        stack::{CheckedStack, UncheckedStack, ValueStack},
+        table::{ObjHash, Table},
+        value::Value,
+        vm::{GcPhase, InterpretError, PrintOptions, StepResult, VM},
+    };
+    use proptest::prelude::*;
+
+    #[test]
+    fn fib() {
+        let src = r#"
+fun fib(x) {
+    if (x <= 1) {
+        return x;
+    }
+    return fib(x - 1) + fib(x - 2);
+}
+
+var result = fib(2);
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn superclasses() {
+        let src = r#"
+class Doughnut {
+  cook() {
+    print "Dunk in the fryer.";
+    this.finish("sprinkles");
+  }
+
+  finish(ingredient) {
+    return "Finish with " + ingredient;
+  }
+}
+
+class Cruller < Doughnut {
+  finish(ingredient) {
+    // No sprinkles, always icing.
+    return super.finish("icing");
+  }
+}
+
+var cruller = Cruller();
+var result = cruller.finish("noice");
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap().as_str().unwrap(), "Finish with icing");
+    }
+
+    #[test]
+    fn invoking_fields() {
+        let src = r#"
+        class Oops {
+            init() {
+                fun f() {
+                    return 420;
+                }
+
+                this.field = f;
+            }
+        }
+
+        var oops = Oops();
+        var result = oops.field();
+        "#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn misusing_this() {
+        let src = r#"
+        fun notMethod() {
+            print this;
+        }
+        "#;
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        if let Err(InterpretError::CompileError) = err {
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn nested_this() {
+        let src = r#"
+        class Nested {
+            method() {
+              fun function() {
+                return this.lol;
+              }
+              return function();
+            }
+        }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn this() {
+        let src = r#"
+        class Nested {
+            method() {
+              return this.lol;
+            }
+          }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn methods() {
+        let src = r#"
+        class Scone {
+            topping(first, second) {
+              return "scone with " + first + " and " + second;
+            }
+          }
+          
+          var scone = Scone();
+          var result = scone.topping("berries", "cream");"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(result_str);
+
+        let expected_str = vm.get_string("scone with berries and cream");
+        println!("VAL: {:?}", value);
+        assert_eq!(value, Some(Value::Obj(expected_str.cast())));
+    }
+
+    #[test]
+    fn instance_get_set() {
+        let src = r#"
+class Pair {}
+
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+var result = pair.first + pair.second;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn upvalue_closed() {
+        let src = r#"
+    fun makeClosure() {
+      var a = 1;
+      fun f() {
+        a = a + 1;
+        return a;
+      }
+      return f;
+    }
+
+    var closure = makeClosure();
+    var first = closure();
+    var anotherClosure = makeClosure();
+    var second = anotherClosure();
+    var third = closure();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let first_str = vm.get_string("first").as_non_null_ptr();
+        let second_str = vm.get_string("second").as_non_null_ptr();
+        let third_str = vm.get_string("third").as_non_null_ptr();
+
+        let value1 = vm.mem.globals.get(first_str);
+        let value2 = vm.mem.globals.get(second_str);
+        let value3 = vm.mem.globals.get(third_str);
+
+        assert_eq!(value1, Some(Value::Number(2.0)));
+        assert_eq!(value2, Some(Value::Number(2.0)));
+        assert_eq!(value3, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn set_immediate_upvalue() {
+        let src = r#"
+    fun outer() {
+      var x = 420;
+      fun inner() {
+        x = x + 1;
+        return x;
+      }
+      return inner();
+    }
+    var value = outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let value_str = vm.get_string("value").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(value_str);
+
+        assert_eq!(value, Some(Value::Number(421.0)));
+    }
+
+    #[test]
+    fn immediate_upvalue() {
+        let src = r#"
+var result = "nothing";
+fun outer() {
+  var x = 420;
+  fun inner() {
+    result = x;
+  }
+  inner();
+}
+outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn call_native_fn() {
+        let src = r#"
+        var num = __dummy();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num").as_non_null_ptr();
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn call_fn() {
+        let src = r#"
+            fun add420(num) {
+              return num + 420;
+            }
+
+            fun add69(num) {
+              return num + 69;
+            }
+
+            var num = add420(1);
+            num = add69(num);
+            num = add420(num);"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num").as_non_null_ptr();
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(910.0)));
+    }
+
+    #[test]
+    fn print_fn() {
+        let src = r#"
+            fun bigNoob() {
+              print "OH YEAH";
+            }
+
+            print bigNoob;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+    }
+
+    #[test]
+    fn if_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (420 > 69) { noob = "NICE"; } else { noob = "NOT NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn if_else_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (69 > 420) { noob = "wtf"; } else { noob = "NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn while_loop() {
+        let src = r#"
+            var noob = 0;
+            while (noob < 10) {
+              noob = noob + 1;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top, Some(Value::Number(10.0)));
+    }
+
+    #[test]
+    fn for_loop() {
+        let src = r#"
+            var noob = 420;
+            for (var x = 0; x < 10; x = x + 1) {
+              noob = x;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        // let noob = vm.get_string("global");
+        // let top = vm.mem.globals.get(noob);
+        // assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn locals() {
+        let src = r#"
+            var global = 420;
+            { var x = "HELLO"; x = "NICE"; global = x; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("global").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn string() {
+        let src = r#"var noob = "hello" + " sir" + " sir";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("hello sir sir"));
+    }
+
+    #[test]
+    fn print() {
+        let src = r#"print 1 + 2;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+    }
+
+    #[test]
+    fn table() {
+        let mut mem = Mem::new();
+        let mut table = Table::new();
+
+        let key = mem.copy_string("bagel").as_non_null_ptr();
+        assert_eq!(table.set(key, Value::Number(420.0)), true);
+        assert_eq!(table.set(key, Value::Number(69.0)), false);
+        assert_eq!(table.get(key), Some(Value::Number(69.0)));
+        assert_eq!(table.delete(key), true);
+        assert_eq!(table.delete(key), false);
+
+        Table::free(&mut table);
+    }
+
+    #[test]
+    fn dump_bytecode_declaration_order() {
+        let src = r#"
+        fun first() {
+            fun nested() {}
+        }
+        fun second() {}
+        "#;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let mut out = vec![];
+        parser
+            .compiler
+            .function
+            .as_ref()
+            .disassemble_all(&mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let headers: Vec<&str> = out
+            .lines()
+            .filter(|l| l.starts_with("== ") && !l.starts_with("== end"))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "== script ==",
+                "== first ==",
+                "== nested ==",
+                "== second =="
+            ]
+        );
+    }
+
+    #[test]
+    fn scanner_iter() {
+        let src = "var noob = \"hi\";\nnoob <= 420;";
+        let tokens: Vec<_> = Scanner::new(src)
+            .map(|tok| {
+                (
+                    tok.kind,
+                    tok.line,
+                    &src[tok.span.start as usize..tok.span.end as usize],
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::Var, 1, "var"),
+                (TokenKind::Identifier, 1, "noob"),
+                (TokenKind::Equal, 1, "="),
+                (TokenKind::String, 1, "\"hi\""),
+                (TokenKind::Semicolon, 1, ";"),
+                (TokenKind::Identifier, 2, "noob"),
+                (TokenKind::LessEqual, 2, "<="),
+                (TokenKind::Number, 2, "420"),
+                (TokenKind::Semicolon, 2, ";"),
+                (TokenKind::Eof, 2, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn number_literals() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = "var xs = [0xFF, 0B1010, 1e6, 1_000_000, 2.5E-1, 0x1_0];";
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            vm.get_global("xs").unwrap().to_string(),
+            "[255, 10, 1000000, 1000000, 0.25, 16]"
+        );
+
+        for malformed in [
+            "0x", "0b102", "1e", "1e+", "1__0", "1_", "1_.5", "0x_1", "12ab",
+        ] {
+            let tokens: Vec<_> = Scanner::new(malformed).collect();
+            assert_eq!(tokens.len(), 2, "{malformed}");
+            assert_eq!(tokens[0].kind, TokenKind::Error, "{malformed}");
+            assert_eq!(
+                ErrorCode::of_scanned(tokens[0].msg),
+                Some(ErrorCode::MalformedNumber)
+            );
+        }
+    }
+
+    #[test]
+    fn block_comments() {
+        let src = "/* one /* nested\n */ still\n comment */ var a = 1; /**/\nprint /* here */ a;";
+        let tokens: Vec<_> = Scanner::new(src).map(|tok| (tok.line, tok.msg)).collect();
+        assert_eq!(
+            tokens,
+            [
+                (3, "var"),
+                (3, "a"),
+                (3, "="),
+                (3, "1"),
+                (3, ";"),
+                (4, "print"),
+                (4, "a"),
+                (4, ";"),
+                (4, "")
+            ]
+        );
+
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("print 1;\n  /* a /* b */\n\nprint 2;", &mut mem);
+        parser.print_errors = false;
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.diagnostics[0].to_string(),
+            "[line 2:3] Error: Unterminated block comment."
+        );
+        assert_eq!(parser.diagnostics[0].code, ErrorCode::UnterminatedComment);
+    }
+
+    #[test]
+    fn incremental_relex() {
+        let src = "fun a() {\n  return 1;\n}\n\nvar b = \"two\";\nprint b;";
+        let mut cache = TokenCache::new(src);
+
+        let edited = src.replace("return 1;", "return 1 + foo;");
+        let start = src.find("1;").unwrap() as u32 + 1;
+        let range = cache.relex(
+            &edited,
+            Edit {
+                start,
+                old_end: start,
+                new_end: start + " + foo".len() as u32,
+            },
+        );
+
+        assert_eq!(cache.tokens, TokenCache::new(&edited).tokens);
+        // Only the edited line got rescanned
+        assert!(range.len() < 6);
+    }
+
+    #[test]
+    fn incremental_document() {
+        let mut doc = Document::new("fun a() {\n  return 1;\n}\nfun b() {\n  return 2;\n}".into());
+        assert!(doc.diagnostics.is_empty());
+
+        let start = doc.src.find("2;").unwrap() as u32;
+        doc.edit(start..start + 1, "2 +");
+        assert_eq!(doc.diagnostics.len(), 1);
+        assert_eq!(doc.diagnostics[0].line, 5);
+        assert_eq!(doc.diagnostics[0].column, 13);
+        assert_eq!(
+            doc.diagnostics[0].span.start as usize,
+            doc.src.find("+;").unwrap() + 1
+        );
+
+        doc.edit(start..start + 3, "\n\n2");
+        assert!(doc.diagnostics.is_empty());
+        assert_eq!(doc.tokens.tokens, TokenCache::new(&doc.src).tokens);
+    }
+
+    #[test]
+    fn coverage() {
+        let src =
+            "fun f(x) {\n  if (x) {\n    return 1;\n  }\n  return 2;\n}\nvar result = f(false);";
+
+        let mut vm = VM::new();
+        vm.coverage = Some(Coverage::new());
+        interpret(&mut vm, src).unwrap();
+
+        let coverage = vm.coverage.as_ref().unwrap();
+        assert!(coverage.hit_count(2) > 0);
+        assert_eq!(coverage.hit_count(3), 0);
+        assert!(coverage.hit_count(5) > 0);
+
+        let mut out = vec![];
+        coverage.write_annotated(src, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[2], "    ##### |     return 1;");
+
+        let mut out = vec![];
+        coverage.write_lcov("f.lox", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("DA:3,0\n"));
+        assert!(out.ends_with("end_of_record\n"));
+    }
+
+    #[test]
+    fn line_profile() {
+        let src = "fun square(x) {\n  return x * x;\n}\nvar total = 0;\n\
+                   for (var i = 0; i < 10; i = i + 1)\n  total = total + square(i);";
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        vm.line_profile = Some(LineProfile::new());
+        interpret(&mut vm, src).unwrap();
+
+        let profile = vm.line_profile.as_ref().unwrap();
+        let body = profile.line(2);
+        assert_eq!((body.hits, body.instructions), (10, 40));
+        assert_eq!(body.cumulative, body.instructions);
+        let call = profile.line(6);
+        // Once more for leaving the loop, which ends on that line
+        assert_eq!(call.hits, 11);
+        assert_eq!(call.cumulative, call.instructions + body.instructions);
+        let lines: u64 = (1..=6).map(|line| profile.line(line).instructions).sum();
+        assert_eq!(lines, profile.instructions());
+
+        let mut out = vec![];
+        profile
+            .write_annotated_over(src, Duration::from_millis(10), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert!(lines[2].ends_with(" |   return x * x;"), "{out}");
+        assert!(lines[2].contains("    10          40 "), "{out}");
+        assert_eq!(lines[1], format!("{:>6}{:52} | fun square(x) {{", 1, ""));
+    }
+
+    #[test]
+    fn opcode_histogram() {
+        let run = |src: &str| {
+            let mut vm = VM::with_options(VmOptions::new().trace(false));
+            vm.opcode_histogram = Some(OpcodeHistogram::new());
+            interpret(&mut vm, src).unwrap();
+            vm.opcode_histogram.unwrap()
+        };
+        let before = run("fun f(x) { return x; }\nfor (var i = 0; i < 10; i = i + 1) f(i);");
+        assert_eq!(before.count(Opcode::Call), 10);
+        // Back to the increment, then from there to the condition
+        assert_eq!(before.count(Opcode::Loop), 20);
+
+        let mut json = vec![];
+        before.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"Call\": 10"), "{json}");
+        assert_eq!(OpcodeHistogram::read_json(&json).unwrap(), before);
+        assert_eq!(
+            OpcodeHistogram::read_json("{}").unwrap(),
+            OpcodeHistogram::new()
+        );
+        assert!(OpcodeHistogram::read_json("{\"Frobnicate\": 1}").is_err());
+        assert!(OpcodeHistogram::read_json("{\"Call\": -1}").is_err());
+
+        let after = run("fun f(x) { return x; }\nfor (var i = 0; i < 10; i = i + 2) f(i);");
+        let mut out = vec![];
+        profile::write_histogram_diff(&before, &after, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert!(lines[0].starts_with("opcode"));
+        let call = lines.iter().find(|line| line.starts_with("Call ")).unwrap();
+        assert_eq!(
+            call.split_whitespace().collect::<Vec<_>>(),
+            ["Call", "10", "5", "-5", "-50.0%"]
+        );
+        assert!(lines.last().unwrap().starts_with("total"));
+    }
+
+    #[test]
+    fn conformance() {
+        for case in CASES {
+            if let Err(err) = case.run(None) {
+                panic!("{}: {err}", case.name);
+            }
+        }
+    }
+
+    #[test]
+    fn conformance_kills_mutants() {
+        let mut survivors = vec![];
+        for case in CASES {
+            for mutation in case.surviving_mutants() {
+                survivors.push(format!("{}: {mutation}", case.name));
+            }
+        }
+
+        assert!(
+            survivors.is_empty(),
+            "Surviving mutants:\n{}",
+            survivors.join("\n")
+        );
+    }
+
+    #[test]
+    fn chunk_passes() {
+        let swap_adds = |chunk: &mut Chunk| {
+            for byte in chunk.code.iter_mut() {
+                if *byte == Opcode::Add as u8 {
+                    *byte = Opcode::Subtract as u8;
+                }
+            }
+        };
+        let sizes = Rc::new(RefCell::new(vec![]));
+        let seen = sizes.clone();
+        let record_sizes = move |chunk: &mut Chunk| seen.borrow_mut().push(chunk.code.len());
+
+        let options = VmOptions::new()
+            .trace(false)
+            .pass(swap_adds)
+            .pass(record_sizes);
+        let mut vm = VM::with_options(options);
+        let executed = vm
+            .execute_statement("fun f() { return 3 + 1; } print f(); print 2 + 2;")
+            .unwrap();
+        assert_eq!(executed.output, "Number(2.0)\nNumber(0.0)\n");
+        // The function, then the script declaring it
+        assert_eq!(sizes.borrow().len(), 2);
+        assert!(sizes.borrow()[0] < sizes.borrow()[1]);
+
+        assert!(vm.execute_statement("print 1 +;").is_err());
+        assert_eq!(sizes.borrow().len(), 2);
+    }
+
+    #[test]
+    fn call_tracing() {
+        struct Calls(Rc<RefCell<Vec<String>>>);
+
+        impl VmEvents for Calls {
+            fn function_entered(&mut self, depth: usize, name: &str, args: &str) {
+                self.0.borrow_mut().push(format!("{depth} {name}({args})"));
+            }
+
+            fn function_returned(&mut self, depth: usize, name: &str, result: &str) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("{depth} {name} -> {result}"));
+            }
+        }
+
+        let src = r#"
+            fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            class A { init(x) { this.x = x; } }
+            var result = fib(2);
+            var a = A("a");
+            fun fail() { var local = 1; return local + nil; }
+            fail();
+        "#;
+        let calls = Rc::new(RefCell::new(vec![]));
+        let mut vm = VM::with_options(
+            VmOptions::new()
+                .trace(false)
+                .pass(crate::passes::CallTracing),
+        );
+        vm.events = Box::new(Calls(calls.clone()));
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        assert_eq!(
+            *calls.borrow(),
+            [
+                "0 fib(n=2)",
+                "1 fib(n=1)",
+                "1 fib -> 1",
+                "1 fib(n=0)",
+                "1 fib -> 0",
+                "0 fib -> 1",
+                "0 init(x=\"a\")",
+                "0 init -> A {...}",
+                "0 fail()",
+            ]
+        );
+        assert_eq!(vm.get_global("result"), Some(Value::Number(1.0)));
+        // Still where the error happened, despite the extra instruction
+        assert_eq!(vm.last_error.unwrap().trace[0], "[line 6:59] in fail()");
+    }
+
+    #[test]
+    fn incremental_gc() {
+        let mut vm = VM::new();
+        interpret(
+            &mut vm,
+            r#"
+class Box {}
+var box = Box();
+box.next = nil;
+for (var i = 0; i < 500; i = i + 1) {
+    var node = Box();
+    node.next = box.next;
+    box.next = node;
+    var garbage = Box();
+}
+"#,
+        )
+        .unwrap();
+        let before = vm.mem.obj_list.len();
+
+        // Start a cycle, then store a new object into one that may already
+        // have been traced before finishing it
+        vm.collect_garbage(Duration::ZERO);
+        assert_eq!(vm.gc_phase, GcPhase::Marking);
+        interpret(&mut vm, r#"box.field = Box(); box.field.name = "kept";"#).unwrap();
+        while !vm.collect_garbage(Duration::ZERO) {}
+
+        assert_eq!(vm.gc_phase, GcPhase::Idle);
+        // With `always_gc` the garbage is already gone by now
+        #[cfg(not(feature = "always_gc"))]
+        assert!(vm.mem.obj_list.len() < before);
+
+        interpret(&mut vm, "var result = box.field.name;").unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("kept"));
+    }
+
+    #[test]
+    fn live_objects() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = r#"
+class Resource {}
+var kept = [Resource(), Resource()];
+for (var i = 0; i < 1000; i = i + 1) Resource();
+"#;
+        interpret(&mut vm, src).unwrap();
+        let class = vm.get_global("Resource").unwrap().as_class().unwrap();
+        let resources = |vm: &VM| {
+            vm.live_objects::<ObjInstance>()
+                .filter(|i| i.class == class)
+                .count()
+        };
+
+        // The garbage isn't swept yet, unless every allocation collects
+        #[cfg(not(feature = "always_gc"))]
+        assert_eq!(resources(&vm), 1002);
+        vm.collect_garbage(Duration::ZERO);
+        while matches!(vm.gc_phase, GcPhase::Marking) {
+            vm.collect_garbage(Duration::ZERO);
+        }
+        #[cfg(not(feature = "always_gc"))]
+        assert!(matches!(vm.gc_phase, GcPhase::Sweeping { .. }));
+        assert_eq!(resources(&vm), 2);
+        while !vm.collect_garbage(Duration::ZERO) {}
+        assert_eq!(resources(&vm), 2);
+    }
+
+    #[test]
+    fn pinning() {
+        let mut vm = VM::new();
+        interpret(&mut vm, "").unwrap();
+
+        let string = vm.copy_string("pinned");
+        let handle = vm.pin(Value::Obj(string.cast()));
+        while !vm.collect_garbage(Duration::from_secs(1)) {}
+
+        let hash = ObjHash::hash_string("pinned");
+        assert!(vm
+            .mem
+            .interned_strings
+            .find_string("pinned", hash)
+            .is_some());
+        assert_eq!(handle.get().as_str(), Some("pinned"));
+
+        let copy = handle.clone();
+        drop(handle);
+        assert_eq!(vm.pins.len(), 1);
+        drop(copy);
+        assert!(vm.pins.is_empty());
+
+        while !vm.collect_garbage(Duration::from_secs(1)) {}
+        assert!(vm
+            .mem
+            .interned_strings
+            .find_string("pinned", hash)
+            .is_none());
+    }
+
+    #[test]
+    fn register_native() {
+        fn greet(ctx: &mut NativeCtx, args: &[Value]) -> Result<Value, String> {
+            let greeting = ctx.string("Hello, ");
+            let name = ctx.string(args[0].as_str().unwrap());
+            let result = format!(
+                "{}{}",
+                greeting.get().as_str().unwrap(),
+                name.get().as_str().unwrap()
+            );
+            Ok(ctx.string(&result).get())
+        }
+
+        let mut vm = VM::new();
+        vm.register_native("greet", greet);
+        interpret(&mut vm, r#"var result = greet("world");"#).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("Hello, world"));
+        assert!(vm.pins.is_empty());
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let prelude = r#"
+fun makeCounter() {
+    var count = 0;
+    fun increment() {
+        count = count + 1;
+        return count;
+    }
+    return increment;
+}
+var counter = makeCounter();
+counter();
+
+class Greeter {
+    init(name) {
+        this.name = name;
+    }
+    greet() {
+        return "Hello, " + this.name;
+    }
+}
+var greeter = Greeter("snapshot");
+var greet = greeter.greet;
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, prelude).unwrap();
+        let mut bytes = vec![];
+        snapshot::write(&vm, &mut bytes).unwrap();
+
+        let mut vm = VM::new();
+        snapshot::read(&mut vm, &bytes).unwrap();
+        interpret(
+            &mut vm,
+            r#"
+var count = counter();
+var result = greet() + "!";
+"#,
+        )
+        .unwrap();
+
+        let count_str = vm.get_string("count").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(count_str), Some(Value::Number(2.0)));
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value.unwrap().as_str(), Some("Hello, snapshot!"));
+
+        assert!(snapshot::read(&mut VM::new(), &bytes[..bytes.len() - 1]).is_err());
+
+        // Code is checked like a .loxb file's: `f` reading local 9 instead
+        // of its argument
+        let mut vm = VM::new();
+        interpret(&mut vm, "fun f(a) { return a; }").unwrap();
+        let mut bytes = vec![];
+        snapshot::write(&vm, &mut bytes).unwrap();
+        let code = [
+            Opcode::GetLocal as u8,
+            1,
+            Opcode::Return as u8,
+            Opcode::Nil as u8,
+        ];
+        let at = bytes.windows(4).position(|window| window == code).unwrap();
+        bytes[at + 1] = 9;
+        assert!(snapshot::read(&mut VM::new(), &bytes).is_err());
+    }
+
+    #[test]
+    fn execute_statement() {
+        let mut vm = VM::new();
+
+        let executed = vm.execute_statement("var x = 1;").unwrap();
+        assert_eq!(executed.value, Value::Nil);
+        assert_eq!(executed.output, "");
+
+        let executed = vm.execute_statement("print x; x + 1").unwrap();
+        assert_eq!(executed.value, Value::Number(2.0));
+        assert_eq!(executed.output, "Number(1.0)\n");
+
+        let executed = vm.execute_statement("x * 10;").unwrap();
+        assert_eq!(executed.value, Value::Number(10.0));
+
+        assert_eq!(
+            vm.execute_statement("x +").unwrap_err(),
+            InterpretError::CompileError
+        );
+        assert_eq!(
+            vm.execute_statement("x + nil").unwrap_err(),
+            InterpretError::RuntimeError
+        );
+        assert!(vm.captured_output.is_none());
+
+        // Natives are globals like any other once the VM is made
+        vm.execute_statement("var len = 5; var math = nil;")
+            .unwrap();
+        let executed = vm.execute_statement("[len, math]").unwrap();
+        assert_eq!(format!("{}", executed.value), "[5, nil]");
+    }
+
+    #[test]
+    fn capture_native() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = "fun inner() { print 2; }
+fun outer() { print 1; print capture(inner) == \"Number(2.0)\n\"; }
+var out = capture(outer);
+fun fails() { print 3; nil(); }";
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            vm.get_global("out").unwrap().to_string(),
+            "Number(1.0)\nBool(true)\n"
+        );
+
+        let executed = vm.execute_statement("print 0; capture(inner)").unwrap();
+        assert_eq!(executed.output, "Number(0.0)\n");
+        assert_eq!(
+            interpret(&mut vm, "capture(fails);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert!(vm.captured_output.is_none());
+    }
+
+    #[test]
+    fn semicolon_insertion() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false).asi(true));
+        let executed = vm
+            .execute_statement(
+                "var x = 1\nfun f(n) {\n  if (n > 1) return\n  return n * 2 }\nprint f(2)\nx = x\n  - 3\nx",
+            )
+            .unwrap();
+        assert_eq!(executed.value, Value::Number(-2.0));
+        assert_eq!(executed.output, "Nil\n");
+        assert_eq!(
+            vm.execute_statement("print 1 print 2").unwrap_err(),
+            InterpretError::CompileError
+        );
+
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        assert!(vm.execute_statement("var y = 1\ny").is_err());
+    }
+
+    #[test]
+    fn log_native() {
+        struct Recorder(Rc<RefCell<Vec<(LogLevel, String)>>>);
+
+        impl VmEvents for Recorder {
+            fn log(&mut self, level: LogLevel, message: &str) {
+                self.0.borrow_mut().push((level, message.to_owned()));
+            }
+        }
+
+        let logs = Rc::new(RefCell::new(vec![]));
+        let mut vm = VM::new();
+        vm.events = Box::new(Recorder(logs.clone()));
+
+        interpret(&mut vm, r#"log("info", "starting"); log("error", 1 + 2);"#).unwrap();
+        assert_eq!(
+            *logs.borrow(),
+            [
+                (LogLevel::Info, "starting".to_owned()),
+                (LogLevel::Error, "Number(3.0)".to_owned())
+            ]
+        );
+
+        assert_eq!(
+            interpret(&mut vm, r#"log("loud", "hi");"#).unwrap_err(),
+            InterpretError::RuntimeError
+        );
+        assert_eq!(
+            interpret(&mut vm, r#"log("info");"#).unwrap_err(),
+            InterpretError::RuntimeError
+        );
+        assert_eq!(logs.borrow().len(), 2);
+    }
+
+    #[test]
+    fn gc_events() {
+        #[derive(Default)]
+        struct Recorder {
+            starts: Vec<usize>,
+            ends: Vec<GcStats>,
+        }
+
+        impl VmEvents for Rc<RefCell<Recorder>> {
+            fn gc_start(&mut self, live_bytes: usize) {
+                self.borrow_mut().starts.push(live_bytes);
+            }
+
+            fn gc_end(&mut self, stats: &GcStats) {
+                self.borrow_mut().ends.push(stats.clone());
+            }
+        }
+
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        vm.events = Box::new(recorder.clone());
+        interpret(&mut vm, "for (var i = 0; i < 100; i = i + 1) [i];").unwrap();
+        let cycles = recorder.borrow().ends.len();
+        vm.collect_all_garbage();
+
+        let recorder = recorder.borrow();
+        assert_eq!(recorder.starts.len(), cycles + 1);
+        assert_eq!(recorder.ends.len(), cycles + 1);
+        assert_eq!(
+            recorder.ends.last().unwrap().bytes_before,
+            *recorder.starts.last().unwrap()
+        );
+        // Nothing is allocated while a whole cycle runs at once
+        for stats in &recorder.ends {
+            let freed: usize = ObjKind::ALL
+                .iter()
+                .map(|&kind| stats.freed(kind) as usize * kind.size())
+                .sum();
+            assert_eq!(stats.bytes_before - stats.bytes_after, freed);
+        }
+        let lists: u64 = recorder
+            .ends
+            .iter()
+            .map(|stats| stats.freed(ObjKind::List))
+            .sum();
+        assert_eq!(lists, 100);
+    }
+
+    #[test]
+    fn step_back() {
+        let src = r#"
+var total = 0;
+for (var i = 0; i < 20; i = i + 1) {
+    total = total + i;
+}
+"#;
+
+        // The `total` global and the stack above the script's closure
+        fn observe(vm: &mut VM) -> (Option<Value>, Vec<Value>) {
+            let name = vm.mem.copy_string("total").as_non_null_ptr();
+            let stack = vm.stack.values()[1..].iter().copied();
+            (vm.mem.globals.get(name), stack.collect())
+        }
+
+        let paused_at = |step| {
+            let mut debugger = Debugger::new();
+            debugger.pause_at = Some(step);
+            let mut vm = VM::new();
+            vm.debugger = Some(debugger);
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+            observe(&mut vm)
+        };
+
+        let mut vm = VM::new();
+        vm.debugger = Some(Debugger::with_history(100, 16));
+        interpret(&mut vm, src).unwrap();
+
+        let debugger = vm.debugger.as_ref().unwrap();
+        let steps = debugger.step();
+        assert_eq!(debugger.trace().count(), 100);
+        assert!(vm.step_back(101).is_err());
+
+        let mut expected = steps;
+        for back in [1, 5, 17, 40, 30] {
+            expected -= back;
+            assert_eq!(vm.step_back(back), Ok(expected));
+            assert_eq!(observe(&mut vm), paused_at(expected));
+        }
+
+        let trace: Vec<_> = vm.debugger.as_ref().unwrap().trace().copied().collect();
+        assert_eq!(trace.last().unwrap().step, expected - 1);
+        for entry in trace.iter().rev().skip(1).take(5) {
+            let before = paused_at(entry.step).1.len() as i32;
+            let after = paused_at(entry.step + 1).1.len() as i32;
+            assert_eq!(entry.stack_delta, after - before);
+        }
+
+        // Resuming runs the rest of the program again
+        vm.run().unwrap();
+        assert_eq!(vm.debugger.as_ref().unwrap().step(), steps);
+        let name = vm.mem.copy_string("total").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(name), Some(Value::Number(190.0)));
+    }
+
+    #[test]
+    fn step_back_past_watchpoint() {
+        let src = r#"
+var total = 0;
+for (var i = 0; i < 20; i = i + 1) {
+    total = total + i;
+}
+"#;
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut debugger = Debugger::with_history(100, 16);
+        debugger.watch(Watch::Global("total".into()));
+        let mut vm = VM::new();
+        vm.debug_sink = Box::new(SharedWriter(output.clone()));
+        vm.debugger = Some(debugger);
+
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+        while vm.debugger.as_ref().unwrap().step() < 120 {
+            assert_eq!(vm.run(), Err(InterpretError::Paused));
+        }
+        let hit = vm.debugger.as_ref().unwrap().last_hit.clone();
+        let written = output.borrow().len();
+
+        // The replay goes over changes to `total` without reporting them
+        let step = vm.debugger.as_ref().unwrap().step();
+        assert_eq!(vm.step_back(30), Ok(step - 30));
+        assert_eq!(output.borrow().len(), written);
+        assert_eq!(vm.debugger.as_ref().unwrap().last_hit, hit);
+    }
+
+    #[test]
+    fn step_back_past_breakpoint() {
+        let src = r#"
+var total = 0;
+for (var i = 0; i < 20; i = i + 1) {
+    total = total + i;
+}
+"#;
+
+        let mut debugger = Debugger::with_history(100, 16);
+        debugger.breakpoints = vec!["3".parse().unwrap()];
+        debugger.watch(Watch::Global("total".into()));
+        let mut vm = VM::new();
+        vm.debug_sink = Box::new(std::io::sink());
+        vm.debugger = Some(debugger);
+
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+        while vm.debugger.as_ref().unwrap().step() < 120 {
+            assert_eq!(vm.run(), Err(InterpretError::Paused));
+        }
+
+        let mut expected = vm.debugger.as_ref().unwrap().step();
+        for back in [10, 30, 7] {
+            expected -= back;
+            assert_eq!(vm.step_back(back), Ok(expected));
+        }
+
+        // Resuming carries on from there
+        assert_eq!(vm.run(), Err(InterpretError::Paused));
+        assert!(vm.debugger.as_ref().unwrap().step() > expected);
+    }
+
+    #[test]
+    fn step_back_restores_objects() {
+        use crate::fields::FieldStorage;
+
+        let src = r#"
+class Counter {
+    init() {
+        this.count = 0;
+    }
+}
+fun makeAdder() {
+    var n = 0;
+    fun add() {
+        n = n + 1;
+        return n;
+    }
+    return add;
+}
+var counter = Counter();
+var items = [];
+var add = makeAdder();
+var last = 0;
+for (var i = 0; i < 20; i = i + 1) {
+    counter.count = counter.count + 1;
+    append(items, i);
+    last = add();
+}
+"#;
+
+        // `counter.count` and how many items there are
+        fn observe(vm: &mut VM) -> (Option<Value>, usize) {
+            let counter = vm.mem.copy_string("counter").as_non_null_ptr();
+            let count = vm.mem.copy_string("count").as_non_null_ptr();
+            let items = vm.mem.copy_string("items").as_non_null_ptr();
+            let counter = vm.mem.globals.get(counter).unwrap();
+            let items = vm.mem.globals.get(items).unwrap();
+            (
+                counter.as_instance_fn().unwrap().fields.get(count),
+                items.as_list().unwrap().items.len(),
+            )
+        }
+
+        let paused_at = |step| {
+            let mut debugger = Debugger::new();
+            debugger.pause_at = Some(step);
+            let mut vm = VM::new();
+            vm.debugger = Some(debugger);
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+            observe(&mut vm)
+        };
+
+        let mut vm = VM::new();
+        vm.debugger = Some(Debugger::with_history(200, 16));
+        interpret(&mut vm, src).unwrap();
+
+        let mut expected = vm.debugger.as_ref().unwrap().step();
+        for back in [1, 30, 45] {
+            expected -= back;
+            assert_eq!(vm.step_back(back), Ok(expected));
+            assert_eq!(observe(&mut vm), paused_at(expected));
+        }
+
+        vm.run().unwrap();
+        assert_eq!(observe(&mut vm), (Some(Value::Number(20.0)), 20));
+        let last = vm.mem.copy_string("last").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(last), Some(Value::Number(20.0)));
+    }
+
+    #[test]
+    fn step_back_past_clock() {
+        let src = r#"
+var start = clock();
+var total = 0;
+for (var i = 0; i < 5; i = i + 1) {
+    total = total + i;
+}
+"#;
+
+        let mut vm = VM::new();
+        vm.debugger = Some(Debugger::with_history(200, 4));
+        interpret(&mut vm, src).unwrap();
+
+        let steps = vm.debugger.as_ref().unwrap().step();
+        assert_eq!(vm.step_back(1), Ok(steps - 1));
+        // Getting to step 3 means replaying from the snapshot at 0, which
+        // calls `clock` again
+        let err = vm.step_back(steps - 4).unwrap_err();
+        assert!(err.contains("`clock`"), "{err}");
+        // Nothing was rewound
+        assert_eq!(vm.debugger.as_ref().unwrap().step(), steps - 1);
+        // Before the call there's nothing to repeat
+        assert_eq!(vm.step_back(steps - 1), Ok(0));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let src = r#"
+var x = 1;
+x = 1;
+x = 2;
+class Point {}
+var p = Point();
+p.y = 3;
+p.y = 3;
+p.y = x;
+"#;
+
+        let mut debugger = Debugger::new();
+        debugger.watch(Watch::Global("x".into()));
+        debugger.watch(Watch::Field("y".into()));
+        let mut vm = VM::new();
+        vm.debug_sink = Box::new(std::io::sink());
+        vm.debugger = Some(debugger);
+
+        let hit = |watch, old, new, line| WatchHit {
+            watch,
+            old,
+            new,
+            line,
+            function: "script".into(),
+        };
+        let global = Watch::Global("x".into());
+        let field = Watch::Field("y".into());
+
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+        assert_eq!(
+            vm.debugger.as_ref().unwrap().last_hit,
+            Some(hit(global, Some(Value::Number(1.0)), Value::Number(2.0), 4))
+        );
+
+        assert_eq!(vm.run(), Err(InterpretError::Paused));
+        assert_eq!(
+            vm.debugger.as_ref().unwrap().last_hit,
+            Some(hit(field.clone(), None, Value::Number(3.0), 7))
+        );
+
+        assert!(vm.debugger.as_mut().unwrap().unwatch(&field));
+        assert!(vm.run().is_ok());
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let src = r#"
+var total = 0;
+fun add(n) {
+    var doubled = n * 2;
+    total = total + doubled;
+    return doubled;
+}
+for (var i = 0; i < 10; i = i + 1) add(i);
+"#;
+
+        let total = |vm: &mut VM| {
+            let name = vm.mem.copy_string("total").as_non_null_ptr();
+            vm.mem.globals.get(name).unwrap()
+        };
+
+        let mut debugger = Debugger::new();
+        debugger.script = Some("examples/sum.lox".into());
+        debugger.breakpoints = vec![
+            "other.lox:5".parse().unwrap(),
+            "sum.lox:5 if doubled > 12 and total < 60".parse().unwrap(),
+        ];
+        let mut vm = VM::new();
+        vm.debug_sink = Box::new(std::io::sink());
+        vm.debugger = Some(debugger);
+
+        // n = 7 and then 8, the rest fail the condition
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::Paused));
+        assert_eq!(total(&mut vm), Value::Number(42.0));
+        assert_eq!(vm.evaluate_condition("n == 7"), Ok(true));
+        assert_eq!(vm.run(), Err(InterpretError::Paused));
+        assert_eq!(total(&mut vm), Value::Number(56.0));
+        assert_eq!(
+            vm.debugger.as_ref().unwrap().last_breakpoint,
+            Some(Breakpoint {
+                file: Some("sum.lox".into()),
+                line: 5,
+                condition: Some("doubled > 12 and total < 60".into())
+            })
+        );
+
+        assert!(vm.evaluate_condition("doubled +").is_err());
+        assert!(vm.evaluate_condition("doubled + nil").is_err());
+        assert_eq!(
+            vm.evaluate_condition("i"),
+            Err(String::from("Runtime error."))
+        );
+
+        assert!(vm.run().is_ok());
+        assert_eq!(total(&mut vm), Value::Number(90.0));
+        assert!("sum.lox:x".parse::<Breakpoint>().is_err());
+    }
+
+    #[test]
+    fn reload_function() {
+        let mut vm = VM::new();
+        vm.execute_statement(
+            r#"
+fun makeCounter() {
+    var count = 0;
+    fun increment() {
+        count = count + 1;
+        return count;
+    }
+    return increment;
+}
+var counter = makeCounter();
+counter();
+counter();
+fun twice(n) { return n * 2; }
+"#,
+        )
+        .unwrap();
+
+        let eval = |vm: &mut VM, src| vm.execute_statement(src).unwrap().value;
+
+        vm.reload_function(
+            "increment",
+            "fun increment() { count = count + 10; return count; }",
+        )
+        .unwrap();
+        // Keeps counting from where it was
+        assert_eq!(eval(&mut vm, "counter()"), Value::Number(12.0));
+        // And new counters get the new code too
+        assert_eq!(eval(&mut vm, "makeCounter()()"), Value::Number(10.0));
+
+        vm.reload_function("twice", "fun twice(n) { return n + n + n; }")
+            .unwrap();
+        assert_eq!(eval(&mut vm, "twice(2)"), Value::Number(6.0));
+
+        assert!(vm.reload_function("thrice", "fun thrice() {}").is_err());
+        assert!(vm.reload_function("twice", "fun twice( {}").is_err());
+        assert!(vm.reload_function("twice", "fun other() {}").is_err());
+
+        let mut debugger = Debugger::new();
+        debugger.breakpoints = vec!["2".parse().unwrap()];
+        vm.debug_sink = Box::new(std::io::sink());
+        vm.debugger = Some(debugger);
+        let paused = vm.execute_statement("fun f() {\n  return twice(1);\n}\nf();");
+        assert_eq!(paused.unwrap_err(), InterpretError::Paused);
+        assert_eq!(
+            vm.reload_function("f", "fun f() {}"),
+            Err("Can't reload `f` while it is running.".into())
+        );
+    }
+
+    #[test]
+    fn lists_args_and_getenv() {
+        let mut vm = VM::new();
+        vm.set_args(&["one".into(), "two".into()]);
+        let executed = vm
+            .execute_statement(
+                r#"
+var xs = [1, "a", nil];
+xs[1] = xs[0] + 1;
+append(xs, ARGS);
+print xs;
+print len(xs) + len(ARGS) + len("abc");
+xs[3][1]
+"#,
+            )
+            .unwrap();
+        assert_eq!(
+            executed.output,
+            "[Number(1.0), Number(2.0), Nil, [\"one\", \"two\"]]\nNumber(9.0)\n"
+        );
+        assert_eq!(executed.value.as_str(), Some("two"));
+
+        for src in [
+            "xs[4];",
+            "xs[0.5];",
+            "xs[\"a\"] = 1;",
+            "len(1);",
+            "getenv(\"PATH\");",
+        ] {
+            assert_eq!(
+                vm.execute_statement(src).unwrap_err(),
+                InterpretError::RuntimeError
+            );
+        }
+
+        vm.capabilities.env = true;
+        let path = vm.execute_statement("getenv(\"PATH\")").unwrap().value;
+        assert_eq!(path.as_str(), std::env::var("PATH").ok().as_deref());
+        let unset = vm.execute_statement("getenv(\"LOXIDE_UNSET_VARIABLE\")");
+        assert_eq!(unset.unwrap().value, Value::Nil);
+    }
+
+    #[test]
+    fn script_return() {
+        let mut vm = VM::new();
+        let src = r#"
+var f;
+{
+    var code = 3;
+    fun get() { return code; }
+    f = get;
+    if (code > 2) return code + 4;
+}
+return 0;
+"#;
+        assert_eq!(interpret(&mut vm, src), Ok(Value::Number(7.0)));
+        assert!(vm.open_upvalues.is_null());
+        assert_eq!(interpret(&mut vm, "return f();"), Ok(Value::Number(3.0)));
+        assert_eq!(interpret(&mut vm, "print 1;"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn print_native() {
+        let mut vm = VM::new();
+        let src = r#"
+class Point {}
+fun show(f, x) { f(x, [x, "y"]); }
+println(1, "two", nil, true, Point, Point(), show);
+show(println, 2.5);
+println(3);
+println();
+print 3;
+print (3);
+print ("a");
+"#;
+        assert_eq!(
+            vm.execute_statement(src).unwrap().output,
+            "1 two nil true Point Point instance <fn show>\n2.5 [2.5, y]\n3\n\nNumber(3.0)\nNumber(3.0)\n\"a\"\n"
+        );
+
+        vm.print_options = PrintOptions {
+            separator: ", ".into(),
+            end: ";".into(),
+        };
+        let executed = vm.execute_statement("println(1, 2); println(3)").unwrap();
+        assert_eq!(executed.output, "1, 2;3;");
+    }
+
+    #[test]
+    fn format_native() {
+        let mut vm = VM::new();
+        let src = r#"
+var s = format("x = {}, y = {:6.2}, {{{}}} [{:4}]", 1, 3.14159, "z", nil);
+printf("{} {:.1}!", s, 2);
+s
+"#;
+        let executed = vm.execute_statement(src).unwrap();
+        let expected = "x = 1, y =   3.14, {z} [nil ]";
+        assert_eq!(executed.value.as_str(), Some(expected));
+        assert_eq!(executed.output, format!("{expected} 2.0!"));
+
+        for src in [
+            r#"format("{} {}", 1);"#,
+            r#"format("{}", 1, 2);"#,
+            r#"format("{:.2}", "a");"#,
+            r#"format("{x}", 1);"#,
+            r#"format("{", 1);"#,
+            r#"format("{:70000}", 1);"#,
+            r#"format("{:.70000}", 1);"#,
+            r#"format(1);"#,
+            r#"printf();"#,
+        ] {
+            assert_eq!(
+                vm.execute_statement(src).unwrap_err(),
+                InterpretError::RuntimeError,
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn inspect_native() {
+        let mut vm = VM::new();
+        let src = r#"
+class Point {}
+var p = Point();
+p.y = [1, "two", []];
+p.x = p;
+var xs = [p, Point()];
+append(xs, xs);
+"#;
+        vm.execute_statement(src).unwrap();
+
+        let inspect = |vm: &mut VM, src| {
+            let value = vm.execute_statement(src).unwrap().value;
+            value.as_str().unwrap().to_owned()
+        };
+        assert_eq!(
+            inspect(&mut vm, "inspect(xs)"),
+            r#"[
+  Point {
+    x: <cycle>,
+    y: [
+      1,
+      "two",
+      []
+    ]
+  },
+  Point {},
+  <cycle>
+]"#
+        );
+        assert_eq!(
+            inspect(&mut vm, "inspect(xs, 1)"),
+            "[\n  Point {...},\n  Point {},\n  <cycle>\n]"
+        );
+        assert_eq!(inspect(&mut vm, "inspect(xs, 0)"), "[...]");
+        assert_eq!(inspect(&mut vm, "inspect(nil)"), "nil");
+        assert!(vm.execute_statement("inspect(xs, \"a\");").is_err());
+    }
+
+    #[test]
+    fn number_pool() {
+        let mut vm = VM::new();
+        let src = r#"
+fun a() { return 1 + 2.5; }
+fun b() { return 2.5 - 1; }
+var result = a() + b() + 1;
+"#;
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.mem.numbers.len(), 2);
+        let name = vm.mem.copy_string("result").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(name), Some(Value::Number(6.0)));
+
+        let mut bytes = vec![];
+        snapshot::write(&vm, &mut bytes).unwrap();
+        let mut loaded = VM::new();
+        loaded.mem.numbers.intern(7.0);
+        snapshot::read(&mut loaded, &bytes).unwrap();
+        assert_eq!(
+            loaded.execute_statement("a() - b()").unwrap().value,
+            Value::Number(2.0)
+        );
+
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var x = 1 + 1 + 2 - 1;", &mut mem);
+        assert!(parser.compile());
+        let script = parser.compiler.function;
+        drop(parser);
+        assert_eq!(script.chunk.numbers.len(), 2);
+        assert_eq!(script.chunk.number_values, [1.0, 2.0]);
+    }
+
+    /// Lets a test read what was written after handing the writer to the VM
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn vm_options() {
+        let src = "fun f(n) { if (n > 0) f(n - 1); } f(100);";
+        let output = Rc::new(RefCell::new(vec![]));
+        let options = VmOptions::new()
+            .trace(false)
+            .gc_threshold(0)
+            .output(Box::new(SharedWriter(output.clone())));
+
+        let mut vm = VM::with_options(options);
+        assert_eq!(vm.call_frames.len(), 64);
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        interpret(&mut vm, "print 1;").unwrap();
+        assert_eq!(output.borrow().as_slice(), b"Number(1.0)\n");
+
+        let mut vm = VM::with_options(VmOptions::new().frame_depth(128).stack_size(128 * 256));
+        assert!(interpret(&mut vm, src).is_ok());
+
+        // Runs out of stack before it runs out of frames
+        let mut vm = VM::with_options(VmOptions::new().frame_depth(128).stack_size(300));
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn native_calls_back() {
+        fn twice(ctx: &mut NativeCtx, args: &[Value]) -> Result<Value, String> {
+            let once = ctx.call(args[0], &args[1..])?;
+            let twice = ctx.call(args[0], &[once.get()])?;
+            Ok(twice.get())
+        }
+
+        let mut vm = VM::new();
+        vm.register_native("twice", twice);
+        let src = r#"
+class Pair { init(a) { this.a = a; } }
+fun addOne(n) { return n + 1; }
+fun nested(n) { return twice(addOne, n) * 10; }
+var result = [twice(addOne, 1), twice(nested, 1), twice(format, "x")];
+"#;
+        interpret(&mut vm, src).unwrap();
+        let result = vm.execute_statement("result").unwrap().value;
+        assert_eq!(format!("{result}"), "[3, 320, x]");
+        assert_eq!(
+            format!(
+                "{}",
+                vm.execute_statement("twice(Pair, 1).a.a").unwrap().value
+            ),
+            "1"
+        );
+
+        assert_eq!(
+            interpret(&mut vm, "twice(addOne, nil);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert_eq!(vm.call_frame_count, 0);
+        assert_eq!(
+            interpret(&mut vm, "twice(1, 2);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert!(vm.pins.is_empty());
+        assert_eq!(
+            interpret(&mut vm, "return twice(addOne, 5);"),
+            Ok(Value::Number(7.0))
+        );
+    }
+
+    #[test]
+    fn list_methods() {
+        let mut vm = VM::new();
+        let src = r#"
+class Person { init(name, age) { this.name = name; this.age = age; } }
+fun byAge(x, y) { return x.age - y.age; }
+fun name(p) { return p.name; }
+fun isOld(p) { return p.age > 25; }
+fun addAge(acc, p) { return acc + p.age; }
+fun descending(a, b) { return b - a; }
+fun nothing(a, b) { return nil; }
+fun broken(n) { return n + nil; }
+
+var people = [Person("b", 30), Person("a", 20), Person("c", 20)];
+people.sort(byAge);
+var names = people.map(name);
+var old = people.filter(isOld).map(name);
+var total = people.reduce(addAge, 0);
+
+var words = ["pear", "apple", "fig"];
+words.sort();
+var numbers = [3, 1, 2];
+numbers.sort(descending);
+var grown = [1, 2];
+fun grow(n) { append(grown, n); return n * 2; }
+var doubled = grown.map(grow);
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "names"), "[a, c, b]");
+        assert_eq!(show(&mut vm, "old"), "[b]");
+        assert_eq!(show(&mut vm, "total"), "70");
+        assert_eq!(show(&mut vm, "words"), "[apple, fig, pear]");
+        assert_eq!(show(&mut vm, "numbers"), "[3, 2, 1]");
+        assert_eq!(show(&mut vm, "doubled"), "[2, 4]");
+        assert_eq!(show(&mut vm, "grown"), "[1, 2, 1, 2]");
+        assert_eq!(show(&mut vm, "[].reduce(nil, 5)"), "5");
+
+        for src in [
+            "[1, \"a\"].sort();",
+            "[1, 2].sort(nothing);",
+            "[1].map(broken);",
+            "[1].filter();",
+            "[1].push(2);",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+            assert_eq!(vm.call_frame_count, 0);
+        }
+        assert!(vm.pins.is_empty());
+    }
+
+    #[test]
+    fn float_arrays() {
+        let mut vm = VM::new();
+        let src = r#"
+var a = floats([3, 1, 2]);
+var b = floats(3);
+b.fill(2);
+b[1] = 0.5;
+var dot = a.dot(b);
+var sum = a.sum();
+a.sort();
+var found = a.search(2);
+var missing = a.search(7);
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "a"), "[1, 2, 3]");
+        assert_eq!(show(&mut vm, "b"), "[2, 0.5, 2]");
+        assert_eq!(show(&mut vm, "dot"), "10.5");
+        assert_eq!(show(&mut vm, "sum"), "6");
+        assert_eq!(show(&mut vm, "found"), "1");
+        assert_eq!(show(&mut vm, "missing"), "nil");
+        assert_eq!(show(&mut vm, "len(b) + b[2]"), "5");
+
+        for src in [
+            "floats([1, nil]);",
+            "floats(1.5);",
+            "floats(1e12);",
+            "a[0] = \"x\";",
+            "a[3];",
+            "a.dot(floats(2));",
+            "a.fill(nil);",
+            "a.push(1);",
+            "nil.sum();",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+
+        let mut bytes = vec![];
+        snapshot::write(&vm, &mut bytes).unwrap();
+        let mut loaded = VM::new();
+        snapshot::read(&mut loaded, &bytes).unwrap();
+        assert_eq!(show(&mut loaded, "a.dot(b)"), "9");
+    }
+
+    #[test]
+    fn matrix_natives() {
+        let mut vm = VM::new();
+        let src = r#"
+var a = floats([1, 2, 3, 4, 5, 6]);
+var b = floats([7, 8, 9, 10, 11, 12]);
+var product = math.matrix.multiply(a, b, 3);
+var transposed = math.matrix.transpose(a, 3);
+var swap = floats([0, 1, 2, 0]);
+var inverse = math.matrix.invert(swap);
+var identity = math.matrix.multiply(inverse, swap, 2);
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "product"), "[58, 64, 139, 154]");
+        assert_eq!(show(&mut vm, "transposed"), "[1, 4, 2, 5, 3, 6]");
+        assert_eq!(show(&mut vm, "inverse"), "[0, 0.5, 1, 0]");
+        assert_eq!(show(&mut vm, "identity"), "[1, 0, 0, 1]");
+
+        for src in [
+            "math.matrix.multiply(a, b, 4);",
+            "math.matrix.transpose([1, 2], 1);",
+            "math.matrix.invert(a);",
+            "math.matrix.invert(floats([1, 2, 2, 4]));",
+            "math.matrix.multiply(floats(8192), floats(8192), 1);",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn string_methods() {
+        let mut vm = VM::new();
+        let src = r#"
+var path = "src/main.lox";
+var checks = [path.startsWith("src/"), path.endsWith(".rs"), "".startsWith("")];
+var renamed = path.replace(".lox", ".rs");
+var same = path.replace("zig", "rust");
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "checks"), "[true, false, true]");
+        assert_eq!(show(&mut vm, "renamed"), "src/main.rs");
+        assert_eq!(show(&mut vm, "\"a-b-c\".replace(\"-\", \"\")"), "abc");
+
+        // Either the receiver comes back, or the interned copy of the result
+        let path = vm.execute_statement("path").unwrap().value;
+        assert_eq!(vm.execute_statement("same").unwrap().value, path);
+        assert_eq!(
+            vm.execute_statement("renamed.replace(\".rs\", \".lox\")")
+                .unwrap()
+                .value,
+            path
+        );
+
+        for src in [
+            "path.startsWith(1);",
+            "path.replace(\"\", \"x\");",
+            "path.endsWith();",
+            "path.trim();",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn string_views() {
+        let mut vm = VM::new();
+        vm.allocations = Some(Allocations::new());
+        let src = r#"
+var line = "name=lox;kind=" + "language";
+var fields = line.split(";");
+var kind = fields[1].split("=")[1];
+"#;
+        interpret(&mut vm, src).unwrap();
+        // Parsing the line only made views, not strings
+        let allocations = vm.allocations.take().unwrap();
+        let split = allocations
+            .sites()
+            .into_iter()
+            .find(|site| site.line == 4)
+            .unwrap();
+        assert_eq!(split.kinds, [(ObjKind::List, 1), (ObjKind::StrView, 2)]);
+
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "fields"), "[name=lox, kind=language]");
+        assert_eq!(show(&mut vm, "kind + \"!\""), "language!");
+        assert_eq!(show(&mut vm, "kind == \"language\""), "true");
+        assert_eq!(show(&mut vm, "len(kind)"), "8");
+        assert_eq!(show(&mut vm, "line.slice(5, 8) == \"lox\""), "true");
+        assert_eq!(show(&mut vm, "kind.slice(0, 4).split(\"g\")"), "[lan, ]");
+
+        // A view of a view shares the string underneath
+        let kind = vm.execute_statement("kind").unwrap().value;
+        let line = vm.execute_statement("line").unwrap().value;
+        let view = kind.as_str_view().unwrap();
+        assert_eq!(Value::Obj(view.parent.cast()), line);
+        assert_eq!(view.offset, 14);
+
+        // Only the parent's characters are kept, by the views
+        interpret(&mut vm, "line = nil; fields = nil;").unwrap();
+        vm.collect_all_garbage();
+        assert_eq!(show(&mut vm, "kind"), "language");
+
+        let interned = vm.intern(kind).unwrap();
+        assert_eq!(
+            Some(interned),
+            vm.execute_statement("\"language\"")
+                .unwrap()
+                .value
+                .as_obj_str()
+        );
+        assert_eq!(vm.intern(Value::Nil), None);
+
+        for src in [
+            "kind.slice(2, 1);",
+            "kind.slice(0, 9);",
+            "kind.slice(0.5, 1);",
+            "\"é\".slice(0, 1);",
+            "kind.split(\"\");",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn date_time_natives() {
+        let mut vm = VM::new();
+        let src = r#"
+var leap = utcDate(951831906123);
+var before = utcDate(0 - 1);
+var stamp = formatTime(951831906123, "%Y-%m-%dT%H:%M:%S.%LZ 100%%");
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(
+            show(
+                &mut vm,
+                "[leap.year, leap.month, leap.day, leap.hour, leap.minute, leap.second, leap.millisecond, leap.weekday]"
+            ),
+            "[2000, 2, 29, 13, 45, 6, 123, 2]"
+        );
+        assert_eq!(
+            show(
+                &mut vm,
+                "[before.year, before.month, before.day, before.millisecond, before.weekday]"
+            ),
+            "[1969, 12, 31, 999, 3]"
+        );
+        assert_eq!(show(&mut vm, "stamp"), "2000-02-29T13:45:06.123Z 100%");
+        assert_eq!(show(&mut vm, "leap"), "Date instance");
+
+        for src in ["nowMillis();", "utcDate();", "formatTime(0, \"%Q\");"] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+
+        vm.capabilities.clock = true;
+        interpret(&mut vm, "var now = nowMillis(); var today = utcDate();").unwrap();
+        assert_eq!(
+            show(&mut vm, "now > 1700000000000 and today.year >= 2023"),
+            "true"
+        );
+    }
+
+    #[test]
+    fn exec_native() {
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "exec(\"true\", []);"),
+            Err(InterpretError::RuntimeError)
+        );
+
+        vm.capabilities.process = true;
+        let src = r#"
+var echoed = exec("sh", ["-c", "echo out; echo err >&2; exit 3"]);
+"#;
+        interpret(&mut vm, src).unwrap();
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "echoed.status"), "3");
+        assert_eq!(show(&mut vm, "echoed.stdout"), "out\n");
+        assert_eq!(show(&mut vm, "echoed.stderr"), "err\n");
+        assert!(vm.pins.is_empty());
+
+        for src in [
+            "exec(\"sh\", [1]);",
+            "exec(\"sh\");",
+            "exec(\"no such program, surely\", []);",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn net_natives() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+        };
+
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_port = http.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = http.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 256];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.0 404 Not Found\r\nContent-Type: text/plain\r\n\r\nnothing here",
+                )
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = echo.accept().unwrap();
+            let mut buf = [0; 256];
+            let read = stream.read(&mut buf).unwrap();
+            stream.write_all(&buf[..read]).unwrap();
+        });
+
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "tcpConnect(\"127.0.0.1\", 80);"),
+            Err(InterpretError::RuntimeError)
+        );
+
+        vm.capabilities.net = true;
+        let src = format!(
+            r#"
+var response = httpGet("http://127.0.0.1:{http_port}/missing");
+var conn = tcpConnect("127.0.0.1", {echo_port});
+var sent = tcpSend(conn, "ping");
+var echoed = tcpRecv(conn);
+var closed = tcpRecv(conn);
+tcpClose(conn);
+"#
+        );
+        interpret(&mut vm, &src).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /missing HTTP/1.0\r\n"));
+
+        let show =
+            |vm: &mut VM, expr: &str| format!("{}", vm.execute_statement(expr).unwrap().value);
+        assert_eq!(show(&mut vm, "response.status"), "404");
+        assert_eq!(show(&mut vm, "response.body"), "nothing here");
+        assert_eq!(show(&mut vm, "[sent, echoed, closed]"), "[4, ping, ]");
+
+        for src in [
+            "tcpSend(conn, \"again\");",
+            "tcpRecv(7);",
+            "httpGet(\"https://example.com\");",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn interrupt_handle() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let interrupt_soon = |vm: &VM| {
+            let handle = vm.interrupt_handle();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                handle.interrupt();
+            })
+        };
+
+        let interrupter = interrupt_soon(&vm);
+        assert_eq!(
+            interpret(&mut vm, "var n = 0; while (true) { n = n + 1; }"),
+            Err(InterpretError::Interrupted)
+        );
+        interrupter.join().unwrap();
+        assert_eq!(vm.call_frame_count, 0);
+
+        // Through a native's callback too
+        let interrupter = interrupt_soon(&vm);
+        assert_eq!(
+            interpret(&mut vm, "fun spin(x) { while (true) {} } [1].map(spin);"),
+            Err(InterpretError::Interrupted)
+        );
+        interrupter.join().unwrap();
+        assert!(vm.pins.is_empty());
+
+        // An interrupt while nothing is running doesn't stop the next script
+        vm.interrupt_handle().interrupt();
+        assert_eq!(
+            interpret(&mut vm, "var i = 0; while (i < 5000) i = i + 1; return i;"),
+            Ok(Value::Number(5000.0))
+        );
+    }
+
+    #[test]
+    fn invalid_bytecode() {
+        fn compile(vm: &mut VM, src: &str) -> Gc<ObjFunction> {
+            let mut parser = Parser::new(src, &mut vm.mem);
+            assert!(parser.compile());
+            parser.compiler.function
+        }
+
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+
+        let mut function = compile(&mut vm, "print 1;");
+        function.chunk.code[0] = 255;
+        vm.init(function);
+        assert_eq!(vm.run(), Err(InterpretError::RuntimeError));
+
+        let mut function = compile(&mut vm, "x;");
+        function.chunk.constants[0] = Value::Number(1.0);
+        vm.init(function);
+        assert_eq!(vm.run(), Err(InterpretError::RuntimeError));
+
+        // Nothing checks constant indices, but the panic doesn't escape
+        let src = "var xs = []; for (var i = 0; i < 2000; i = i + 1) append(xs, [i]);";
+        interpret(&mut vm, src).unwrap();
+        vm.collect_garbage(Duration::ZERO);
+        assert_eq!(vm.gc_phase, GcPhase::Marking);
+        let mut function = compile(&mut vm, "x;");
+        function.chunk.code[1] = 200;
+        vm.init(function);
+        assert_eq!(vm.catch_panics(VM::run), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.call_frame_count, 0);
+        // Along with the collection it interrupted
+        assert_eq!(vm.gc_phase, GcPhase::Idle);
+        assert!(vm.grey_stack.is_empty());
+        vm.collect_all_garbage();
+
+        assert_eq!(interpret(&mut vm, "return 1;"), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn conversion_errors() {
+        let mut vm = VM::new();
+        let err = Value::Number(1.0).try_as_str().unwrap_err();
+        assert_eq!(err.to_string(), "Expected string but got number.");
+        assert_eq!(
+            Value::Nil.try_as_number().unwrap_err().to_string(),
+            "Expected number but got nil."
+        );
+        assert_eq!(Value::Number(2.0).try_as_number(), Ok(2.0));
+
+        let list = vm.execute_statement("[1]").unwrap().value;
+        assert_eq!(
+            list.try_as_class().unwrap_err().to_string(),
+            "Expected class but got list."
+        );
+        let string = vm.execute_statement("\"hi\"").unwrap().value;
+        assert_eq!(string.try_as_str(), Ok("hi"));
+        assert_eq!(
+            string.try_as_float_array().unwrap_err().got.to_string(),
+            "string"
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    enum TableOp {
+        Set(usize, f64),
+        Delete(usize),
+        Get(usize),
+    }
+
+    fn table_op() -> impl Strategy<Value = TableOp> {
+        // Few enough keys that they collide and get deleted and set again
+        prop_oneof![
+            (0..40usize, -1e6..1e6f64).prop_map(|(key, n)| TableOp::Set(key, n)),
+            (0..40usize).prop_map(TableOp::Delete),
+            (0..40usize).prop_map(TableOp::Get),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn table_matches_hash_map(ops in prop::collection::vec(table_op(), 0..400)) {
+            let mut mem = Mem::new();
+            let keys: Vec<_> = (0..40)
+                .map(|i| mem.copy_string(&format!("key{i}")).as_non_null_ptr())
+                .collect();
+            let mut table = Table::new();
+            let mut model = HashMap::new();
+
+            for op in ops {
+                match op {
+                    TableOp::Set(key, n) => prop_assert_eq!(
+                        table.set(keys[key], Value::Number(n)),
+                        model.insert(key, n).is_none()
+                    ),
+                    TableOp::Delete(key) => {
+                        prop_assert_eq!(table.delete(keys[key]), model.remove(&key).is_some())
+                    }
+                    TableOp::Get(key) => prop_assert_eq!(
+                        table.get(keys[key]),
+                        model.get(&key).copied().map(Value::Number)
+                    ),
+                }
+            }
+
+            for (key, n) in &model {
+                prop_assert_eq!(table.get(keys[*key]), Some(Value::Number(*n)));
+            }
+            prop_assert_eq!(table.iter().count(), model.len());
+
+            Table::free(&mut table);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum StackOp {
+        Push(f64),
+        Pop,
+        Peek(u32),
+    }
+
+    fn stack_op() -> impl Strategy<Value = StackOp> {
+        prop_oneof![
+            any::<f64>().prop_map(StackOp::Push),
+            Just(StackOp::Pop),
+            (0..8u32).prop_map(StackOp::Peek),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn stack_matches_vec(ops in prop::collection::vec(stack_op(), 0..400)) {
+            let mut vm = VM::new();
+            vm.reset_stack();
+            let mut model = vec![];
+
+            for op in ops {
+                match op {
+                    StackOp::Push(n) => {
+                        vm.push(Value::Number(n));
+                        model.push(n);
+                    }
+                    // Popping an empty stack is a compiler bug, not something
+                    // to check here
+                    StackOp::Pop if model.is_empty() => {}
+                    StackOp::Pop => {
+                        let n = model.pop().unwrap();
+                        prop_assert_eq!(vm.pop().try_as_number().map(f64::to_bits), Ok(n.to_bits()));
+                    }
+                    StackOp::Peek(distance) if distance as usize >= model.len() => {}
+                    StackOp::Peek(distance) => {
+                        let n = model[model.len() - 1 - distance as usize];
+                        prop_assert_eq!(vm.peek(distance).try_as_number().map(f64::to_bits), Ok(n.to_bits()));
+                    }
+                }
+            }
+
+            let stack: Vec<_> = vm.iter_stack().map(|value| value.try_as_number().unwrap().to_bits()).collect();
+            let model: Vec<_> = model.iter().map(|n| n.to_bits()).collect();
+            prop_assert_eq!(stack, model);
+        }
+    }
+
+    /// Checks the tokens of each `golden/scanner/*.lox` against the
+    /// `.tokens` file next to it. Run with `UPDATE_GOLDEN=1` to rewrite them
+    /// after changing the scanner on purpose.
+    #[test]
+    fn scanner_golden() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/scanner");
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut checked = 0;
+        let mut mismatched = vec![];
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "lox") {
+                continue;
+            }
+
+            let src = std::fs::read_to_string(&path).unwrap();
+            let tokens = scanner::dump(&src);
+            let golden = path.with_extension("tokens");
+            if update {
+                std::fs::write(&golden, &tokens).unwrap();
+            } else if std::fs::read_to_string(&golden).ok().as_deref() != Some(&tokens) {
+                mismatched.push(format!("{}:\n{tokens}", path.display()));
+            }
+            checked += 1;
+        }
+
+        assert!(checked > 0, "No golden files in {}", dir.display());
+        assert!(mismatched.is_empty(), "{}", mismatched.join("\n"));
+    }
+
+    /// The first compile error in `src`, if any
+    fn compile_error(src: &str) -> Option<String> {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        parser.print_errors = false;
+        parser.compile();
+        parser.diagnostics.first().map(ToString::to_string)
+    }
+
+    #[test]
+    fn compile_limits() {
+        fn repeat(n: usize, item: impl Fn(usize) -> String, sep: &str) -> String {
+            (0..n).map(item).collect::<Vec<_>>().join(sep)
+        }
+
+        let params = |n| format!("fun f({}) {{}}", repeat(n, |i| format!("p{i}"), ", "));
+        assert_eq!(compile_error(&params(255)), None);
+        assert_eq!(
+            compile_error(&params(256)).as_deref(),
+            Some("[line 1:1427] Error at p255: Can't have more than 255 parameters.")
+        );
+
+        let args = |n| format!("fun f() {{}}\nf({});", repeat(n, |_| "nil".into(), ", "));
+        assert_eq!(compile_error(&args(255)), None);
+        assert_eq!(
+            compile_error(&args(256)).as_deref(),
+            Some("[line 2:1278] Error at nil: Can't have more than 255 arguments.")
+        );
+
+        let items = |n| format!("[{}];", repeat(n, |_| "nil".into(), ", "));
+        assert_eq!(compile_error(&items(255)), None);
+        assert_eq!(
+            compile_error(&items(256)).as_deref(),
+            Some("[line 1:1277] Error at nil: Can't have more than 255 items in a list literal.")
+        );
+
+        let constants = |n| repeat(n, |i| format!("\"s{i}\";"), "\n");
+        assert_eq!(compile_error(&constants(256)), None);
+        assert_eq!(
+            compile_error(&constants(257)).as_deref(),
+            Some("[line 257:1] Error at \"s256\": Too many constants in one chunk.")
+        );
+
+        let numbers = |n| repeat(n, |i| format!("{i};"), "\n");
+        assert_eq!(compile_error(&numbers(256)), None);
+        assert_eq!(
+            compile_error(&numbers(257)).as_deref(),
+            Some("[line 257:1] Error at 256: Too many numbers in one chunk.")
+        );
+
+        // Slot zero is taken by the function itself
+        let locals = |n| format!("{{\n{}\n}}", repeat(n, |i| format!("var l{i};"), "\n"));
+        assert_eq!(compile_error(&locals(255)), None);
+        assert_eq!(
+            compile_error(&locals(256)).as_deref(),
+            Some("[line 257:5] Error at l255: Too many local variables in function.")
+        );
+
+        // Captured from two enclosing functions, since neither can have
+        // enough locals on its own
+        let upvalues = |n: usize| {
+            format!(
+                "fun outer() {{ {} fun middle() {{ {} fun inner() {{ {} {} }} }} }}",
+                repeat(200, |i| format!("var a{i};"), " "),
+                repeat(n - 200, |i| format!("var b{i};"), " "),
+                repeat(200, |i| format!("a{i};"), " "),
+                repeat(n - 200, |i| format!("b{i};"), " "),
+            )
+        };
+        assert_eq!(compile_error(&upvalues(255)), None);
+        assert_eq!(
+            compile_error(&upvalues(256)).as_deref(),
+            Some("[line 1:3783] Error at b55: Too many closure variables in function.")
+        );
+
+        // `nil;` is two bytes, and the jump also skips the `Pop` before the
+        // body and the `Jump` over the (missing) else branch after it
+        let jump = |n| format!("if (true) {{\n{}\n}}", repeat(n, |_| "nil;".into(), "\n"));
+        assert_eq!(compile_error(&jump(32765)), None);
+        assert_eq!(
+            compile_error(&jump(32766)).as_deref(),
+            Some("[line 32768:1] Error at }: Too much code to jump over.")
+        );
+
+        let body = |n| {
+            format!(
+                "while (false) {{\n{}\n}}",
+                repeat(n, |_| "nil;".into(), "\n")
+            )
+        };
+        assert_eq!(compile_error(&body(32763)), None);
+        assert_eq!(
+            compile_error(&body(32764)).as_deref(),
+            Some("[line 32766:1] Error at }: Loop body too large.")
+        );
+    }
+
+    #[test]
+    fn expression_depth_limit() {
+        let parens = |n| format!("{}1{};", "(".repeat(n), ")".repeat(n));
+        assert_eq!(compile_error(&parens(255)), None);
+        assert_eq!(
+            compile_error(&parens(256)).as_deref(),
+            Some("[line 1:257] Error at 1: Expression too deeply nested.")
+        );
+
+        let nested = [
+            parens(10_000),
+            format!("{}true;", "!".repeat(10_000)),
+            format!("{}1;", "-".repeat(10_000)),
+            format!("{}{};", "[".repeat(10_000), "]".repeat(10_000)),
+            format!(
+                "fun g(f) {{\n{}nil{};\n}}",
+                "f(".repeat(10_000),
+                ")".repeat(10_000)
+            ),
+            format!("fun g(a) {{\n{}1;\n}}", "a = ".repeat(10_000)),
+            format!(
+                "fun g(l) {{\n{}0{};\n}}",
+                "l[".repeat(10_000),
+                "]".repeat(10_000)
+            ),
+            format!("{}1;", "1 + (".repeat(10_000)),
+        ];
+        for src in nested {
+            let err = compile_error(&src).unwrap();
+            assert!(err.ends_with("Expression too deeply nested."), "{err}");
+        }
+
+        assert_eq!(
+            compile_error("var a;\n!a = 1;").as_deref(),
+            Some("[line 2:4] Error at =: Invalid assignment target.")
+        );
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "return 1 == 2 < 3;"),
+            Ok(Value::Bool(false))
+        );
+
+        let mut vm = VM::with_options(VmOptions::new().max_expression_depth(2));
+        assert_eq!(interpret(&mut vm, "var x = (1);"), Ok(Value::Nil));
+        assert_eq!(
+            interpret(&mut vm, "var x = ((1));"),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        // Whatever nesting it's given, compiling mustn't overflow the stack
+        // or panic. The first thing this found was `=` parsing as a binary
+        // operator.
+        #[test]
+        fn deep_nesting_fuzz(parts in prop::collection::vec(
+            prop::sample::select(vec!["(", ")", "[", "]", "!", "-", "f(", "a = ", "1", "+", ","]),
+            0..20_000,
+        )) {
+            compile_error(&format!("fun g(a, f) {{\n{};\n}}", parts.concat()));
+        }
+    }
+
+    #[test]
+    fn columns() {
+        assert_eq!(
+            compile_error("var x = 1;\n  print x +;").as_deref(),
+            Some("[line 2:12] Error at ;: Expect expression")
+        );
+
+        let src = "print 1;\n  1 + nil;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+        let chunk = &parser.compiler.function.as_ref().chunk;
+        let add = chunk
+            .code
+            .iter()
+            .position(|&byte| byte == Opcode::Add as u8)
+            .unwrap();
+        assert_eq!(chunk.location(add), "[line 2:7]");
+
+        let columns: Vec<_> = Scanner::new("a\n  \"b\nc\" d")
+            .map(|tok| (tok.line, tok.column))
+            .collect();
+        assert_eq!(columns, [(1, 1), (2, 3), (3, 4), (3, 5)]);
+    }
+
+    #[test]
+    fn colored_diagnostics() {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var x = 1;\n  print x + y y;", &mut mem);
+        parser.print_errors = false;
+        parser.compile();
+        let diagnostic = &parser.diagnostics[0];
+        let line = "  print x + y y;";
+
+        assert_eq!(diagnostic.render(Some(line), false), diagnostic.to_string());
+        let lexemes = Lexemes::default();
+        let mut streamed = Scanner::streaming(&b"var x = 1;\n  print x + y y;"[..], &lexemes);
+        let tokens: Vec<_> = std::iter::from_fn(|| Some(streamed.token()))
+            .take(10)
+            .collect();
+        assert_eq!(streamed.line_text(tokens[8].span.start), Some(line));
+        assert_eq!(
+            diagnostic.render(Some(line), true),
+            "\x1b[36m[line 2:15]\x1b[0m \x1b[1;31mError\x1b[0m at y: Expect ';' after value.\n\
+             \x1b[2m    2 | \x1b[0m  print x + y \x1b[4;31my\x1b[0m;\n\
+             \x1b[2m      | \x1b[0m              \x1b[1;31m^\x1b[0m"
+        );
+
+        let mut out = vec![];
+        parser
+            .compiler
+            .function
+            .as_ref()
+            .disassemble_all_colored(&mut out, true)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("\x1b[1m== script ==\x1b[0m\n\x1b[2m0000\x1b[0m \x1b[36m   1\x1b[0m")
+        );
+    }
+
+    #[test]
+    fn json_diagnostics() {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var x = 1;\n  print x + y y;", &mut mem);
+        parser.print_errors = false;
+        parser.file = Some("main.lox".into());
+        parser.compile();
+        assert_eq!(
+            parser.diagnostics[0].to_json(),
+            "{\"severity\":\"error\",\"code\":\"E0113\",\"message\":\"Expect ';' after value.\",\
+             \"file\":\"main.lox\",\"line\":2,\"column\":15,\"span\":{\"start\":25,\"end\":26}}"
+        );
+        assert_eq!(
+            errors::json_string("a \"b\"\\\n\u{1}"),
+            r#""a \"b\"\\\n\u0001""#
+        );
+    }
+
+    #[test]
+    fn identifier_constants_shared() {
+        let src = "var a = 1;\na = a + a;\nprint a.b;\nprint a.b;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let chunk = &parser.compiler.function.as_ref().chunk;
+        let mut out = vec![];
+        chunk.disassemble("script", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\"a\"").count(), 6, "{out}");
+        assert_eq!(out.matches("\"b\"").count(), 2, "{out}");
+        assert_eq!(chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn chunk_view() {
+        let src = "fun add(a, b) {\n  return a + b * 2.5;\n}\nprint add;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+        let script = parser.compiler.function;
+        drop(parser);
+
+        let script = script.chunk_view();
+        assert_eq!(script.name(), "script");
+        assert_eq!(script.code().len(), script.lines().len());
+        let add = script.functions().next().unwrap();
+        assert_eq!(
+            (add.name(), add.arity(), add.upvalue_count()),
+            ("add", 2, 0)
+        );
+        let locals: Vec<_> = add.locals().iter().map(|local| &local.name).collect();
+        assert_eq!(locals, ["a", "b"]);
+
+        let instructions: Vec<_> = add.instructions().collect();
+        let operand = instructions
+            .iter()
+            .find_map(|(_, instr)| match instr {
+                Instruction::Byte(Opcode::Number, operand) => Some(*operand),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(add.number(operand, &mem.numbers), Some(2.5));
+        let (offset, _) = instructions
+            .iter()
+            .find(|(_, instr)| matches!(instr, Instruction::Simple(Opcode::Add)))
+            .unwrap();
+        assert_eq!(add.position(*offset), Some((2, Some(18))));
+        assert_eq!(add.position(add.code().len()), None);
+    }
+
+    #[test]
+    fn call_graph() {
+        let src = r#"
+            fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            class Shape { init() {} area() { return 0; } describe() { return this.area(); } }
+            class Square < Shape {
+                init(side) { super.init(); this.side = side; }
+                area() { return square(this.side); }
+            }
+            fun square(x) { return x * x; }
+            fun outer() {
+                fun helper() { return fib(3); }
+                return helper();
+            }
+            var f = outer;
+            print Square(2).describe() + f();
+            fun unknown(g) { return g(); }
+        "#;
+        let graph = crate::callgraph::CallGraph::of_source(src).unwrap();
+        let calls: Vec<_> = graph
+            .calls
+            .iter()
+            .map(|&(caller, callee)| {
+                format!("{} -> {}", graph.functions[caller], graph.functions[callee])
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "script -> Square.init",
+                "script -> outer",
+                "fib -> fib",
+                "Shape.describe -> Shape.area",
+                "Square.init -> Shape.init",
+                "Square.area -> square",
+                "outer -> outer.helper",
+                "outer.helper -> fib",
+            ]
+        );
+        assert!(graph.functions.iter().any(|name| name == "unknown"));
+
+        let mut dot = vec![];
+        graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(
+            dot.starts_with("digraph calls {\n    \"script\";\n"),
+            "{dot}"
+        );
+        assert!(dot.contains("    \"fib\" -> \"fib\";\n"), "{dot}");
+    }
+
+    #[test]
+    fn opcode_numbers() {
+        for (byte, &op) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(op as usize, byte);
+            assert_eq!(Opcode::try_from(byte as u8), Ok(op));
+            assert_eq!(op.as_str(), format!("{op:?}"));
+        }
+        // Shared with zlox and written to .loxb files
+        assert_eq!(Opcode::Return as u8, 0);
+        assert_eq!(Opcode::SuperInvoke as u8, 36);
+        assert_eq!(Opcode::Slice as u8, 44);
+        let unknown = Opcode::ALL.len() as u8;
+        assert_eq!(Opcode::try_from(unknown), Err(UnknownOpcode(unknown)));
+
+        let src = "var xs = [1, 2];\nfun f(a) { return a.b(xs[0]); }\nprint f;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+        let script = parser.compiler.function;
+        drop(parser);
+        for view in [
+            script.chunk_view(),
+            script.chunk_view().functions().next().unwrap(),
+        ] {
+            let mut offsets = view.instructions().map(|(offset, _)| offset).peekable();
+            while let Some(offset) = offsets.next() {
+                let op = Opcode::try_from(view.code()[offset]).unwrap();
+                let next = offsets.peek().copied().unwrap_or(view.code().len());
+                assert_eq!(next - offset, 1 + op.operand_len(), "{op:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn closures_share_constants() {
+        let src = r#"
+        var made = [];
+        for (var i = 0; i < 3; i = i + 1) {
+            var x = i;
+            fun f() { return x + y + y + z; }
+            append(made, f);
+        }
+        return made;
+        "#;
+        let mut vm = VM::new();
+        let made = interpret(&mut vm, src).unwrap().try_as_list().unwrap();
+        let functions: Vec<_> = made
+            .items
+            .iter()
+            .map(|f| f.as_obj_closure().unwrap().function)
+            .collect();
+
+        assert_eq!(functions.len(), 3);
+        assert!(functions.iter().all(|&f| f == functions[0]));
+        assert_eq!(functions[0].chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn allocation_sites() {
+        let src = "fun make() {\n  return [1, 2];\n}\nfor (var i = 0; i < 10; i = i + 1) make();";
+        let mut vm = VM::new();
+        assert_eq!(vm.allocation_report(), None);
+
+        vm.allocations = Some(Allocations::new());
+        interpret(&mut vm, src).unwrap();
+
+        let allocations = vm.allocations.as_ref().unwrap();
+        let busiest = allocations.sites()[0];
+        assert_eq!(busiest.function, "make");
+        assert_eq!(busiest.line, 2);
+        assert_eq!(busiest.count, 10);
+        assert_eq!(busiest.kinds, [(ObjKind::List, 10)]);
+        // And the closure for `make`
+        assert_eq!(allocations.total(), 11);
+
+        let report = vm.allocation_report().unwrap();
+        assert!(report.contains("make [line 2]"), "{report}");
+    }
+
+    #[test]
+    fn alloc_trace() {
+        // Every concatenation but the last is garbage by the next one
+        let src = "var s = \"\"; for (var i = 0; i < 500; i = i + 1) s = s + \"x\";";
+        let out = Rc::new(RefCell::new(vec![]));
+        let mut vm = VM::with_options(VmOptions::new().gc_threshold(1024));
+        vm.mem.trace = Some(AllocTrace::new(Box::new(SharedWriter(out.clone()))).unwrap());
+        interpret(&mut vm, src).unwrap();
+        vm.mem.trace.take().unwrap().finish().unwrap();
+
+        let events = alloc_trace::read_trace(&out.borrow()).unwrap();
+        assert!(matches!(events[0], TraceEvent::Alloc { live: 1, .. }));
+        let report = TraceReport::new(&events);
+        assert!(report.collections > 0);
+        assert!(report.freed > 0);
+        assert_eq!(
+            report.by_age.iter().sum::<u64>() + report.live_at_exit(),
+            report.allocated
+        );
+        assert_eq!(report.by_collections.iter().sum::<u64>(), report.freed);
+        let strings = report
+            .kinds
+            .iter()
+            .find(|k| k.kind == ObjKind::Str)
+            .unwrap();
+        // Collecting before every allocation lets nothing die young
+        #[cfg(not(feature = "always_gc"))]
+        assert!(strings.died_young > 0);
+        assert!(strings.freed > 0);
+
+        let mut text = vec![];
+        report.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("collections survived"), "{text}");
+        assert!(text.contains("\n            0 "), "{text}");
+
+        // Cut off in the middle of an event
+        let bytes = out.borrow();
+        assert!(alloc_trace::read_trace(&bytes[..bytes.len() - 1]).is_err());
+        assert!(alloc_trace::read_trace(b"LOXB\x01\x00").is_err());
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[test]
+    fn leak_check() {
+        // The second concatenation finds "ab" interned and has to free its
+        // own copy
+        let src = "var a = \"a\" + \"b\"; var b = \"a\" + \"b\"; var c = [a, b];";
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.mem.leak_report(), None);
+
+        vm.collect_all_garbage();
+        assert_eq!(vm.mem.leak_report(), None);
+
+        let chars = vm.mem.alloc_chars(3);
+        let report = vm.mem.leak_report().unwrap();
+        assert!(report.contains("1 (3 bytes)"), "{report}");
+        vm.mem.free_chars(chars, 3);
+        assert_eq!(vm.mem.leak_report(), None);
+    }
+
+    #[test]
+    fn downcasting() {
+        let mut vm = VM::new();
+        interpret(&mut vm, "var items = [1, 2];").unwrap();
+        let items = match vm.execute_statement("items").unwrap().value {
+            Value::Obj(obj) => obj,
+            _ => panic!("Not an object"),
+        };
+        assert_eq!(items.downcast::<ObjList>().unwrap().items.len(), 2);
+        assert!(items.downcast::<ObjString>().is_none());
+        assert!(items.downcast::<ObjClosure>().is_none());
+
+        // It's only checked in debug builds, where it's a panic rather than
+        // reading a list as a closure
+        #[cfg(debug_assertions)]
+        assert!(std::panic::catch_unwind(|| items.downcast_unchecked::<ObjClosure>()).is_err());
+    }
+
+    #[test]
+    fn keywords_and_interning() {
+        let kinds = |src: &str| -> Vec<_> { Scanner::new(src).map(|token| token.kind).collect() };
+        let keywords =
+            "and class else false for fun if nil or print return super this true var while";
+        use TokenKind::*;
+        assert_eq!(
+            kinds(keywords),
+            [
+                And, Class, Else, False, For, Fun, If, Nil, Or, Print, Return, Super, This, True,
+                Var, While, Eof
+            ]
+        );
+        for near_miss in [
+            "an", "classy", "els", "fals", "fo", "fn", "i", "nill", "o", "prin",
+        ] {
+            assert_eq!(kinds(near_miss)[0], TokenKind::Identifier, "{near_miss}");
+        }
+        for near_miss in [
+            "returns", "supe", "thus", "tru", "va", "whale", "r", "e", "s",
+        ] {
+            assert_eq!(kinds(near_miss)[0], TokenKind::Identifier, "{near_miss}");
+        }
+
+        let src = "foo bar foo this this";
+        let msgs =
+            |scanner: Scanner<'static>| -> Vec<_> { scanner.map(|token| token.msg).collect() };
+        let plain = msgs(Scanner::new(src));
+        assert!(!std::ptr::eq(plain[0], plain[2]));
+        let interned = msgs(Scanner::new(src).interning());
+        assert!(std::ptr::eq(interned[0], interned[2]));
+        assert!(!std::ptr::eq(interned[0], interned[1]));
+        assert!(std::ptr::eq(interned[3], interned[4]));
+        assert_eq!(interned, plain);
+
+        // Names the compiler makes up resolve to the same locals
+        let mut vm = VM::new();
+        let src = "class A { f() { return this; } } class B < A { g() { return super.f(); } }
+                   var b = B(); var same = b.g() == b;";
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            vm.execute_statement("same").unwrap().value,
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn streamed_source() {
+        let src = "var greeting = \"héllo\n  wörld\"; // a comment\n\
+                   fun f(a) { return a?.b ?? 1.5; }\n  print f(nil) + f(nil);\n  @";
+        let tokens = |scanner: Scanner| -> Vec<_> {
+            scanner
+                .map(|token| {
+                    (
+                        token.kind,
+                        token.line,
+                        token.column,
+                        token.msg.to_owned(),
+                        token.span,
+                    )
+                })
+                .collect()
+        };
+        let expected = tokens(Scanner::new(src));
+        for capacity in [1, 2, 7, 64] {
+            let lexemes = Lexemes::default();
+            let reader = std::io::BufReader::with_capacity(capacity, src.as_bytes());
+            assert_eq!(
+                tokens(Scanner::streaming(reader, &lexemes)),
+                expected,
+                "{capacity}"
+            );
+        }
+
+        // Each name is kept once
+        let src = "var total = 0;\n".repeat(1000);
+        let lexemes = Lexemes::default();
+        let mut scanner = Scanner::streaming(src.as_bytes(), &lexemes);
+        while scanner.token().kind != TokenKind::Eof {}
+        assert_eq!(lexemes.len(), 6);
+        assert!(lexemes.bytes() < 20);
+
+        let src =
+            "var total = 0;\nfor (var i = 0; i < 10; i = i + 1) total = total + i;\nreturn total;";
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let reader = std::io::BufReader::with_capacity(4, src.as_bytes());
+        assert_eq!(interpret_reader(&mut vm, reader), Ok(Value::Number(45.0)));
+
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "disk on fire",
+                ))
+            }
+        }
+        let reader = std::io::BufReader::new(std::io::Read::chain("return 1;".as_bytes(), Failing));
+        assert_eq!(
+            load_reader(&mut vm, reader),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    #[test]
+    fn several_files() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let lib = "var greeting = \"hi\";\nfun shout(s) {\n  return s + 1;\n}";
+        assert_eq!(
+            interpret_file(&mut vm, "lib.lox", lib.as_bytes()),
+            Ok(Value::Nil)
+        );
+        let main = "var ok = greeting == \"hi\";\nshout(greeting);";
+        assert!(interpret_file(&mut vm, "main.lox", main.as_bytes()).is_err());
+        assert_eq!(
+            vm.last_error.take().unwrap().trace,
+            [
+                "[lib.lox:3:15] in shout(s=\"hi\")",
+                "[main.lox:2:16] in script"
+            ]
+        );
+        assert_eq!(vm.get_global("ok"), Some(Value::Bool(true)));
+
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("print ;", &mut mem);
+        parser.print_errors = false;
+        parser.file = Some("bad.lox".into());
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.diagnostics[0].to_string(),
+            "[bad.lox:1:7] Error at ;: Expect expression"
+        );
+    }
+
+    #[test]
+    fn bench_compile() {
+        let src = "fun add(a, b) { return a + b; }\nvar x = add(1, 2);";
+        let timings = bench::bench_compile(src, 3).unwrap();
+        assert_eq!(timings.iterations, 3);
+        assert_eq!(timings.bytes, src.len());
+        assert_eq!(timings.tokens, Scanner::new(src).count());
+        assert_eq!(
+            timings.total(),
+            timings.scan + timings.parse + timings.codegen
+        );
+
+        let mut report = vec![];
+        timings.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("3 iterations, "), "{report}");
+        for phase in ["scan", "parse", "codegen", "total"] {
+            assert!(report.contains(&format!("\n{phase} ")), "{report}");
+        }
+
+        assert_eq!(
+            bench::bench_compile("var = 1;", 3),
+            Err("[line 1:5] Error at =: Expect variable name.".into())
+        );
+    }
+
+    #[test]
+    fn bench_comparison() {
+        let measurement = |millis: &[u64], rss, heap| bench::Measurement {
+            times: millis.iter().map(|&ms| Duration::from_millis(ms)).collect(),
+            peak_rss: rss,
+            peak_heap: heap,
+            error: None,
+        };
+        let comparison = bench::Comparison {
+            implementations: vec!["loxide".into(), "zlox".into()],
+            benchmarks: vec![
+                (
+                    "fib".into(),
+                    vec![
+                        measurement(&[300, 100, 200], Some(2 * 1024 * 1024), Some(512 * 1024)),
+                        measurement(&[500], Some(1024 * 1024), Some(3 * 1024 * 1024)),
+                    ],
+                ),
+                (
+                    "zoo".into(),
+                    vec![
+                        measurement(&[100], None, None),
+                        bench::Measurement {
+                            error: Some("zlox failed on zoo.lox".into()),
+                            ..measurement(&[], None, None)
+                        },
+                    ],
+                ),
+            ],
+        };
+
+        let mut markdown = vec![];
+        comparison.write_markdown(&mut markdown).unwrap();
+        assert_eq!(
+            String::from_utf8(markdown).unwrap(),
+            "| benchmark | loxide time | zlox time | loxide RSS | zlox RSS | loxide heap | zlox heap | vs zlox |\n\
+             | --- | --- | --- | --- | --- | --- | --- | --- |\n\
+             | fib | 0.200 s | 0.500 s | 2.0 MiB | 1.0 MiB | 512.0 KiB | 3.0 MiB | 2.50x |\n\
+             | zoo | 0.100 s | failed | - | - | - | - | - |\n"
+        );
+
+        let mut csv = vec![];
+        comparison.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "benchmark,loxide_seconds,zlox_seconds,loxide_rss_bytes,zlox_rss_bytes,loxide_heap_bytes,zlox_heap_bytes,speedup_vs_zlox\n\
+             fib,0.2,0.5,2097152,1048576,524288,3145728,2.5\n\
+             zoo,0.1,,,,,,\n"
+        );
+
+        #[cfg(unix)]
+        {
+            let implementation = |command: &str| bench::Implementation {
+                name: command.into(),
+                command: command.into(),
+            };
+            let script = std::path::PathBuf::from("script.lox");
+            let ran = bench::compare(
+                &[implementation("true"), implementation("false")],
+                &[script],
+                1,
+                2,
+            );
+            let (name, measurements) = &ran.benchmarks[0];
+            assert_eq!(name, "script");
+            assert_eq!(measurements[0].times.len(), 2);
+            assert!(measurements[0].peak_rss.is_some());
+            // `true` doesn't know about heap stats
+            assert_eq!(measurements[0].peak_heap, None);
+            assert!(measurements[1].error.is_some());
+        }
+
+        // Garbage is freed, so less is live than was ever allocated
+        let mut vm = VM::with_options(VmOptions::new().gc_threshold(1024));
+        interpret(
+            &mut vm,
+            "var s = \"\"; for (var i = 0; i < 100; i = i + 1) s = s + \"x\";",
+        )
+        .unwrap();
+        assert!(vm.mem.live_bytes < vm.mem.bytes_allocated());
+        assert!(vm.mem.live_bytes <= vm.mem.peak_bytes);
+        assert!(vm.mem.live_bytes > 0);
+    }
+
+    #[test]
+    fn trailing_commas_and_list_recovery() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = "fun add(a, b,) { return a + b; } var xs = [add(1, 2,), 4,];";
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("xs").unwrap().to_string(), "[3, 4]");
+
+        let mut mem = Mem::new();
+        let src = "print f(1 +, 2 *, g(3 4), 5);\nfun h(a, 1, b, 2) {}\nprint [, 1];\nvar y = (1;";
+        let mut parser = Parser::new(src, &mut mem);
+        parser.print_errors = false;
+        assert!(!parser.compile());
+        let errors: Vec<_> = parser.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "[line 1:12] Error at ,: Expect expression",
+                "[line 1:17] Error at ,: Expect expression",
+                "[line 1:23] Error at 4: Expect ')' after arguments.",
+                "[line 2:10] Error at 1: Expect parameter name.",
+                "[line 2:16] Error at 2: Expect parameter name.",
+                "[line 3:8] Error at ,: Expect expression",
+                "[line 4:11] Error at ;: Expect ')' after expression.",
+            ]
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        let codes: HashSet<_> = ErrorCode::ALL.iter().map(|code| code.code()).collect();
+        assert_eq!(codes.len(), ErrorCode::ALL.len());
+        assert!(codes
+            .iter()
+            .all(|code| code.len() == 5 && code.starts_with(['E', 'W'])));
+
+        let compile_code = |src| {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            parser.print_errors = false;
+            parser.compile();
+            parser.diagnostics[0].code
+        };
+        assert_eq!(compile_code("var = 1;"), ErrorCode::ExpectVariableName);
+        assert_eq!(compile_code("var a = @;"), ErrorCode::UnexpectedCharacter);
+        assert_eq!(compile_code("this;"), ErrorCode::ThisOutsideClass);
+
+        let mut vm = VM::new();
+        for (src, code, message) in [
+            ("x;", ErrorCode::UndefinedVariable, "Undefined variable: x"),
+            (
+                "fun f(a) {} f();",
+                ErrorCode::WrongArity,
+                "Expected 1 arguments but got 0.",
+            ),
+            (
+                "[1][2];",
+                ErrorCode::IndexOutOfRange,
+                "Index 2 is out of range for a list of length 1.",
+            ),
+            (
+                "len(1);",
+                ErrorCode::Native,
+                "Can only take the length of a list, float array or string.",
+            ),
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+            let last = vm.last_error.clone().unwrap();
+            assert_eq!((last.code, last.message.as_str()), (code, message), "{src}");
+        }
+
+        // Only translates a message no other test looks at, they share it
+        errors::set_translator(Some(|code| match code {
+            ErrorCode::InheritFromSelf => Some("Eine Klasse kann nicht von sich selbst erben."),
+            _ => None,
+        }));
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("class A < A {}", &mut mem);
+        parser.print_errors = false;
+        parser.compile();
+        errors::set_translator(None);
+        assert_eq!(parser.diagnostics[0].code, ErrorCode::InheritFromSelf);
+        assert_eq!(
+            parser.diagnostics[0].message,
+            "Eine Klasse kann nicht von sich selbst erben."
+        );
+        assert_eq!(
+            ErrorCode::IndexOutOfRange.message(&[&3, &"list", &0]),
+            "Index 3 is out of range for a list of length 0."
+        );
+    }
+
+    #[test]
+    fn undeclared_assignment_warnings() {
+        let src = "var count = 0;
+fun bump(by) { cuont = count + by; by = 1; }
+later = 1;
+var later;
+clock = nil;
+{ var local; local = 2; }";
+        let warnings = |warn_undeclared| {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            parser.print_errors = false;
+            parser.warn_undeclared = warn_undeclared;
+            assert!(parser.compile());
+            parser.warnings
+        };
+
+        assert_eq!(warnings(false), vec![]);
+        let warnings = warnings(true);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, ErrorCode::UndeclaredAssignment);
+        assert_eq!(
+            warnings[0].to_string(),
+            "[line 2:16] Warning at cuont: Assignment to a global that is never declared."
+        );
+    }
+
+    #[test]
+    fn unused_warnings() {
+        let src = "fun main() { helper(); }
+fun helper() { return LIMIT; }
+fun unused() { return unused() + helper(); }
+var LIMIT = 3;
+var (spare, used) = [1, 2];
+class Shape { area() { return Helper2(); } }
+fun Helper2() {}
+class extension Shape { more() {} }
+var written;
+written = used;
+class Base {} class Derived < Base {}
+main();";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        parser.print_errors = false;
+        parser.warn_unused = true;
+        assert!(parser.compile());
+        let warnings: Vec<_> = parser.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "[line 3:5] Warning at unused: Function is never used.",
+                "[line 5:6] Warning at spare: Global is never used.",
+                "[line 6:7] Warning at Shape: Global is never used.",
+                "[line 7:5] Warning at Helper2: Function is never used.",
+                "[line 9:5] Warning at written: Global is never used.",
+                "[line 11:7] Warning at Base: Global is never used.",
+                "[line 11:21] Warning at Derived: Global is never used.",
+            ]
+        );
+    }
+
+    #[test]
+    fn comparison_strictness() {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var ok = 1 < 2 == true; var bad = 1 < 2 < 3;", &mut mem);
+        parser.print_errors = false;
+        assert!(parser.compile());
+        let codes: Vec<_> = parser.warnings.iter().map(|w| w.code).collect();
+        assert_eq!(codes, [ErrorCode::ChainedComparison]);
+        assert_eq!(parser.warnings[0].column, 41);
+
+        let src = "var a = 1 == \"1\"; var b = nil == 1; var c = \"x\" != \"y\";";
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("a"), Some(Value::Bool(false)));
+
+        let mut vm = VM::with_options(VmOptions::new().strict_equality(true));
+        let src = "var b = nil == 1; var c = \"x\" != \"y\";";
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("b"), Some(Value::Bool(false)));
+        assert_eq!(vm.get_global("c"), Some(Value::Bool(true)));
+        assert_eq!(
+            interpret(&mut vm, "1 == \"1\";"),
+            Err(InterpretError::RuntimeError)
+        );
+    }
+
+    #[test]
+    fn strict_mode() {
+        let compile_errors = |src, strict| {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            parser.print_errors = false;
+            parser.strict = strict;
+            parser.compile();
+            let codes: Vec<_> = parser.diagnostics.iter().map(|d| d.code).collect();
+            (codes, parser.warnings.len())
+        };
+        let src = "fun f() { cuont = 1; } var x = 1 < 2 < 3;";
+        assert_eq!(compile_errors(src, false), (vec![], 1));
+        let errors = vec![
+            ErrorCode::ChainedComparison,
+            ErrorCode::UndeclaredAssignment,
+        ];
+        assert_eq!(compile_errors(src, true), (errors.clone(), 0));
+        let directive = format!("// A strict file\n//#strict\n{src}");
+        assert_eq!(compile_errors(&directive, false), (errors, 0));
+        let late = format!("{src}\n//#strict");
+        assert_eq!(compile_errors(&late, false), (vec![], 1));
+
+        // Only the code from the strict file checks at runtime
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        interpret(&mut vm, "fun lax(a, b) { return a / b; }").unwrap();
+        interpret(&mut vm, "//#strict\nfun strict(a, b) { return a / b; }").unwrap();
+        interpret(&mut vm, "var inf = lax(1, 0); var same = lax == 1;").unwrap();
+        assert_eq!(vm.get_global("inf"), Some(Value::Number(f64::INFINITY)));
+        for src in ["strict(1, 0);", "//#strict\nlax == 1;", "//#strict\n0 / 0;"] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn deterministic_mode() {
+        let options = VmOptions::new().deterministic(true);
+        let mut capabilities = Capabilities::default();
+        capabilities.allow("clock");
+        let mut vm = VM::with_options(options.capabilities(capabilities));
+        let src = r#"var date = utcDate(0).year; var n = len(format("{}", 1));"#;
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("date"), Some(Value::Number(1970.0)));
+
+        for src in [
+            "clock();",
+            "nowMillis();",
+            "utcDate();",
+            "WeakRef([]);",
+            "getenv(\"HOME\");",
+        ] {
+            assert!(interpret(&mut vm, src).is_err(), "{src}");
+            let error = vm.last_error.as_ref().unwrap();
+            assert_eq!(error.code, ErrorCode::Nondeterministic, "{src}");
+        }
+        assert_eq!(
+            vm.last_error.unwrap().message,
+            "Can't call getenv in deterministic mode, its result isn't reproducible."
+        );
+    }
+
+    #[test]
+    fn describe_types() {
+        let mut vm = VM::new();
+        interpret(
+            &mut vm,
+            r#"
+fun counter() { var n = 0; fun inc(by) { n = n + by; return n; } return inc; }
+var count = counter();
+class Point { init(x) { this.x = x; } getX() { return this.x; } }
+var point = Point(1);
+var getX = point.getX;
+var name = "lox";
+var items = [1, 2];
+var number = 1.5;
+"#,
+        )
+        .unwrap();
+
+        let describe = |name| native_fn::describe_type(vm.get_global(name).unwrap());
+        assert_eq!(describe("count"), "closure <fn inc>, arity 1, 1 upvalue");
+        assert_eq!(describe("Point"), "class Point, 2 methods");
+        assert_eq!(describe("point"), "instance of Point, 1 field");
+        assert_eq!(
+            describe("getX"),
+            "method <fn getX>, arity 0, bound to Point instance"
+        );
+        assert_eq!(describe("name"), "string, 3 bytes");
+        assert_eq!(describe("items"), "list, 2 items");
+        assert_eq!(describe("number"), "number");
+        assert_eq!(describe("len"), "native function");
+        assert!(vm.get_global("undefined").is_none());
+    }
+
+    #[test]
+    fn run_bounded() {
+        let src = "
+fun add(a, b) { return a + b; }
+var total = 0;
+for (var i = 0; i < 100; i = i + 1) {
+  total = total + [i, 1].reduce(add, 0);
+}
+return total;";
+        let mut vm = VM::new();
+        crate::load(&mut vm, src).unwrap();
+        let mut slices = 0;
+        let result = loop {
+            slices += 1;
+            match vm.run_bounded(50) {
+                StepResult::Yielded => (),
+                done => break done,
+            }
+        };
+        assert_eq!(result, StepResult::Done(Value::Number(5050.0)));
+        assert!(slices > 10, "{slices}");
+
+        // A plain run isn't bounded by the last bounded one
+        assert_eq!(interpret(&mut vm, src), Ok(Value::Number(5050.0)));
+
+        crate::load(&mut vm, "var a = 1; a();").unwrap();
+        assert_eq!(vm.run_bounded(0), StepResult::Yielded);
+        assert_eq!(
+            vm.run_bounded(100),
+            StepResult::Error(InterpretError::RuntimeError)
+        );
+    }
+
+    #[test]
+    fn budget_offenders() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = "fun down(n) { return down(n + 1); } down(0);";
+        assert!(interpret(&mut vm, src).is_err());
+        let error = vm.last_error.clone().unwrap();
+        assert_eq!(error.code, ErrorCode::StackOverflow);
+        assert_eq!(error.notes, ["Most frames: down (63), script (1)"]);
+
+        vm.step_limit = Some(1000);
+        let src = "
+fun spin() { var i = 0; while (true) i = i + 1; }
+fun once() { return 1; }
+once();
+spin();";
+        assert!(interpret(&mut vm, src).is_err());
+        let error = vm.last_error.unwrap();
+        assert_eq!(error.code, ErrorCode::StepLimitExceeded);
+        assert_eq!(
+            error.notes,
+            ["Most instructions: spin (989), script (9), once (2)"]
+        );
+    }
+
+    #[test]
+    fn stack_trace() {
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        let src = "
+fun down(n, tag) {
+  return down(n + 1, tag);
+}
+down(0, \"deep\");";
+        assert!(interpret(&mut vm, src).is_err());
+        let error = vm.last_error.clone().unwrap();
+        assert_eq!(
+            error.trace,
+            [
+                "[line 3:26] in down(n=62, tag=\"deep\")",
+                "[line 3:26] in down(n=61, tag=\"deep\")",
+                "[line 3:26] in down(n=60, tag=\"deep\")",
+                "[line 3:26] in down",
+                "... previous frame repeated 59 times",
+                "[line 5:16] in script",
+            ]
+        );
+
+        let src = "
+fun fail(xs) { return xs + 1; }
+fun twice() { fail([1, 2]); }
+twice();";
+        assert!(interpret(&mut vm, src).is_err());
+        assert_eq!(
+            vm.last_error.unwrap().trace,
+            [
+                "[line 2:29] in fail(xs=[...])",
+                "[line 3:27] in twice()",
+                "[line 4:8] in script",
+            ]
+        );
+    }
+
+    #[test]
+    fn verbose_errors() {
+        let src = "
+fun average(xs, label) {
+  var total = 0;
+  for (var i = 0; i < len(xs); i = i + 1) total = total + xs[i];
+  var mean = total / len(xs);
+  return label + mean;
+}
+average([1, 2, 3], \"mean: \");";
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        assert!(interpret(&mut vm, src).is_err());
+        assert!(vm.last_error.unwrap().notes.is_empty());
+
+        let mut vm = VM::with_options(VmOptions::new().trace(false).verbose_errors(true));
+        assert!(interpret(&mut vm, src).is_err());
+        assert_eq!(
+            vm.last_error.unwrap().notes,
+            [
+                "Locals of average:",
+                "  xs = [...]",
+                "  label = \"mean: \"",
+                "  total = 6",
+                "  mean = 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn checkpoint_and_restore() {
+        let src = "
+fun counter() {
+  var count = 0;
+  fun inc() { count = count + 1; return count; }
+  var total = 0;
+  for (var i = 0; i < 50; i = i + 1) total = total + inc();
+  return total + count;
+}
+return counter();";
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        crate::load(&mut vm, src).unwrap();
+        // Inside `counter`, with `count` captured
+        assert_eq!(vm.run_bounded(200), StepResult::Yielded);
+        assert!(!vm.open_upvalues.is_null());
+        let bytes = vm.checkpoint().unwrap();
+
+        let mut restored = VM::restore(&bytes).unwrap();
+        restored.trace_execution = false;
+        let done = StepResult::Done(Value::Number(1325.0));
+        assert_eq!(restored.run_bounded(u64::MAX), done);
+        assert_eq!(vm.run_bounded(u64::MAX), done);
+
+        assert!(VM::restore(&bytes[..bytes.len() - 1]).is_err());
+        let mut snapshot = vec![];
+        snapshot::write(&vm, &mut snapshot).unwrap();
+        assert!(VM::restore(&snapshot).is_err());
+    }
+
+    #[test]
+    fn stack_implementations_agree() {
+        fn run<S: ValueStack>() -> Vec<Value> {
+            let mut stack = S::with_capacity(8);
+            for n in 0..5 {
+                stack.push(Value::Number(n as f64));
+            }
+            let slots = unsafe { stack.base().add(1) };
+            let popped = stack.pop();
+            stack.set(1, popped);
+            unsafe { stack.set_slot(slots, 0, Value::Nil) };
+            stack.push(unsafe { stack.slot(slots, 2) });
+            stack.push(stack.peek(1));
+            stack.sub(1);
+            stack.set_top(unsafe { stack.top().sub(1) });
+            assert_eq!(stack.len(), stack.values().len());
+            stack.values().to_vec()
+        }
+
+        let values = run::<UncheckedStack>();
+        assert_eq!(values, run::<CheckedStack>());
+        assert_eq!(
+            values,
+            vec![
+                Value::Number(0.0),
+                Value::Nil,
+                Value::Number(4.0),
+                Value::Number(3.0)
+            ]
+        );
+
+        let mut checked = CheckedStack::with_capacity(2);
+        checked.push(Value::Nil);
+        assert!(std::panic::catch_unwind(|| checked.peek(1)).is_err());
+    }
+
+    #[test]
+    fn inline_caches() {
+        let src = r#"
+class A { init() { this.x = "a"; } m() { return "A.m"; } }
+class B { init() { this.y = 0; this.x = "b"; } m() { return "B.m"; } }
+fun get(o) { return o.x + o.m(); }
+var out = "";
+var a = A();
+var b = B();
+for (var i = 0; i < 3; i = i + 1) out = out + get(a) + get(b);
+// A field shadows the method that was cached
+fun m() { return "field"; }
+a.m = m;
+out = out + get(a);
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap();
+        assert_eq!(out.as_str().unwrap(), "aA.mbB.m".repeat(3) + "afield");
+
+        // `o.x` and `o.m()`, unless instances keep their fields in tables
+        let get = vm.get_global("get").unwrap().as_obj_closure().unwrap();
+        let cached = get.function.caches.len();
+        assert_eq!(cached, if cfg!(feature = "table_fields") { 0 } else { 2 });
+
+        // A class with the same name and new methods isn't served from the
+        // old one's entries
+        let src = r#"
+class A { init() { this.x = "new"; } m() { return "new A.m"; } }
+out = get(A());
+"#;
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap();
+        assert_eq!(out.as_str().unwrap(), "newnew A.m");
+    }
+
+    #[test]
+    fn class_extension() {
+        let src = r#"
+class extension { m() { return "not special"; } }
+class Point { init(x) { this.x = x; } show() { return "old"; } }
+fun show(p) { return p.show(); }
+var p = Point(1);
+var before = show(p) + show(p);
+class extension Point {
+  show() { return "x=" + format("{}", this.x); }
+  double() { return this.x * 2; }
+}
+var after = show(p) + " " + format("{}", p.double()) + " " + extension().m();
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let global = |vm: &VM, name| vm.get_global(name).unwrap().as_str().unwrap().to_owned();
+        assert_eq!(global(&vm, "before"), "oldold");
+        // The cached `show` is replaced, existing instances get `double`
+        assert_eq!(global(&vm, "after"), "x=1 2 not special");
+
+        let mut vm = VM::new();
+        let src = "var n = 1; class extension n { m() {} }";
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        let err = compile_error("class Base {} class extension Base { m() { super.m(); } }");
+        assert!(err
+            .unwrap()
+            .ends_with(ErrorCode::SuperWithoutSuperclass.template()));
+    }
+
+    #[test]
+    fn is_and_typeof() {
+        let src = r#"
+class Shape {}
+class Circle < Shape { area() { return 3; } }
+class Other {}
+fun f() {}
+var c = Circle();
+var checks = [c is Circle, c is Shape, c is Other, Shape() is Circle, 1 is Shape, nil is Other];
+var types = [typeof(1), typeof("s"), typeof(nil), typeof(true), typeof(typeof), typeof(c.area),
+  typeof(f), typeof(c), typeof(Circle), typeof([])];
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let checks = vm.get_global("checks").unwrap().as_list().unwrap();
+        let checks: Vec<_> = checks
+            .items
+            .iter()
+            .map(|v| *v == Value::Bool(true))
+            .collect();
+        assert_eq!(checks, [true, true, false, false, false, false]);
+        let types = vm.get_global("types").unwrap().as_list().unwrap();
+        let types: Vec<_> = types.items.iter().map(|v| v.as_str().unwrap()).collect();
+        assert_eq!(
+            types,
+            [
+                "number", "string", "nil", "bool", "function", "function", "function", "Circle",
+                "class", "list"
+            ]
+        );
+
+        // Snapshots keep what each class inherits from
+        let mut bytes = vec![];
+        snapshot::write(&vm, &mut bytes).unwrap();
+        let mut loaded = VM::new();
+        snapshot::read(&mut loaded, &bytes).unwrap();
+        interpret(&mut loaded, "var inherited = c is Shape;").unwrap();
+        assert_eq!(loaded.get_global("inherited"), Some(Value::Bool(true)));
+
+        let src = "class A {} var a = A(); a is a;";
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn nil_safe_operators() {
+        let src = r#"
+class Node { init(next) { this.next = next; this.name = "node"; } label() { return this.name; } }
+var list = Node(Node(nil));
+var none = nil;
+var out = [
+  list?.next?.name, list.next.next?.name, none?.next.next.name, none?.label().x[0],
+  list?.label(), none ?? "default", false ?? "default", list.next.next ?? 1 ?? 2
+];
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap().as_list().unwrap();
+        let out: Vec<_> = out.items.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            out,
+            ["node", "nil", "nil", "nil", "node", "default", "false", "1"]
+        );
+
+        // Only nil is skipped
+        let src = "var n = 1; n?.x;";
+        assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        assert!(compile_error("var a; a?.b = 1;").is_some());
+        assert!(compile_error("var a; a ? b;").is_some());
+    }
+
+    #[test]
+    fn multiple_returns() {
+        let src = r#"
+fun divmod(a, b) { var q = 0; while (q * b + b <= a) q = q + 1; return q, a - q * b; }
+var (q, r) = divmod(17, 5);
+var local;
+{
+  var (x, y, z) = [1, "two", divmod(9, 4)];
+  fun sum() { return x + z[0] + z[1]; }
+  local = format("{} {} {}", x, y, sum());
+}
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("q"), Some(Value::Number(3.0)));
+        assert_eq!(vm.get_global("r"), Some(Value::Number(2.0)));
+        let local = vm.get_global("local").unwrap();
+        assert_eq!(local.as_str().unwrap(), "1 two 4");
+
+        for src in [
+            "var (a, b) = [1];",
+            "var (a) = 1;",
+            "{ var (a, b) = [1, 2, 3]; }",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+        assert!(compile_error("var (a, b) = [1, 2] var c;").is_some());
+        assert!(compile_error("{ var (a, b) = [a, 2]; }").is_some());
+    }
+
+    #[test]
+    fn repetition() {
+        let src = r#"
+var s = "ab" * 3;
+var t = 2 * "xy";
+var empty = "ab" * 0;
+var zeros = [0] * 4;
+var pairs = [1, nil] * 2;
+var n = 3 * 4;
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let global = |name| vm.get_global(name).unwrap().to_string();
+        assert_eq!(global("s"), "ababab");
+        assert_eq!(global("t"), "xyxy");
+        assert_eq!(global("empty"), "");
+        assert_eq!(global("zeros"), "[0, 0, 0, 0]");
+        assert_eq!(global("pairs"), "[1, nil, 1, nil]");
+        assert_eq!(global("n"), "12");
+
+        for src in [
+            "\"a\" * -1;",
+            "[1] * 1.5;",
+            "\"a\" * \"b\";",
+            "[1, 2] * 100000000;",
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+        }
+    }
+
+    #[test]
+    fn slices() {
+        let src = r#"
+var xs = [0, 1, 2, 3, 4];
+var s = "hello";
+var n = 2;
+var out = [xs[1:4], xs[:n], xs[n:], xs[:], xs[-2:], xs[1:-1], xs[3:3],
+  s[1:4], s[:-3], s[2:], s[5:]];
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap().to_string();
+        assert_eq!(
+            out,
+            "[[1, 2, 3], [0, 1], [2, 3, 4], [0, 1, 2, 3, 4], [3, 4], [1, 2, 3], [], ell, he, llo, ]"
+        );
+        // A new list, not the same one
+        interpret(&mut vm, "var copy = xs[:]; copy[0] = 9;").unwrap();
+        assert_eq!(vm.get_global("xs").unwrap().to_string(), "[0, 1, 2, 3, 4]");
+
+        for src in [
+            "xs[2:1];",
+            "xs[0:6];",
+            "xs[-6:];",
+            "xs[0.5:];",
+            "nil[1:];",
+            "\"é\"[1:];",
+            "xs[1:2] = 3;",
+        ] {
+            assert!(interpret(&mut vm, src).is_err(), "{src}");
+        }
+
+        for (src, message) in [
+            (
+                "s[10:];",
+                "Slice 10: is out of range for a string of length 5.",
+            ),
+            (
+                "xs[:0 - 6];",
+                "Slice :-6 is out of range for a list of length 5.",
+            ),
+            ("\"é\"[1:];", "Slice 1: splits a character of the string."),
+        ] {
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::RuntimeError));
+            assert_eq!(vm.last_error.as_ref().unwrap().message, message);
+        }
+    }
+
+    #[test]
+    fn negative_indices() {
+        let src = r#"
+var xs = [1, 2, 3];
+var s = "añb";
+xs[-1] = 30;
+var out = [xs[-1], xs[-3], xs[0], s[0], s[1], s[-1], -xs[0]];
+"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap().to_string();
+        assert_eq!(out, "[30, 1, 1, a, ñ, b, -1]");
+
+        for src in [
+            "xs[-4];",
+            "xs[3];",
+            "xs[-4] = 1;",
+            "s[2];",
+            "s[0] = \"b\";",
+            "xs[0.5];",
+        ] {
+            assert!(interpret(&mut vm, src).is_err(), "{src}");
+        }
+        let err = ErrorCode::IndexOutOfRange.message(&[&Value::Number(-4.0), &"list", &3]);
+        assert_eq!(err, "Index -4 is out of range for a list of length 3.");
+    }
+
+    #[test]
+    fn clone_and_deep_equals() {
+        let src = r#"
+class Node { init(value) { this.value = value; this.next = nil; } }
+var a = Node([1, "two", floats([3])]);
+a.next = Node(a.value);
+a.next.next = a;
+var b = clone(a);
+var out = [
+  deepEquals(a, b), a == b, b.next.next == b, b.value == b.next.value, b.value == a.value,
+  deepEquals([1, [2]], [1, [2]]), deepEquals([1], [1, 2]), deepEquals(Node(1), 1),
+  deepEquals(clone("s"), "s")
+];
+b.value[0] = 5;
+var changed = [deepEquals(a, b), a.value[0]];
+"#;
+        // Small enough that cloning collects along the way
+        let options = VmOptions::new().gc_threshold(1024).trace(false);
+        let mut vm = VM::with_options(options);
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap().to_string();
+        assert_eq!(
+            out,
+            "[true, false, true, true, false, true, false, false, true]"
+        );
+        let changed = vm.get_global("changed").unwrap().to_string();
+        assert_eq!(changed, "[false, 1]");
+
+        // Long chains don't recurse
+        let length = if cfg!(feature = "always_gc") {
+            1000
+        } else {
+            100000
+        };
+        let src = format!(
+            "var head = nil;
+for (var i = 0; i < {length}; i = i + 1) head = [i, head];
+var same = deepEquals(head, clone(head));"
+        );
+        interpret(&mut vm, &src).unwrap();
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn weak_refs() {
+        let src = r#"
+class Thing {}
+var kept = Thing();
+var strong = WeakRef(kept);
+var weak = WeakRef(Thing());
+WeakRef([1]);
+var before = strong.get() == kept;
+"#;
+        let mut vm = VM::with_options(VmOptions::new().trace(false));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(vm.get_global("before"), Some(Value::Bool(true)));
+
+        vm.collect_all_garbage();
+        // Only the one with a target left
+        assert_eq!(vm.mem.weak_refs.len(), 1);
+        let src = "var out = [weak.get(), strong.get() == kept, typeof(weak), weak];";
+        interpret(&mut vm, src).unwrap();
+        let out = vm.get_global("out").unwrap().to_string();
+        assert_eq!(out, "[nil, true, weak ref, <weak ref>]");
+
+        for src in [
+            "WeakRef(1);",
+            "WeakRef();",
+            "weak.get(1);",
+            "weak.set(kept);",
+        ] {
+            assert!(interpret(&mut vm, src).is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    fn field_storages_agree() {
+        fn run<F: FieldStorage>(mem: &mut Mem, root: NonNull<Shape>) -> Vec<(String, Value)> {
+            let mut fields = F::new(root);
+            // Enough to spill past the slots
+            let names: Vec<_> = (0..fields::MAX_SLOTS + 8)
+                .map(|n| mem.copy_string(&format!("f{n}")).as_non_null_ptr())
+                .collect();
+            for (n, &name) in names.iter().enumerate() {
+                assert!(fields.set(name, Value::Number(n as f64)));
+            }
+            assert!(!fields.set(names[1], Value::Nil));
+            assert!(!fields.set(names[fields::MAX_SLOTS + 1], Value::Bool(true)));
+            assert_eq!(fields.len(), names.len());
+            assert_eq!(fields.get(names[1]), Some(Value::Nil));
+            assert_eq!(fields.get(names[2]), Some(Value::Number(2.0)));
+            let missing = mem.copy_string("missing").as_non_null_ptr();
+            assert_eq!(fields.get(missing), None);
+
+            let mut entries: Vec<_> = fields
+                .iter()
+                .map(|(name, value)| (unsafe { name.as_ref() }.as_str().to_owned(), value))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            fields.free();
+            entries
+        }
+
+        let mut mem = Mem::new();
+        let root = Shape::root();
+        let shaped = run::<ShapedFields>(&mut mem, root);
+        // The second time through every shape already exists
+        assert_eq!(shaped, run::<ShapedFields>(&mut mem, root));
+        assert_eq!(shaped, run::<TableFields>(&mut mem, root));
+        unsafe { Shape::free(root) };
+
+        // Instances of one class set up differently still find their fields
+        let src = "class P {}
+                   var a = P(); a.x = 1; a.y = 2;
+                   var b = P(); b.y = 3; b.x = 4;
+                   var c = P(); c.x = 5;
+                   return a.y * 1000 + b.x * 100 + c.x * 10 + a.x;";
+        assert_eq!(interpret(&mut VM::new(), src), Ok(Value::Number(2451.0)));
+    }
+
+    #[test]
+    fn ohshit() {
+        // let bytes = [0, 1, 2, 3];
+
+        println!("NOOB: {:?}", std::mem::size_of::<Token>());
+
+        let values = [0, 1, 2, 3, 4, 5];
+        println!(
+            "NICE: {:?}",
+            values.iter().take(3).rev().collect::<Vec<_>>()
+        );
+
+        // 0
+        // 1
+        // 2 ---
+        // 3 ---
+        // 4
+        // 5
+        //
+        // 6
+        // -2 to adjust for the 2 bytes for the jump offset
+        let mut chunk = [0, 1, 2, 3, 4, 5];
+        let offset = 2;
+        let jump = chunk.len() as u32 - offset - 2;
+
+        chunk[offset as usize] = (jump >> 8) as u8;
+        chunk[offset as usize + 1] = jump as u8;
+
+        let val = ((chunk[offset as usize] as u16) << 8) | (chunk[offset as usize + 1] as u16);
+
+        println!("{jump} NOOB: {chunk:?} JUMP: {val} {}", 2u16);
+    }
+
+    // #[test]
+    // fn miri_test() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     let foo = unsafe { *obj };
+    //     *obj2 = 9999;
+    // }
+    // #[test]
+    // fn miri_test2() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     unsafe {
+    //         *obj = 420;
+    //     };
+    //     *obj2 = 9999;
+    // }
+}
+
+fn _f(_a: i32, _b: i32) -> i32 {
+    420
+}
+
+fn _noob() {
+    let _noob = _f(_f(1, 2), _f(3, 4));
+}
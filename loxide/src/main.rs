@@ -1,556 +1,642 @@
-#![feature(ptr_sub_ptr)]
-#![feature(allocator_api)]
-#![feature(slice_ptr_get)]
-#![feature(let_chains)]
-
-pub mod chunk;
-pub mod compile;
-pub mod mem;
-pub mod native_fn;
-pub mod obj;
-pub mod table;
-pub mod value;
-pub mod vm;
-
-use std::{io::BufRead, mem::MaybeUninit, path::Path};
-
-use compile::Parser;
-use mem::Mem;
-
-use vm::{InterpretError, InterpretResult, ValueStack, STACK_MAX};
-
-use crate::vm::VM;
-
-#[macro_export]
-macro_rules! debug_println {
-    () => {
-        #[cfg(debug_assertions)]
-        $std::print!("\n")
-    };
-    ($($arg:tt)*) => {
-        #[cfg(debug_assertions)]
-        println!($($arg)*);
-    };
-}
+use std::{
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use loxide::{
+    alloc_trace::{self, AllocTrace, TraceReport},
+    allocations::Allocations,
+    bench,
+    callgraph::CallGraph,
+    color::ColorChoice,
+    coverage::Coverage,
+    errors::{ErrorCode, ErrorFormat},
+    events::CallTreeEvents,
+    interpret,
+    native_fn::{self, Capabilities, INSPECT_DEPTH},
+    options::VmOptions,
+    passes::CallTracing,
+    profile::{self, LineProfile, OpcodeHistogram},
+    snapshot,
+    value::Value,
+    vm::{InterpretError, InterpretResult, U8_COUNT, VM},
+};
+
+/// A prelude to run when there's no `--prelude`
+const PRELUDE_VAR: &str = "LOXIDE_PRELUDE";
+/// Where to keep the REPL's history instead of `~/.loxide_history`
+const HISTORY_VAR: &str = "LOXIDE_HISTORY";
 
 fn main() {
     // run_file("./test.lox")
 
-    let mut args = std::env::args();
-    let _ = args.next();
+    let mut options = VmOptions::new();
+    let mut capabilities = Capabilities::default();
+    let mut path = None;
+    let mut script_args = vec![];
+    let mut coverage_report = None;
+    let mut track_allocations = false;
+    let mut profile_lines = false;
+    let mut trace_calls = false;
+    let mut prelude = None;
+    let mut opcode_histogram = None;
+    let mut load_snapshot = None;
+    let mut save_snapshot = None;
+    let mut emit_loxb = None;
+    let mut trace_allocs = None;
+    let mut files: Vec<String> = vec![];
+    let mut color = ColorChoice::Auto;
+
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("bench-compile") {
+        args.next();
+        std::process::exit(bench_compile(args));
+    }
+    if args.peek().map(String::as_str) == Some("bench") {
+        args.next();
+        std::process::exit(bench_suite(args));
+    }
+    if args.peek().map(String::as_str) == Some("profile-diff") {
+        args.next();
+        let before = args.next().unwrap_or_else(|| usage());
+        let after = args.next().unwrap_or_else(|| usage());
+        std::process::exit(profile_diff(&before, &after));
+    }
+    if args.peek().map(String::as_str) == Some("callgraph") {
+        args.next();
+        let path = args.next().unwrap_or_else(|| usage());
+        std::process::exit(callgraph(&path));
+    }
+    if args.peek().map(String::as_str) == Some("trace-report") {
+        args.next();
+        let path = args.next().unwrap_or_else(|| usage());
+        std::process::exit(trace_report(&path));
+    }
 
-    match args.len() {
-        0 => {
-            repl();
-        }
-        1 => {
-            let mut vm = VM::new();
-            run_file(&mut vm, args.next().unwrap());
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump-bytecode" => options = options.dump_bytecode(true),
+            "--trace" => options = options.trace(true),
+            "--warn-undeclared" => options = options.warn_undeclared(true),
+            "--warn-unused" => options = options.warn_unused(true),
+            "--asi" => options = options.asi(true),
+            "--strict" => options = options.strict(true),
+            "--strict-equality" => options = options.strict_equality(true),
+            "--deterministic" => options = options.deterministic(true),
+            "--verbose-errors" => options = options.verbose_errors(true),
+            "--stack-size" => options = options.stack_size(number(&mut args, U8_COUNT)),
+            "--frame-depth" => options = options.frame_depth(number(&mut args, 1)),
+            "--gc-threshold" => options = options.gc_threshold(number(&mut args, 0)),
+            "--coverage" => coverage_report = Some(CoverageReport::Annotated),
+            "--coverage=lcov" => coverage_report = Some(CoverageReport::Lcov),
+            "--allocs" => track_allocations = true,
+            "--profile-lines" => profile_lines = true,
+            "--trace-calls" => trace_calls = true,
+            "--prelude" => prelude = Some(args.next().unwrap_or_else(|| usage())),
+            "--opcode-histogram" => opcode_histogram = Some(args.next().unwrap_or_else(|| usage())),
+            "--snapshot" => load_snapshot = Some(args.next().unwrap_or_else(|| usage())),
+            "--save-snapshot" => save_snapshot = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace-allocs" => trace_allocs = Some(args.next().unwrap_or_else(|| usage())),
+            "--emit-loxb" => emit_loxb = Some(args.next().unwrap_or_else(|| usage())),
+            "run" => {
+                // Files up to `--`, then the scripts' arguments
+                files = args.by_ref().take_while(|arg| arg != "--").collect();
+                script_args = args.by_ref().collect();
+                if files.is_empty() || coverage_report.is_some() || profile_lines {
+                    usage()
+                }
+            }
+            _ if arg.starts_with("--error-format=") => {
+                let format = ErrorFormat::parse(&arg["--error-format=".len()..]);
+                options = options.error_format(format.unwrap_or_else(|| usage()))
+            }
+            _ if arg.starts_with("--color=") => {
+                color = ColorChoice::parse(&arg["--color=".len()..]).unwrap_or_else(|| usage())
+            }
+            _ if arg.starts_with("--allow=") => {
+                if !capabilities.allow(&arg["--allow=".len()..]) {
+                    usage()
+                }
+            }
+            _ if !arg.starts_with("--") => {
+                // The rest belongs to the script
+                path = Some(arg);
+                script_args = args.by_ref().collect();
+            }
+            _ => usage(),
         }
-        _ => panic!(),
     }
-}
-
-fn repl() {
-    let stdin = std::io::stdin();
-    let lines = stdin.lock().lines();
-    let mut vm = VM::new();
+    options = options.color(color.enabled());
+    if trace_calls {
+        options = options.pass(CallTracing);
+    }
 
-    for line in lines {
-        let line = line.unwrap();
-        interpret(&mut vm, &line).unwrap();
+    if let Some(out) = emit_loxb {
+        std::process::exit(write_loxb(&path.unwrap_or_else(|| usage()), &out));
     }
-}
 
-fn run_file<P: AsRef<Path>>(vm: &mut VM, path: P) {
-    let string = std::fs::read_to_string(path).unwrap();
-    interpret(vm, &string).unwrap();
-}
+    let mut vm = VM::with_options(options.capabilities(capabilities));
 
-fn interpret(vm: &mut VM, src: &str) -> InterpretResult<()> {
-    let function = {
-        let mut parser = Parser::new(src, &mut vm.mem);
-        if !parser.compile() {
-            return Err(InterpretError::CompileError);
+    // Ctrl-C stops the script, or the REPL line, that's running. Pressing it
+    // again before the VM notices quits.
+    let interrupt = vm.interrupt_handle();
+    ctrlc::set_handler(move || {
+        if interrupt.is_pending() {
+            std::process::exit(130);
         }
-        parser.compiler.function
-    };
-    vm.init(function);
-
-    vm.run()
-}
+        interrupt.interrupt();
+    })
+    .expect("Failed to set the Ctrl-C handler.");
+    if coverage_report.is_some() {
+        vm.coverage = Some(Coverage::new());
+    }
+    if track_allocations {
+        vm.allocations = Some(Allocations::new());
+    }
+    if trace_calls {
+        vm.events = Box::new(CallTreeEvents);
+    }
+    if profile_lines {
+        vm.line_profile = Some(LineProfile::new());
+    }
+    if opcode_histogram.is_some() {
+        vm.opcode_histogram = Some(OpcodeHistogram::new());
+    }
+    if let Some(trace_path) = &trace_allocs {
+        let file = std::fs::File::create(trace_path).expect("Failed to create the trace.");
+        let out = Box::new(std::io::BufWriter::new(file));
+        vm.mem.trace = Some(AllocTrace::new(out).expect("Failed to write the trace."));
+    }
 
-#[cfg(test)]
-mod test {
+    if let Some(snapshot_path) = load_snapshot {
+        let bytes = std::fs::read(snapshot_path).unwrap();
+        snapshot::read(&mut vm, &bytes).expect("Failed to load snapshot.");
+    }
 
-    use std::{cell::UnsafeCell, mem::MaybeUninit};
+    let prelude = prelude.or_else(|| std::env::var(PRELUDE_VAR).ok().filter(|p| !p.is_empty()));
+    if let Some(prelude) = prelude {
+        let status = run_prelude(&mut vm, &prelude);
+        if status != 0 {
+            std::process::exit(status);
+        }
+    }
 
-    use crate::{
-        compile::Token,
-        interpret,
-        mem::Mem,
-        table::Table,
-        value::Value,
-        vm::{InterpretError, ValueStack, STACK_MAX, VM},
+    let ran_file = path.is_some() || !files.is_empty();
+    let status = match path {
+        None if !files.is_empty() => run_files(&mut vm, &files, &script_args),
+        None => {
+            repl(&mut vm);
+            0
+        }
+        Some(path) => run_file(&mut vm, path, &script_args, coverage_report),
     };
 
-    #[test]
-    fn fib() {
-        let src = r#"
-fun fib(x) {
-    if (x <= 1) {
-        return x;
+    // The REPL has `:allocs` instead
+    if ran_file {
+        if let Some(report) = vm.allocation_report() {
+            eprint!("{report}");
+        }
     }
-    return fib(x - 1) + fib(x - 2);
-}
 
-var result = fib(2);
-"#;
+    if let (Some(path), Some(histogram)) = (opcode_histogram, &vm.opcode_histogram) {
+        let written =
+            std::fs::File::create(&path).and_then(|mut file| histogram.write_json(&mut file));
+        if let Err(err) = written {
+            eprintln!("Failed to write the opcode histogram: {err}");
+        }
+    }
+
+    // Exiting skips dropping the VM, which would flush it
+    if let Some(trace) = vm.mem.trace.take() {
+        if let Err(err) = trace.finish() {
+            eprintln!("Failed to write the allocation trace: {err}");
+        }
+    }
 
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_var_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_var_str);
-        assert_eq!(value.unwrap(), Value::Number(1.0));
+    // For `loxide bench`, or anything else comparing memory use
+    if let Some(stats_path) = std::env::var_os(bench::HEAP_STATS_VAR) {
+        if let Err(err) = bench::write_heap_stats(stats_path.as_ref(), vm.mem.peak_bytes) {
+            eprintln!("Failed to write heap stats: {err}");
+        }
     }
 
-    #[test]
-    fn superclasses() {
-        let src = r#"
-class Doughnut {
-  cook() {
-    print "Dunk in the fryer.";
-    this.finish("sprinkles");
-  }
+    if let Some(snapshot_path) = save_snapshot {
+        let mut file = std::fs::File::create(snapshot_path).unwrap();
+        snapshot::write(&vm, &mut file).expect("Failed to write snapshot.");
+    }
 
-  finish(ingredient) {
-    return "Finish with " + ingredient;
-  }
+    std::process::exit(status);
 }
 
-class Cruller < Doughnut {
-  finish(ingredient) {
-    // No sprinkles, always icing.
-    return super.finish("icing");
-  }
+fn usage() -> ! {
+    eprintln!(
+        "Usage: loxide [--dump-bytecode] [--trace] [--warn-undeclared] [--warn-unused] [--asi] [--strict] [--strict-equality] [--deterministic] [--verbose-errors] [--color=auto|always|never] [--error-format=human|json] [--stack-size values] [--frame-depth frames] [--gc-threshold bytes] [--coverage[=lcov]] [--allocs] [--profile-lines] [--trace-calls] [--prelude file] [--opcode-histogram file] [--snapshot file] [--save-snapshot file] [--emit-loxb file] [--trace-allocs file] [--allow=env|clock|process|net] [path [args...] | run path... [-- args...]]
+       loxide bench-compile [--iterations n] path
+       loxide bench [--compare binary] [--runs n] [--warmup n] [--csv] [dir]
+       loxide trace-report file
+       loxide profile-diff before.json after.json
+       loxide callgraph path"
+    );
+    std::process::exit(64);
 }
 
-var cruller = Cruller();
-var result = cruller.finish("noice");
-"#;
+/// The next argument, which has to be a number no less than `at_least`
+fn number(args: &mut impl Iterator<Item = String>, at_least: usize) -> usize {
+    args.next()
+        .and_then(|arg| arg.parse().ok())
+        .filter(|&n| n >= at_least)
+        .unwrap_or_else(|| usage())
+}
 
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_var_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_var_str);
-        assert_eq!(value.unwrap().as_str().unwrap(), "Finish with icing");
+/// `loxide bench`, running every script in the benchmarks folder with this
+/// loxide and any implementation to compare it with, returning the exit
+/// status
+fn bench_suite(mut args: impl Iterator<Item = String>) -> i32 {
+    let mut implementations = vec![bench::Implementation {
+        name: "loxide".to_string(),
+        command: std::env::current_exe().unwrap(),
+    }];
+    let mut runs = 5;
+    let mut warmup = 1;
+    let mut csv = false;
+    let mut dir = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--compare" => {
+                let command = args.next().unwrap_or_else(|| usage());
+                let name = Path::new(&command)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| command.clone());
+                implementations.push(bench::Implementation {
+                    name,
+                    command: command.into(),
+                });
+            }
+            "--runs" => runs = number(&mut args, 1),
+            "--warmup" => warmup = number(&mut args, 0),
+            "--csv" => csv = true,
+            _ if !arg.starts_with("--") && dir.is_none() => dir = Some(arg),
+            _ => usage(),
+        }
     }
 
-    #[test]
-    fn invoking_fields() {
-        let src = r#"
-        class Oops {
-            init() {
-                fun f() {
-                    return 420;
-                }
+    let dir = dir.unwrap_or_else(|| "benchmarks".to_string());
+    let mut scripts: Vec<_> = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "lox"))
+            .collect(),
+        Err(err) => {
+            eprintln!("{dir}: {err}");
+            return 66;
+        }
+    };
+    scripts.sort();
+
+    let comparison = bench::compare(
+        &implementations,
+        &scripts,
+        warmup.try_into().unwrap_or(u32::MAX),
+        runs.try_into().unwrap_or(u32::MAX),
+    );
+    let mut out = std::io::stdout();
+    match csv {
+        true => comparison.write_csv(&mut out),
+        false => comparison.write_markdown(&mut out),
+    }
+    .expect("Failed to write the results.");
 
-                this.field = f;
+    for (name, measurements) in comparison.benchmarks.iter() {
+        for (implementation, measurement) in comparison.implementations.iter().zip(measurements) {
+            if let Some(err) = &measurement.error {
+                eprintln!("{name} ({implementation}): {err}");
             }
         }
+    }
+    0
+}
+
+/// Compile the script at `path` to a `.loxb` file at `out`, returning the
+/// exit status
+fn write_loxb(path: &str, out: &str) -> i32 {
+    let src = std::fs::read_to_string(path).unwrap();
+    match loxide::compile_loxb(&src) {
+        Ok(bytes) => {
+            std::fs::write(out, bytes).unwrap();
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            65
+        }
+    }
+}
 
-        var oops = Oops();
-        var result = oops.field();
-        "#;
+/// `loxide profile-diff`, comparing two files from `--opcode-histogram`
+fn profile_diff(before: &str, after: &str) -> i32 {
+    let read = |path: &str| {
+        std::fs::read_to_string(path)
+            .and_then(|json| OpcodeHistogram::read_json(&json))
+            .map_err(|err| eprintln!("{path}: {err}"))
+    };
+    let (Ok(before), Ok(after)) = (read(before), read(after)) else {
+        return 65;
+    };
+    profile::write_histogram_diff(&before, &after, &mut std::io::stdout())
+        .expect("Failed to write the diff.");
+    0
+}
 
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
+/// `loxide callgraph`, writing a dot graph of which functions call which,
+/// returning the exit status
+fn callgraph(path: &str) -> i32 {
+    let src = std::fs::read_to_string(path).unwrap();
+    match CallGraph::of_source(&src) {
+        Ok(graph) => {
+            graph
+                .write_dot(&mut std::io::stdout())
+                .expect("Failed to write the call graph.");
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            65
+        }
     }
+}
 
-    #[test]
-    fn misusing_this() {
-        let src = r#"
-        fun notMethod() {
-            print this;
+/// `loxide trace-report`, summing up a trace from `--trace-allocs`, returning
+/// the exit status
+fn trace_report(path: &str) -> i32 {
+    let events = std::fs::read(path).and_then(|bytes| alloc_trace::read_trace(&bytes));
+    match events {
+        Ok(events) => {
+            TraceReport::new(&events)
+                .write(&mut std::io::stdout())
+                .expect("Failed to write the report.");
+            0
+        }
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            65
         }
-        "#;
+    }
+}
 
-        let mut vm = VM::new();
-        let err = interpret(&mut vm, src);
-        if let Err(InterpretError::CompileError) = err {
-        } else {
-            panic!()
+/// `loxide bench-compile`, returning the exit status
+fn bench_compile(mut args: impl Iterator<Item = String>) -> i32 {
+    let mut iterations = 1000;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => iterations = number(&mut args, 1),
+            _ if !arg.starts_with("--") && path.is_none() => path = Some(arg),
+            _ => usage(),
         }
     }
 
-    #[test]
-    fn nested_this() {
-        let src = r#"
-        class Nested {
-            method() {
-              fun function() {
-                return this.lol;
-              }
-              return function();
-            }
+    let path = path.unwrap_or_else(|| usage());
+    let src = std::fs::read_to_string(&path).unwrap();
+    match bench::bench_compile(&src, iterations.try_into().unwrap_or(u32::MAX)) {
+        Ok(timings) => {
+            println!("{path}");
+            timings
+                .write_report(&mut std::io::stdout())
+                .expect("Failed to write the report.");
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            65
         }
-          
-        var nested = Nested();
-        nested.lol = 420;
-        var result = nested.method();"#;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CoverageReport {
+    /// `<path>.cov`, the source annotated with per-line counts
+    Annotated,
+    /// `lcov.info` in the working directory
+    Lcov,
+}
 
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
+fn write_coverage(vm: &VM, report: CoverageReport, path: &str, src: &str) -> std::io::Result<()> {
+    let coverage = match vm.coverage.as_ref() {
+        Some(coverage) => coverage,
+        None => return Ok(()),
+    };
+
+    match report {
+        CoverageReport::Annotated => {
+            let mut file = std::fs::File::create(format!("{path}.cov"))?;
+            coverage.write_annotated(src, &mut file)
+        }
+        CoverageReport::Lcov => {
+            let mut file = std::fs::File::create("lcov.info")?;
+            coverage.write_lcov(path, &mut file)
+        }
     }
+}
+
+fn repl(vm: &mut VM) {
+    let stdin = std::io::stdin();
+    let lines = stdin.lock().lines();
+    let mut history = History::open();
+    // The inputs that ran, for `:save`
+    let mut session: Vec<String> = vec![];
 
-    #[test]
-    fn this() {
-        let src = r#"
-        class Nested {
-            method() {
-              return this.lol;
+    for line in lines {
+        let line = line.unwrap();
+        history.add(&line);
+
+        if line.trim() == ":history" {
+            for (i, entry) in history.lines.iter().enumerate() {
+                println!("{:5}  {entry}", i + 1);
             }
-          }
-          
-        var nested = Nested();
-        nested.lol = 420;
-        var result = nested.method();"#;
-
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn methods() {
-        let src = r#"
-        class Scone {
-            topping(first, second) {
-              return "scone with " + first + " and " + second;
+            continue;
+        }
+
+        if let Some((":save", path)) = line.trim().split_once(' ') {
+            let mut src = session.join("\n");
+            src.push('\n');
+            match std::fs::write(path.trim(), src) {
+                Ok(()) => println!("Saved {} inputs to {}.", session.len(), path.trim()),
+                Err(err) => eprintln!("Failed to save the session: {err}"),
             }
-          }
-          
-          var scone = Scone();
-          var result = scone.topping("berries", "cream");"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let result_str = vm.get_string("result").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(result_str);
-
-        let expected_str = vm.get_string("scone with berries and cream");
-        println!("VAL: {:?}", value);
-        assert_eq!(value, Some(Value::Obj(expected_str.cast())));
-    }
-
-    #[test]
-    fn instance_get_set() {
-        let src = r#"
-class Pair {}
-
-var pair = Pair();
-pair.first = 1;
-pair.second = 2;
-var result = pair.first + pair.second;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let result_str = vm.get_string("result").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(result_str);
-
-        assert_eq!(value, Some(Value::Number(3.0)));
-    }
-
-    #[test]
-    fn upvalue_closed() {
-        let src = r#"
-    fun makeClosure() {
-      var a = 1;
-      fun f() {
-        a = a + 1;
-        return a;
-      }
-      return f;
-    }
-
-    var closure = makeClosure();
-    var first = closure();
-    var anotherClosure = makeClosure();
-    var second = anotherClosure();
-    var third = closure();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let first_str = vm.get_string("first").as_non_null_ptr();
-        let second_str = vm.get_string("second").as_non_null_ptr();
-        let third_str = vm.get_string("third").as_non_null_ptr();
-
-        let value1 = vm.mem.globals.get(first_str);
-        let value2 = vm.mem.globals.get(second_str);
-        let value3 = vm.mem.globals.get(third_str);
-
-        assert_eq!(value1, Some(Value::Number(2.0)));
-        assert_eq!(value2, Some(Value::Number(2.0)));
-        assert_eq!(value3, Some(Value::Number(3.0)));
-    }
-
-    #[test]
-    fn set_immediate_upvalue() {
-        let src = r#"
-    fun outer() {
-      var x = 420;
-      fun inner() {
-        x = x + 1;
-        return x;
-      }
-      return inner();
-    }
-    var value = outer();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let value_str = vm.get_string("value").as_non_null_ptr();
-
-        let value = vm.mem.globals.get(value_str);
-
-        assert_eq!(value, Some(Value::Number(421.0)));
-    }
-
-    #[test]
-    fn immediate_upvalue() {
-        let src = r#"
-var result = "nothing";
-fun outer() {
-  var x = 420;
-  fun inner() {
-    result = x;
-  }
-  inner();
-}
-outer();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let result_str = vm.get_string("result").as_non_null_ptr();
-        let value = vm.mem.globals.get(result_str);
-
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn call_native_fn() {
-        let src = r#"
-        var num = __dummy();"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let num_str = vm.get_string("num").as_non_null_ptr();
-        let value = vm.mem.globals.get(num_str);
-        assert_eq!(value, Some(Value::Number(420.0)));
-    }
-
-    #[test]
-    fn call_fn() {
-        let src = r#"
-            fun add420(num) {
-              return num + 420;
+            continue;
+        }
+        if let Some((":load", path)) = line.trim().split_once(' ') {
+            match std::fs::read_to_string(path.trim()) {
+                Ok(src) => {
+                    if loxide::interpret_file(vm, path.trim(), src.as_bytes()).is_ok() {
+                        session.push(src.trim_end().to_string());
+                    }
+                }
+                Err(err) => eprintln!("Failed to load {}: {err}", path.trim()),
             }
-
-            fun add69(num) {
-              return num + 69;
+            continue;
+        }
+        if line.trim() == ":allocs" {
+            match vm.allocation_report() {
+                Some(report) => print!("{report}"),
+                None => println!("Allocations aren't being tracked, start with --allocs."),
             }
-
-            var num = add420(1);
-            num = add69(num);
-            num = add420(num);"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-        let num_str = vm.get_string("num").as_non_null_ptr();
-        let value = vm.mem.globals.get(num_str);
-        assert_eq!(value, Some(Value::Number(910.0)));
-    }
-
-    #[test]
-    fn print_fn() {
-        let src = r#"
-            fun bigNoob() {
-              print "OH YEAH";
+            continue;
+        }
+        if let Some((command @ (":type" | ":inspect"), name)) = line.trim().split_once(' ') {
+            let name = name.trim();
+            match (vm.get_global(name), command) {
+                (None, _) => println!("{}", ErrorCode::UndefinedVariable.message(&[&name])),
+                (Some(value), ":type") => println!("{}", native_fn::describe_type(value)),
+                (Some(value), _) => println!("{}", native_fn::inspect(value, INSPECT_DEPTH)),
             }
+            continue;
+        }
 
-            print bigNoob;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-    }
-
-    #[test]
-    fn if_stmt() {
-        let src = r#"
-            var noob = 420;
-            if (420 > 69) { noob = "NICE"; } else { noob = "NOT NICE"; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
-    }
-
-    #[test]
-    fn if_else_stmt() {
-        let src = r#"
-            var noob = 420;
-            if (69 > 420) { noob = "wtf"; } else { noob = "NICE"; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
-    }
-
-    #[test]
-    fn while_loop() {
-        let src = r#"
-            var noob = 0;
-            while (noob < 10) {
-              noob = noob + 1;
-            }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
+        // A line that failed has said why, and the REPL carries on
+        if interpret(vm, &line).is_ok() {
+            session.push(line);
+        }
+    }
+}
+
+/// The lines entered in the REPL, in earlier sessions too, which `:history`
+/// lists
+struct History {
+    lines: Vec<String>,
+    /// Where each new line is appended, None if it can't be saved
+    file: Option<LineWriter<std::fs::File>>,
+}
 
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top, Some(Value::Number(10.0)));
+impl History {
+    /// Read the history kept in `LOXIDE_HISTORY`, or `.loxide_history` in the
+    /// home directory, and carry on adding to it
+    fn open() -> Self {
+        let path = match std::env::var_os(HISTORY_VAR) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => std::env::var_os("HOME").map(|home| Path::new(&home).join(".loxide_history")),
+        };
+        let lines = match path.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(saved)) => saved.lines().map(str::to_owned).collect(),
+            _ => vec![],
+        };
+        let file = path.and_then(|path| {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path);
+            file.ok().map(LineWriter::new)
+        });
+        Self { lines, file }
     }
 
-    #[test]
-    fn for_loop() {
-        let src = r#"
-            var noob = 420;
-            for (var x = 0; x < 10; x = x + 1) {
-              noob = x;
+    fn add(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        self.lines.push(line.to_owned());
+        if let Some(file) = self.file.as_mut() {
+            if writeln!(file, "{line}").is_err() {
+                eprintln!("Failed to write the REPL history, no longer saving it.");
+                self.file = None;
             }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        // let noob = vm.get_string("global");
-        // let top = vm.mem.globals.get(noob);
-        // assert_eq!(top.unwrap().as_str(), Some("NICE"));
-    }
-
-    #[test]
-    fn locals() {
-        let src = r#"
-            var global = 420;
-            { var x = "HELLO"; x = "NICE"; global = x; }
-    "#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("global").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("NICE"));
-    }
-
-    #[test]
-    fn string() {
-        let src = r#"var noob = "hello" + " sir" + " sir";"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-
-        let noob = vm.get_string("noob").as_non_null_ptr();
-        let top = vm.mem.globals.get(noob);
-        assert_eq!(top.unwrap().as_str(), Some("hello sir sir"));
-    }
-
-    #[test]
-    fn print() {
-        let src = r#"print 1 + 2;"#;
-        let mut vm = VM::new();
-        interpret(&mut vm, src).unwrap();
-    }
-
-    #[test]
-    fn table() {
-        let mut mem = Mem::new();
-        let mut table = Table::new();
-
-        let key = mem.copy_string("bagel").as_non_null_ptr();
-        assert_eq!(table.set(key, Value::Number(420.0)), true);
-        assert_eq!(table.set(key, Value::Number(69.0)), false);
-        assert_eq!(table.get(key), Some(Value::Number(69.0)));
-        assert_eq!(table.delete(key), true);
-        assert_eq!(table.delete(key), false);
-
-        Table::free(&mut table);
-    }
-
-    #[test]
-    fn ohshit() {
-        // let bytes = [0, 1, 2, 3];
-
-        println!("NOOB: {:?}", std::mem::size_of::<Token>());
-
-        let values = [0, 1, 2, 3, 4, 5];
-        println!(
-            "NICE: {:?}",
-            values.iter().take(3).rev().collect::<Vec<_>>()
-        );
-
-        // 0
-        // 1
-        // 2 ---
-        // 3 ---
-        // 4
-        // 5
-        //
-        // 6
-        // -2 to adjust for the 2 bytes for the jump offset
-        let mut chunk = [0, 1, 2, 3, 4, 5];
-        let offset = 2;
-        let jump = chunk.len() as u32 - offset - 2;
-
-        chunk[offset as usize] = (jump >> 8) as u8;
-        chunk[offset as usize + 1] = jump as u8;
-
-        let val = ((chunk[offset as usize] as u16) << 8) | (chunk[offset as usize + 1] as u16);
-
-        println!("{jump} NOOB: {chunk:?} JUMP: {val} {}", 2u16);
-    }
-
-    // #[test]
-    // fn miri_test() {
-    //     let mut obj = Box::into_raw(Box::new(69));
-    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
-    //     let foo = unsafe { *obj };
-    //     *obj2 = 9999;
-    // }
-    // #[test]
-    // fn miri_test2() {
-    //     let mut obj = Box::into_raw(Box::new(69));
-    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
-    //     unsafe {
-    //         *obj = 420;
-    //     };
-    //     *obj2 = 9999;
-    // }
+        }
+    }
 }
 
-fn _f(_a: i32, _b: i32) -> i32 {
-    420
+/// Returns the exit status: what the script returned, or the sysexits code
+/// clox uses for the error it failed with
+fn run_file<P: AsRef<Path>>(
+    vm: &mut VM,
+    path: P,
+    args: &[String],
+    coverage_report: Option<CoverageReport>,
+) -> i32 {
+    vm.set_args(args);
+    if path.as_ref().extension().map_or(false, |ext| ext == "loxb") {
+        let bytes = std::fs::read(&path).unwrap();
+        if let Err(err) = loxide::load_loxb(vm, &bytes) {
+            eprintln!("{err}");
+            return 65;
+        }
+        return exit_status(loxide::run(vm));
+    }
+
+    // The coverage report and the line profile show the source, otherwise
+    // it's compiled as it's read
+    if coverage_report.is_none() && vm.line_profile.is_none() {
+        let file = std::fs::File::open(&path).unwrap();
+        return exit_status(loxide::interpret_reader(vm, BufReader::new(file)));
+    }
+
+    let string = std::fs::read_to_string(&path).unwrap();
+    let result = interpret(vm, &string);
+    if let Some(report) = coverage_report {
+        let path = path.as_ref().to_string_lossy();
+        write_coverage(vm, report, &path, &string).expect("Failed to write coverage report.");
+    }
+    if let Some(profile) = vm.line_profile.as_ref() {
+        profile
+            .write_annotated(&string, &mut std::io::stderr())
+            .expect("Failed to write the line profile.");
+    }
+
+    exit_status(result)
+}
+
+/// Run the file with helpers for the script or REPL session after it,
+/// returning 0 if it ran, whatever it returned, or the exit status it
+/// failed with
+fn run_prelude(vm: &mut VM, path: &str) -> i32 {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Failed to open the prelude {path}: {err}");
+            return 66;
+        }
+    };
+    match loxide::interpret_file(vm, path, BufReader::new(file)) {
+        Ok(_) => 0,
+        Err(err) => exit_status(Err(err)),
+    }
 }
 
-fn _noob() {
-    let _noob = _f(_f(1, 2), _f(3, 4));
+/// Run each of `paths` in turn in the same VM, stopping at the first that
+/// fails. The last one's result is the exit status.
+fn run_files(vm: &mut VM, paths: &[String], args: &[String]) -> i32 {
+    vm.set_args(args);
+    let mut result = Ok(Value::Nil);
+    for path in paths {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Failed to open {path}: {err}");
+                return 66;
+            }
+        };
+        result = loxide::interpret_file(vm, path, BufReader::new(file));
+        if result.is_err() {
+            break;
+        }
+    }
+    exit_status(result)
+}
+
+fn exit_status(result: InterpretResult<Value>) -> i32 {
+    match result {
+        Ok(Value::Nil) => 0,
+        Ok(Value::Number(status)) if status.fract() == 0.0 && (0.0..=255.0).contains(&status) => {
+            status as i32
+        }
+        Ok(value) => {
+            eprintln!(
+                "Script returned {value:?}, expected a whole number from 0 to 255 to exit with."
+            );
+            70
+        }
+        Err(InterpretError::CompileError) => 65,
+        Err(InterpretError::RuntimeError | InterpretError::Paused | InterpretError::Yielded) => 70,
+        Err(InterpretError::Interrupted) => 130,
+    }
 }
@@ -0,0 +1,105 @@
+//! Matrix math on float arrays, for the `math.matrix` natives. A matrix is
+//! a float array holding its rows one after another, and its shape is
+//! passed alongside it.
+
+use crate::native_fn::MAX_FLOATS_LEN;
+
+/// Pivots smaller than this are treated as zero when inverting
+const SINGULAR_EPSILON: f64 = 1e-12;
+
+/// The product of `a`, which has `inner` columns, and `b`, which has `inner`
+/// rows
+pub fn multiply(a: &[f64], b: &[f64], inner: usize) -> Result<Vec<f64>, String> {
+    let rows = split(a.len(), inner, "columns")?;
+    let cols = split(b.len(), inner, "rows")?;
+    if rows.saturating_mul(cols) > MAX_FLOATS_LEN {
+        return Err(format!(
+            "A {rows}x{cols} product is bigger than a float array can be."
+        ));
+    }
+
+    let mut product = vec![0.0; rows * cols];
+    for row in 0..rows {
+        for k in 0..inner {
+            let a = a[row * inner + k];
+            for col in 0..cols {
+                product[row * cols + col] += a * b[k * cols + col];
+            }
+        }
+    }
+
+    Ok(product)
+}
+
+/// `a`, which has `cols` columns, with its rows and columns swapped
+pub fn transpose(a: &[f64], cols: usize) -> Result<Vec<f64>, String> {
+    let rows = split(a.len(), cols, "columns")?;
+
+    let mut transposed = vec![0.0; a.len()];
+    for row in 0..rows {
+        for col in 0..cols {
+            transposed[col * rows + row] = a[row * cols + col];
+        }
+    }
+
+    Ok(transposed)
+}
+
+/// The inverse of the square matrix `a`, by Gauss-Jordan elimination. It
+/// takes cubic time, so it's meant for small matrices.
+pub fn invert(a: &[f64]) -> Result<Vec<f64>, String> {
+    let n = (a.len() as f64).sqrt() as usize;
+    if n == 0 || n * n != a.len() {
+        return Err(format!(
+            "Can only invert a square matrix, not one with {} items.",
+            a.len()
+        ));
+    }
+
+    let mut a = a.to_vec();
+    let mut inverse = vec![0.0; n * n];
+    for i in 0..n {
+        inverse[i * n + i] = 1.0;
+    }
+
+    for col in 0..n {
+        // The row with the biggest pivot keeps the error down
+        let pivot = (col..n)
+            .max_by(|&x, &y| a[x * n + col].abs().total_cmp(&a[y * n + col].abs()))
+            .unwrap();
+        if a[pivot * n + col].abs() < SINGULAR_EPSILON {
+            return Err("Matrix is singular.".into());
+        }
+        for k in 0..n {
+            a.swap(col * n + k, pivot * n + k);
+            inverse.swap(col * n + k, pivot * n + k);
+        }
+
+        let scale = a[col * n + col];
+        for k in 0..n {
+            a[col * n + k] /= scale;
+            inverse[col * n + k] /= scale;
+        }
+
+        for row in (0..n).filter(|&row| row != col) {
+            let factor = a[row * n + col];
+            for k in 0..n {
+                a[row * n + k] -= factor * a[col * n + k];
+                inverse[row * n + k] -= factor * inverse[col * n + k];
+            }
+        }
+    }
+
+    Ok(inverse)
+}
+
+/// How many of the other dimension a matrix with `len` items and `by` of
+/// one dimension has
+fn split(len: usize, by: usize, dimension: &str) -> Result<usize, String> {
+    if by == 0 || len % by != 0 {
+        return Err(format!(
+            "A matrix with {len} items can't have {by} {dimension}."
+        ));
+    }
+    Ok(len / by)
+}
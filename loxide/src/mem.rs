@@ -1,15 +1,20 @@
 use std::{
     alloc::{self, handle_alloc_error, Layout},
+    collections::HashMap,
     ops::{Deref, DerefMut},
     ptr::{self, NonNull},
 };
 
 use crate::{
-    obj::{Obj, ObjList, ObjPunnable, ObjString},
+    alloc_trace::AllocTrace,
+    obj::{Obj, ObjPunnable, ObjString, ObjWeakRef, Objects},
     table::{ObjHash, Table},
     value::Value,
 };
 
+#[cfg(feature = "leak_check")]
+use crate::obj::ObjKind;
+
 pub type Greystack = Vec<NonNull<Obj>>;
 
 // Borrowed from https://github.com/ceronman/loxido/blob/a605c17e4d35bc75022e65387c200201704ec37c/src/gc.rs#L286
@@ -43,12 +48,71 @@ pub type Greystack = Vec<NonNull<Obj>>;
 // #[global_allocator]
 // pub static GLOBAL: GlobalAllocator = GlobalAllocator { bytes_allocated: 0 };
 
+/// Every number literal compiled for this VM, each stored once. Chunks refer
+/// to them by index, so a program with thousands of functions using the same
+/// few numbers only keeps one copy of each.
+///
+/// Numbers are never removed, they're small and programs only have so many
+/// literals.
+#[derive(Default)]
+pub struct NumberPool {
+    values: Vec<f64>,
+    /// By bit pattern, so `0.0` and `-0.0` stay apart and NaN can be found
+    indices: HashMap<u64, u32>,
+}
+
+impl NumberPool {
+    /// The index of `number`, adding it if it isn't in the pool yet
+    pub fn intern(&mut self, number: f64) -> u32 {
+        *self.indices.entry(number.to_bits()).or_insert_with(|| {
+            self.values.push(number);
+            self.values.len() as u32 - 1
+        })
+    }
+
+    #[inline]
+    pub fn get(&self, index: u32) -> f64 {
+        self.values[index as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Everything `Mem` has allocated and not freed yet, so dropping it can
+/// check nothing leaked and nothing was freed twice
+#[cfg(feature = "leak_check")]
+#[derive(Default)]
+pub struct Ledger {
+    objects: HashMap<usize, ObjKind>,
+    /// String characters by address, with their length
+    chars: HashMap<usize, usize>,
+}
+
 pub struct Mem {
-    pub obj_list: ObjList,
+    pub obj_list: Objects,
     pub globals: Table,
     pub interned_strings: Table,
+    /// The weak refs that still have a target, see [`Mem::clear_weak_refs`]
+    pub weak_refs: Vec<Gc<ObjWeakRef>>,
+    pub numbers: NumberPool,
     pub next_gc: usize,
+    /// Every object allocated so far, it never goes down. Collections are
+    /// paced by it.
     pub bytes_allocated: usize,
+    /// The objects allocated and not freed yet
+    pub live_bytes: usize,
+    /// The most `live_bytes` has been
+    pub peak_bytes: usize,
+    /// Where every allocation and free is written, if anywhere
+    pub trace: Option<AllocTrace>,
+    #[cfg(feature = "leak_check")]
+    pub ledger: Ledger,
 }
 
 impl Mem {
@@ -57,8 +121,15 @@ impl Mem {
             obj_list: Default::default(),
             globals: Table::new(),
             interned_strings: Table::new(),
+            weak_refs: vec![],
+            numbers: NumberPool::default(),
             next_gc: 1024 * 1024,
             bytes_allocated: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            trace: None,
+            #[cfg(feature = "leak_check")]
+            ledger: Ledger::default(),
         }
     }
 
@@ -92,6 +163,20 @@ impl Mem {
         self.obj_list.push_front(val.cast());
 
         self.bytes_allocated += std::mem::size_of::<T>();
+        self.live_bytes += std::mem::size_of::<T>();
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        if let Some(trace) = self.trace.as_mut() {
+            trace.alloc(
+                val.as_ptr() as usize,
+                unsafe { (*val.as_ptr()).kind() },
+                std::mem::size_of::<T>(),
+            );
+        }
+
+        #[cfg(feature = "leak_check")]
+        self.ledger
+            .objects
+            .insert(val.as_ptr() as usize, unsafe { (*val.as_ptr()).kind() });
 
         #[cfg(feature = "debug_gc")]
         println!(
@@ -109,6 +194,24 @@ impl Mem {
         self.interned_strings.set(obj_string, Value::Nil);
     }
 
+    /// After marking, take the target from every weak ref whose target
+    /// wasn't marked, and forget the weak refs that weren't marked either
+    /// since they're about to be freed
+    pub fn clear_weak_refs(&mut self) {
+        self.weak_refs.retain_mut(|weak| {
+            if !weak.obj.is_marked {
+                return false;
+            }
+            match weak.target {
+                Some(target) if target.is_marked => true,
+                _ => {
+                    weak.target = None;
+                    false
+                }
+            }
+        });
+    }
+
     pub fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
         let hash = ObjHash::hash_string(string);
         match self.interned_strings.find_string(string, hash) {
@@ -124,30 +227,128 @@ impl Mem {
             return self.alloc_obj_string(empty_string);
         }
 
-        let layout = Layout::for_value(string.as_bytes());
-        let chars = unsafe { alloc::alloc(layout) };
+        let chars = self.alloc_chars(string.len());
         unsafe {
-            ptr::copy_nonoverlapping(string.as_ptr(), chars, string.len());
+            ptr::copy_nonoverlapping(string.as_ptr(), chars.as_ptr(), string.len());
         }
-        let chars = match NonNull::new(chars) {
+
+        let obj_str = ObjString::new(chars, string.len() as u32, hash);
+        self.alloc_obj_string(obj_str)
+    }
+
+    /// Room for a string's `len` characters, which the string frees. `len`
+    /// can't be zero.
+    pub fn alloc_chars(&mut self, len: usize) -> NonNull<u8> {
+        let layout = Layout::array::<u8>(len).unwrap();
+        let chars = match NonNull::new(unsafe { alloc::alloc(layout) }) {
             Some(ptr) => ptr,
             None => handle_alloc_error(layout),
         };
 
-        let obj_str = ObjString::new(chars, string.len() as u32, hash);
-        self.alloc_obj_string(obj_str)
+        #[cfg(feature = "leak_check")]
+        self.ledger.chars.insert(chars.as_ptr() as usize, len);
+
+        chars
+    }
+
+    /// Free characters from `alloc_chars` that never made it into a string
+    pub fn free_chars(&mut self, chars: NonNull<u8>, len: usize) {
+        #[cfg(feature = "leak_check")]
+        assert_eq!(
+            self.ledger.chars.remove(&(chars.as_ptr() as usize)),
+            Some(len),
+            "Freed string characters at {chars:p} that weren't allocated"
+        );
+
+        unsafe { alloc::dealloc(chars.as_ptr(), Layout::array::<u8>(len).unwrap()) }
+    }
+
+    /// Free an object that's been taken out of `obj_list`
+    pub fn free_obj(&mut self, obj: Gc<Obj>) {
+        #[cfg(feature = "leak_check")]
+        {
+            // Check before looking at the object, it might not be there anymore
+            let addr = obj.as_ptr() as usize;
+            assert!(
+                self.ledger.objects.remove(&addr).is_some(),
+                "Freed the object at {addr:#x} twice"
+            );
+            if obj.as_ref().kind == ObjKind::Str {
+                let string = unsafe { &*obj.cast::<ObjString>().as_ptr() };
+                if string.len != 0 {
+                    self.ledger.chars.remove(&(string.chars.as_ptr() as usize));
+                }
+            }
+        }
+
+        let kind = obj.as_ref().kind;
+        self.live_bytes -= kind.size();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.free(obj.as_ptr() as usize, kind, kind.size());
+        }
+        Obj::free(obj.as_non_null_ptr())
+    }
+
+    /// What's been allocated and isn't owned by anything in `obj_list`, by
+    /// kind, or `None` if there's nothing
+    #[cfg(feature = "leak_check")]
+    pub fn leak_report(&self) -> Option<String> {
+        let mut objects = self.ledger.objects.clone();
+        let mut chars = self.ledger.chars.clone();
+        for obj in &self.obj_list {
+            objects.remove(&(obj.as_ptr() as usize));
+            if obj.as_ref().kind == ObjKind::Str {
+                let string = unsafe { &*obj.cast::<ObjString>().as_ptr() };
+                chars.remove(&(string.chars.as_ptr() as usize));
+            }
+        }
+        if objects.is_empty() && chars.is_empty() {
+            return None;
+        }
+
+        let mut kinds: Vec<(ObjKind, u64)> = vec![];
+        for kind in objects.into_values() {
+            match kinds.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, count)) => *count += 1,
+                None => kinds.push((kind, 1)),
+            }
+        }
+        kinds.sort_by_key(|&(kind, count)| (std::cmp::Reverse(count), kind as u8));
+        let kinds: Vec<_> = kinds
+            .iter()
+            .map(|(kind, count)| format!("{kind:?} {count}"))
+            .collect();
+
+        Some(format!(
+            "leaked objects: [{}], leaked string characters: {} ({} bytes)",
+            kinds.join(", "),
+            chars.len(),
+            chars.values().sum::<usize>()
+        ))
     }
 }
 
 impl Drop for Mem {
     fn drop(&mut self) {
-        // free obj list
-        for obj in self.obj_list.iter_mut() {
-            Obj::free(obj.as_non_null_ptr())
+        // What's still live at the end isn't freed as far as the trace goes
+        if let Some(trace) = self.trace.take() {
+            let _ = trace.finish();
+        }
+
+        while let Some(obj) = self.obj_list.pop_front() {
+            self.free_obj(obj)
         }
 
         Table::free(&mut self.interned_strings);
         Table::free(&mut self.globals);
+
+        #[cfg(feature = "leak_check")]
+        if let Some(report) = self.leak_report() {
+            // Don't turn a failing test into an abort
+            if !std::thread::panicking() {
+                panic!("Mem dropped with {report}");
+            }
+        }
     }
 }
 
@@ -191,6 +392,14 @@ impl<T> Clone for Gc<T> {
     }
 }
 
+/// Identity, like `==` on objects in Lox
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+impl<T> Eq for Gc<T> {}
+
 impl AsRef<str> for Gc<ObjString> {
     fn as_ref(&self) -> &str {
         unsafe { self.inner.as_ref().as_str() }
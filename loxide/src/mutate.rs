@@ -0,0 +1,125 @@
+use std::fmt::Display;
+
+use crate::{
+    chunk::{Instruction, Opcode},
+    mem::{Gc, Mem},
+    obj::ObjFunction,
+};
+
+/// Opcodes that can stand in for each other without changing how many bytes
+/// the instruction takes or what it leaves on the stack, so a swapped program
+/// still runs (just wrongly)
+const SWAPS: &[(Opcode, Opcode)] = &[
+    (Opcode::Add, Opcode::Subtract),
+    (Opcode::Subtract, Opcode::Add),
+    (Opcode::Multiply, Opcode::Divide),
+    (Opcode::Divide, Opcode::Multiply),
+    (Opcode::Greater, Opcode::Less),
+    (Opcode::Less, Opcode::Greater),
+    (Opcode::True, Opcode::False),
+    (Opcode::False, Opcode::True),
+    (Opcode::JumpIfFalse, Opcode::Jump),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MutationKind {
+    /// Replace the opcode at `offset` with `to`
+    SwapOp {
+        offset: usize,
+        from: Opcode,
+        to: Opcode,
+    },
+    /// Add one to the number at `index` in the chunk's numbers, which changes
+    /// every use of it in that chunk
+    OffByOne { index: usize },
+}
+
+/// A single deliberate change to compiled bytecode, used to check that the
+/// conformance suite notices when the compiler or VM gets something wrong
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mutation {
+    /// Index of the function to change, counting the script as 0 and then
+    /// the nested functions in declaration order
+    pub function: usize,
+    pub kind: MutationKind,
+}
+
+impl Mutation {
+    /// Every mutation that can be applied to `script` and the functions
+    /// nested in it
+    pub fn all(script: Gc<ObjFunction>) -> Vec<Mutation> {
+        let mut mutations = vec![];
+        for (i, function) in functions(script).into_iter().enumerate() {
+            let chunk = &function.chunk;
+
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let start = offset;
+                let op = match chunk.disassemble_instruction(&mut offset) {
+                    Some(Instruction::Simple(op) | Instruction::Jump(op, _)) => op,
+                    _ => continue,
+                };
+
+                for &(from, to) in SWAPS.iter().filter(|(from, _)| *from == op) {
+                    mutations.push(Mutation {
+                        function: i,
+                        kind: MutationKind::SwapOp {
+                            offset: start,
+                            from,
+                            to,
+                        },
+                    });
+                }
+            }
+
+            for index in 0..chunk.numbers.len() {
+                mutations.push(Mutation {
+                    function: i,
+                    kind: MutationKind::OffByOne { index },
+                });
+            }
+        }
+
+        mutations
+    }
+
+    /// Apply the mutation to `script`, which must have been compiled from the
+    /// same source the mutation was found in, into `mem`
+    pub fn apply(&self, script: Gc<ObjFunction>, mem: &mut Mem) {
+        let mut function = functions(script)[self.function];
+        let chunk = &mut function.chunk;
+
+        match self.kind {
+            MutationKind::SwapOp { offset, from, to } => {
+                assert_eq!(Opcode::try_from(chunk.code[offset]), Ok(from));
+                chunk.code[offset] = to as u8;
+            }
+            // Pooled numbers are shared, so point this one at a new number
+            MutationKind::OffByOne { index } => {
+                let n = chunk.number_values[index] + 1.0;
+                chunk.numbers[index] = mem.numbers.intern(n);
+                chunk.number_values[index] = n;
+            }
+        }
+    }
+}
+
+impl Display for Mutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            MutationKind::SwapOp { offset, from, to } => {
+                write!(f, "fn #{} @{offset:04}: {from:?} -> {to:?}", self.function)
+            }
+            MutationKind::OffByOne { index } => {
+                write!(f, "fn #{} constant {index}: +1", self.function)
+            }
+        }
+    }
+}
+
+/// `script` followed by every function nested in it, in declaration order
+fn functions(script: Gc<ObjFunction>) -> Vec<Gc<ObjFunction>> {
+    let mut out = vec![script];
+    out.extend(script.nested_functions());
+    out
+}
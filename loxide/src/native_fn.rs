@@ -1,13 +1,207 @@
-use std::fmt::Debug;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    process::Command,
+};
 
-use crate::value::Value;
+use crate::{
+    errors::ErrorCode,
+    events::LogLevel,
+    fields::FieldStorage,
+    handle::Handle,
+    matrix,
+    mem::Gc,
+    net,
+    obj::{Obj, ObjClass, ObjFloatArray, ObjInstance, ObjKind, ObjList, ObjStringView, ObjWeakRef},
+    time::{self, Date},
+    value::{Value, ValueKind},
+    vm::VM,
+};
 
-pub type NativeFn = fn(&[Value]) -> Value;
+/// The longest float array `floats(len)` makes, like
+/// [`MAX_REPEAT_LEN`](crate::vm::MAX_REPEAT_LEN) for `*`
+pub const MAX_FLOATS_LEN: usize = 1 << 24;
+
+/// A native function. Its arguments live on the VM stack, so they stay alive
+/// for the whole call, but anything it creates through the [`NativeCtx`] is
+/// only reachable from the Rust side and comes back pinned.
+///
+/// Returning an error raises a runtime error with that message.
+pub type NativeFn = fn(&mut NativeCtx, &[Value]) -> Result<Value, String>;
+
+/// What a native function can do to the VM while it runs.
+///
+/// Any allocation may run the GC, which can't see values held in Rust
+/// locals, so everything handed out here is a [`Handle`]. Keep the handles
+/// around until the values are stored somewhere the GC can see, or returned:
+/// nothing allocates between a native returning and its result being pushed.
+pub struct NativeCtx<'vm> {
+    vm: &'vm mut VM,
+}
+
+impl<'vm> NativeCtx<'vm> {
+    pub fn string(&mut self, string: &str) -> Handle {
+        let string = self.vm.copy_string(string);
+        self.vm.pin(Value::Obj(string.cast()))
+    }
+
+    pub fn pin(&mut self, value: Value) -> Handle {
+        self.vm.pin(value)
+    }
+
+    /// Call a Lox function (or anything callable) with `args`, see
+    /// [`VM::call_function`]. Pass on the error if it fails.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Handle, String> {
+        let result = self.vm.call_function(callee, args)?;
+        Ok(self.vm.pin(result))
+    }
+}
+
+/// What scripts are allowed to reach outside the VM for. Everything is off
+/// by default, natives that need a capability raise a runtime error without
+/// it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    /// Reading environment variables with `getenv`
+    pub env: bool,
+    /// Reading the wall clock with `nowMillis` and `utcDate`
+    pub clock: bool,
+    /// Running other programs with `exec`
+    pub process: bool,
+    /// Making network connections with `httpGet` and the `tcp` natives
+    pub net: bool,
+}
+
+impl Capabilities {
+    /// Turn on the capability called `name`, returning false if there isn't
+    /// one
+    pub fn allow(&mut self, name: &str) -> bool {
+        match name {
+            "env" => self.env = true,
+            "clock" => self.clock = true,
+            "process" => self.process = true,
+            "net" => self.net = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// The natives every script can call, by the global each is defined as
+pub const GLOBAL_NATIVES: &[(&str, NativeFnKind)] = &[
+    ("clock", NativeFnKind::Clock),
+    ("__dummy", NativeFnKind::Dummy),
+    ("log", NativeFnKind::Log),
+    ("len", NativeFnKind::Len),
+    ("append", NativeFnKind::Append),
+    ("getenv", NativeFnKind::GetEnv),
+    ("println", NativeFnKind::Print),
+    ("format", NativeFnKind::Format),
+    ("printf", NativeFnKind::Printf),
+    ("capture", NativeFnKind::Capture),
+    ("inspect", NativeFnKind::Inspect),
+    ("typeof", NativeFnKind::TypeOf),
+    ("clone", NativeFnKind::DeepClone),
+    ("deepEquals", NativeFnKind::DeepEquals),
+    ("WeakRef", NativeFnKind::WeakRef),
+    ("floats", NativeFnKind::Floats),
+    ("nowMillis", NativeFnKind::NowMillis),
+    ("utcDate", NativeFnKind::UtcDate),
+    ("formatTime", NativeFnKind::FormatTime),
+    ("exec", NativeFnKind::Exec),
+    ("httpGet", NativeFnKind::HttpGet),
+    ("tcpConnect", NativeFnKind::TcpConnect),
+    ("tcpSend", NativeFnKind::TcpSend),
+    ("tcpRecv", NativeFnKind::TcpRecv),
+    ("tcpClose", NativeFnKind::TcpClose),
+];
+
+/// The global holding the math natives
+pub const MATH_MODULE: &str = "math";
+
+/// The natives in `math.matrix`, by field
+pub const MATRIX_NATIVES: &[(&str, NativeFnKind)] = &[
+    ("multiply", NativeFnKind::MatrixMultiply),
+    ("transpose", NativeFnKind::MatrixTranspose),
+    ("invert", NativeFnKind::MatrixInvert),
+];
+
+/// Whether `name` is a global every VM defines before running a script
+pub fn is_builtin_global(name: &str) -> bool {
+    name == MATH_MODULE || GLOBAL_NATIVES.iter().any(|&(native, _)| native == name)
+}
 
 #[derive(Clone, Copy)]
 pub enum NativeFnKind {
     Clock,
     Dummy,
+    /// `log(level, message)`, handed to [`VmEvents::log`](crate::events::VmEvents::log)
+    Log,
+    /// `len(list_or_string)`
+    Len,
+    /// `append(list, value)`
+    Append,
+    /// `getenv(name)`, nil if the variable isn't set. Needs the `env`
+    /// capability.
+    GetEnv,
+    /// `println(value, ...)`, written with [`Display`](std::fmt::Display) and
+    /// the VM's [`PrintOptions`](crate::vm::PrintOptions)
+    Print,
+    /// `format(template, value, ...)`, see [`format_values`]
+    Format,
+    /// `printf(template, value, ...)`, like `format` but written to the
+    /// output instead of returned
+    Printf,
+    /// `capture(function)`, calling `function` with no arguments and
+    /// returning what it printed as a string instead of writing it to the
+    /// output
+    Capture,
+    /// `inspect(value[, depth])`, see [`inspect`]
+    Inspect,
+    /// `typeof(value)`, the name of the value's type, see [`type_name`]
+    TypeOf,
+    /// `clone(value)`, see [`deep_clone`]
+    DeepClone,
+    /// `deepEquals(a, b)`, see [`deep_equals`]
+    DeepEquals,
+    /// `WeakRef(object)`, a weak ref whose `get()` returns `object` until
+    /// it's collected and nil after, see [`ObjWeakRef`]
+    WeakRef,
+    /// `floats(length)` for a float array of zeros, or `floats(list)` for
+    /// one with the numbers in `list`
+    Floats,
+    /// `math.matrix.multiply(a, b, inner)`, see [`matrix::multiply`]
+    MatrixMultiply,
+    /// `math.matrix.transpose(a, cols)`, see [`matrix::transpose`]
+    MatrixTranspose,
+    /// `math.matrix.invert(a)`, see [`matrix::invert`]
+    MatrixInvert,
+    /// `nowMillis()`, milliseconds since the Unix epoch. Needs the `clock`
+    /// capability.
+    NowMillis,
+    /// `utcDate([millis])`, a `Date` instance with the year, month, day,
+    /// hour, minute, second, millisecond and weekday fields of `millis`, or
+    /// of now. Without `millis` it needs the `clock` capability.
+    UtcDate,
+    /// `formatTime(millis, format)`, see [`Date::format`]
+    FormatTime,
+    /// `exec(command, args)`, running `command` with the list of strings
+    /// `args` until it exits. Returns a `Process` instance with its `status`
+    /// (nil if it was killed by a signal), `stdout` and `stderr`. Needs the
+    /// `process` capability.
+    Exec,
+    /// `httpGet(url)`, a `Response` instance with the `status` and `body`.
+    /// Needs the `net` capability, like the `tcp` natives.
+    HttpGet,
+    /// `tcpConnect(host, port)`, returning the connection's number
+    TcpConnect,
+    /// `tcpSend(connection, string)`, returning how many bytes were sent
+    TcpSend,
+    /// `tcpRecv(connection[, max])`, up to `max` bytes as a string, empty
+    /// once the connection is closed
+    TcpRecv,
+    /// `tcpClose(connection)`
+    TcpClose,
     Custom(NativeFn),
 }
 
@@ -16,6 +210,32 @@ impl Debug for NativeFnKind {
         match self {
             Self::Clock => write!(f, "Clock"),
             Self::Dummy => write!(f, "Dummy"),
+            Self::Log => write!(f, "Log"),
+            Self::Len => write!(f, "Len"),
+            Self::Append => write!(f, "Append"),
+            Self::GetEnv => write!(f, "GetEnv"),
+            Self::Print => write!(f, "Print"),
+            Self::Format => write!(f, "Format"),
+            Self::Printf => write!(f, "Printf"),
+            Self::Capture => write!(f, "Capture"),
+            Self::Inspect => write!(f, "Inspect"),
+            Self::TypeOf => write!(f, "TypeOf"),
+            Self::DeepClone => write!(f, "DeepClone"),
+            Self::DeepEquals => write!(f, "DeepEquals"),
+            Self::WeakRef => write!(f, "WeakRef"),
+            Self::Floats => write!(f, "Floats"),
+            Self::MatrixMultiply => write!(f, "MatrixMultiply"),
+            Self::MatrixTranspose => write!(f, "MatrixTranspose"),
+            Self::MatrixInvert => write!(f, "MatrixInvert"),
+            Self::NowMillis => write!(f, "NowMillis"),
+            Self::UtcDate => write!(f, "UtcDate"),
+            Self::FormatTime => write!(f, "FormatTime"),
+            Self::Exec => write!(f, "Exec"),
+            Self::HttpGet => write!(f, "HttpGet"),
+            Self::TcpConnect => write!(f, "TcpConnect"),
+            Self::TcpSend => write!(f, "TcpSend"),
+            Self::TcpRecv => write!(f, "TcpRecv"),
+            Self::TcpClose => write!(f, "TcpClose"),
             Self::Custom(arg0) => {
                 let fn_pointer: *const NativeFn = arg0;
                 f.debug_tuple("Custom").field(&fn_pointer).finish()
@@ -25,11 +245,79 @@ impl Debug for NativeFnKind {
 }
 
 impl NativeFnKind {
-    pub fn call(&self, values: &[Value]) -> Value {
+    /// Whether calling it with `values` gives the same result every run.
+    /// The clock, the environment, other processes and the network aren't
+    /// reproducible, nor is `clock`, which stands for the clock even though
+    /// it's a stub here. Weak refs aren't either, since when their target
+    /// goes depends on when the GC runs. Custom natives are up to the host.
+    pub fn is_deterministic(&self, values: &[Value]) -> bool {
+        match self {
+            Self::Clock
+            | Self::GetEnv
+            | Self::NowMillis
+            | Self::Exec
+            | Self::HttpGet
+            | Self::TcpConnect
+            | Self::TcpSend
+            | Self::TcpRecv
+            | Self::TcpClose
+            | Self::WeakRef => false,
+            // Now, without a time to convert
+            Self::UtcDate => !values.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// The global it's defined as, or its [`Debug`] name if it isn't one
+    pub fn name(&self) -> String {
+        let kind = std::mem::discriminant(self);
+        match GLOBAL_NATIVES
+            .iter()
+            .find(|(_, native)| std::mem::discriminant(native) == kind)
+        {
+            Some((name, _)) => name.to_string(),
+            None => format!("{self:?}"),
+        }
+    }
+
+    /// Returns the message of the runtime error to raise if the call failed
+    pub fn call(&self, vm: &mut VM, values: &[Value]) -> Result<Value, String> {
         match self {
-            NativeFnKind::Clock => Self::call_clock(values),
-            NativeFnKind::Dummy => Self::call_dummy(values),
-            NativeFnKind::Custom(native_fn) => native_fn(values),
+            NativeFnKind::Clock => Ok(Self::call_clock(values)),
+            NativeFnKind::Dummy => Ok(Self::call_dummy(values)),
+            NativeFnKind::Log => Self::call_log(vm, values),
+            NativeFnKind::Len => Self::call_len(values),
+            NativeFnKind::Append => Self::call_append(vm, values),
+            NativeFnKind::GetEnv => Self::call_getenv(vm, values),
+            NativeFnKind::Print => Ok(Self::call_print(vm, values)),
+            NativeFnKind::Format => Self::call_format(vm, values),
+            NativeFnKind::Printf => Self::call_printf(vm, values),
+            NativeFnKind::Capture => Self::call_capture(vm, values),
+            NativeFnKind::Inspect => Self::call_inspect(vm, values),
+            NativeFnKind::TypeOf => Self::call_typeof(vm, values),
+            NativeFnKind::DeepClone => match values {
+                [value] => Ok(deep_clone(vm, *value)),
+                _ => Err(format!("Expected 1 argument but got {}.", values.len())),
+            },
+            NativeFnKind::DeepEquals => match values {
+                [a, b] => Ok(Value::Bool(deep_equals(*a, *b))),
+                _ => Err(format!("Expected 2 arguments but got {}.", values.len())),
+            },
+            NativeFnKind::WeakRef => Self::call_weak_ref(vm, values),
+            NativeFnKind::Floats => Self::call_floats(vm, values),
+            NativeFnKind::MatrixMultiply => Self::call_matrix_multiply(vm, values),
+            NativeFnKind::MatrixTranspose => Self::call_matrix_transpose(vm, values),
+            NativeFnKind::MatrixInvert => Self::call_matrix_invert(vm, values),
+            NativeFnKind::NowMillis => Self::call_now_millis(vm, values),
+            NativeFnKind::UtcDate => Self::call_utc_date(vm, values),
+            NativeFnKind::FormatTime => Self::call_format_time(vm, values),
+            NativeFnKind::Exec => Self::call_exec(vm, values),
+            NativeFnKind::HttpGet => Self::call_http_get(vm, values),
+            NativeFnKind::TcpConnect => Self::call_tcp_connect(vm, values),
+            NativeFnKind::TcpSend => Self::call_tcp_send(vm, values),
+            NativeFnKind::TcpRecv => Self::call_tcp_recv(vm, values),
+            NativeFnKind::TcpClose => Self::call_tcp_close(vm, values),
+            NativeFnKind::Custom(native_fn) => native_fn(&mut NativeCtx { vm }, values),
         }
     }
 
@@ -40,4 +328,1159 @@ impl NativeFnKind {
     fn call_dummy(_values: &[Value]) -> Value {
         Value::Number(420.0)
     }
+
+    fn call_log(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (level, message) = match values {
+            [level, message] => (level, message),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+
+        let level = level.as_str().and_then(LogLevel::from_name).ok_or(
+            "Log level must be one of \"trace\", \"debug\", \"info\", \"warn\" or \"error\".",
+        )?;
+
+        match message.as_str() {
+            Some(message) => vm.events.log(level, message),
+            None => vm.events.log(level, &format!("{message:?}")),
+        }
+
+        Ok(Value::Nil)
+    }
+
+    fn call_len(values: &[Value]) -> Result<Value, String> {
+        let value = match values {
+            [value] => value,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        let len = if let Some(list) = value.as_list() {
+            list.items.len()
+        } else if let Some(array) = value.as_float_array() {
+            array.values.len()
+        } else if let Some(string) = value.as_str() {
+            string.len()
+        } else {
+            return Err("Can only take the length of a list, float array or string.".into());
+        };
+
+        Ok(Value::Number(len as f64))
+    }
+
+    fn call_append(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (list, value) = match values {
+            [list, value] => (list, *value),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+
+        let mut list = list.as_list().ok_or("Can only append to a list.")?;
+        vm.write_barrier(value);
+        list.items.push(value);
+
+        Ok(Value::Nil)
+    }
+
+    fn call_print(vm: &mut VM, values: &[Value]) -> Value {
+        let mut line = String::new();
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                line.push_str(&vm.print_options.separator);
+            }
+            line.push_str(&value.to_string());
+        }
+        line.push_str(&vm.print_options.end);
+
+        vm.write_output(format_args!("{line}"));
+        Value::Nil
+    }
+
+    fn call_format(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let formatted = Self::format_args(values)?;
+        Ok(Value::Obj(vm.copy_string(&formatted).cast()))
+    }
+
+    fn call_printf(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let formatted = Self::format_args(values)?;
+        vm.write_output(format_args!("{formatted}"));
+        Ok(Value::Nil)
+    }
+
+    fn format_args(values: &[Value]) -> Result<String, String> {
+        let (template, values) = values.split_first().ok_or("Expected a format string.")?;
+        let template = template.try_as_str()?;
+        format_values(template, values)
+    }
+
+    fn call_inspect(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (value, depth) = match values {
+            [value] => (*value, INSPECT_DEPTH),
+            [value, Value::Number(depth)] if *depth >= 0.0 => (*value, *depth as usize),
+            [_, _] => return Err("Depth must be a non-negative number.".into()),
+            _ => {
+                return Err(format!(
+                    "Expected 1 or 2 arguments but got {}.",
+                    values.len()
+                ))
+            }
+        };
+
+        let inspected = inspect(value, depth);
+        Ok(Value::Obj(vm.copy_string(&inspected).cast()))
+    }
+
+    fn call_capture(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let function = match values {
+            [function] => *function,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        // Captures nest, the outer one getting what the inner one returns
+        let previous = vm.captured_output.replace(vec![]);
+        let result = vm.call_function(function, &[]);
+        let output = std::mem::replace(&mut vm.captured_output, previous).unwrap_or_default();
+        result?;
+        Ok(Value::Obj(
+            vm.copy_string(&String::from_utf8_lossy(&output)).cast(),
+        ))
+    }
+
+    fn call_typeof(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let value = match values {
+            [value] => *value,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        let name = type_name(value);
+        Ok(Value::Obj(vm.copy_string(&name).cast()))
+    }
+
+    fn call_weak_ref(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let target = match values {
+            [Value::Obj(target)] => *target,
+            [_] => return Err("Can only make a weak ref to an object.".into()),
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        let weak = vm.alloc_obj(ObjWeakRef::new(target));
+        vm.mem.weak_refs.push(weak);
+        Ok(Value::Obj(weak.cast()))
+    }
+
+    fn call_floats(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let values = match values {
+            [Value::Number(len)] if len.fract() == 0.0 && *len >= 0.0 => {
+                if *len > MAX_FLOATS_LEN as f64 {
+                    return Err(format!(
+                        "Float array length {len} is longer than {MAX_FLOATS_LEN}."
+                    ));
+                }
+                vec![0.0; *len as usize]
+            }
+            [items] => match items.as_list() {
+                Some(list) => list
+                    .items
+                    .iter()
+                    .map(|item| match item {
+                        Value::Number(n) => Ok(*n),
+                        _ => Err("Float array items must be numbers."),
+                    })
+                    .collect::<Result<_, _>>()?,
+                None => return Err("Expected a length or a list of numbers.".into()),
+            },
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        let array = vm.alloc_obj(ObjFloatArray::new(values));
+        Ok(Value::Obj(array.cast()))
+    }
+
+    fn call_matrix_multiply(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (a, b, inner) = match values {
+            [a, b, inner] => (Self::matrix(a)?, Self::matrix(b)?, Self::dimension(inner)?),
+            _ => return Err(format!("Expected 3 arguments but got {}.", values.len())),
+        };
+        let product = matrix::multiply(&a.values, &b.values, inner)?;
+        Ok(Value::Obj(vm.alloc_obj(ObjFloatArray::new(product)).cast()))
+    }
+
+    fn call_matrix_transpose(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (a, cols) = match values {
+            [a, cols] => (Self::matrix(a)?, Self::dimension(cols)?),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+        let transposed = matrix::transpose(&a.values, cols)?;
+        Ok(Value::Obj(
+            vm.alloc_obj(ObjFloatArray::new(transposed)).cast(),
+        ))
+    }
+
+    fn call_matrix_invert(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let a = match values {
+            [a] => Self::matrix(a)?,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+        let inverse = matrix::invert(&a.values)?;
+        Ok(Value::Obj(vm.alloc_obj(ObjFloatArray::new(inverse)).cast()))
+    }
+
+    fn matrix(value: &Value) -> Result<Gc<ObjFloatArray>, String> {
+        Ok(value.try_as_float_array()?)
+    }
+
+    fn dimension(value: &Value) -> Result<usize, String> {
+        match *value {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => Ok(n as usize),
+            _ => Err("Matrix dimensions must be non-negative integers.".into()),
+        }
+    }
+
+    fn call_now_millis(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        if !vm.capabilities.clock {
+            return Err("nowMillis needs the clock capability.".into());
+        }
+        if !values.is_empty() {
+            return Err(format!("Expected 0 arguments but got {}.", values.len()));
+        }
+
+        Ok(Value::Number(time::now_millis()))
+    }
+
+    fn call_utc_date(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let millis = match values {
+            [] if vm.capabilities.clock => time::now_millis(),
+            [] => return Err("utcDate needs the clock capability to get the time.".into()),
+            [millis] => millis.try_as_number()?,
+            _ => {
+                return Err(format!(
+                    "Expected 0 or 1 arguments but got {}.",
+                    values.len()
+                ))
+            }
+        };
+
+        let date = Date::from_millis(millis);
+        let fields = [
+            ("year", date.year as f64),
+            ("month", date.month as f64),
+            ("day", date.day as f64),
+            ("hour", date.hour as f64),
+            ("minute", date.minute as f64),
+            ("second", date.second as f64),
+            ("millisecond", date.millisecond as f64),
+            ("weekday", date.weekday as f64),
+        ]
+        .map(|(name, n)| (name, Value::Number(n)));
+        Ok(record(vm, "Date", &fields))
+    }
+
+    fn call_format_time(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        let (millis, format) = match values {
+            [millis, format] => (millis.try_as_number()?, format),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+        let format = format.try_as_str()?;
+
+        let formatted = Date::from_millis(millis).format(format)?;
+        Ok(Value::Obj(vm.copy_string(&formatted).cast()))
+    }
+
+    fn call_exec(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        if !vm.capabilities.process {
+            return Err("exec needs the process capability.".into());
+        }
+
+        let (command, args) = match values {
+            [command, args] => (command, args),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+        let command = command.try_as_str()?;
+        let args = args.try_as_list()?;
+        let args = args
+            .items
+            .iter()
+            .map(Value::try_as_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let output = Command::new(command)
+            .args(args)
+            .output()
+            .map_err(|err| format!("Couldn't run '{command}': {err}."))?;
+
+        let status = match output.status.code() {
+            Some(code) => Value::Number(code as f64),
+            None => Value::Nil,
+        };
+        let stdout = vm.copy_string(&String::from_utf8_lossy(&output.stdout));
+        let stdout = vm.pin(Value::Obj(stdout.cast()));
+        let stderr = vm.copy_string(&String::from_utf8_lossy(&output.stderr));
+        let stderr = vm.pin(Value::Obj(stderr.cast()));
+
+        Ok(record(
+            vm,
+            "Process",
+            &[
+                ("status", status),
+                ("stdout", stdout.get()),
+                ("stderr", stderr.get()),
+            ],
+        ))
+    }
+
+    fn call_http_get(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        Self::check_net(vm, "httpGet")?;
+        let url = match values {
+            [url] => url.try_as_str()?,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        let (status, body) = net::http_get(url)?;
+        let body = vm.copy_string(&String::from_utf8_lossy(&body));
+        let body = vm.pin(Value::Obj(body.cast()));
+
+        Ok(record(
+            vm,
+            "Response",
+            &[
+                ("status", Value::Number(status as f64)),
+                ("body", body.get()),
+            ],
+        ))
+    }
+
+    fn call_tcp_connect(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        Self::check_net(vm, "tcpConnect")?;
+        let (host, port) = match values {
+            [host, Value::Number(port)]
+                if port.fract() == 0.0 && (0.0..=65535.0).contains(port) =>
+            {
+                (host.try_as_str()?, *port as u16)
+            }
+            [_, _] => return Err("Port must be an integer from 0 to 65535.".into()),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+
+        let id = vm.connections.connect(host, port)?;
+        Ok(Value::Number(id as f64))
+    }
+
+    fn call_tcp_send(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        Self::check_net(vm, "tcpSend")?;
+        let (id, data) = match values {
+            [id, data] => (Self::connection(id)?, data),
+            _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+        };
+        let data = data.try_as_str()?;
+
+        let sent = vm.connections.send(id, data.as_bytes())?;
+        Ok(Value::Number(sent as f64))
+    }
+
+    fn call_tcp_recv(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        Self::check_net(vm, "tcpRecv")?;
+        let (id, max) = match values {
+            [id] => (Self::connection(id)?, 4096),
+            [id, Value::Number(max)] if max.fract() == 0.0 && *max >= 1.0 => {
+                (Self::connection(id)?, *max as usize)
+            }
+            [_, _] => return Err("Maximum length must be a positive integer.".into()),
+            _ => {
+                return Err(format!(
+                    "Expected 1 or 2 arguments but got {}.",
+                    values.len()
+                ))
+            }
+        };
+
+        let received = vm.connections.recv(id, max)?;
+        let received = vm.copy_string(&String::from_utf8_lossy(&received));
+        Ok(Value::Obj(received.cast()))
+    }
+
+    fn call_tcp_close(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        Self::check_net(vm, "tcpClose")?;
+        let id = match values {
+            [id] => Self::connection(id)?,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        vm.connections.close(id)?;
+        Ok(Value::Nil)
+    }
+
+    fn check_net(vm: &VM, name: &str) -> Result<(), String> {
+        match vm.capabilities.net {
+            true => Ok(()),
+            false => Err(format!("{name} needs the net capability.")),
+        }
+    }
+
+    fn connection(value: &Value) -> Result<usize, String> {
+        match *value {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => Ok(n as usize),
+            _ => Err("Connection must be a non-negative integer.".into()),
+        }
+    }
+
+    fn call_getenv(vm: &mut VM, values: &[Value]) -> Result<Value, String> {
+        if !vm.capabilities.env {
+            return Err("getenv needs the env capability.".into());
+        }
+
+        let name = match values {
+            [name] => name.try_as_str()?,
+            _ => return Err(format!("Expected 1 argument but got {}.", values.len())),
+        };
+
+        match std::env::var(name) {
+            Ok(value) => Ok(Value::Obj(vm.copy_string(&value).cast())),
+            Err(_) => Ok(Value::Nil),
+        }
+    }
+}
+
+/// Call the method called `name` on a list, float array, string or weak
+/// ref, the values that have methods without being instances
+pub fn call_method(
+    vm: &mut VM,
+    receiver: Value,
+    name: &str,
+    values: &[Value],
+) -> Result<Value, String> {
+    let undefined = || ErrorCode::UndefinedProperty.message(&[&name]);
+    if let Some(list) = receiver.as_list() {
+        ListMethod::from_name(name)
+            .ok_or_else(undefined)?
+            .call(vm, list, values)
+    } else if let Some(array) = receiver.as_float_array() {
+        FloatArrayMethod::from_name(name)
+            .ok_or_else(undefined)?
+            .call(array, values)
+    } else if receiver.as_str().is_some() {
+        StringMethod::from_name(name)
+            .ok_or_else(undefined)?
+            .call(vm, receiver, values)
+    } else if let Some(weak) = receiver.as_weak_ref() {
+        match (name, values) {
+            ("get", []) => Ok(weak.target.map_or(Value::Nil, Value::Obj)),
+            ("get", _) => Err(format!("Expected 0 arguments but got {}.", values.len())),
+            _ => Err(undefined()),
+        }
+    } else {
+        Err("Only instances have methods.".into())
+    }
+}
+
+/// Methods float arrays have. None of them allocate or call back into Lox.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloatArrayMethod {
+    /// `array.fill(n)`, setting every item to `n`
+    Fill,
+    /// `array.sum()`
+    Sum,
+    /// `array.dot(other)`, the dot product with an array of the same length
+    Dot,
+    /// `array.sort()`, in ascending order
+    Sort,
+    /// `array.search(n)`, the index of `n` in a sorted array, nil if it isn't
+    /// there
+    Search,
+}
+
+impl FloatArrayMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fill" => Some(Self::Fill),
+            "sum" => Some(Self::Sum),
+            "dot" => Some(Self::Dot),
+            "sort" => Some(Self::Sort),
+            "search" => Some(Self::Search),
+            _ => None,
+        }
+    }
+
+    pub fn call(self, mut array: Gc<ObjFloatArray>, values: &[Value]) -> Result<Value, String> {
+        match (self, values) {
+            (Self::Fill, [n]) => {
+                array.values.fill(n.try_as_number()?);
+                Ok(Value::Nil)
+            }
+            (Self::Sum, []) => Ok(Value::Number(array.values.iter().sum())),
+            (Self::Dot, [other]) => {
+                let other = other
+                    .as_float_array()
+                    .ok_or("Can only take the dot product with a float array.")?;
+                if other.values.len() != array.values.len() {
+                    return Err(format!(
+                        "Can't take the dot product of float arrays of length {} and {}.",
+                        array.values.len(),
+                        other.values.len()
+                    ));
+                }
+                let dot = array
+                    .values
+                    .iter()
+                    .zip(other.values.iter())
+                    .map(|(a, b)| a * b)
+                    .sum();
+                Ok(Value::Number(dot))
+            }
+            (Self::Sort, []) => {
+                array.values.sort_by(f64::total_cmp);
+                Ok(Value::Nil)
+            }
+            (Self::Search, [n]) => {
+                let n = n.try_as_number()?;
+                match array.values.binary_search_by(|probe| probe.total_cmp(&n)) {
+                    Ok(index) => Ok(Value::Number(index as f64)),
+                    Err(_) => Ok(Value::Nil),
+                }
+            }
+            (Self::Fill | Self::Dot | Self::Search, _) => {
+                Err(format!("Expected 1 argument but got {}.", values.len()))
+            }
+            (Self::Sum | Self::Sort, _) => {
+                Err(format!("Expected 0 arguments but got {}.", values.len()))
+            }
+        }
+    }
+}
+
+/// Methods strings have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StringMethod {
+    /// `string.startsWith(prefix)`
+    StartsWith,
+    /// `string.endsWith(suffix)`
+    EndsWith,
+    /// `string.replace(from, to)`, with every `from` replaced by `to`
+    Replace,
+    /// `string.slice(start, end)`, the bytes from `start` up to `end`
+    Slice,
+    /// `string.split(separator)`, a list of the parts between separators
+    Split,
+}
+
+impl StringMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "startsWith" => Some(Self::StartsWith),
+            "endsWith" => Some(Self::EndsWith),
+            "replace" => Some(Self::Replace),
+            "slice" => Some(Self::Slice),
+            "split" => Some(Self::Split),
+            _ => None,
+        }
+    }
+
+    /// `string` is the receiver, which can be a view. `slice` and `split`
+    /// return views of it rather than copies, and `replace` only allocates if
+    /// something was replaced and the result isn't interned already.
+    pub fn call(self, vm: &mut VM, string: Value, values: &[Value]) -> Result<Value, String> {
+        let receiver = string.as_str().unwrap();
+        if self == Self::Slice {
+            let (start, end) = match values {
+                [start, end] => (Self::bound(start)?, Self::bound(end)?),
+                _ => return Err(format!("Expected 2 arguments but got {}.", values.len())),
+            };
+            if receiver.get(start..end).is_none() {
+                return Err(format!(
+                    "Can't slice a string of length {} from {start} to {end}.",
+                    receiver.len()
+                ));
+            }
+            return Ok(Self::view(vm, string, start, end));
+        }
+
+        let strings = values
+            .iter()
+            .map(Value::try_as_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (self, strings.as_slice()) {
+            (Self::StartsWith, [prefix]) => Ok(Value::Bool(receiver.starts_with(prefix))),
+            (Self::EndsWith, [suffix]) => Ok(Value::Bool(receiver.ends_with(suffix))),
+            (Self::Replace, ["", _]) => Err("Can't replace an empty string.".into()),
+            (Self::Replace, [from, _]) if !receiver.contains(from) => Ok(string),
+            (Self::Replace, [from, to]) => {
+                let replaced = receiver.replace(from, to);
+                Ok(Value::Obj(vm.copy_string(&replaced).cast()))
+            }
+            (Self::Split, [""]) => Err("Can't split on an empty string.".into()),
+            (Self::Split, [separator]) => {
+                let mut list = vm.alloc_obj(ObjList::new(vec![]));
+                let _list = vm.pin(Value::Obj(list.cast()));
+
+                for part in receiver.split(separator) {
+                    let start = part.as_ptr() as usize - receiver.as_ptr() as usize;
+                    let part = Self::view(vm, string, start, start + part.len());
+                    vm.write_barrier(part);
+                    list.items.push(part);
+                }
+
+                Ok(Value::Obj(list.cast()))
+            }
+            (Self::Slice, _) => unreachable!("Sliced above"),
+            (Self::StartsWith | Self::EndsWith | Self::Split, _) => {
+                Err(format!("Expected 1 argument but got {}.", values.len()))
+            }
+            (Self::Replace, _) => Err(format!("Expected 2 arguments but got {}.", values.len())),
+        }
+    }
+
+    fn bound(value: &Value) -> Result<usize, String> {
+        match *value {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => Ok(n as usize),
+            _ => Err("Slice bounds must be non-negative integers.".into()),
+        }
+    }
+
+    /// `string[start..end]`, sharing the characters of `string`, which has to
+    /// be on the stack so they stay alive. The whole string, and the empty
+    /// one, are returned without making a view.
+    pub(crate) fn view(vm: &mut VM, string: Value, start: usize, end: usize) -> Value {
+        let (parent, offset) = match string.as_str_view() {
+            Some(view) => (view.parent, view.offset as usize),
+            None => (string.as_obj_str().unwrap(), 0),
+        };
+
+        if start == 0 && end == string.as_str().unwrap().len() {
+            string
+        } else if start == end {
+            Value::Obj(vm.copy_string("").cast())
+        } else {
+            let (start, end) = ((offset + start) as u32, (offset + end) as u32);
+            Value::Obj(vm.alloc_obj(ObjStringView::new(parent, start, end)).cast())
+        }
+    }
+}
+
+/// Methods lists have, called like `list.map(fn)`. The ones that take a
+/// function call back into Lox for each item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListMethod {
+    /// `list.sort([cmp])`, in place and stable. `cmp(a, b)` returns a
+    /// negative number if `a` goes first, without one numbers and strings are
+    /// sorted in ascending order.
+    Sort,
+    /// `list.map(fn)`, a new list of `fn(item)` for each item
+    Map,
+    /// `list.filter(fn)`, a new list of the items `fn(item)` is truthy for
+    Filter,
+    /// `list.reduce(fn, init)`, folding the items into `init` with
+    /// `fn(acc, item)`
+    Reduce,
+}
+
+impl ListMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sort" => Some(Self::Sort),
+            "map" => Some(Self::Map),
+            "filter" => Some(Self::Filter),
+            "reduce" => Some(Self::Reduce),
+            _ => None,
+        }
+    }
+
+    /// Like [`NativeFnKind::call`], with `list` as the receiver. The list
+    /// and `values` are on the VM stack for the whole call.
+    pub fn call(self, vm: &mut VM, list: Gc<ObjList>, values: &[Value]) -> Result<Value, String> {
+        match (self, values) {
+            (Self::Sort, []) => Self::sort(vm, list, None),
+            (Self::Sort, [cmp]) => Self::sort(vm, list, Some(*cmp)),
+            (Self::Map, [function]) => Self::map(vm, list, *function),
+            (Self::Filter, [function]) => Self::filter(vm, list, *function),
+            (Self::Reduce, [function, init]) => Self::reduce(vm, list, *function, *init),
+            (Self::Sort, _) => Err(format!(
+                "Expected 0 or 1 arguments but got {}.",
+                values.len()
+            )),
+            (Self::Map | Self::Filter, _) => {
+                Err(format!("Expected 1 argument but got {}.", values.len()))
+            }
+            (Self::Reduce, _) => Err(format!("Expected 2 arguments but got {}.", values.len())),
+        }
+    }
+
+    fn sort(vm: &mut VM, mut list: Gc<ObjList>, cmp: Option<Value>) -> Result<Value, String> {
+        // The comparator can change the list while it's being sorted, so
+        // sort positions in a copy of it instead
+        let items = list.items.clone();
+        let copy = vm.alloc_obj(ObjList::new(items));
+        let _copy = vm.pin(Value::Obj(copy.cast()));
+
+        let mut order: Vec<usize> = (0..copy.items.len()).collect();
+        merge_sort(&mut order, &mut |a, b| {
+            let (a, b) = (copy.items[a], copy.items[b]);
+            match cmp {
+                Some(cmp) => match vm.call_function(cmp, &[a, b])? {
+                    Value::Number(n) => Ok(n <= 0.0),
+                    _ => Err("Comparator must return a number.".into()),
+                },
+                None => match (a, b) {
+                    (Value::Number(a), Value::Number(b)) => Ok(a <= b),
+                    _ => match (a.as_str(), b.as_str()) {
+                        (Some(a), Some(b)) => Ok(a <= b),
+                        _ => Err("Can only sort numbers or strings without a comparator.".into()),
+                    },
+                },
+            }
+        })?;
+
+        list.items = order.into_iter().map(|i| copy.items[i]).collect();
+        // The items are back in a list that may already have been traced
+        for &item in list.items.iter() {
+            vm.write_barrier(item);
+        }
+
+        Ok(Value::Nil)
+    }
+
+    fn map(vm: &mut VM, list: Gc<ObjList>, function: Value) -> Result<Value, String> {
+        let mut mapped = vm.alloc_obj(ObjList::new(vec![]));
+        let _mapped = vm.pin(Value::Obj(mapped.cast()));
+
+        // Items the callback appends aren't visited, and nothing can remove
+        // any
+        for i in 0..list.items.len() {
+            let result = vm.call_function(function, &[list.items[i]])?;
+            vm.write_barrier(result);
+            mapped.items.push(result);
+        }
+
+        Ok(Value::Obj(mapped.cast()))
+    }
+
+    fn filter(vm: &mut VM, list: Gc<ObjList>, function: Value) -> Result<Value, String> {
+        let mut filtered = vm.alloc_obj(ObjList::new(vec![]));
+        let _filtered = vm.pin(Value::Obj(filtered.cast()));
+
+        for i in 0..list.items.len() {
+            let item = list.items[i];
+            if !vm.call_function(function, &[item])?.is_falsey() {
+                vm.write_barrier(item);
+                filtered.items.push(item);
+            }
+        }
+
+        Ok(Value::Obj(filtered.cast()))
+    }
+
+    fn reduce(
+        vm: &mut VM,
+        list: Gc<ObjList>,
+        function: Value,
+        init: Value,
+    ) -> Result<Value, String> {
+        // Nothing allocates between getting the accumulator back and passing
+        // it to the next call, which puts it on the stack
+        let mut acc = init;
+        for i in 0..list.items.len() {
+            acc = vm.call_function(function, &[acc, list.items[i]])?;
+        }
+
+        Ok(acc)
+    }
+}
+
+/// A stable sort of `items` that stops at the first error from `le`, which
+/// says whether its first argument can go before its second. Unlike the
+/// standard library's sorts it's fine with a comparator that isn't a total
+/// order, which a Lox one needn't be.
+fn merge_sort<T: Copy>(
+    items: &mut [T],
+    le: &mut dyn FnMut(T, T) -> Result<bool, String>,
+) -> Result<(), String> {
+    if items.len() < 2 {
+        return Ok(());
+    }
+
+    let mid = items.len() / 2;
+    merge_sort(&mut items[..mid], le)?;
+    merge_sort(&mut items[mid..], le)?;
+
+    let (left, right) = items.split_at(mid);
+    let mut merged = Vec::with_capacity(items.len());
+    let (mut l, mut r) = (0, 0);
+    while l < left.len() && r < right.len() {
+        if le(left[l], right[r])? {
+            merged.push(left[l]);
+            l += 1;
+        } else {
+            merged.push(right[r]);
+            r += 1;
+        }
+    }
+    merged.extend_from_slice(&left[l..]);
+    merged.extend_from_slice(&right[r..]);
+    items.copy_from_slice(&merged);
+
+    Ok(())
+}
+
+/// An instance of a new class called `class`, with `fields`. It's how
+/// natives return several named values, since Lox has no maps. Object
+/// values in `fields` have to be rooted by the caller.
+fn record(vm: &mut VM, class: &str, fields: &[(&str, Value)]) -> Value {
+    let name = vm.copy_string(class);
+    let _name = vm.pin(Value::Obj(name.cast()));
+    let class = vm.alloc_obj(ObjClass::new(name.as_non_null_ptr()));
+    let _class = vm.pin(Value::Obj(class.cast()));
+    let mut instance = vm.alloc_obj(ObjInstance::new(class));
+    let _instance = vm.pin(Value::Obj(instance.cast()));
+
+    for &(name, value) in fields {
+        let name = vm.copy_string(name);
+        vm.write_barrier(Value::Obj(name.cast()));
+        vm.write_barrier(value);
+        instance.fields.set(name.as_non_null_ptr(), value);
+    }
+
+    Value::Obj(instance.cast())
+}
+
+/// What `typeof` says `value` is: "number", "string", "function", "nil",
+/// "bool", the name of an instance's class, or for the rest what
+/// [`ValueKind`] calls it
+pub fn type_name(value: Value) -> String {
+    match value.kind() {
+        ValueKind::Bool => "bool".into(),
+        ValueKind::Obj(ObjKind::Native | ObjKind::BoundMethod) => "function".into(),
+        ValueKind::Obj(ObjKind::Instance) => {
+            let instance = value.as_instance_fn().unwrap();
+            unsafe { instance.class.name.as_ref() }.as_str().into()
+        }
+        kind => kind.to_string(),
+    }
+}
+
+/// Fill in the `{}` placeholders in `template` with `values`, in order.
+///
+/// A placeholder can have a width and a precision, like `{:8.2}`: numbers
+/// are right-aligned and shown with that many decimals, everything else is
+/// left-aligned. `{{` and `}}` are literal braces.
+pub fn format_values(template: &str, values: &[Value]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut values = values.iter();
+    let mut used = 0;
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or("Unterminated '{' in format string.")?;
+                let spec = &rest[..end];
+                chars = rest[end + 1..].chars();
+
+                let (width, precision) = parse_spec(spec)?;
+                let value = values.next().ok_or_else(|| {
+                    format!("Format string has more placeholders than the {used} values given.")
+                })?;
+                used += 1;
+
+                match (value, precision) {
+                    (Value::Number(n), Some(precision)) => {
+                        out.push_str(&format!("{n:>width$.precision$}"))
+                    }
+                    (Value::Number(n), None) => out.push_str(&format!("{n:>width$}")),
+                    (_, Some(_)) => return Err("Precision can only be used with numbers.".into()),
+                    (value, None) => out.push_str(&format!("{:width$}", value.to_string())),
+                }
+            }
+            '}' => return Err("Unmatched '}' in format string.".into()),
+            c => out.push(c),
+        }
+    }
+
+    let extra = values.count();
+    if extra > 0 {
+        return Err(format!(
+            "Format string has {used} placeholders but {} values were given.",
+            used + extra
+        ));
+    }
+
+    Ok(out)
+}
+
+/// The width and precision in a placeholder, from `""` or `":width.precision"`
+/// where both parts are optional
+fn parse_spec(spec: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || format!("Invalid placeholder '{{{spec}}}'.");
+    // Rust's formatting panics on anything wider
+    let too_wide = || {
+        format!(
+            "Placeholder '{{{spec}}}' is too wide, the most is {}.",
+            u16::MAX
+        )
+    };
+    if spec.is_empty() {
+        return Ok((0, None));
+    }
+
+    let spec = spec.strip_prefix(':').ok_or_else(invalid)?;
+    let (width, precision) = match spec.split_once('.') {
+        Some((width, precision)) => (width, Some(precision)),
+        None => (spec, None),
+    };
+
+    let width = match width {
+        "" => 0,
+        width => width.parse().map_err(|_| invalid())?,
+    };
+    let precision = match precision {
+        Some(precision) => Some(precision.parse().map_err(|_| invalid())?),
+        None => None,
+    };
+
+    if width.max(precision.unwrap_or(0)) > u16::MAX as usize {
+        return Err(too_wide());
+    }
+    Ok((width, precision))
+}
+
+/// How many levels of lists and instances `inspect` shows by default
+pub const INSPECT_DEPTH: usize = 4;
+
+/// Render `value` for debugging, with lists and instances spread over
+/// indented lines. Anything nested deeper than `max_depth` is elided as
+/// `...`, and a container that contains itself shows up as `<cycle>`.
+pub fn inspect(value: Value, max_depth: usize) -> String {
+    let mut out = String::new();
+    inspect_into(&mut out, value, 0, max_depth, &mut vec![]);
+    out
+}
+
+/// A copy of `value` with every list, float array and instance reachable
+/// from it copied too, each once, so structure that's shared or cyclic in
+/// the original is in the copy. Everything else is shared with the original:
+/// strings, numbers and the like can't change, and functions and classes
+/// aren't data.
+///
+/// `value` has to be somewhere the GC can see, like the arguments of a
+/// native.
+pub fn deep_clone(vm: &mut VM, value: Value) -> Value {
+    struct Cloner {
+        /// Every copy, keeping them alive until they're all linked up
+        made: Gc<ObjList>,
+        copies: HashMap<*mut Obj, Value>,
+        /// Originals whose copies don't have their items or fields yet
+        unfilled: Vec<(Value, Value)>,
+    }
+
+    impl Cloner {
+        fn copy_of(&mut self, vm: &mut VM, value: Value) -> Value {
+            let Value::Obj(obj) = value else {
+                return value;
+            };
+            if let Some(&copy) = self.copies.get(&obj.as_ptr()) {
+                return copy;
+            }
+            let copy = if let Some(array) = value.as_float_array() {
+                let values = array.values.clone();
+                Value::Obj(vm.alloc_obj(ObjFloatArray::new(values)).cast())
+            } else if value.as_list().is_some() {
+                Value::Obj(vm.alloc_obj(ObjList::new(vec![])).cast())
+            } else if let Some(instance) = value.as_instance_fn() {
+                Value::Obj(vm.alloc_obj(ObjInstance::new(instance.class)).cast())
+            } else {
+                return value;
+            };
+            vm.write_barrier(copy);
+            self.made.items.push(copy);
+            self.copies.insert(obj.as_ptr(), copy);
+            self.unfilled.push((value, copy));
+            copy
+        }
+    }
+
+    let made = vm.alloc_obj(ObjList::new(vec![]));
+    let _made = vm.pin(Value::Obj(made.cast()));
+    let mut cloner = Cloner {
+        made,
+        copies: HashMap::new(),
+        unfilled: vec![],
+    };
+
+    let copy = cloner.copy_of(vm, value);
+    while let Some((original, copy)) = cloner.unfilled.pop() {
+        if let (Some(original), Some(mut copy)) = (original.as_list(), copy.as_list()) {
+            for &item in &original.items {
+                let item = cloner.copy_of(vm, item);
+                vm.write_barrier(item);
+                copy.items.push(item);
+            }
+        } else if let (Some(original), Some(mut copy)) =
+            (original.as_instance_fn(), copy.as_instance_fn())
+        {
+            for (name, value) in original.fields.iter() {
+                let value = cloner.copy_of(vm, value);
+                vm.write_barrier(value);
+                copy.fields.set(name, value);
+            }
+        }
+    }
+    copy
+}
+
+/// Whether `a` and `b` are equal, or are lists, float arrays or instances
+/// of the same class whose items or fields are, all the way down. Structures
+/// that are cyclic in the same way are equal.
+pub fn deep_equals(a: Value, b: Value) -> bool {
+    // Pairs already being compared are taken to be equal, so cycles end
+    let mut assumed = HashSet::new();
+    let mut pending = vec![(a, b)];
+    while let Some((a, b)) = pending.pop() {
+        if a == b {
+            continue;
+        }
+        let (Value::Obj(a_obj), Value::Obj(b_obj)) = (a, b) else {
+            return false;
+        };
+        if !assumed.insert((a_obj.as_ptr(), b_obj.as_ptr())) {
+            continue;
+        }
+
+        if let (Some(a), Some(b)) = (a.as_list(), b.as_list()) {
+            if a.items.len() != b.items.len() {
+                return false;
+            }
+            pending.extend(a.items.iter().copied().zip(b.items.iter().copied()));
+        } else if let (Some(a), Some(b)) = (a.as_float_array(), b.as_float_array()) {
+            if a.values != b.values {
+                return false;
+            }
+        } else if let (Some(a), Some(b)) = (a.as_instance_fn(), b.as_instance_fn()) {
+            if a.class.as_ptr() != b.class.as_ptr() || a.fields.len() != b.fields.len() {
+                return false;
+            }
+            for (name, value) in a.fields.iter() {
+                match b.fields.get(name) {
+                    Some(other) => pending.push((value, other)),
+                    None => return false,
+                }
+            }
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// What type `value` is at runtime, with what matters about a value of that
+/// type, e.g. "closure <fn add>, arity 2, 1 upvalue"
+pub fn describe_type(value: Value) -> String {
+    let plural = |count: usize, noun: &str| match count {
+        1 => format!("1 {noun}"),
+        _ => format!("{count} {noun}s"),
+    };
+
+    if let Some(closure) = value.as_obj_closure() {
+        let upvalues = plural(closure.upvalue_count as usize, "upvalue");
+        format!(
+            "closure {value}, arity {}, {upvalues}",
+            closure.function.arity
+        )
+    } else if let Some(method) = value.as_bound_method() {
+        let receiver = method.receiver;
+        let arity = method.method.function.arity;
+        format!("method {value}, arity {arity}, bound to {receiver}")
+    } else if let Some(string) = value.as_str() {
+        format!("string, {}", plural(string.len(), "byte"))
+    } else if let Some(list) = value.as_list() {
+        format!("list, {}", plural(list.items.len(), "item"))
+    } else if let Some(array) = value.as_float_array() {
+        format!("float array, {}", plural(array.values.len(), "number"))
+    } else if let Some(class) = value.as_class() {
+        format!(
+            "class {value}, {}",
+            plural(class.methods.iter().count(), "method")
+        )
+    } else if let Some(instance) = value.as_instance_fn() {
+        let class = Value::Obj(instance.class.cast());
+        format!(
+            "instance of {class}, {}",
+            plural(instance.fields.len(), "field")
+        )
+    } else {
+        value.kind().to_string()
+    }
+}
+
+/// `path` holds the containers `value` is nested in
+fn inspect_into(
+    out: &mut String,
+    value: Value,
+    depth: usize,
+    max_depth: usize,
+    path: &mut Vec<Value>,
+) {
+    if let Some(string) = value.as_str() {
+        out.push_str(&format!("{string:?}"));
+        return;
+    }
+
+    let (open, close, entries): (_, _, Vec<(Option<String>, Value)>) =
+        if let Some(list) = value.as_list() {
+            let items = list.items.iter().map(|&item| (None, item)).collect();
+            ("[".to_owned(), "]", items)
+        } else if let Some(instance) = value.as_instance_fn() {
+            let mut fields: Vec<_> = instance
+                .fields
+                .iter()
+                .map(|(name, value)| {
+                    let name = unsafe { name.as_ref() }.as_str().to_owned();
+                    (Some(name), value)
+                })
+                .collect();
+            // Table order depends on the hashes, and shapes on the order the
+            // fields were set
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            let class = Value::Obj(instance.class.cast());
+            (format!("{class} {{"), "}", fields)
+        } else {
+            out.push_str(&value.to_string());
+            return;
+        };
+
+    if path.contains(&value) {
+        out.push_str("<cycle>");
+        return;
+    }
+    let indent = "  ".repeat(depth + 1);
+
+    out.push_str(&open);
+    if entries.is_empty() {
+        out.push_str(close);
+        return;
+    }
+    if depth >= max_depth {
+        out.push_str("...");
+        out.push_str(close);
+        return;
+    }
+
+    path.push(value);
+    for (i, (name, entry)) in entries.into_iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str(&indent);
+        if let Some(name) = name {
+            out.push_str(&name);
+            out.push_str(": ");
+        }
+        inspect_into(out, entry, depth + 1, max_depth, path);
+    }
+    path.pop();
+
+    out.push('\n');
+    out.push_str(&indent[2..]);
+    out.push_str(close);
 }
@@ -0,0 +1,102 @@
+//! Plain TCP and HTTP for the networking natives. There's no TLS, so only
+//! `http://` URLs work.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+/// The TCP connections scripts have open. Scripts refer to them by their
+/// index, which isn't reused after they're closed.
+#[derive(Default)]
+pub struct Connections {
+    streams: Vec<Option<TcpStream>>,
+}
+
+impl Connections {
+    pub fn connect(&mut self, host: &str, port: u16) -> Result<usize, String> {
+        let stream = TcpStream::connect((host, port))
+            .map_err(|err| format!("Couldn't connect to {host}:{port}: {err}."))?;
+        self.streams.push(Some(stream));
+        Ok(self.streams.len() - 1)
+    }
+
+    /// Returns how many bytes were sent, which is all of them
+    pub fn send(&mut self, id: usize, bytes: &[u8]) -> Result<usize, String> {
+        self.stream(id)?
+            .write_all(bytes)
+            .map_err(|err| format!("Couldn't send: {err}."))?;
+        Ok(bytes.len())
+    }
+
+    /// Up to `max` bytes, empty once the other end has closed the connection
+    pub fn recv(&mut self, id: usize, max: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0; max];
+        let read = self
+            .stream(id)?
+            .read(&mut buf)
+            .map_err(|err| format!("Couldn't receive: {err}."))?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    pub fn close(&mut self, id: usize) -> Result<(), String> {
+        self.stream(id)?;
+        self.streams[id] = None;
+        Ok(())
+    }
+
+    fn stream(&mut self, id: usize) -> Result<&mut TcpStream, String> {
+        self.streams
+            .get_mut(id)
+            .and_then(Option::as_mut)
+            .ok_or_else(|| format!("There is no open connection {id}."))
+    }
+}
+
+/// Fetch `url` with a `GET` request, returning the status code and body.
+/// It's HTTP/1.0 so the server closes the connection after the body, and
+/// doesn't chunk it.
+pub fn http_get(url: &str) -> Result<(u16, Vec<u8>), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Only http:// URLs are supported, not '{url}'."))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid port in '{url}'."))?,
+        ),
+        None => (authority, 80),
+    };
+
+    let mut stream = TcpStream::connect((host, port))
+        .map_err(|err| format!("Couldn't connect to {host}:{port}: {err}."))?;
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {authority}\r\n\r\n")
+        .map_err(|err| format!("Couldn't send the request: {err}."))?;
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .map_err(|err| format!("Couldn't read the response: {err}."))?;
+
+    let malformed = || format!("Malformed response from '{url}'.");
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let status_line = response[..header_end]
+        .split(|&b| b == b'\r')
+        .next()
+        .ok_or_else(malformed)?;
+    let status = std::str::from_utf8(status_line)
+        .ok()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(malformed)?;
+
+    Ok((status, response[header_end + 4..].to_vec()))
+}
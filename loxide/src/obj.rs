@@ -1,19 +1,23 @@
 use std::{
     alloc::{self, Layout},
+    cell::RefCell,
     collections::VecDeque,
+    io::{self, Write},
     ptr::NonNull,
     slice,
 };
 
 use crate::{
-    chunk::Chunk,
+    chunk::{Chunk, ChunkView},
+    fields::{FieldStorage, Fields, Shape},
+    inline_cache::InlineCaches,
     mem::{Gc, Greystack},
     native_fn::NativeFnKind,
     table::{ObjHash, Table},
     value::Value,
 };
 
-pub type ObjList = VecDeque<Gc<Obj>>;
+pub type Objects = VecDeque<Gc<Obj>>;
 
 /// This is to enable type-safe functions generic over types that are type punnable to Obj
 pub trait ObjPunnable: Sized {
@@ -25,45 +29,53 @@ impl ObjPunnable for Obj {
         self.kind
     }
 }
-impl ObjPunnable for ObjNative {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Native
-    }
+
+/// An object type, which an `Obj` of its kind can be downcast to
+pub trait ObjType: Sized {
+    const KIND: ObjKind;
 }
-impl ObjPunnable for ObjUpvalue {
+
+impl<T: ObjType> ObjPunnable for T {
     fn kind(&self) -> ObjKind {
-        ObjKind::Upvalue
+        T::KIND
     }
 }
-impl ObjPunnable for ObjFunction {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Fn
-    }
+
+impl ObjType for ObjNative {
+    const KIND: ObjKind = ObjKind::Native;
 }
-impl ObjPunnable for ObjClosure {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Closure
-    }
+impl ObjType for ObjUpvalue {
+    const KIND: ObjKind = ObjKind::Upvalue;
 }
-impl ObjPunnable for ObjString {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Str
-    }
+impl ObjType for ObjFunction {
+    const KIND: ObjKind = ObjKind::Fn;
 }
-impl ObjPunnable for ObjClass {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Class
-    }
+impl ObjType for ObjClosure {
+    const KIND: ObjKind = ObjKind::Closure;
 }
-impl ObjPunnable for ObjInstance {
-    fn kind(&self) -> ObjKind {
-        ObjKind::Instance
-    }
+impl ObjType for ObjString {
+    const KIND: ObjKind = ObjKind::Str;
 }
-impl ObjPunnable for ObjBoundMethod {
-    fn kind(&self) -> ObjKind {
-        ObjKind::BoundMethod
-    }
+impl ObjType for ObjStringView {
+    const KIND: ObjKind = ObjKind::StrView;
+}
+impl ObjType for ObjClass {
+    const KIND: ObjKind = ObjKind::Class;
+}
+impl ObjType for ObjInstance {
+    const KIND: ObjKind = ObjKind::Instance;
+}
+impl ObjType for ObjBoundMethod {
+    const KIND: ObjKind = ObjKind::BoundMethod;
+}
+impl ObjType for ObjList {
+    const KIND: ObjKind = ObjKind::List;
+}
+impl ObjType for ObjFloatArray {
+    const KIND: ObjKind = ObjKind::FloatArray;
+}
+impl ObjType for ObjWeakRef {
+    const KIND: ObjKind = ObjKind::WeakRef;
 }
 
 #[repr(C)]
@@ -77,6 +89,46 @@ pub enum ObjKind {
     Class,
     Instance,
     BoundMethod,
+    List,
+    FloatArray,
+    StrView,
+    WeakRef,
+}
+
+impl ObjKind {
+    /// Every kind, in declaration order, so `ALL[kind as usize] == kind`
+    pub const ALL: [ObjKind; 12] = [
+        ObjKind::Str,
+        ObjKind::Fn,
+        ObjKind::Native,
+        ObjKind::Closure,
+        ObjKind::Upvalue,
+        ObjKind::Class,
+        ObjKind::Instance,
+        ObjKind::BoundMethod,
+        ObjKind::List,
+        ObjKind::FloatArray,
+        ObjKind::StrView,
+        ObjKind::WeakRef,
+    ];
+
+    /// The size of an object of this kind, without what it points to
+    pub fn size(self) -> usize {
+        match self {
+            ObjKind::Str => std::mem::size_of::<ObjString>(),
+            ObjKind::Fn => std::mem::size_of::<ObjFunction>(),
+            ObjKind::Native => std::mem::size_of::<ObjNative>(),
+            ObjKind::Closure => std::mem::size_of::<ObjClosure>(),
+            ObjKind::Upvalue => std::mem::size_of::<ObjUpvalue>(),
+            ObjKind::Class => std::mem::size_of::<ObjClass>(),
+            ObjKind::Instance => std::mem::size_of::<ObjInstance>(),
+            ObjKind::BoundMethod => std::mem::size_of::<ObjBoundMethod>(),
+            ObjKind::List => std::mem::size_of::<ObjList>(),
+            ObjKind::FloatArray => std::mem::size_of::<ObjFloatArray>(),
+            ObjKind::StrView => std::mem::size_of::<ObjStringView>(),
+            ObjKind::WeakRef => std::mem::size_of::<ObjWeakRef>(),
+        }
+    }
 }
 
 #[repr(C)]
@@ -115,6 +167,10 @@ pub struct ObjClass {
     pub obj: Obj,
     pub name: NonNull<ObjString>,
     pub methods: Table,
+    /// The empty shape its instances start with, see [`Shape`]
+    pub shapes: NonNull<Shape>,
+    /// Only for `is`, the methods were copied down when it inherited them
+    pub superclass: Option<Gc<ObjClass>>,
 }
 
 #[repr(C)]
@@ -128,7 +184,21 @@ pub struct ObjBoundMethod {
 pub struct ObjInstance {
     pub obj: Obj,
     pub class: Gc<ObjClass>,
-    pub fields: Table,
+    pub fields: Fields,
+}
+
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+/// Numbers stored unboxed, for numeric code that doesn't need a list's
+/// flexibility
+#[repr(C)]
+pub struct ObjFloatArray {
+    pub obj: Obj,
+    pub values: Vec<f64>,
 }
 
 #[repr(C)]
@@ -138,6 +208,8 @@ pub struct ObjFunction {
     pub chunk: Chunk,
     pub name: *mut ObjString,
     pub upvalue_count: u8,
+    /// Property lookups in `chunk`, see [`InlineCaches`]
+    pub caches: InlineCaches,
 }
 
 #[repr(C)]
@@ -148,6 +220,48 @@ pub struct ObjString {
     pub chars: NonNull<u8>,
 }
 
+/// Part of a string, from `slice` or `split`, sharing the string's
+/// characters instead of copying them. It isn't interned, so it's compared
+/// by its characters, and it keeps the whole string alive.
+#[repr(C)]
+pub struct ObjStringView {
+    pub obj: Obj,
+    /// Never a view itself, a view of a view shares the string underneath
+    pub parent: Gc<ObjString>,
+    /// In bytes, like `len`
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// `WeakRef(obj)`, which doesn't keep `obj` alive. Every weak ref with a
+/// target is in [`Mem::weak_refs`](crate::mem::Mem::weak_refs), a weak
+/// table like the interned strings, and loses its target when a collection
+/// finds nothing else referencing it.
+#[repr(C)]
+pub struct ObjWeakRef {
+    pub obj: Obj,
+    /// `None` once it's been collected
+    pub target: Option<Gc<Obj>>,
+}
+
+impl Gc<Obj> {
+    /// This object as a `T`, if it's one
+    #[inline]
+    pub fn downcast<T: ObjType>(self) -> Option<Gc<T>> {
+        match self.kind == T::KIND {
+            true => Some(self.cast()),
+            false => None,
+        }
+    }
+
+    /// This object as a `T`, when its kind has already been checked
+    #[inline]
+    pub fn downcast_unchecked<T: ObjType>(self) -> Gc<T> {
+        debug_assert_eq!(self.kind, T::KIND, "Downcast to the wrong kind of object");
+        self.cast()
+    }
+}
+
 impl Obj {
     pub unsafe fn blacken(obj: NonNull<Obj>, greystack: &mut Greystack) {
         #[cfg(feature = "debug_gc")]
@@ -175,12 +289,24 @@ impl Obj {
             }
             ObjKind::Upvalue => obj.cast::<ObjUpvalue>().as_ref().closed.mark(greystack),
             ObjKind::Native | ObjKind::Str => (),
+            ObjKind::StrView => Obj::mark(
+                obj.cast::<ObjStringView>().as_ref().parent.as_ptr().cast(),
+                greystack,
+            ),
             ObjKind::Class => {
                 Obj::mark(
                     obj.cast::<ObjClass>().as_ref().name.cast().as_ptr(),
                     greystack,
                 );
                 (*obj.cast::<ObjClass>().as_ref()).methods.mark(greystack);
+                obj.cast::<ObjClass>()
+                    .as_ref()
+                    .shapes
+                    .as_ref()
+                    .mark(greystack);
+                if let Some(superclass) = obj.cast::<ObjClass>().as_ref().superclass {
+                    Obj::mark(superclass.as_ptr().cast(), greystack);
+                }
             }
             ObjKind::Instance => {
                 let instance_ptr = obj.cast::<ObjInstance>().as_ptr();
@@ -192,6 +318,13 @@ impl Obj {
                 (*bound).receiver.mark(greystack);
                 Obj::mark((*bound).method.as_ptr() as *mut _, greystack);
             }
+            ObjKind::List => {
+                for item in obj.cast::<ObjList>().as_ref().items.iter() {
+                    item.mark(greystack)
+                }
+            }
+            // The target is only cleared, see `Mem::clear_weak_refs`
+            ObjKind::FloatArray | ObjKind::WeakRef => (),
         }
     }
 
@@ -276,15 +409,28 @@ impl Obj {
                 ObjKind::Class => {
                     let mut obj = Box::from_raw(obj as *mut ObjClass);
                     Table::free(&mut obj.methods);
+                    Shape::free(obj.shapes);
                 }
                 ObjKind::Instance => {
-                    Table::free(&mut (*(obj as *mut ObjInstance)).fields);
+                    (*(obj as *mut ObjInstance)).fields.free();
 
                     let _ = Box::from_raw(obj as *mut ObjInstance);
                 }
                 ObjKind::BoundMethod => {
                     let _ = Box::from_raw(obj as *mut ObjBoundMethod);
                 }
+                ObjKind::List => {
+                    let _ = Box::from_raw(obj as *mut ObjList);
+                }
+                ObjKind::FloatArray => {
+                    let _ = Box::from_raw(obj as *mut ObjFloatArray);
+                }
+                ObjKind::StrView => {
+                    let _ = Box::from_raw(obj as *mut ObjStringView);
+                }
+                ObjKind::WeakRef => {
+                    let _ = Box::from_raw(obj as *mut ObjWeakRef);
+                }
             }
         }
     }
@@ -366,6 +512,25 @@ impl std::fmt::Debug for ObjPtrWrapper {
                     .field("name", &ObjPtrWrapper(name.cast::<Obj>()))
                     .finish()
             },
+            ObjKind::List => {
+                let list = unsafe { ptr.cast::<ObjList>().as_ref() };
+                list.fmt_items(f, |item, f| write!(f, "{item:?}"))
+            }
+            ObjKind::FloatArray => {
+                let array = unsafe { ptr.cast::<ObjFloatArray>().as_ref() };
+                f.debug_tuple("FloatArray").field(&array.values).finish()
+            }
+            ObjKind::StrView => {
+                let view = unsafe { ptr.cast::<ObjStringView>().as_ref() };
+                write!(f, "{:?}", view.as_str())
+            }
+            ObjKind::WeakRef => {
+                let weak = unsafe { ptr.cast::<ObjWeakRef>().as_ref() };
+                let target = weak.target.map_or(std::ptr::null_mut(), Gc::as_ptr);
+                f.debug_tuple("WeakRef")
+                    .field(&ObjPtrWrapper(target))
+                    .finish()
+            }
         }
     }
 }
@@ -443,7 +608,23 @@ impl ObjClass {
             },
             name,
             methods: Table::new(),
+            shapes: Shape::root(),
+            superclass: None,
+        }
+    }
+}
+
+impl ObjClass {
+    /// Whether this is `class` or inherits from it
+    pub fn is_a(&self, class: Gc<ObjClass>) -> bool {
+        let mut current = Some(self);
+        while let Some(c) = current {
+            if std::ptr::eq(c, class.as_ptr()) {
+                return true;
+            }
+            current = c.superclass.as_ref().map(|superclass| superclass.as_ref());
         }
+        false
     }
 }
 
@@ -455,7 +636,7 @@ impl ObjInstance {
                 is_marked: false,
             },
             class,
-            fields: Table::new(),
+            fields: Fields::new(class.shapes),
         }
     }
 }
@@ -473,6 +654,51 @@ impl ObjBoundMethod {
     }
 }
 
+thread_local! {
+    /// The lists being formatted, outermost first
+    static FORMATTING: RefCell<Vec<*const ObjList>> = const { RefCell::new(vec![]) };
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::List,
+                is_marked: false,
+            },
+            items,
+        }
+    }
+
+    /// Write the items between brackets with `item`, and a list that
+    /// contains itself as `[...]` where it shows up again
+    pub fn fmt_items(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        item: fn(&Value, &mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+    ) -> std::fmt::Result {
+        let ptr = self as *const ObjList;
+        if FORMATTING.with(|lists| lists.borrow().contains(&ptr)) {
+            return write!(f, "[...]");
+        }
+
+        FORMATTING.with(|lists| lists.borrow_mut().push(ptr));
+        let result = (|| {
+            write!(f, "[")?;
+            for (i, value) in self.items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                item(value, f)?;
+            }
+            write!(f, "]")
+        })();
+        FORMATTING.with(|lists| lists.borrow_mut().pop());
+
+        result
+    }
+}
+
 impl ObjFunction {
     pub fn new(name: *mut ObjString) -> Self {
         Self {
@@ -484,8 +710,57 @@ impl ObjFunction {
             chunk: Chunk::new(),
             name,
             upvalue_count: 0,
+            caches: InlineCaches::default(),
+        }
+    }
+
+    pub fn name_str(&self) -> &str {
+        unsafe {
+            self.name
+                .as_ref()
+                .map(|name| name.as_str())
+                .unwrap_or("script")
         }
     }
+
+    /// Its bytecode, constants and line table, read-only
+    pub fn chunk_view(&self) -> ChunkView<'_> {
+        ChunkView::new(self)
+    }
+
+    /// Disassemble this function and every function nested in its constants,
+    /// in the order they were declared in the source
+    pub fn disassemble_all(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.disassemble_all_colored(out, false)
+    }
+
+    /// [`disassemble_all`](ObjFunction::disassemble_all), in color when
+    /// `color` is set
+    pub fn disassemble_all_colored(&self, out: &mut dyn Write, color: bool) -> io::Result<()> {
+        self.chunk
+            .disassemble_colored(self.name_str(), out, color)?;
+
+        for constant in self.chunk.constants.iter() {
+            if let Some(function) = constant.as_fn() {
+                function.disassemble_all_colored(out, color)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every function declared inside this one, however deeply, in the order
+    /// they were declared
+    pub fn nested_functions(&self) -> Vec<Gc<ObjFunction>> {
+        let mut out = vec![];
+        for constant in self.chunk.constants.iter() {
+            if let Some(function) = constant.as_fn() {
+                out.push(function);
+                out.extend(function.nested_functions());
+            }
+        }
+        out
+    }
 }
 
 impl ObjString {
@@ -508,3 +783,48 @@ impl ObjString {
         }
     }
 }
+
+impl ObjStringView {
+    pub fn as_str(&self) -> &str {
+        let offset = self.offset as usize;
+        &self.parent.as_str()[offset..offset + self.len as usize]
+    }
+
+    /// `string[start..end]`, where `start` and `end` are on character
+    /// boundaries
+    pub fn new(parent: Gc<ObjString>, start: u32, end: u32) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::StrView,
+                is_marked: false,
+            },
+            parent,
+            offset: start,
+            len: end - start,
+        }
+    }
+}
+
+impl ObjFloatArray {
+    pub fn new(values: Vec<f64>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::FloatArray,
+                is_marked: false,
+            },
+            values,
+        }
+    }
+}
+
+impl ObjWeakRef {
+    pub fn new(target: Gc<Obj>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::WeakRef,
+                is_marked: false,
+            },
+            target: Some(target),
+        }
+    }
+}
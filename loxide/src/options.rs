@@ -0,0 +1,205 @@
+//! Settings a [`VM`](crate::vm::VM) is created with.
+
+use std::{io::Write, rc::Rc};
+
+use crate::{
+    compile::DEFAULT_MAX_EXPRESSION_DEPTH, errors::ErrorFormat, native_fn::Capabilities,
+    passes::ChunkPass, vm::U8_COUNT,
+};
+
+/// How many call frames can be active at once by default
+pub const DEFAULT_FRAME_DEPTH: usize = 64;
+/// How many values fit on the stack by default, enough for every frame to
+/// use all its local slots
+pub const DEFAULT_STACK_SIZE: usize = DEFAULT_FRAME_DEPTH * U8_COUNT;
+/// How many bytes can be allocated before the first collection by default
+pub const DEFAULT_GC_THRESHOLD: usize = 1024 * 1024;
+
+/// Everything that can be configured about a VM up front, passed to
+/// [`VM::with_options`](crate::vm::VM::with_options).
+///
+/// ```
+/// # use loxide::{options::VmOptions, vm::VM};
+/// let vm = VM::with_options(VmOptions::new().frame_depth(256).stack_size(1 << 16));
+/// ```
+pub struct VmOptions {
+    pub(crate) stack_size: usize,
+    pub(crate) frame_depth: usize,
+    pub(crate) gc_threshold: usize,
+    pub(crate) trace: bool,
+    pub(crate) dump_bytecode: bool,
+    pub(crate) max_expression_depth: usize,
+    pub(crate) warn_undeclared: bool,
+    pub(crate) warn_unused: bool,
+    pub(crate) asi: bool,
+    pub(crate) strict: bool,
+    pub(crate) strict_equality: bool,
+    pub(crate) deterministic: bool,
+    pub(crate) verbose_errors: bool,
+    pub(crate) color: bool,
+    pub(crate) error_format: ErrorFormat,
+    pub(crate) capabilities: Capabilities,
+    pub(crate) passes: Vec<Rc<dyn ChunkPass>>,
+    pub(crate) output: Box<dyn Write>,
+}
+
+impl VmOptions {
+    pub fn new() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            frame_depth: DEFAULT_FRAME_DEPTH,
+            gc_threshold: DEFAULT_GC_THRESHOLD,
+            trace: cfg!(debug_assertions),
+            dump_bytecode: false,
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            warn_undeclared: false,
+            warn_unused: false,
+            asi: false,
+            strict: false,
+            strict_equality: false,
+            deterministic: false,
+            verbose_errors: false,
+            color: false,
+            error_format: ErrorFormat::Human,
+            capabilities: Capabilities::default(),
+            passes: vec![],
+            output: Box::new(std::io::stdout()),
+        }
+    }
+
+    /// How many values the stack holds. Calls fail with a stack overflow
+    /// once there isn't room for another frame's locals, so it can't be less
+    /// than that.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        assert!(
+            stack_size >= U8_COUNT,
+            "The stack must have room for at least {U8_COUNT} values."
+        );
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// How deep calls can nest before a stack overflow
+    pub fn frame_depth(mut self, frame_depth: usize) -> Self {
+        assert!(frame_depth > 0, "The script needs a frame to run in.");
+        self.frame_depth = frame_depth;
+        self
+    }
+
+    /// How many bytes can be allocated before the first collection. Later
+    /// ones are scheduled from how much survived.
+    pub fn gc_threshold(mut self, gc_threshold: usize) -> Self {
+        self.gc_threshold = gc_threshold;
+        self
+    }
+
+    /// Print the stack and each instruction as it runs. On by default in
+    /// debug builds.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Print the disassembly of every compiled function before running it
+    pub fn dump_bytecode(mut self, dump_bytecode: bool) -> Self {
+        self.dump_bytecode = dump_bytecode;
+        self
+    }
+
+    /// How deeply expressions can nest before it's a compile error
+    pub fn max_expression_depth(mut self, max_expression_depth: usize) -> Self {
+        self.max_expression_depth = max_expression_depth;
+        self
+    }
+
+    /// Warn when compiling an assignment to a global the script never
+    /// declares
+    pub fn warn_undeclared(mut self, warn_undeclared: bool) -> Self {
+        self.warn_undeclared = warn_undeclared;
+        self
+    }
+
+    /// Warn when compiling a script that declares functions or globals it
+    /// never uses
+    pub fn warn_unused(mut self, warn_unused: bool) -> Self {
+        self.warn_unused = warn_unused;
+        self
+    }
+
+    /// Let a newline stand in for a missing `;` at the end of a statement,
+    /// see [`Parser::asi`](crate::compile::Parser::asi)
+    pub fn asi(mut self, asi: bool) -> Self {
+        self.asi = asi;
+        self
+    }
+
+    /// Compile everything in strict mode, like files starting with
+    /// `//#strict`: assigning to a global that's never declared and other
+    /// warnings are compile errors, and `==` and `!=` on different types
+    /// and arithmetic with a result that isn't finite are runtime errors
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Make `==` and `!=` a runtime error when the operands are different
+    /// types, rather than false or true. Anything can still be compared
+    /// with nil.
+    pub fn strict_equality(mut self, strict_equality: bool) -> Self {
+        self.strict_equality = strict_equality;
+        self
+    }
+
+    /// Make calling a native whose result can differ from one run to the
+    /// next a runtime error, so a script's output can be reproduced
+    /// exactly, see [`NativeFnKind::is_deterministic`](crate::native_fn::NativeFnKind::is_deterministic)
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Print the locals of the frame a runtime error happened in, after
+    /// the stack trace
+    pub fn verbose_errors(mut self, verbose_errors: bool) -> Self {
+        self.verbose_errors = verbose_errors;
+        self
+    }
+
+    /// Write compile errors, stack traces and bytecode listings in color,
+    /// see [`color`](crate::color)
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Write compile errors and runtime errors to stderr as JSON, or as
+    /// text, see [`ErrorFormat`]
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Run `pass` over each function's bytecode as it's compiled, after
+    /// the passes added before it, see [`passes`](crate::passes)
+    pub fn pass(mut self, pass: impl ChunkPass + 'static) -> Self {
+        self.passes.push(Rc::new(pass));
+        self
+    }
+
+    /// Where `print` writes to
+    pub fn output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
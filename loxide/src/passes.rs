@@ -0,0 +1,64 @@
+//! Passes over bytecode, run on each function as the compiler finishes it,
+//! so an experiment like a peephole optimization or injected
+//! instrumentation doesn't need changes to the compiler itself.
+//!
+//! Register one with [`VmOptions::pass`](crate::options::VmOptions::pass),
+//! or by adding to [`VM::passes`](crate::vm::VM::passes). They run in the
+//! order they were registered, on nested functions before the function
+//! they're declared in, and not at all on a program with compile errors.
+//!
+//! Nothing checks what a pass leaves behind. A chunk that changes size has
+//! to keep [`Chunk::lines`] and [`Chunk::columns`] as long as its code, with
+//! [`Chunk::write`], and fix up the jumps over what moved.
+
+use crate::chunk::{Chunk, Opcode};
+
+pub trait ChunkPass {
+    fn run(&self, chunk: &mut Chunk);
+}
+
+/// Reports every call of the functions it's run on to
+/// [`VmEvents::function_entered`](crate::events::VmEvents::function_entered)
+/// and every return to
+/// [`VmEvents::function_returned`](crate::events::VmEvents::function_returned),
+/// for `loxide --trace-calls` or a profiler timing functions.
+///
+/// A `TraceEnter` goes in front of the function's code, which moves nothing
+/// a jump goes to, and each `Return` becomes a `TraceReturn`. The script
+/// gets them too, but isn't reported.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CallTracing;
+
+impl ChunkPass for CallTracing {
+    fn run(&self, chunk: &mut Chunk) {
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let start = offset;
+            if chunk.disassemble_instruction(&mut offset).is_none() {
+                break;
+            }
+            if chunk.code[start] == Opcode::Return as u8 {
+                chunk.code[start] = Opcode::TraceReturn as u8;
+            }
+        }
+
+        let line = chunk.lines.first().copied().unwrap_or(0);
+        let column = chunk.columns.first().copied().unwrap_or(0);
+        chunk.code.insert(0, Opcode::TraceEnter as u8);
+        chunk.lines.insert(0, line);
+        chunk.columns.insert(0, column);
+        for local in chunk.locals.iter_mut() {
+            // Parameters are in scope from the start, which is still 0
+            if local.start > 0 {
+                local.start += 1;
+            }
+            local.end = local.end.saturating_add(1);
+        }
+    }
+}
+
+impl<F: Fn(&mut Chunk)> ChunkPass for F {
+    fn run(&self, chunk: &mut Chunk) {
+        self(chunk)
+    }
+}
@@ -0,0 +1,279 @@
+//! A profile of which lines a script spends its time on, printed by
+//! `loxide --profile-lines` after the script's source, one line at a time.
+//!
+//! Time is estimated from how many instructions ran rather than measured:
+//! each line gets the share of the run's time that its instructions were of
+//! all of them. A line's cumulative count includes the instructions of the
+//! calls it made, so a line calling a slow function shows up as slow too.
+//! Recursive calls are counted at every level they're made from.
+//!
+//! Also how many times each opcode ran, written by `loxide
+//! --opcode-histogram file` as a JSON object from opcode names to counts,
+//! so `loxide profile-diff before.json after.json` can show what a change
+//! to the compiler or the VM did to them.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::chunk::Opcode;
+
+/// Counts for one line of the source
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LineCounts {
+    /// How many times the line started running
+    pub hits: u64,
+    /// Instructions compiled for the line that ran
+    pub instructions: u64,
+    /// The same plus the instructions of everything it called
+    pub cumulative: u64,
+}
+
+#[derive(Debug)]
+pub struct LineProfile {
+    /// Indexed by line number
+    lines: Vec<LineCounts>,
+    instructions: u64,
+    /// The line of each call being made, with the instruction count when
+    /// it was made, outermost first
+    calls: Vec<(u32, u64)>,
+    /// The line and call depth of the last instruction
+    last: (u32, u32),
+    started: Instant,
+}
+
+impl LineProfile {
+    pub fn new() -> Self {
+        Self {
+            lines: vec![],
+            instructions: 0,
+            calls: vec![],
+            last: (0, 0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Count an instruction on `line`, run `depth` frames deep
+    #[inline]
+    pub fn record(&mut self, line: u32, depth: u32) {
+        // Returned from calls since the last instruction, to the line of the
+        // outermost one
+        let mut returned_to = None;
+        while self.calls.len() as u32 + 1 > depth {
+            let Some((call_line, start)) = self.calls.pop() else {
+                break;
+            };
+            let instructions = self.instructions;
+            self.counts(call_line).cumulative += instructions - start;
+            returned_to = Some(call_line);
+        }
+        // Called something, from the line the last instruction was on
+        while (self.calls.len() as u32) + 1 < depth {
+            self.calls.push((self.last.0, self.instructions));
+        }
+
+        let hit = match returned_to {
+            Some(call_line) => call_line != line,
+            None => (line, depth) != self.last,
+        };
+        let counts = self.counts(line);
+        counts.hits += hit as u64;
+        counts.instructions += 1;
+        counts.cumulative += 1;
+        self.instructions += 1;
+        self.last = (line, depth);
+    }
+
+    fn counts(&mut self, line: u32) -> &mut LineCounts {
+        let line = line as usize;
+        if line >= self.lines.len() {
+            self.lines.resize(line + 1, LineCounts::default());
+        }
+        &mut self.lines[line]
+    }
+
+    /// What's been counted for `line`, with the calls still running
+    /// counted up to now
+    pub fn line(&self, line: u32) -> LineCounts {
+        let mut counts = self.lines.get(line as usize).copied().unwrap_or_default();
+        for &(call_line, start) in &self.calls {
+            if call_line == line {
+                counts.cumulative += self.instructions - start;
+            }
+        }
+        counts
+    }
+
+    /// Instructions counted on every line
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The share of `elapsed` that `instructions` of them took
+    fn estimate(&self, instructions: u64, elapsed: Duration) -> Duration {
+        match self.instructions {
+            0 => Duration::ZERO,
+            total => elapsed.mul_f64(instructions as f64 / total as f64),
+        }
+    }
+
+    /// Print `src` with each line's counts and estimated cumulative time in
+    /// the margin, timed from when profiling started until now
+    pub fn write_annotated(&self, src: &str, out: &mut dyn Write) -> io::Result<()> {
+        self.write_annotated_over(src, self.started.elapsed(), out)
+    }
+
+    /// [`write_annotated`](LineProfile::write_annotated), with the run
+    /// having taken `elapsed`
+    pub fn write_annotated_over(
+        &self,
+        src: &str,
+        elapsed: Duration,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:>6} {:>9} {:>11} {:>6} {:>11} {:>10} | source",
+            "line", "hits", "instrs", "%", "cum. instrs", "cum. time"
+        )?;
+        for (i, text) in src.lines().enumerate() {
+            let line = i as u32 + 1;
+            let counts = self.line(line);
+            if counts.hits == 0 {
+                writeln!(out, "{line:>6} {:>51} | {text}", "")?;
+                continue;
+            }
+            writeln!(
+                out,
+                "{line:>6} {:>9} {:>11} {:>5.1}% {:>11} {:>10} | {text}",
+                counts.hits,
+                counts.instructions,
+                counts.instructions as f64 * 100.0 / self.instructions as f64,
+                counts.cumulative,
+                format!("{:.1?}", self.estimate(counts.cumulative, elapsed)),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for LineProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How many times each opcode ran
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeHistogram {
+    /// By opcode number
+    pub counts: [u64; Opcode::ALL.len()],
+}
+
+impl OpcodeHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: [0; Opcode::ALL.len()],
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, byte: u8) {
+        if let Some(count) = self.counts.get_mut(byte as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn count(&self, op: Opcode) -> u64 {
+        self.counts[op as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// A JSON object of the opcodes that ran, in opcode order
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        let entries: Vec<_> = Opcode::ALL
+            .iter()
+            .filter(|&&op| self.count(op) > 0)
+            .map(|&op| format!("  \"{}\": {}", op.as_str(), self.count(op)))
+            .collect();
+        match entries.is_empty() {
+            true => writeln!(out, "{{}}"),
+            false => writeln!(out, "{{\n{}\n}}", entries.join(",\n")),
+        }
+    }
+
+    /// Read what [`write_json`](OpcodeHistogram::write_json) wrote: a flat
+    /// object of opcode names and counts, ones it leaves out being 0
+    pub fn read_json(json: &str) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let body = json
+            .trim()
+            .strip_prefix('{')
+            .and_then(|rest| rest.strip_suffix('}'))
+            .ok_or_else(|| invalid("Expected a JSON object of opcode counts.".to_string()))?;
+
+        let mut histogram = Self::new();
+        for entry in body.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (name, count) = entry
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("Expected \"opcode\": count, got {entry:?}.")))?;
+            let name = name.trim().trim_matches('"');
+            let op = Opcode::ALL
+                .iter()
+                .find(|op| op.as_str() == name)
+                .ok_or_else(|| invalid(format!("Unknown opcode {name:?}.")))?;
+            histogram.counts[*op as usize] = count
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("Bad count for {name}: {:?}.", count.trim())))?;
+        }
+        Ok(histogram)
+    }
+}
+
+impl Default for OpcodeHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A line per opcode that ran in either, with how its count changed from
+/// `before` to `after`, the biggest changes first, then the totals
+pub fn write_histogram_diff(
+    before: &OpcodeHistogram,
+    after: &OpcodeHistogram,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut ops: Vec<_> = Opcode::ALL
+        .iter()
+        .copied()
+        .filter(|&op| before.count(op) > 0 || after.count(op) > 0)
+        .collect();
+    let delta = |op: Opcode| after.count(op) as i128 - before.count(op) as i128;
+    ops.sort_by_key(|&op| (std::cmp::Reverse(delta(op).abs()), op as u8));
+
+    writeln!(
+        out,
+        "{:<14} {:>12} {:>12} {:>12} {:>9}",
+        "opcode", "before", "after", "delta", "change"
+    )?;
+    let mut row = |name: &str, before: u64, after: u64| {
+        let change = match before {
+            0 => "new".to_string(),
+            _ => format!("{:+.1}%", (after as f64 / before as f64 - 1.0) * 100.0),
+        };
+        writeln!(
+            out,
+            "{name:<14} {before:>12} {after:>12} {:>+12} {change:>9}",
+            after as i128 - before as i128
+        )
+    };
+    for op in ops {
+        row(op.as_str(), before.count(op), after.count(op))?;
+    }
+    row("total", before.total(), after.total())
+}
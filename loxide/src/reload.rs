@@ -0,0 +1,111 @@
+//! Swapping in a new version of a function while the program is running.
+
+use crate::{
+    compile::Parser,
+    mem::Gc,
+    obj::{ObjClosure, ObjFunction, ObjKind},
+    value::Value,
+    vm::VM,
+};
+
+impl VM {
+    /// Recompile the function called `name` from `src`, and make everything
+    /// that would run the old version run the new one instead: existing
+    /// closures, bound methods, and closures created later on.
+    ///
+    /// `src` declares the function, and can declare other things around it,
+    /// which are ignored. To reload a method, declare it in a class there so
+    /// `this` compiles. Variables the old version captured stay captured, and
+    /// are matched to the new code by name.
+    ///
+    /// Fails if the function is running, since its frames would be left
+    /// pointing into the old code.
+    pub fn reload_function(&mut self, name: &str, src: &str) -> Result<(), String> {
+        let old = self.find_function(name)?;
+
+        let running = self.call_frames[..self.call_frame_count as usize]
+            .iter()
+            .any(|frame| unsafe { frame.assume_init_ref() }.closure.function == old);
+        if running {
+            return Err(format!("Can't reload `{name}` while it is running."));
+        }
+
+        let upvalues: Vec<_> = old.chunk.upvalue_names.iter().map(String::as_str).collect();
+        let script = {
+            let mut parser = Parser::new(src, &mut self.mem);
+            parser.print_errors = false;
+            parser.passes = self.passes.clone();
+            parser.strict = self.strict;
+            if !parser.compile_reload(&upvalues) {
+                let errors: Vec<_> = parser.diagnostics.iter().map(ToString::to_string).collect();
+                return Err(errors.join(" "));
+            }
+            parser.compiler.function
+        };
+
+        let new = script
+            .nested_functions()
+            .into_iter()
+            .find(|function| function.name_str() == name)
+            .ok_or_else(|| format!("`{name}` isn't declared in the new source."))?;
+        if new.upvalue_count != old.upvalue_count {
+            return Err(format!(
+                "The new `{name}` captures variables the old one didn't."
+            ));
+        }
+
+        for &obj in self.mem.obj_list.iter() {
+            match obj.kind {
+                ObjKind::Closure => {
+                    let mut closure: Gc<ObjClosure> = obj.cast();
+                    if closure.function == old {
+                        closure.function = new;
+                    }
+                }
+                // So closures made later from the enclosing function's
+                // constant are new too
+                ObjKind::Fn => {
+                    let mut function: Gc<ObjFunction> = obj.cast();
+                    for constant in function.chunk.constants.iter_mut() {
+                        if constant.as_fn() == Some(old) {
+                            *constant = Value::Obj(new.cast());
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Closures that were already marked now point to something that
+        // might not be
+        self.write_barrier(Value::Obj(new.cast()));
+
+        Ok(())
+    }
+
+    /// The only live function called `name`
+    fn find_function(&mut self, name: &str) -> Result<Gc<ObjFunction>, String> {
+        // Otherwise an old version of a function that's been redeclared could
+        // look like a second one
+        self.collect_all_garbage();
+
+        let mut found: Option<Gc<ObjFunction>> = None;
+        for &obj in self.mem.obj_list.iter() {
+            if obj.kind != ObjKind::Fn {
+                continue;
+            }
+            let function: Gc<ObjFunction> = obj.cast();
+            if function.name_str() != name {
+                continue;
+            }
+            match found {
+                Some(other) if other != function => {
+                    return Err(format!("There is more than one function called `{name}`."))
+                }
+                _ => found = Some(function),
+            }
+        }
+
+        found.ok_or_else(|| format!("There is no function called `{name}`."))
+    }
+}
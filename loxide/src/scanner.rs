@@ -0,0 +1,827 @@
+use std::{
+    cell::RefCell,
+    io::{self, BufRead},
+};
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::errors::ErrorCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // Single-character tokens.
+    LeftParen = 0,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Colon,
+
+    // One or two character tokens.
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    QuestionDot,
+    QuestionQuestion,
+
+    // Literals.
+    Identifier,
+    String,
+    Number,
+
+    // Keywords.
+    And,
+    Class,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    Is,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Super,
+    This,
+    True,
+    Var,
+    While,
+
+    Error,
+    Eof,
+
+    Synthetic,
+}
+
+/// Byte range of a token in the source it was scanned from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    pub const EMPTY: Self = Span { start: 0, end: 0 };
+
+    #[inline]
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Token<'src> {
+    pub kind: TokenKind,
+    pub line: u32,
+    /// Where on its line the token starts, counting bytes from 1. Zero for
+    /// synthetic tokens.
+    pub column: u32,
+    /// The lexeme, or the error message for `TokenKind::Error` tokens
+    pub msg: &'src str,
+    pub span: Span,
+}
+
+impl<'src> Token<'src> {
+    pub fn synthetic(msg: &'src str) -> Self {
+        Self {
+            kind: TokenKind::Synthetic,
+            line: u32::MAX,
+            column: 0,
+            msg,
+            span: Span::EMPTY,
+        }
+    }
+}
+
+const KEYWORD_LIST: [(&str, TokenKind); 17] = [
+    ("and", TokenKind::And),
+    ("class", TokenKind::Class),
+    ("else", TokenKind::Else),
+    ("false", TokenKind::False),
+    ("for", TokenKind::For),
+    ("fun", TokenKind::Fun),
+    ("if", TokenKind::If),
+    ("is", TokenKind::Is),
+    ("nil", TokenKind::Nil),
+    ("or", TokenKind::Or),
+    ("print", TokenKind::Print),
+    ("return", TokenKind::Return),
+    ("super", TokenKind::Super),
+    ("this", TokenKind::This),
+    ("true", TokenKind::True),
+    ("var", TokenKind::Var),
+    ("while", TokenKind::While),
+];
+
+const KEYWORD_SLOTS: usize = 32;
+
+/// The keywords in the slots `keyword_slot` gives them. No two share one,
+/// so telling whether an identifier is a keyword takes one comparison.
+const KEYWORDS: [Option<(&str, TokenKind)>; KEYWORD_SLOTS] = {
+    let mut table = [None; KEYWORD_SLOTS];
+    let mut i = 0;
+    while i < KEYWORD_LIST.len() {
+        let (keyword, kind) = KEYWORD_LIST[i];
+        let slot = keyword_slot(keyword.as_bytes());
+        assert!(table[slot].is_none(), "Two keywords have the same slot.");
+        table[slot] = Some((keyword, kind));
+        i += 1;
+    }
+    table
+};
+
+/// A perfect hash of the keywords, from their first and last letters and
+/// their length. `lexeme` can't be empty.
+const fn keyword_slot(lexeme: &[u8]) -> usize {
+    (lexeme[0] as usize + 5 * lexeme[lexeme.len() - 1] as usize + lexeme.len()) % KEYWORD_SLOTS
+}
+
+/// One copy of each identifier seen so far, and of `this` and `super`.
+/// Tokens for the same identifier get the same lexeme, so they can be
+/// compared by pointer.
+#[derive(Default)]
+pub struct Interner<'src> {
+    /// FNV rather than SipHash, identifiers are short and hashing them is
+    /// most of the cost
+    lexemes: FnvHashMap<&'src str, &'src str>,
+}
+
+impl<'src> Interner<'src> {
+    pub fn intern(&mut self, lexeme: &'src str) -> &'src str {
+        self.lexemes.entry(lexeme).or_insert(lexeme)
+    }
+}
+
+/// The lexemes of a source scanned with [`Scanner::streaming`], one copy of
+/// each, kept for as long as the tokens are. Only what tokens are made of is
+/// kept, not the whitespace and comments between them, and a name used all
+/// over a program is kept once.
+#[derive(Default)]
+pub struct Lexemes {
+    // Never removed from, so the strings don't move while they're borrowed
+    strings: RefCell<FnvHashSet<Box<str>>>,
+}
+
+impl Lexemes {
+    pub fn intern(&self, lexeme: &str) -> &str {
+        let mut strings = self.strings.borrow_mut();
+        let string: *const str = match strings.get(lexeme) {
+            Some(string) => &**string,
+            None => {
+                let string = Box::<str>::from(lexeme);
+                let ptr: *const str = &*string;
+                strings.insert(string);
+                ptr
+            }
+        };
+        unsafe { &*string }
+    }
+
+    /// How many different lexemes there are
+    pub fn len(&self) -> usize {
+        self.strings.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many bytes they take up together
+    pub fn bytes(&self) -> usize {
+        self.strings
+            .borrow()
+            .iter()
+            .map(|string| string.len())
+            .sum()
+    }
+}
+
+/// Where a streamed source comes from, see [`Scanner::streaming`]
+struct Stream<'src> {
+    reader: Box<dyn BufRead + 'src>,
+    /// What's been read and not scanned yet, plus the token being scanned
+    /// and, unless it's too far back, the start of the line the token
+    /// before it is on. The scanner's `src` is this.
+    buf: Vec<u8>,
+    /// Where the line of the token before the one being scanned starts, in
+    /// the whole source. Errors are often reported at that token, after the
+    /// parser's scanned the next one.
+    previous_line: usize,
+    /// Whether `buf` starts at the start of a line
+    at_line_start: bool,
+    lexemes: &'src Lexemes,
+    error: Option<io::Error>,
+}
+
+/// The most of the source before the token being scanned that a streamed
+/// source keeps, to quote its lines in errors
+const KEPT_LINES: usize = 256;
+
+/// Every token in `src`, one per line as its line and column, kind and
+/// lexeme.
+/// It's the format of the scanner's golden files, so changing it means
+/// regenerating them.
+pub fn dump(src: &str) -> String {
+    let mut out = String::new();
+    for token in Scanner::new(src) {
+        out.push_str(&format!(
+            "{}:{} {:?} {:?}\n",
+            token.line, token.column, token.kind, token.msg
+        ));
+    }
+    out
+}
+
+/// The value of a number token's lexeme. Hex and binary ones too big to be
+/// exact round like decimal ones do.
+pub fn number_value(lexeme: &str) -> f64 {
+    let digits = lexeme.replace('_', "");
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        _ => return digits.parse().unwrap(),
+    };
+    match u128::from_str_radix(digits, radix) {
+        Ok(value) => value as f64,
+        Err(_) => digits.chars().fold(0.0, |value, digit| {
+            value * radix as f64 + digit.to_digit(radix).unwrap() as f64
+        }),
+    }
+}
+
+/// Turns source text into tokens on demand.
+///
+/// The compiler pulls tokens one at a time with `Scanner::token()`, but the
+/// scanner can also be used on its own as an iterator, which yields every
+/// token up to and including the final `TokenKind::Eof`.
+pub struct Scanner<'src> {
+    /// All of the source, or what's buffered of a streamed one
+    src: &'src [u8],
+    /// The offset in the whole source of `src[0]`, which moves on as a
+    /// streamed source is read
+    base: usize,
+    start: usize,
+    current: usize,
+    line: usize,
+    /// Where the line being scanned starts, in the whole source
+    line_start: usize,
+    /// The line and column the token being scanned starts at, which for a
+    /// string running over several lines isn't the line it ends on
+    token_line: u32,
+    column: u32,
+    reached_eof: bool,
+    /// Only when asked for, with [`Scanner::interning`]
+    interner: Option<Interner<'src>>,
+    stream: Option<Box<Stream<'src>>>,
+    /// Whether no token's been scanned yet, so a comment is a directive
+    leading: bool,
+    /// Whether the source starts with a `//#strict` directive, before any
+    /// token
+    pub strict: bool,
+}
+
+impl<'src> Iterator for Scanner<'src> {
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reached_eof {
+            return None;
+        }
+
+        let token = self.token();
+        self.reached_eof = token.kind == TokenKind::Eof;
+        Some(token)
+    }
+}
+
+impl<'src> Scanner<'src> {
+    pub fn new(src: &'src str) -> Self {
+        Self {
+            src: src.as_bytes(),
+            base: 0,
+            start: 0,
+            current: 0,
+            line: 1,
+            line_start: 0,
+            token_line: 1,
+            column: 1,
+            reached_eof: false,
+            interner: None,
+            stream: None,
+            leading: true,
+            strict: false,
+        }
+    }
+
+    /// Scan what `reader` reads a buffer at a time, rather than a source
+    /// that's all in memory. Only the token being scanned is buffered, the
+    /// lexemes of the tokens are copied into `lexemes`. Lines, columns and
+    /// spans are where they'd be in the whole source.
+    ///
+    /// The source ends where reading it fails, with the error kept for
+    /// [`Scanner::read_error`]. Text that isn't UTF-8 is replaced with
+    /// U+FFFD in lexemes.
+    pub fn streaming(reader: impl BufRead + 'src, lexemes: &'src Lexemes) -> Self {
+        let mut scanner = Self::new("");
+        scanner.stream = Some(Box::new(Stream {
+            reader: Box::new(reader),
+            buf: vec![],
+            previous_line: 0,
+            at_line_start: true,
+            lexemes,
+            error: None,
+        }));
+        scanner
+    }
+
+    /// The line of the source the byte at `offset` is on, without its line
+    /// break. A streamed source only keeps the line being scanned, so it's
+    /// None for others, and can end where what's been read of it does.
+    /// Borrowed from the scanner, since a stream's buffer is replaced as
+    /// it reads on.
+    pub fn line_text(&self, offset: u32) -> Option<&str> {
+        let offset = (offset as usize)
+            .checked_sub(self.base)?
+            .min(self.src.len());
+        let start = match self.src[..offset].iter().rposition(|&c| c == b'\n') {
+            Some(i) => i + 1,
+            None if self.stream.as_ref().map_or(false, |s| !s.at_line_start) => return None,
+            None => 0,
+        };
+        let end = self.src[offset..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(self.src.len(), |i| offset + i);
+        let line = std::str::from_utf8(&self.src[start..end]).ok()?;
+        Some(line.strip_suffix('\r').unwrap_or(line))
+    }
+
+    /// Why reading a streamed source failed, if it did
+    pub fn read_error(&mut self) -> Option<io::Error> {
+        self.stream.as_mut()?.error.take()
+    }
+
+    /// Resume scanning `src` at byte `offset`, which must be the start of a
+    /// token (or of the whitespace before one) on line `line`
+    pub fn starting_at(src: &'src str, offset: u32, line: u32) -> Self {
+        Self {
+            src: src.as_bytes(),
+            base: 0,
+            start: offset as usize,
+            current: offset as usize,
+            line: line as usize,
+            line_start: src[..offset as usize].rfind('\n').map_or(0, |i| i + 1),
+            token_line: line,
+            column: 1,
+            reached_eof: false,
+            interner: None,
+            stream: None,
+            leading: offset == 0,
+            strict: false,
+        }
+    }
+
+    /// Intern the lexemes of identifiers, see [`Interner`]
+    pub fn interning(mut self) -> Self {
+        self.interner.get_or_insert_with(Interner::default);
+        self
+    }
+
+    /// The lexeme identifiers called `name` have, for names that don't come
+    /// from the source. Only interned if the scanner is interning.
+    pub fn intern(&mut self, name: &'src str) -> &'src str {
+        match &mut self.interner {
+            Some(interner) => interner.intern(name),
+            None => name,
+        }
+    }
+
+    fn advance(&mut self) -> u8 {
+        let ret = self.src[self.current];
+        self.current += 1;
+        ret
+    }
+
+    /// Whether there's a byte `ahead` of the current one, reading more of a
+    /// streamed source if it has to
+    #[inline]
+    fn has(&mut self, ahead: usize) -> bool {
+        self.current + ahead < self.src.len() || self.read_more(ahead)
+    }
+
+    #[cold]
+    fn read_more(&mut self, ahead: usize) -> bool {
+        let Some(stream) = self.stream.as_mut() else {
+            return false;
+        };
+
+        // Nothing before the token being scanned is needed again, but the
+        // lines of it and the token before are kept for errors to quote
+        let token = self.start.min(self.current);
+        let keep = stream.previous_line.min(self.line_start);
+        let kept = keep >= self.base && self.base + token - keep <= KEPT_LINES;
+        let scanned = match kept {
+            true => keep - self.base,
+            false => token,
+        };
+        stream.at_line_start = kept;
+        stream.buf.drain(..scanned);
+        self.base += scanned;
+        self.start -= scanned;
+        self.current -= scanned;
+
+        while stream.error.is_none() && self.current + ahead >= stream.buf.len() {
+            match stream.reader.fill_buf() {
+                Ok([]) => break,
+                Ok(bytes) => {
+                    let read = bytes.len();
+                    stream.buf.extend_from_slice(bytes);
+                    stream.reader.consume(read);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => stream.error = Some(err),
+            }
+        }
+
+        // Safety: the buffer is boxed along with the stream, and `src` is
+        // pointed at it again whenever it changes
+        self.src = unsafe { &*(stream.buf.as_slice() as *const [u8]) };
+        self.current + ahead < self.src.len()
+    }
+
+    fn peek(&mut self) -> u8 {
+        match self.has(0) {
+            true => self.src[self.current],
+            false => b'\0',
+        }
+    }
+
+    fn peek_next(&mut self) -> u8 {
+        match self.has(1) {
+            true => self.src[self.current + 1],
+            false => b'\0',
+        }
+    }
+
+    /// Skip whitespace and comments, up to the next token. A block comment
+    /// that's never closed is skipped to the end, and the line, column and
+    /// offset it started at returned to report it at.
+    fn skip_whitespace(&mut self) -> Option<(u32, u32, u32)> {
+        loop {
+            // Lets a streamed source drop what's been skipped
+            self.start = self.current;
+            let c = self.peek();
+
+            match c {
+                b' ' | b'\r' | b'\t' => {
+                    self.advance();
+                }
+                b'\n' => {
+                    self.line += 1;
+                    self.advance();
+                    self.line_start = self.base + self.current;
+                }
+                b'/' => match self.peek_next() {
+                    b'/' => {
+                        while self.peek() != b'\n' && !self.is_at_end() {
+                            self.advance();
+                        }
+                        let comment = &self.src[self.start..self.current];
+                        let directive = comment
+                            .strip_prefix(b"//#strict")
+                            .map_or(false, |rest| rest.iter().all(u8::is_ascii_whitespace));
+                        if self.leading && directive {
+                            self.strict = true;
+                        }
+                    }
+                    b'*' => {
+                        let start = (
+                            self.line as u32,
+                            (self.base + self.current - self.line_start) as u32 + 1,
+                            (self.base + self.current) as u32,
+                        );
+                        if !self.block_comment() {
+                            return Some(start);
+                        }
+                    }
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+    }
+
+    /// Skip a `/* ... */` comment, which can have others nested in it,
+    /// returning whether it was closed
+    fn block_comment(&mut self) -> bool {
+        self.current += 2;
+        let mut depth = 1;
+        while depth > 0 {
+            // Lets a streamed source drop what's been skipped
+            self.start = self.current;
+            if self.is_at_end() {
+                return false;
+            }
+            match (self.peek(), self.peek_next()) {
+                (b'/', b'*') => {
+                    self.current += 2;
+                    depth += 1;
+                }
+                (b'*', b'/') => {
+                    self.current += 2;
+                    depth -= 1;
+                }
+                (b'\n', _) => {
+                    self.line += 1;
+                    self.advance();
+                    self.line_start = self.base + self.current;
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+        true
+    }
+
+    pub fn token(&mut self) -> Token<'src> {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.previous_line = self.base + self.start + 1 - self.column as usize;
+        }
+        let unterminated = self.skip_whitespace();
+        self.leading = false;
+        self.start = self.current;
+        self.token_line = self.line as u32;
+        self.column = (self.base + self.start - self.line_start) as u32 + 1;
+
+        if let Some((line, column, offset)) = unterminated {
+            let mut token = self.error_token(ErrorCode::UnterminatedComment);
+            token.line = line;
+            token.column = column;
+            token.span.start = offset;
+            return token;
+        }
+
+        if self.is_at_end() {
+            return self.make_token(TokenKind::Eof);
+        }
+
+        let c = self.advance();
+
+        if Self::is_alpha(c) {
+            return self.identifier();
+        }
+
+        if Self::is_digit(c) {
+            return self.number();
+        }
+
+        match c {
+            b'(' => return self.make_token(TokenKind::LeftParen),
+            b')' => return self.make_token(TokenKind::RightParen),
+            b'{' => return self.make_token(TokenKind::LeftBrace),
+            b'}' => return self.make_token(TokenKind::RightBrace),
+            b'[' => return self.make_token(TokenKind::LeftBracket),
+            b']' => return self.make_token(TokenKind::RightBracket),
+            b';' => return self.make_token(TokenKind::Semicolon),
+            b',' => return self.make_token(TokenKind::Comma),
+            b'.' => return self.make_token(TokenKind::Dot),
+            b'-' => return self.make_token(TokenKind::Minus),
+            b'+' => return self.make_token(TokenKind::Plus),
+            b'/' => return self.make_token(TokenKind::Slash),
+            b'*' => return self.make_token(TokenKind::Star),
+            b':' => return self.make_token(TokenKind::Colon),
+            b'!' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::BangEqual
+                } else {
+                    TokenKind::Bang
+                };
+                return self.make_token(kind);
+            }
+            b'?' if self.matches(b'.') => return self.make_token(TokenKind::QuestionDot),
+            b'?' if self.matches(b'?') => return self.make_token(TokenKind::QuestionQuestion),
+            b'=' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::EqualEqual
+                } else {
+                    TokenKind::Equal
+                };
+                return self.make_token(kind);
+            }
+            b'<' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::LessEqual
+                } else {
+                    TokenKind::Less
+                };
+                return self.make_token(kind);
+            }
+            b'>' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::GreaterEqual
+                } else {
+                    TokenKind::Greater
+                };
+                return self.make_token(kind);
+            }
+            b'"' => return self.string(),
+            _ => (),
+        }
+
+        self.error_token(ErrorCode::UnexpectedCharacter)
+    }
+
+    fn is_alpha(c: u8) -> bool {
+        (b'a'..=b'z').contains(&c) || (b'A'..=b'Z').contains(&c) || c == b'_'
+    }
+
+    fn identifier(&mut self) -> Token<'src> {
+        while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
+            self.advance();
+        }
+
+        let mut token = self.make_token(self.identifier_kind());
+        // `this` and `super` are variables too, as far as the compiler is
+        // concerned
+        if matches!(
+            token.kind,
+            TokenKind::Identifier | TokenKind::This | TokenKind::Super
+        ) {
+            token.msg = self.intern(token.msg);
+        }
+        token
+    }
+
+    fn identifier_kind(&self) -> TokenKind {
+        let lexeme = &self.src[self.start..self.current];
+        match KEYWORDS[keyword_slot(lexeme)] {
+            Some((keyword, kind)) if keyword.as_bytes() == lexeme => kind,
+            _ => TokenKind::Identifier,
+        }
+    }
+
+    fn string(&mut self) -> Token<'src> {
+        while self.peek() != b'"' && !self.is_at_end() {
+            if self.peek() == b'\n' {
+                self.line += 1;
+                self.line_start = self.base + self.current + 1;
+            }
+
+            self.advance();
+        }
+
+        if self.is_at_end() {
+            return self.error_token(ErrorCode::UnterminatedString);
+        }
+
+        // closing quote
+        self.advance();
+        self.make_token(TokenKind::String)
+    }
+
+    fn is_digit(c: u8) -> bool {
+        match c {
+            b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7' | b'8' | b'9' => true,
+            _ => false,
+        }
+    }
+
+    /// A decimal number like `1_000.5e-3`, or an integer in hex like `0xFF`
+    /// or binary like `0b1010`. `_` separates digits, and can only go
+    /// between two of them. Letters and digits running on from a number
+    /// are part of it, so `0b12` or `1e` is malformed rather than a number
+    /// followed by something else.
+    fn number(&mut self) -> Token<'src> {
+        let radix = match (self.src[self.start], self.peek()) {
+            (b'0', b'x' | b'X') => 16,
+            (b'0', b'b' | b'B') => 2,
+            _ => 10,
+        };
+        let mut valid = true;
+        if radix != 10 {
+            self.advance();
+            valid &= self.digits(radix, false);
+        } else {
+            valid &= self.digits(10, true);
+
+            if self.peek() == b'.' && Self::is_digit(self.peek_next()) {
+                // consume the '.'
+                self.advance();
+                valid &= self.digits(10, false);
+            }
+
+            if matches!(self.peek(), b'e' | b'E') {
+                self.advance();
+                if matches!(self.peek(), b'+' | b'-') {
+                    self.advance();
+                }
+                valid &= self.digits(10, false);
+            }
+        }
+
+        while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
+            self.advance();
+            valid = false;
+        }
+
+        match valid {
+            true => self.make_token(TokenKind::Number),
+            false => self.error_token(ErrorCode::MalformedNumber),
+        }
+    }
+
+    /// Skip digits in `radix` and the `_`s between them, whether there
+    /// were any and the `_`s were all between two digits. The first digit
+    /// is already skipped if `started`.
+    fn digits(&mut self, radix: u32, started: bool) -> bool {
+        let mut previous = match started {
+            true => Some(self.src[self.current - 1]),
+            false => None,
+        };
+        let mut valid = true;
+        loop {
+            let c = self.peek();
+            if c == b'_' {
+                valid &= previous.map_or(false, |p| p != b'_');
+            } else if !(c as char).is_digit(radix) {
+                break;
+            }
+            self.advance();
+            previous = Some(c);
+        }
+        valid && previous.map_or(false, |p| p != b'_')
+    }
+
+    fn matches(&mut self, expected: u8) -> bool {
+        if self.is_at_end() {
+            return false;
+        }
+
+        if self.src[self.current] != expected {
+            return false;
+        }
+
+        self.current += 1;
+
+        true
+    }
+
+    fn is_at_end(&mut self) -> bool {
+        !self.has(0)
+    }
+
+    fn make_token(&self, kind: TokenKind) -> Token<'src> {
+        let lexeme = &self.src[self.start..self.current];
+        let msg = match &self.stream {
+            // Safety:
+            // The input is guaranteed to be valid utf8 so this is safe
+            None => unsafe { std::str::from_utf8_unchecked(lexeme) },
+            Some(stream) => stream.lexemes.intern(&String::from_utf8_lossy(lexeme)),
+        };
+        Token {
+            kind,
+            msg,
+            line: self.token_line,
+            column: self.column,
+            span: self.span(),
+        }
+    }
+
+    /// An error token, its message is the English one for `code`
+    fn error_token(&self, code: ErrorCode) -> Token<'src> {
+        Token {
+            kind: TokenKind::Error,
+            msg: code.template(),
+            line: self.token_line,
+            column: self.column,
+            span: self.span(),
+        }
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: (self.base + self.start) as u32,
+            end: (self.base + self.current) as u32,
+        }
+    }
+}
@@ -0,0 +1,915 @@
+//! Binary snapshots of the VM's globals and everything reachable from them
+//! (functions, classes, instances, the strings they use), so a prelude can be
+//! compiled and run once and then loaded straight into a fresh VM.
+//!
+//! The format is little-endian: a magic header, every object in the graph,
+//! then the globals. Objects refer to each other by their position in the
+//! object list, so loading happens in stages: allocate every object (in an
+//! order that lets constructors see what they need), then fill in the
+//! references between them.
+//!
+//! [`checkpoint`] and [`restore`] use the same format under a different
+//! header, with the stack, the call frames and the open upvalues after the
+//! globals, to pick a running script back up.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    mem::MaybeUninit,
+    ptr::{addr_of_mut, null_mut, NonNull},
+};
+
+use crate::{
+    chunk,
+    fields::FieldStorage,
+    mem::Gc,
+    native_fn::{NativeFnKind, GLOBAL_NATIVES, MATRIX_NATIVES},
+    obj::{
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFloatArray, ObjFunction, ObjInstance,
+        ObjKind, ObjList, ObjNative, ObjString, ObjUpvalue,
+    },
+    stack::ValueStack,
+    table::Table,
+    value::Value,
+    vm::{CallFrame, VM},
+};
+
+const MAGIC: &[u8; 8] = b"LOXSNAP\x05";
+const CHECKPOINT_MAGIC: &[u8; 8] = b"LOXCKPT\x02";
+const NULL_INDEX: u32 = u32::MAX;
+
+/// A native every VM has, stored by its name in [`builtin_natives`]
+const NATIVE_BUILTIN: u8 = 0;
+/// One the host registered, stored by the global it's defined as
+const NATIVE_CUSTOM: u8 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Write the globals of `vm` and every object reachable from them.
+///
+/// Must be called between runs, open upvalues point into the stack and can't
+/// be saved. Custom natives are saved by the global name they're defined
+/// under, and the VM loading the snapshot must have registered a native by
+/// that name beforehand. Weak refs can't be saved, whether their target
+/// would be alive after loading depends on what else the snapshot has.
+pub fn write(vm: &VM, out: &mut dyn Write) -> io::Result<()> {
+    if !vm.open_upvalues.is_null() {
+        return Err(invalid("Can't snapshot a VM with open upvalues"));
+    }
+
+    let writer = Writer::new(vm);
+    let buf = writer.write_heap(MAGIC)?;
+    out.write_all(&buf)
+}
+
+/// Load a snapshot made by [`write`] into `vm`, defining its globals
+pub fn read(vm: &mut VM, bytes: &[u8]) -> io::Result<()> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("Not a loxide snapshot"));
+    }
+    read_heap(vm, &mut reader)?;
+    Ok(())
+}
+
+/// Write everything `vm` needs to carry on running its script: what
+/// [`write`] saves, plus the stack, the call frames and the open upvalues.
+///
+/// Experimental. Meant for between [`run_bounded`](VM::run_bounded) steps,
+/// or while paused in the debugger, and fails inside a native's callback,
+/// since the native's own state can't be saved. What lives outside the heap
+/// isn't saved either: TCP connections, pins, the debugger, coverage.
+pub fn checkpoint(vm: &VM) -> io::Result<Vec<u8>> {
+    if vm.base_frame != 0 {
+        return Err(invalid("Can't checkpoint inside a native's callback"));
+    }
+
+    let mut writer = Writer::new(vm);
+    for value in vm.iter_stack() {
+        writer.discover_value(value);
+    }
+    let frames = &vm.call_frames[..vm.call_frame_count as usize];
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|frame| unsafe { frame.assume_init_ref() })
+        .collect();
+    for frame in &frames {
+        writer.discover(frame.closure.as_ptr().cast());
+    }
+    let mut upvalues = vec![];
+    let mut upvalue = vm.open_upvalues;
+    while let Some(open) = NonNull::new(upvalue) {
+        writer.discover(open.as_ptr().cast());
+        upvalues.push(open);
+        upvalue = unsafe { open.as_ref() }.next;
+    }
+
+    let mut buf = writer.write_heap(CHECKPOINT_MAGIC)?;
+    let base = vm.stack.base();
+    put_u32(&mut buf, vm.stack.len() as u32);
+    for value in vm.iter_stack() {
+        writer.write_value(&mut buf, value);
+    }
+    put_u32(&mut buf, frames.len() as u32);
+    for frame in &frames {
+        put_u32(&mut buf, writer.index(frame.closure.as_ptr().cast()));
+        put_u32(&mut buf, frame.instr_offset);
+        put_u32(&mut buf, unsafe { frame.slots_ptr.offset_from(base) }
+            as u32);
+    }
+    // From the top of the stack down, like the list
+    put_u32(&mut buf, upvalues.len() as u32);
+    for upvalue in upvalues {
+        put_u32(&mut buf, writer.index(upvalue.as_ptr().cast()));
+        let location = unsafe { upvalue.as_ref() }.location;
+        put_u32(&mut buf, unsafe { location.as_ptr().offset_from(base) }
+            as u32);
+    }
+    Ok(buf)
+}
+
+/// Load a checkpoint made by [`checkpoint`] into `vm`, replacing whatever
+/// it was running, so [`VM::run`] carries on where the checkpoint was made.
+/// Custom natives are looked up like [`read`] does, so register them first.
+pub fn restore(vm: &mut VM, bytes: &[u8]) -> io::Result<()> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(CHECKPOINT_MAGIC.len())? != CHECKPOINT_MAGIC {
+        return Err(invalid("Not a loxide checkpoint"));
+    }
+    let objects = read_heap(vm, &mut reader)?;
+
+    let base = vm.stack.base();
+    let stack_len = reader.u32()? as usize;
+    if stack_len > vm.stack_size {
+        return Err(invalid("Checkpoint's stack is too deep"));
+    }
+    vm.stack.set_top(base);
+    for _ in 0..stack_len {
+        let value = load_value(&objects, &reader.value()?)?;
+        vm.stack.push(value);
+    }
+
+    let frame_count = reader.u32()?;
+    if frame_count as usize > vm.call_frames.len() {
+        return Err(invalid("Checkpoint has too many call frames"));
+    }
+    for i in 0..frame_count as usize {
+        let closure = obj_at(&objects, reader.u32()?)?
+            .downcast::<ObjClosure>()
+            .ok_or_else(|| invalid("Object has the wrong kind"))?;
+        let instr_offset = reader.u32()?;
+        let slot = reader.u32()? as usize;
+        if instr_offset as usize >= closure.function.chunk.code.len() || slot > stack_len {
+            return Err(invalid("Call frame out of range"));
+        }
+        vm.call_frames[i] = MaybeUninit::new(CallFrame {
+            instr_offset,
+            slots_ptr: unsafe { base.add(slot) },
+            closure,
+        });
+    }
+    vm.call_frame_count = frame_count;
+
+    let mut open_upvalues: *mut ObjUpvalue = null_mut();
+    let mut last: *mut ObjUpvalue = null_mut();
+    for _ in 0..reader.u32()? {
+        let upvalue = obj_at(&objects, reader.u32()?)?
+            .downcast::<ObjUpvalue>()
+            .ok_or_else(|| invalid("Object has the wrong kind"))?
+            .as_ptr();
+        let slot = reader.u32()? as usize;
+        if slot >= stack_len {
+            return Err(invalid("Open upvalue out of range"));
+        }
+        unsafe {
+            (*upvalue).location = NonNull::new_unchecked(base.add(slot));
+            (*upvalue).next = null_mut();
+            match last.is_null() {
+                true => open_upvalues = upvalue,
+                false => (*last).next = upvalue,
+            }
+        }
+        last = upvalue;
+    }
+    vm.open_upvalues = open_upvalues;
+    Ok(())
+}
+
+impl VM {
+    /// See [`checkpoint`]
+    pub fn checkpoint(&self) -> io::Result<Vec<u8>> {
+        checkpoint(self)
+    }
+
+    /// A new VM carrying on from a [`checkpoint`], see [`restore`]
+    pub fn restore(bytes: &[u8]) -> io::Result<VM> {
+        let mut vm = VM::new();
+        restore(&mut vm, bytes)?;
+        Ok(vm)
+    }
+}
+
+/// Read the objects and the globals of a snapshot or checkpoint, defining
+/// the globals in `vm`. Returns the objects, by their index.
+fn read_heap(vm: &mut VM, reader: &mut Reader) -> io::Result<Vec<Gc<Obj>>> {
+    let count = reader.u32()? as usize;
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        records.push(reader.record()?);
+    }
+    let globals = reader.table()?;
+
+    // Allocate through `vm.mem` so nothing is collected while the objects
+    // aren't reachable from anywhere yet
+    let mut objects: Vec<Gc<Obj>> = Vec::with_capacity(count);
+    for record in records.iter() {
+        let obj = match record {
+            Record::Str(string) => vm.mem.copy_string(string).cast(),
+            Record::Fn { .. } => vm.mem.alloc_obj(ObjFunction::new(null_mut())).cast(),
+            Record::Native(kind, name) => {
+                let kind = match *kind {
+                    NATIVE_BUILTIN => builtin_natives()
+                        .find(|&(builtin, _)| builtin == name.as_str())
+                        .map(|(_, kind)| kind)
+                        .ok_or_else(|| invalid(&format!("Unknown native `{name}`")))?,
+                    _ => registered_native(vm, name)?,
+                };
+                vm.mem.alloc_obj(ObjNative::new(kind)).cast()
+            }
+            Record::Upvalue(_) => vm
+                .mem
+                .alloc_obj(ObjUpvalue::new(NonNull::dangling(), null_mut()))
+                .cast(),
+            Record::List(_) => vm.mem.alloc_obj(ObjList::new(vec![])).cast(),
+            Record::FloatArray(values) => {
+                vm.mem.alloc_obj(ObjFloatArray::new(values.clone())).cast()
+            }
+            // Need the function's upvalue count, so allocated below
+            Record::Closure { .. } => Gc::new(NonNull::dangling()),
+            // Need the other objects, so allocated below
+            Record::Class { .. } | Record::Instance { .. } | Record::BoundMethod { .. } => {
+                Gc::new(NonNull::dangling())
+            }
+        };
+        objects.push(obj);
+    }
+
+    let obj_of_kind = |objects: &[Gc<Obj>], index: u32, kind: ObjKind| -> io::Result<Gc<Obj>> {
+        match index as usize {
+            i if i < records.len() && records[i].kind() == kind => obj_at(objects, index),
+            _ => Err(invalid("Object has the wrong kind")),
+        }
+    };
+
+    for (i, record) in records.iter().enumerate() {
+        match record {
+            Record::Fn {
+                name,
+                arity,
+                upvalue_count,
+                ..
+            } => {
+                let mut function = objects[i].cast::<ObjFunction>();
+                function.arity = *arity;
+                function.upvalue_count = *upvalue_count;
+                if *name != NULL_INDEX {
+                    function.name = obj_of_kind(&objects, *name, ObjKind::Str)?
+                        .cast::<ObjString>()
+                        .as_ptr();
+                }
+            }
+            Record::Class { name, .. } => {
+                let name = obj_of_kind(&objects, *name, ObjKind::Str)?;
+                objects[i] = vm
+                    .mem
+                    .alloc_obj(ObjClass::new(name.cast::<ObjString>().as_non_null_ptr()))
+                    .cast();
+            }
+            _ => (),
+        }
+    }
+
+    for (i, record) in records.iter().enumerate() {
+        match record {
+            Record::Closure { function, .. } => {
+                let function = obj_of_kind(&objects, *function, ObjKind::Fn)?;
+                objects[i] = vm.mem.alloc_obj(ObjClosure::new(function.cast())).cast();
+            }
+            Record::Instance { class, .. } => {
+                let class = obj_of_kind(&objects, *class, ObjKind::Class)?;
+                objects[i] = vm.mem.alloc_obj(ObjInstance::new(class.cast())).cast();
+            }
+            _ => (),
+        }
+    }
+
+    for (i, record) in records.iter().enumerate() {
+        if let Record::BoundMethod { method, .. } = record {
+            let method = obj_of_kind(&objects, *method, ObjKind::Closure)?;
+            objects[i] = vm
+                .mem
+                .alloc_obj(ObjBoundMethod::new(Value::Nil, method.cast()))
+                .cast();
+        }
+    }
+
+    let value = load_value;
+    let fill_table = |objects: &[Gc<Obj>], table: &mut Table, entries: &[(u32, RecordValue)]| {
+        for (key, val) in entries {
+            let key = obj_of_kind(objects, *key, ObjKind::Str)?;
+            table.set(
+                key.cast::<ObjString>().as_non_null_ptr(),
+                value(objects, val)?,
+            );
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    for (i, record) in records.iter().enumerate() {
+        match record {
+            Record::Fn {
+                code,
+                lines,
+                columns,
+                constants,
+                numbers,
+                ..
+            } => {
+                let mut function = objects[i].cast::<ObjFunction>();
+                function.chunk.code = code.clone();
+                function.chunk.lines = lines.clone();
+                function.chunk.columns = columns.clone();
+                for constant in constants {
+                    let constant = value(&objects, constant)?;
+                    function.chunk.constants.push(constant);
+                }
+                for &number in numbers {
+                    let index = vm.mem.numbers.intern(number);
+                    function.chunk.numbers.push(index);
+                    function.chunk.number_values.push(number);
+                }
+            }
+            Record::Upvalue(closed) => {
+                let upvalue = objects[i].cast::<ObjUpvalue>().as_ptr();
+                unsafe {
+                    (*upvalue).closed = value(&objects, closed)?;
+                    (*upvalue).location = NonNull::new(addr_of_mut!((*upvalue).closed)).unwrap();
+                }
+            }
+            Record::Closure { upvalues, .. } => {
+                let closure = objects[i].cast::<ObjClosure>();
+                if upvalues.len() != closure.upvalue_count as usize {
+                    return Err(invalid("Closure has the wrong number of upvalues"));
+                }
+                for (slot, &upvalue) in upvalues.iter().enumerate() {
+                    let upvalue = match upvalue {
+                        NULL_INDEX => null_mut(),
+                        index => obj_of_kind(&objects, index, ObjKind::Upvalue)?
+                            .cast::<ObjUpvalue>()
+                            .as_ptr(),
+                    };
+                    unsafe { *closure.upvalues.as_ptr().add(slot) = upvalue };
+                }
+            }
+            Record::Class {
+                superclass,
+                methods,
+                ..
+            } => {
+                let mut class = objects[i].cast::<ObjClass>();
+                if *superclass != NULL_INDEX {
+                    let superclass = obj_of_kind(&objects, *superclass, ObjKind::Class)?;
+                    class.superclass = Some(superclass.cast());
+                }
+                fill_table(&objects, &mut class.methods, methods)?;
+            }
+            Record::Instance { fields, .. } => {
+                let mut instance = objects[i].cast::<ObjInstance>();
+                for (key, val) in fields {
+                    let key = obj_of_kind(&objects, *key, ObjKind::Str)?;
+                    instance.fields.set(
+                        key.cast::<ObjString>().as_non_null_ptr(),
+                        value(&objects, val)?,
+                    );
+                }
+            }
+            Record::BoundMethod { receiver, .. } => {
+                let mut bound = objects[i].cast::<ObjBoundMethod>();
+                bound.receiver = value(&objects, receiver)?;
+            }
+            Record::List(items) => {
+                let mut list = objects[i].cast::<ObjList>();
+                for item in items {
+                    let item = value(&objects, item)?;
+                    list.items.push(item);
+                }
+            }
+            Record::Str(_) | Record::Native(..) | Record::FloatArray(_) => (),
+        }
+    }
+
+    // Once every function is filled in, since checking a `Closure` needs
+    // the upvalue count of the function it closes over
+    for (i, record) in records.iter().enumerate() {
+        if let Record::Fn { .. } = record {
+            chunk::verify(objects[i].cast::<ObjFunction>().as_ref())?;
+        }
+    }
+
+    let mut table = std::mem::replace(&mut vm.mem.globals, Table::new());
+    let result = fill_table(&objects, &mut table, &globals);
+    vm.mem.globals = table;
+    result.map(|()| objects)
+}
+
+fn obj_at(objects: &[Gc<Obj>], index: u32) -> io::Result<Gc<Obj>> {
+    objects
+        .get(index as usize)
+        .cloned()
+        .ok_or_else(|| invalid("Object index out of range"))
+}
+
+fn load_value(objects: &[Gc<Obj>], value: &RecordValue) -> io::Result<Value> {
+    Ok(match *value {
+        RecordValue::Nil => Value::Nil,
+        RecordValue::Bool(b) => Value::Bool(b),
+        RecordValue::Number(n) => Value::Number(n),
+        RecordValue::Obj(index) => Value::Obj(obj_at(objects, index)?),
+    })
+}
+
+/// The natives every VM defines, as globals or in modules, by a name that's
+/// unique among them
+fn builtin_natives() -> impl Iterator<Item = (&'static str, NativeFnKind)> {
+    GLOBAL_NATIVES.iter().chain(MATRIX_NATIVES).copied()
+}
+
+fn registered_native(vm: &mut VM, name: &str) -> io::Result<NativeFnKind> {
+    let key = vm.mem.copy_string(name);
+    match vm.mem.globals.get(key.as_non_null_ptr()) {
+        Some(value) => match value.as_obj_native() {
+            Some(native) => Ok(native.function),
+            None => Err(invalid(&format!("Global `{name}` isn't a native"))),
+        },
+        None => Err(invalid(&format!("Native `{name}` isn't registered"))),
+    }
+}
+
+struct Writer<'a> {
+    vm: &'a VM,
+    indices: HashMap<*mut Obj, u32>,
+    objects: Vec<Gc<Obj>>,
+}
+
+impl<'a> Writer<'a> {
+    /// A writer that's found the globals and everything they reference
+    fn new(vm: &'a VM) -> Self {
+        let mut writer = Writer {
+            vm,
+            indices: HashMap::new(),
+            objects: vec![],
+        };
+        for entry in vm.mem.globals.iter() {
+            writer.discover(entry.key.cast());
+            writer.discover_value(entry.value);
+        }
+        writer
+    }
+
+    /// `magic`, every object found so far, then the globals
+    fn write_heap(&self, magic: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buf = magic.to_vec();
+        put_u32(&mut buf, self.objects.len() as u32);
+        for &obj in &self.objects {
+            self.write_object(&mut buf, obj)?;
+        }
+        self.write_table(&mut buf, &self.vm.mem.globals);
+        Ok(buf)
+    }
+
+    fn discover_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.discover(obj.as_ptr());
+        }
+    }
+
+    /// Give `obj` and everything it references an index
+    fn discover(&mut self, obj: *mut Obj) {
+        let obj = match NonNull::new(obj) {
+            Some(obj) => Gc::new(obj),
+            None => return,
+        };
+        if self.indices.contains_key(&obj.as_ptr()) {
+            return;
+        }
+        self.indices.insert(obj.as_ptr(), self.objects.len() as u32);
+        self.objects.push(obj);
+
+        match obj.kind {
+            // A view is saved as a copy of its characters
+            ObjKind::Str | ObjKind::StrView | ObjKind::Native | ObjKind::FloatArray => (),
+            // Refused when it's written
+            ObjKind::WeakRef => (),
+            ObjKind::Fn => {
+                let function = obj.cast::<ObjFunction>();
+                self.discover(function.name.cast());
+                for &constant in function.chunk.constants.iter() {
+                    self.discover_value(constant);
+                }
+            }
+            ObjKind::Closure => {
+                let closure = obj.cast::<ObjClosure>();
+                self.discover(closure.function.as_ptr().cast());
+                for slot in 0..closure.upvalue_count as usize {
+                    self.discover(unsafe { *closure.upvalues.as_ptr().add(slot) }.cast());
+                }
+            }
+            ObjKind::Upvalue => {
+                let upvalue = obj.cast::<ObjUpvalue>();
+                self.discover_value(upvalue.closed);
+            }
+            ObjKind::Class => {
+                let class = obj.cast::<ObjClass>();
+                self.discover(class.name.as_ptr().cast());
+                if let Some(superclass) = class.superclass {
+                    self.discover(superclass.as_ptr().cast());
+                }
+                self.discover_table(&class.as_ref().methods);
+            }
+            ObjKind::Instance => {
+                let instance = obj.cast::<ObjInstance>();
+                self.discover(instance.class.as_ptr().cast());
+                let fields = instance.as_ref().fields.iter();
+                self.discover_entries(fields.map(|(name, value)| (name.as_ptr(), value)));
+            }
+            ObjKind::BoundMethod => {
+                let bound = obj.cast::<ObjBoundMethod>();
+                self.discover_value(bound.receiver);
+                self.discover(bound.method.as_ptr().cast());
+            }
+            ObjKind::List => {
+                for &item in obj.cast::<ObjList>().items.iter() {
+                    self.discover_value(item);
+                }
+            }
+        }
+    }
+
+    fn discover_table(&mut self, table: &Table) {
+        self.discover_entries(table.iter().map(|entry| (entry.key, entry.value)));
+    }
+
+    fn discover_entries(&mut self, entries: impl Iterator<Item = (*mut ObjString, Value)>) {
+        for (key, value) in entries {
+            self.discover(key.cast());
+            self.discover_value(value);
+        }
+    }
+
+    fn index(&self, obj: *mut Obj) -> u32 {
+        match obj.is_null() {
+            true => NULL_INDEX,
+            false => self.indices[&obj],
+        }
+    }
+
+    fn write_object(&self, buf: &mut Vec<u8>, obj: Gc<Obj>) -> io::Result<()> {
+        match obj.kind {
+            ObjKind::StrView => buf.push(ObjKind::Str as u8),
+            kind => buf.push(kind as u8),
+        }
+        match obj.kind {
+            ObjKind::Str | ObjKind::StrView => {
+                put_bytes(buf, Value::Obj(obj).as_str().unwrap().as_bytes());
+            }
+            ObjKind::Fn => {
+                let function = obj.cast::<ObjFunction>();
+                put_u32(buf, self.index(function.name.cast()));
+                buf.push(function.arity);
+                buf.push(function.upvalue_count);
+                put_bytes(buf, &function.chunk.code);
+                for &line in function.chunk.lines.iter() {
+                    put_u32(buf, line);
+                }
+                for &column in function.chunk.columns.iter() {
+                    put_u32(buf, column);
+                }
+                put_u32(buf, function.chunk.constants.len() as u32);
+                for &constant in function.chunk.constants.iter() {
+                    self.write_value(buf, constant);
+                }
+                // The numbers themselves, the pool indices only mean
+                // something in this VM
+                put_u32(buf, function.chunk.numbers.len() as u32);
+                for &index in function.chunk.numbers.iter() {
+                    buf.extend_from_slice(&self.vm.mem.numbers.get(index).to_le_bytes());
+                }
+            }
+            ObjKind::Native => match obj.cast::<ObjNative>().function {
+                NativeFnKind::Custom(_) => {
+                    buf.push(NATIVE_CUSTOM);
+                    let name = self.native_name(obj)?;
+                    put_bytes(buf, name.as_bytes());
+                }
+                function => {
+                    let kind = std::mem::discriminant(&function);
+                    let (name, _) = builtin_natives()
+                        .find(|(_, builtin)| std::mem::discriminant(builtin) == kind)
+                        .expect("Every native but a custom one is built in");
+                    buf.push(NATIVE_BUILTIN);
+                    put_bytes(buf, name.as_bytes());
+                }
+            },
+            ObjKind::Closure => {
+                let closure = obj.cast::<ObjClosure>();
+                put_u32(buf, self.index(closure.function.as_ptr().cast()));
+                buf.push(closure.upvalue_count);
+                for slot in 0..closure.upvalue_count as usize {
+                    let upvalue = unsafe { *closure.upvalues.as_ptr().add(slot) };
+                    put_u32(buf, self.index(upvalue.cast()));
+                }
+            }
+            ObjKind::Upvalue => self.write_value(buf, obj.cast::<ObjUpvalue>().closed),
+            ObjKind::Class => {
+                let class = obj.cast::<ObjClass>();
+                put_u32(buf, self.index(class.name.as_ptr().cast()));
+                match class.superclass {
+                    Some(superclass) => put_u32(buf, self.index(superclass.as_ptr().cast())),
+                    None => put_u32(buf, NULL_INDEX),
+                }
+                self.write_table(buf, &class.as_ref().methods);
+            }
+            ObjKind::Instance => {
+                let instance = obj.cast::<ObjInstance>();
+                put_u32(buf, self.index(instance.class.as_ptr().cast()));
+                let fields = instance.as_ref().fields.iter();
+                self.write_entries(
+                    buf,
+                    fields.map(|(name, value)| (name.as_ptr(), value)).collect(),
+                );
+            }
+            ObjKind::BoundMethod => {
+                let bound = obj.cast::<ObjBoundMethod>();
+                self.write_value(buf, bound.receiver);
+                put_u32(buf, self.index(bound.method.as_ptr().cast()));
+            }
+            ObjKind::List => {
+                let list = obj.cast::<ObjList>();
+                put_u32(buf, list.items.len() as u32);
+                for &item in list.items.iter() {
+                    self.write_value(buf, item);
+                }
+            }
+            ObjKind::FloatArray => {
+                let array = obj.cast::<ObjFloatArray>();
+                put_u32(buf, array.values.len() as u32);
+                for n in array.values.iter() {
+                    buf.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+            ObjKind::WeakRef => return Err(invalid("Can't snapshot a weak ref")),
+        }
+
+        Ok(())
+    }
+
+    /// The name of the global a custom native is defined under
+    fn native_name(&self, native: Gc<Obj>) -> io::Result<&'a str> {
+        self.vm
+            .mem
+            .globals
+            .iter()
+            .find(|entry| match entry.value {
+                Value::Obj(obj) => obj.as_ptr() == native.as_ptr(),
+                _ => false,
+            })
+            .map(|entry| unsafe { (*entry.key).as_str() })
+            .ok_or_else(|| invalid("Native function isn't defined as a global"))
+    }
+
+    fn write_value(&self, buf: &mut Vec<u8>, value: Value) {
+        match value {
+            Value::Nil => buf.push(0),
+            Value::Bool(false) => buf.push(1),
+            Value::Bool(true) => buf.push(2),
+            Value::Number(n) => {
+                buf.push(3);
+                buf.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Obj(obj) => {
+                buf.push(4);
+                put_u32(buf, self.index(obj.as_ptr()));
+            }
+        }
+    }
+
+    fn write_table(&self, buf: &mut Vec<u8>, table: &Table) {
+        let entries = table.iter().map(|entry| (entry.key, entry.value));
+        self.write_entries(buf, entries.collect());
+    }
+
+    fn write_entries(&self, buf: &mut Vec<u8>, entries: Vec<(*mut ObjString, Value)>) {
+        put_u32(buf, entries.len() as u32);
+        for (key, value) in entries {
+            put_u32(buf, self.index(key.cast()));
+            self.write_value(buf, value);
+        }
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+/// An object as it appears in the snapshot, with references as indices
+enum Record {
+    Str(String),
+    Fn {
+        name: u32,
+        arity: u8,
+        upvalue_count: u8,
+        code: Vec<u8>,
+        lines: Vec<u32>,
+        columns: Vec<u32>,
+        constants: Vec<RecordValue>,
+        numbers: Vec<f64>,
+    },
+    Native(u8, String),
+    Closure {
+        function: u32,
+        upvalues: Vec<u32>,
+    },
+    Upvalue(RecordValue),
+    Class {
+        name: u32,
+        superclass: u32,
+        methods: Vec<(u32, RecordValue)>,
+    },
+    Instance {
+        class: u32,
+        fields: Vec<(u32, RecordValue)>,
+    },
+    BoundMethod {
+        receiver: RecordValue,
+        method: u32,
+    },
+    List(Vec<RecordValue>),
+    FloatArray(Vec<f64>),
+}
+
+impl Record {
+    fn kind(&self) -> ObjKind {
+        match self {
+            Record::Str(_) => ObjKind::Str,
+            Record::Fn { .. } => ObjKind::Fn,
+            Record::Native(..) => ObjKind::Native,
+            Record::Closure { .. } => ObjKind::Closure,
+            Record::Upvalue(_) => ObjKind::Upvalue,
+            Record::Class { .. } => ObjKind::Class,
+            Record::Instance { .. } => ObjKind::Instance,
+            Record::BoundMethod { .. } => ObjKind::BoundMethod,
+            Record::List(_) => ObjKind::List,
+            Record::FloatArray(_) => ObjKind::FloatArray,
+        }
+    }
+}
+
+enum RecordValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(u32),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(invalid("Snapshot is truncated"));
+        }
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("String isn't utf-8"))
+    }
+
+    fn value(&mut self) -> io::Result<RecordValue> {
+        Ok(match self.u8()? {
+            0 => RecordValue::Nil,
+            1 => RecordValue::Bool(false),
+            2 => RecordValue::Bool(true),
+            3 => RecordValue::Number(self.f64()?),
+            4 => RecordValue::Obj(self.u32()?),
+            _ => return Err(invalid("Unknown value tag")),
+        })
+    }
+
+    fn table(&mut self) -> io::Result<Vec<(u32, RecordValue)>> {
+        let len = self.u32()?;
+        (0..len).map(|_| Ok((self.u32()?, self.value()?))).collect()
+    }
+
+    fn record(&mut self) -> io::Result<Record> {
+        let kind = self.u8()?;
+        Ok(match kind {
+            k if k == ObjKind::Str as u8 => Record::Str(self.string()?),
+            k if k == ObjKind::Fn as u8 => {
+                let name = self.u32()?;
+                let arity = self.u8()?;
+                let upvalue_count = self.u8()?;
+                let code = self.bytes()?.to_vec();
+                let lines = (0..code.len())
+                    .map(|_| self.u32())
+                    .collect::<io::Result<_>>()?;
+                let columns = (0..code.len())
+                    .map(|_| self.u32())
+                    .collect::<io::Result<_>>()?;
+                let constant_count = self.u32()?;
+                let constants = (0..constant_count)
+                    .map(|_| self.value())
+                    .collect::<io::Result<_>>()?;
+                let number_count = self.u32()?;
+                let numbers = (0..number_count)
+                    .map(|_| self.f64())
+                    .collect::<io::Result<_>>()?;
+                Record::Fn {
+                    name,
+                    arity,
+                    upvalue_count,
+                    code,
+                    lines,
+                    columns,
+                    constants,
+                    numbers,
+                }
+            }
+            k if k == ObjKind::Native as u8 => match self.u8()? {
+                kind @ (NATIVE_BUILTIN | NATIVE_CUSTOM) => Record::Native(kind, self.string()?),
+                _ => return Err(invalid("Unknown native")),
+            },
+            k if k == ObjKind::Closure as u8 => {
+                let function = self.u32()?;
+                let count = self.u8()?;
+                let upvalues = (0..count).map(|_| self.u32()).collect::<io::Result<_>>()?;
+                Record::Closure { function, upvalues }
+            }
+            k if k == ObjKind::Upvalue as u8 => Record::Upvalue(self.value()?),
+            k if k == ObjKind::Class as u8 => Record::Class {
+                name: self.u32()?,
+                superclass: self.u32()?,
+                methods: self.table()?,
+            },
+            k if k == ObjKind::Instance as u8 => Record::Instance {
+                class: self.u32()?,
+                fields: self.table()?,
+            },
+            k if k == ObjKind::BoundMethod as u8 => Record::BoundMethod {
+                receiver: self.value()?,
+                method: self.u32()?,
+            },
+            k if k == ObjKind::List as u8 => {
+                let len = self.u32()?;
+                let items = (0..len).map(|_| self.value()).collect::<io::Result<_>>()?;
+                Record::List(items)
+            }
+            k if k == ObjKind::FloatArray as u8 => {
+                let len = self.u32()?;
+                let values = (0..len).map(|_| self.f64()).collect::<io::Result<_>>()?;
+                Record::FloatArray(values)
+            }
+            _ => return Err(invalid("Unknown object kind")),
+        })
+    }
+}
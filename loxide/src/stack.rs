@@ -0,0 +1,219 @@
+//! The VM's value stack, in two versions with the same interface:
+//! [`UncheckedStack`], which reads and writes through raw pointers, and
+//! [`CheckedStack`], which bounds checks every access to a slice. The
+//! `checked_stack` feature makes the VM use the checked one, and
+//! `cargo bench --bench stack` measures what the checks cost.
+//!
+//! Call frames and open upvalues point into the stack either way, so both
+//! hand out pointers to their slots, and never move them.
+
+use crate::value::Value;
+
+pub trait ValueStack {
+    /// Room for `capacity` values, allocated up front
+    fn with_capacity(capacity: usize) -> Self;
+
+    /// The first slot
+    fn base(&self) -> *mut Value;
+    /// The slot after the last value
+    fn top(&self) -> *mut Value;
+    /// Move the top to `top`, which must be a slot of this stack
+    fn set_top(&mut self, top: *mut Value);
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Every value on the stack, from the bottom
+    fn values(&self) -> &[Value];
+
+    fn push(&mut self, value: Value);
+    fn pop(&mut self) -> Value;
+    /// The value `distance` below the top one
+    fn peek(&self, distance: u32) -> Value;
+    fn set(&mut self, distance: u32, value: Value);
+    /// Drop the top `amount` values
+    fn sub(&mut self, amount: u32);
+
+    /// Local `index` of the frame whose slots start at `slots`
+    ///
+    /// # Safety
+    /// `slots` has to point into this stack, with `index` below the top
+    unsafe fn slot(&self, slots: *mut Value, index: usize) -> Value;
+    /// # Safety
+    /// See [`slot`](ValueStack::slot)
+    unsafe fn set_slot(&mut self, slots: *mut Value, index: usize, value: Value);
+}
+
+/// The VM's stack, [`CheckedStack`] with the `checked_stack` feature
+#[cfg(not(feature = "checked_stack"))]
+pub type Stack = UncheckedStack;
+#[cfg(feature = "checked_stack")]
+pub type Stack = CheckedStack;
+
+/// Trusts the compiler and the VM to keep every access in bounds. Its
+/// memory lives as long as the program.
+pub struct UncheckedStack {
+    base: *mut Value,
+    top: *mut Value,
+}
+
+impl ValueStack for UncheckedStack {
+    fn with_capacity(capacity: usize) -> Self {
+        let base = Vec::<Value>::with_capacity(capacity).leak().as_mut_ptr();
+        Self { base, top: base }
+    }
+
+    #[inline]
+    fn base(&self) -> *mut Value {
+        self.base
+    }
+
+    #[inline]
+    fn top(&self) -> *mut Value {
+        self.top
+    }
+
+    #[inline]
+    fn set_top(&mut self, top: *mut Value) {
+        self.top = top;
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        unsafe { self.top.offset_from(self.base) as usize }
+    }
+
+    fn values(&self) -> &[Value] {
+        unsafe { std::slice::from_raw_parts(self.base, self.len()) }
+    }
+
+    #[inline]
+    fn push(&mut self, value: Value) {
+        unsafe {
+            *self.top = value;
+            self.top = self.top.add(1);
+        }
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Value {
+        unsafe {
+            self.top = self.top.sub(1);
+            *self.top
+        }
+    }
+
+    #[inline]
+    fn peek(&self, distance: u32) -> Value {
+        unsafe { *self.top.offset(-(distance as isize + 1)) }
+    }
+
+    #[inline]
+    fn set(&mut self, distance: u32, value: Value) {
+        unsafe {
+            *self.top.offset(-(distance as isize + 1)) = value;
+        }
+    }
+
+    #[inline]
+    fn sub(&mut self, amount: u32) {
+        self.top = unsafe { self.top.sub(amount as usize) };
+    }
+
+    #[inline]
+    unsafe fn slot(&self, slots: *mut Value, index: usize) -> Value {
+        *slots.add(index)
+    }
+
+    #[inline]
+    unsafe fn set_slot(&mut self, slots: *mut Value, index: usize, value: Value) {
+        *slots.add(index) = value;
+    }
+}
+
+/// Panics rather than reading or writing outside the values on the stack
+pub struct CheckedStack {
+    values: Vec<Value>,
+    len: usize,
+}
+
+impl CheckedStack {
+    /// Which slot `slot` points at
+    fn index_of(&self, slot: *mut Value) -> usize {
+        (slot as usize - self.values.as_ptr() as usize) / std::mem::size_of::<Value>()
+    }
+}
+
+impl ValueStack for CheckedStack {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            values: vec![Value::Nil; capacity],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    fn base(&self) -> *mut Value {
+        self.values.as_ptr() as *mut Value
+    }
+
+    #[inline]
+    fn top(&self) -> *mut Value {
+        self.base().wrapping_add(self.len)
+    }
+
+    #[inline]
+    fn set_top(&mut self, top: *mut Value) {
+        let len = self.index_of(top);
+        assert!(len <= self.values.len(), "Stack top out of bounds.");
+        self.len = len;
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn values(&self) -> &[Value] {
+        &self.values[..self.len]
+    }
+
+    #[inline]
+    fn push(&mut self, value: Value) {
+        self.values[self.len] = value;
+        self.len += 1;
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Value {
+        self.len -= 1;
+        self.values[self.len]
+    }
+
+    #[inline]
+    fn peek(&self, distance: u32) -> Value {
+        self.values[..self.len][self.len - 1 - distance as usize]
+    }
+
+    #[inline]
+    fn set(&mut self, distance: u32, value: Value) {
+        let index = self.len - 1 - distance as usize;
+        self.values[..self.len][index] = value;
+    }
+
+    #[inline]
+    fn sub(&mut self, amount: u32) {
+        self.len -= amount as usize;
+    }
+
+    #[inline]
+    unsafe fn slot(&self, slots: *mut Value, index: usize) -> Value {
+        self.values[..self.len][self.index_of(slots) + index]
+    }
+
+    #[inline]
+    unsafe fn set_slot(&mut self, slots: *mut Value, index: usize, value: Value) {
+        let index = self.index_of(slots) + index;
+        self.values[..self.len][index] = value;
+    }
+}
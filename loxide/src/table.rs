@@ -225,7 +225,8 @@ impl Table {
 
         let entry = self.find_entry_mut(key);
 
-        let is_new_key = entry.is_uninitialized();
+        // Reusing a tombstone is a new key too, but it was already counted
+        let is_new_key = entry.key.is_null();
         let should_increment_len = entry.is_uninitialized();
 
         entry.key = key.as_ptr();
@@ -0,0 +1,96 @@
+//! Wall clock time for the date and time natives. Times are milliseconds
+//! since the Unix epoch, and dates are always in UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Milliseconds since the Unix epoch
+pub fn now_millis() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as f64,
+        Err(before) => -(before.duration().as_millis() as f64),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millisecond: u32,
+    /// 0 is Sunday
+    pub weekday: u32,
+}
+
+impl Date {
+    /// The UTC date `millis` milliseconds after the epoch, rounded down to
+    /// the millisecond
+    pub fn from_millis(millis: f64) -> Self {
+        let millis = millis.floor() as i64;
+        let days = millis.div_euclid(MILLIS_PER_DAY);
+        let time = millis.rem_euclid(MILLIS_PER_DAY);
+
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (time / 3_600_000) as u32,
+            minute: (time / 60_000 % 60) as u32,
+            second: (time / 1000 % 60) as u32,
+            millisecond: (time % 1000) as u32,
+            // The epoch was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    /// Fill in the directives in `format`, like `strftime`: `%Y`, `%m`,
+    /// `%d`, `%H`, `%M` and `%S` are zero-padded, `%L` is milliseconds and
+    /// `%%` is a percent sign.
+    pub fn format(&self, format: &str) -> Result<String, String> {
+        let mut out = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => out.push_str(&format!("{:04}", self.year)),
+                Some('m') => out.push_str(&format!("{:02}", self.month)),
+                Some('d') => out.push_str(&format!("{:02}", self.day)),
+                Some('H') => out.push_str(&format!("{:02}", self.hour)),
+                Some('M') => out.push_str(&format!("{:02}", self.minute)),
+                Some('S') => out.push_str(&format!("{:02}", self.second)),
+                Some('L') => out.push_str(&format!("{:03}", self.millisecond)),
+                Some('%') => out.push('%'),
+                Some(other) => return Err(format!("Unknown time format directive '%{other}'.")),
+                None => return Err("Time format ends in '%'.".into()),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// The year, month and day `days` days after the epoch, in the proleptic
+/// Gregorian calendar. From Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Counting from March, so the leap day comes last
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
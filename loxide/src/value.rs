@@ -1,5 +1,5 @@
 use std::{
-    fmt::Debug,
+    fmt::{Debug, Display},
     ops::{Add, Div, Mul, Neg, Sub},
     ptr::NonNull,
 };
@@ -7,13 +7,69 @@ use std::{
 use crate::{
     mem::{Gc, Greystack},
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjString,
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFloatArray, ObjFunction, ObjInstance,
+        ObjKind, ObjList, ObjNative, ObjPtrWrapper, ObjString, ObjStringView, ObjWeakRef,
     },
 };
 
 pub type ValueArray = Vec<Value>;
 
+/// What type a value is, for error messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Bool,
+    Number,
+    Nil,
+    Obj(ObjKind),
+}
+
+impl ValueKind {
+    /// What error messages call it
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueKind::Bool => "boolean",
+            ValueKind::Number => "number",
+            ValueKind::Nil => "nil",
+            ValueKind::Obj(ObjKind::Str | ObjKind::StrView) => "string",
+            ValueKind::Obj(ObjKind::Fn | ObjKind::Closure) => "function",
+            ValueKind::Obj(ObjKind::Native) => "native function",
+            ValueKind::Obj(ObjKind::Upvalue) => "upvalue",
+            ValueKind::Obj(ObjKind::Class) => "class",
+            ValueKind::Obj(ObjKind::Instance) => "instance",
+            ValueKind::Obj(ObjKind::BoundMethod) => "method",
+            ValueKind::Obj(ObjKind::List) => "list",
+            ValueKind::Obj(ObjKind::FloatArray) => "float array",
+            ValueKind::Obj(ObjKind::WeakRef) => "weak ref",
+        }
+    }
+}
+
+impl Display for ValueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A value wasn't the type it had to be, from the `try_as_*` methods of
+/// [`Value`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConversionError {
+    pub expected: ValueKind,
+    pub got: ValueKind,
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected {} but got {}.", self.expected, self.got)
+    }
+}
+
+impl From<ConversionError> for String {
+    fn from(err: ConversionError) -> Self {
+        err.to_string()
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Value {
     Bool(bool),
@@ -40,9 +96,10 @@ impl Value {
         }
     }
 
+    /// Whether it's a string or a view of one
     pub fn is_str(&self) -> bool {
         match *self {
-            Value::Obj(obj) => obj.kind == ObjKind::Str,
+            Value::Obj(obj) => matches!(obj.kind, ObjKind::Str | ObjKind::StrView),
             _ => false,
         }
     }
@@ -63,58 +120,152 @@ impl Value {
 
     pub fn as_bound_method(&self) -> Option<Gc<ObjBoundMethod>> {
         match self {
-            Value::Obj(obj) if obj.kind == ObjKind::BoundMethod => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
     pub fn as_instance_fn(&self) -> Option<Gc<ObjInstance>> {
         match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Instance => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
     pub fn as_class(&self) -> Option<Gc<ObjClass>> {
         match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Class => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<Gc<ObjList>> {
+        match *self {
+            Value::Obj(obj) => obj.downcast(),
+            _ => None,
+        }
+    }
+
+    pub fn as_float_array(&self) -> Option<Gc<ObjFloatArray>> {
+        match *self {
+            Value::Obj(obj) => obj.downcast(),
+            _ => None,
+        }
+    }
+
+    pub fn as_weak_ref(&self) -> Option<Gc<ObjWeakRef>> {
+        match *self {
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
     pub fn as_fn(&self) -> Option<Gc<ObjFunction>> {
         match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Fn => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
     pub fn as_obj_native(&self) -> Option<Gc<ObjNative>> {
         match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Native => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
     pub fn as_obj_closure(&self) -> Option<Gc<ObjClosure>> {
         match self {
-            Value::Obj(obj) if obj.kind == ObjKind::Closure => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
+    /// Only an interned string, not a view. [`VM::intern`] turns either into
+    /// one.
+    ///
+    /// [`VM::intern`]: crate::vm::VM::intern
     pub fn as_obj_str(&self) -> Option<Gc<ObjString>> {
         match *self {
-            Value::Obj(obj) if obj.kind == ObjKind::Str => Some(obj.cast()),
+            Value::Obj(obj) => obj.downcast(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str_view(&self) -> Option<Gc<ObjStringView>> {
+        match *self {
+            Value::Obj(obj) => obj.downcast(),
             _ => None,
         }
     }
 
+    /// The characters of a string or a view of one
     pub fn as_str<'a>(&'a self) -> Option<&'a str> {
+        if let Some(view) = self.as_str_view() {
+            return Some(unsafe { (*view.as_ptr()).as_str() });
+        }
         let noob = self.as_obj_str()?;
         Some(unsafe { (*noob.as_ptr()).as_str() })
     }
 
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::Bool(_) => ValueKind::Bool,
+            Value::Number(_) => ValueKind::Number,
+            Value::Nil => ValueKind::Nil,
+            Value::Obj(obj) => ValueKind::Obj(obj.kind),
+        }
+    }
+
+    fn conversion_error(&self, expected: ValueKind) -> ConversionError {
+        ConversionError {
+            expected,
+            got: self.kind(),
+        }
+    }
+
+    pub fn try_as_number(&self) -> Result<f64, ConversionError> {
+        match *self {
+            Value::Number(n) => Ok(n),
+            _ => Err(self.conversion_error(ValueKind::Number)),
+        }
+    }
+
+    pub fn try_as_str(&self) -> Result<&str, ConversionError> {
+        self.as_str()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::Str)))
+    }
+
+    pub fn try_as_obj_str(&self) -> Result<Gc<ObjString>, ConversionError> {
+        self.as_obj_str()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::Str)))
+    }
+
+    pub fn try_as_fn(&self) -> Result<Gc<ObjFunction>, ConversionError> {
+        self.as_fn()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::Fn)))
+    }
+
+    pub fn try_as_class(&self) -> Result<Gc<ObjClass>, ConversionError> {
+        self.as_class()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::Class)))
+    }
+
+    pub fn try_as_instance(&self) -> Result<Gc<ObjInstance>, ConversionError> {
+        self.as_instance_fn()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::Instance)))
+    }
+
+    pub fn try_as_list(&self) -> Result<Gc<ObjList>, ConversionError> {
+        self.as_list()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::List)))
+    }
+
+    pub fn try_as_float_array(&self) -> Result<Gc<ObjFloatArray>, ConversionError> {
+        self.as_float_array()
+            .ok_or_else(|| self.conversion_error(ValueKind::Obj(ObjKind::FloatArray)))
+    }
+
     #[inline]
     pub fn is_nil(self) -> bool {
         matches!(self, Value::Nil)
@@ -136,22 +287,19 @@ impl Value {
         self.lt(&other).into()
     }
 
-    fn objs_eq(a: *mut Obj, b: *mut Obj) -> bool {
-        a == b
-        // unsafe {
-        // if (*a).kind != (*b).kind {
-        //     return false;
-        // }
-
-        // match (*a).kind {
-        //     ObjKind::Str => {
-        //         let a_str = (&*(a as *mut ObjString)).as_str();
-        //         let b_str = (&*(b as *mut ObjString)).as_str();
-
-        //         a_str == b_str
-        //     }
-        // }
-        // }
+    /// Strings are interned so they're equal if they're the same object,
+    /// but views aren't so they're compared by their characters
+    fn objs_eq(a: Gc<Obj>, b: Gc<Obj>) -> bool {
+        if a == b {
+            return true;
+        }
+        if a.kind != ObjKind::StrView && b.kind != ObjKind::StrView {
+            return false;
+        }
+        match (Value::Obj(a).as_str(), Value::Obj(b).as_str()) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
     }
 }
 
@@ -168,6 +316,69 @@ impl Debug for Value {
     }
 }
 
+/// How values look to Lox programs, like clox's `printValue`
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let obj = match *self {
+            Self::Bool(b) => return write!(f, "{b}"),
+            Self::Number(n) => return write!(f, "{n}"),
+            Self::Nil => return write!(f, "nil"),
+            Self::Obj(obj) => obj,
+        };
+
+        match obj.kind {
+            ObjKind::Str => write!(f, "{}", obj.downcast_unchecked::<ObjString>().as_str()),
+            ObjKind::StrView => write!(f, "{}", obj.downcast_unchecked::<ObjStringView>().as_str()),
+            ObjKind::Fn => write!(
+                f,
+                "<fn {}>",
+                obj.downcast_unchecked::<ObjFunction>().name_str()
+            ),
+            ObjKind::Closure => write!(
+                f,
+                "{}",
+                Value::Obj(obj.downcast_unchecked::<ObjClosure>().function.cast())
+            ),
+            ObjKind::BoundMethod => write!(
+                f,
+                "{}",
+                Value::Obj(obj.downcast_unchecked::<ObjBoundMethod>().method.cast())
+            ),
+            ObjKind::Native => write!(f, "<native fn>"),
+            ObjKind::Upvalue => write!(f, "upvalue"),
+            ObjKind::Class => write!(
+                f,
+                "{}",
+                unsafe { obj.downcast_unchecked::<ObjClass>().name.as_ref() }.as_str()
+            ),
+            ObjKind::Instance => {
+                let class = obj.downcast_unchecked::<ObjInstance>().class;
+                write!(f, "{} instance", unsafe { class.name.as_ref() }.as_str())
+            }
+            ObjKind::List => obj
+                .downcast_unchecked::<ObjList>()
+                .as_ref()
+                .fmt_items(f, |item, f| write!(f, "{item}")),
+            ObjKind::FloatArray => {
+                write!(f, "[")?;
+                for (i, n) in obj
+                    .downcast_unchecked::<ObjFloatArray>()
+                    .values
+                    .iter()
+                    .enumerate()
+                {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{n}")?;
+                }
+                write!(f, "]")
+            }
+            ObjKind::WeakRef => write!(f, "<weak ref>"),
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -182,7 +393,7 @@ impl PartialEq for Value {
         match (*self, *other) {
             (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
-            (Self::Obj(a), Self::Obj(b)) => Self::objs_eq(a.as_ptr(), b.as_ptr()),
+            (Self::Obj(a), Self::Obj(b)) => Self::objs_eq(a, b),
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -206,7 +417,7 @@ impl Neg for Value {
     fn neg(self) -> Self::Output {
         match self {
             Value::Bool(b) => Value::Bool(!b),
-            Value::Number(num) => Value::Number(num),
+            Value::Number(num) => Value::Number(-num),
             _ => unreachable!(),
         }
     }
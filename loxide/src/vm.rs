@@ -1,31 +1,155 @@
 use std::{
-    alloc::{self, handle_alloc_error, Layout},
-    borrow::Cow,
+    cell::Cell,
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
     mem::{transmute, MaybeUninit},
     num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
     ptr::{self, addr_of_mut, null_mut, NonNull},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Once,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
+    allocations::Allocations,
     chunk::{InstructionDebug, Opcode},
+    color::{paint, Style},
+    compile::Parser,
+    coverage::Coverage,
+    debugger::Debugger,
+    errors::{json_string, Error, ErrorCode, ErrorFormat},
+    events::{GcStats, StderrEvents, VmEvents},
+    fields::{FieldStorage, MAX_SLOTS},
+    handle::{Handle, PinTable},
+    inline_cache::CacheHit,
     mem::{Gc, Greystack, Mem},
-    native_fn::NativeFnKind,
+    mutate::Mutation,
+    native_fn::{self, Capabilities, NativeFn, NativeFnKind, StringMethod},
+    net::Connections,
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjPunnable, ObjString, ObjUpvalue,
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjList,
+        ObjNative, ObjPtrWrapper, ObjPunnable, ObjString, ObjType, ObjUpvalue,
     },
+    options::VmOptions,
+    passes::ChunkPass,
+    profile::{LineProfile, OpcodeHistogram},
+    stack::{Stack, ValueStack},
     table::ObjHash,
     value::Value,
 };
 
 const GC_HEAP_GROW_FACTOR: usize = 2;
+/// How many objects an incremental collection step processes between looking
+/// at the clock
+const GC_DEADLINE_CHECK_INTERVAL: usize = 256;
 
 pub type InterpretResult<T> = Result<T, InterpretError>;
 
+/// The result of [`VM::execute_statement`]
+#[derive(Debug)]
+pub struct Executed {
+    /// The value of the last statement if it was an expression, otherwise nil
+    pub value: Value,
+    /// Everything the statements printed
+    pub output: String,
+}
+
+/// Where a collection cycle is up to. Cycles can be spread over several
+/// [`VM::collect_garbage`] calls, with the program running in between.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GcPhase {
+    Idle,
+    /// Roots have been marked and the grey stack is being drained
+    Marking,
+    /// `remaining` objects from before the sweep started still have to be
+    /// looked at, the `survivors` are at the back of the object list
+    Sweeping {
+        remaining: usize,
+        survivors: usize,
+    },
+}
+
+/// Where [`VM::property`] found a name
+enum Property {
+    Field(Value),
+    Method(Value),
+}
+
+/// The longest string or list `*` can make by repeating one
+pub const MAX_REPEAT_LEN: usize = 1 << 24;
+
+/// How many instructions run between checks for an interrupt
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+/// How many functions a stack overflow or running out of steps blames
+const MAX_OFFENDERS: usize = 3;
+/// How many of the innermost frames a stack trace shows the arguments of
+const TRACE_ARGS_FRAMES: usize = 3;
+
+/// Stops a running VM from another thread, or a signal handler. Get one
+/// from [`VM::interrupt_handle`].
+#[derive(Debug, Clone)]
+pub struct VmInterruptHandle(Arc<AtomicBool>);
+
+impl VmInterruptHandle {
+    /// Make the VM's `run` stop with [`InterpretError::Interrupted`] within
+    /// the next few instructions. If it isn't running, the next script it
+    /// starts clears the interrupt.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether an interrupt hasn't been noticed by the VM yet, like when
+    /// it's stuck in a native
+    pub fn is_pending(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Formatting for the `println` native
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintOptions {
+    /// Written between arguments
+    pub separator: String,
+    /// Written after the last argument
+    pub end: String,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self {
+            separator: " ".into(),
+            end: "\n".into(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum InterpretError {
     RuntimeError,
     CompileError,
+    /// Stopped by the [`Debugger`], call [`VM::run`] again to resume
+    Paused,
+    /// Stopped through a [`VmInterruptHandle`]. It's reported like a runtime
+    /// error, with a stack trace of where the script was.
+    Interrupted,
+    /// Ran as many instructions as [`VM::run_bounded`] allowed
+    Yielded,
+}
+
+/// How far a [`VM::run_bounded`] call got
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    /// The script returned this
+    Done(Value),
+    /// Out of instructions, call `run_bounded` again to carry on
+    Yielded,
+    Error(InterpretError),
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -43,7 +167,7 @@ pub struct CallFrame {
 
 impl CallFrame {
     #[inline]
-    fn function(&self) -> &ObjFunction {
+    pub(crate) fn function(&self) -> &ObjFunction {
         self.closure.as_ref().function.as_ref()
     }
     #[inline]
@@ -51,90 +175,16 @@ impl CallFrame {
         self.closure.as_ref()
     }
     #[inline]
-    fn index(&self, index: usize) -> Value {
+    pub(crate) fn index(&self, index: usize) -> Value {
         unsafe { *self.slots_ptr.add(index) }
     }
     #[inline]
     fn index_ptr(&self, index: usize) -> *mut Value {
         unsafe { self.slots_ptr.add(index) }
     }
-    fn set(&mut self, index: usize, value: Value) {
-        unsafe {
-            *self.slots_ptr.add(index) = value;
-        }
-    }
-}
-
-pub struct StackIter {
-    stack: *mut Value,
-    end: *mut Value,
-}
-
-impl Iterator for StackIter {
-    type Item = Value;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.stack >= self.end {
-            None
-        } else {
-            let value = unsafe { *self.stack };
-            self.stack = unsafe { self.stack.add(1) };
-            Some(value)
-        }
-    }
-}
-
-pub struct Stack {
-    pub stack: *mut Value,
-    pub top: *mut Value,
-}
-
-impl Stack {
-    fn top(&self) -> Value {
-        unsafe {
-            let val = *(self.top.offset(1));
-            val
-        }
-    }
-
-    fn peek(&self, dist: u32) -> Value {
-        unsafe { *self.top.offset(-(dist as isize + 1)) }
-    }
-
-    fn set(&self, dist: u32, value: Value) {
-        unsafe {
-            *self.top.offset(-(dist as isize + 1)) = value;
-        }
-    }
-
-    fn iter(&self) -> StackIter {
-        // let take_amount = self.top as usize - self.stack.as_ptr() as usize;
-        // self.stack
-        //     .iter()
-        //     .take(take_amount)
-        //     .map(|val| unsafe { val.assume_init_read() })
-        StackIter {
-            stack: self.stack,
-            end: self.top,
-        }
-    }
-
-    pub fn add(&mut self, amount: u32) {
-        self.offset(amount as isize)
-    }
-    pub fn sub(&mut self, amount: u32) {
-        self.offset(-(amount as isize))
-    }
-    pub fn offset(&mut self, offset: isize) {
-        self.top = unsafe { self.top.offset(offset) };
-    }
 }
 
 pub const U8_COUNT: usize = (u8::MAX) as usize + 1; // 256
-const FRAMES_MAX: usize = 64;
-pub const STACK_MAX: usize = 64 * U8_COUNT;
-pub static mut STACK: [MaybeUninit<Value>; STACK_MAX] = [MaybeUninit::uninit(); STACK_MAX];
-pub type ValueStack = [MaybeUninit<Value>; STACK_MAX];
 
 pub struct VM {
     pub stack: Stack,
@@ -144,31 +194,132 @@ pub struct VM {
     /// (so last in this list is the first open upvalue on the stack)
     pub open_upvalues: *mut ObjUpvalue,
 
-    pub call_frames: [MaybeUninit<CallFrame>; FRAMES_MAX],
+    /// As many as [`VmOptions::frame_depth`] allows
+    pub call_frames: Vec<MaybeUninit<CallFrame>>,
     pub call_frame_count: u32,
+    /// How many values `stack` has room for
+    pub stack_size: usize,
 
     pub mem: Mem,
     pub grey_stack: Greystack,
 
     pub init_string: Gc<ObjString>,
+
+    /// Print the disassembly of every compiled function before running it
+    pub dump_bytecode: bool,
+    /// How deeply expressions can nest before it's a compile error
+    pub max_expression_depth: usize,
+    /// Warn about assignments to globals a script never declares, see
+    /// [`Parser::warn_undeclared`]
+    pub warn_undeclared: bool,
+    /// Warn about functions and globals a script never uses, see
+    /// [`Parser::warn_unused`]
+    pub warn_unused: bool,
+    /// Let newlines end statements, see [`Parser::asi`]
+    pub asi: bool,
+    /// Compile everything in strict mode, see [`Parser::strict`]
+    pub strict: bool,
+    /// Raise a runtime error for `==` and `!=` on different types, see
+    /// [`VmOptions::strict_equality`]
+    pub strict_equality: bool,
+    /// Refuse to call nondeterministic natives, see
+    /// [`VmOptions::deterministic`]
+    pub deterministic: bool,
+    /// Report the locals of the failing frame with a runtime error, see
+    /// [`VmOptions::verbose_errors`]
+    pub verbose_errors: bool,
+    /// Write errors and listings in color, see [`VmOptions::color`]
+    pub color: bool,
+    /// How errors are written, see [`VmOptions::error_format`]
+    pub error_format: ErrorFormat,
+    /// Print the stack and each instruction as it runs
+    pub trace_execution: bool,
+    /// Where `print` writes to
+    pub output: Box<dyn Write>,
+    /// When set, `print` output is collected here instead of going to
+    /// `output`
+    pub captured_output: Option<Vec<u8>>,
+    /// How the `println` native separates and ends what it writes
+    pub print_options: PrintOptions,
+    /// Where debugging output (bytecode listings, traces) is written
+    pub debug_sink: Box<dyn Write>,
+    /// Per-line execution counts, only recorded when this is set
+    pub coverage: Option<Coverage>,
+    /// Objects allocated by each instruction, only recorded when this is set
+    pub allocations: Option<Allocations>,
+    /// Instructions run on each line, only recorded when this is set
+    pub line_profile: Option<LineProfile>,
+    /// How many times each opcode ran, only recorded when this is set
+    pub opcode_histogram: Option<OpcodeHistogram>,
+    /// Where the current (possibly incremental) collection cycle is up to
+    pub gc_phase: GcPhase,
+    /// What the current cycle has done so far, handed to
+    /// [`VmEvents::gc_end`] when it finishes
+    gc_stats: GcStats,
+    /// Moves on when a class gets a method or is freed, which throws away
+    /// every inline cache entry, see [`InlineCaches`](crate::inline_cache::InlineCaches)
+    pub(crate) cache_epoch: u32,
+    /// Values pinned by the host, see [`Handle`]
+    pub pins: PinTable,
+    /// Applied to the next script compiled for this VM, see [`Mutation`]
+    pub mutation: Option<Mutation>,
+    /// Run over every function compiled for this VM, see
+    /// [`passes`](crate::passes)
+    pub passes: Vec<Rc<dyn ChunkPass>>,
+    /// How many more instructions may run before execution is aborted with
+    /// a runtime error, unlimited when `None`
+    pub step_limit: Option<u64>,
+    /// Instructions each function has run, counted while there's a step
+    /// limit. By address, with the name, like [`Allocations`].
+    steps_by_function: HashMap<*const ObjFunction, (String, u64)>,
+    /// Receives what scripts send to the host, like `log` calls
+    pub events: Box<dyn VmEvents>,
+    /// What natives are allowed to do outside the VM
+    pub capabilities: Capabilities,
+    /// TCP connections opened by scripts
+    pub(crate) connections: Connections,
+    pub debugger: Option<Debugger>,
+    /// How many frames were already active when the current `run` started.
+    /// It returns once they are all that's left, so the host can run code
+    /// on top of a paused script, or a native can call back into Lox.
+    pub base_frame: u32,
+    /// The last runtime error reported, for tools that match on its code
+    pub last_error: Option<Error>,
+    /// Set once a runtime error has been reported, until the next native
+    /// call starts, so natives failing because something they called failed
+    /// don't report it again, however deeply they are nested
+    error_reported: bool,
+    interrupt: Arc<AtomicBool>,
+    /// Instructions left before the run loop takes its slow path, see
+    /// [`VM::budget_spent`]: the fewest until the next check of `interrupt`,
+    /// a bounded run yielding or the step limit running out
+    budget: u32,
+    /// What `budget` was last set to, so the instructions run since are
+    /// known, see [`VM::settle_budget`]
+    budget_granted: u32,
+    /// Instructions left until `run` yields, when running bounded
+    yield_countdown: Option<u64>,
+    /// Set once an interrupt stopped `run`, so an interrupt in a native's
+    /// callback stops the run that called the native too
+    interrupted: bool,
 }
 
 impl VM {
     pub fn init(&mut self, function: Gc<ObjFunction>) {
+        // An interrupt meant for a script that already finished
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.steps_by_function.clear();
+
         let closure = self.mem.alloc_obj(ObjClosure::new(function));
 
         self.call_frames[0] = MaybeUninit::new(CallFrame {
             instr_offset: 0,
-            slots_ptr: self.stack.stack,
+            slots_ptr: self.stack.base(),
             closure,
         });
 
-        unsafe {
-            *self.stack.stack = Value::Obj(closure.cast());
-            self.stack.top = self.stack.stack.add(1);
-        }
-        self.define_native("clock", NativeFnKind::Clock);
-        self.define_native("__dummy", NativeFnKind::Dummy);
+        self.stack.set_top(self.stack.base());
+        self.stack.push(Value::Obj(closure.cast()));
 
         self.call_frame_count = 1;
 
@@ -183,29 +334,72 @@ impl VM {
     }
 
     pub fn new() -> Self {
-        let mut mem = Mem::new();
-        let mut stack = Vec::<Value>::with_capacity(STACK_MAX);
-        // let raw = Box::into_raw(stack.into_boxed_slice());
-
-        let raw = stack.as_mut_ptr();
-        stack.leak();
+        Self::with_options(VmOptions::default())
+    }
 
-        Self {
+    pub fn with_options(options: VmOptions) -> Self {
+        let mut mem = Mem::new();
+        mem.next_gc = options.gc_threshold;
+        let mut vm = Self {
             init_string: mem.copy_string("init"),
-            stack: Stack {
-                stack: raw,
-                top: null_mut(),
-            },
+            stack: Stack::with_capacity(options.stack_size),
             open_upvalues: null_mut(),
-            call_frames: [MaybeUninit::uninit(); FRAMES_MAX],
+            call_frames: vec![MaybeUninit::uninit(); options.frame_depth],
             call_frame_count: 0,
+            stack_size: options.stack_size,
             mem,
             grey_stack: vec![],
+            dump_bytecode: options.dump_bytecode,
+            max_expression_depth: options.max_expression_depth,
+            warn_undeclared: options.warn_undeclared,
+            warn_unused: options.warn_unused,
+            asi: options.asi,
+            strict: options.strict,
+            strict_equality: options.strict_equality,
+            deterministic: options.deterministic,
+            verbose_errors: options.verbose_errors,
+            color: options.color,
+            error_format: options.error_format,
+            trace_execution: options.trace,
+            output: options.output,
+            captured_output: None,
+            print_options: PrintOptions::default(),
+            debug_sink: Box::new(std::io::stdout()),
+            coverage: None,
+            line_profile: None,
+            opcode_histogram: None,
+            allocations: None,
+            gc_phase: GcPhase::Idle,
+            gc_stats: GcStats::new(0),
+            cache_epoch: 0,
+            pins: PinTable::new(),
+            mutation: None,
+            passes: options.passes,
+            step_limit: None,
+            steps_by_function: HashMap::new(),
+            events: Box::new(StderrEvents),
+            capabilities: options.capabilities,
+            connections: Connections::default(),
+            debugger: None,
+            base_frame: 0,
+            error_reported: false,
+            last_error: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            budget: 0,
+            budget_granted: 0,
+            yield_countdown: None,
+            interrupted: false,
+        };
+        // Once, so a script or a REPL line can redefine them for the rest
+        for &(name, kind) in native_fn::GLOBAL_NATIVES {
+            vm.define_native(name, kind);
         }
+        vm.define_math();
+        vm
     }
 
-    fn iter_stack(&self) -> StackIter {
-        self.stack.iter()
+    pub(crate) fn iter_stack(&self) -> impl Iterator<Item = Value> + '_ {
+        self.stack.values().iter().copied()
     }
 
     fn iter_frames(
@@ -220,40 +414,78 @@ impl VM {
             .map(|val| unsafe { val.assume_init_read() })
     }
 
-    fn trace_references(&mut self, greystack: &mut Greystack) {
-        loop {
+    /// Blacken grey objects until there are none left, or until `deadline`
+    /// passes. Returns true if marking finished.
+    fn trace_references(&mut self, deadline: Option<Instant>) -> bool {
+        let mut greystack = std::mem::take(&mut self.grey_stack);
+
+        let mut work = 0;
+        let finished = loop {
             if greystack.is_empty() {
-                break;
+                break true;
             }
 
             // Safety:
             // We checked that the greystack is non-empty
             let obj = unsafe { greystack.pop().unwrap_unchecked() };
-            unsafe { Obj::blacken(obj, greystack) };
-        }
+            unsafe { Obj::blacken(obj, &mut greystack) };
+
+            work += 1;
+            if work % GC_DEADLINE_CHECK_INTERVAL == 0 && past(deadline) {
+                break greystack.is_empty();
+            }
+        };
+
+        self.grey_stack = greystack;
+        finished
     }
 
-    fn sweep(&mut self) {
-        // Clear references to unmarked strings
-        self.mem.interned_strings.remove_white();
+    /// Free unmarked objects that existed when the cycle's sweep started,
+    /// oldest first, until there are none left or `deadline` passes. Returns
+    /// true if sweeping finished.
+    ///
+    /// Objects allocated since the sweep started are pushed to the front of
+    /// the object list, so counting from the back keeps our place stable.
+    fn sweep(&mut self, deadline: Option<Instant>) -> bool {
+        let (mut remaining, mut survivors) = match self.gc_phase {
+            GcPhase::Sweeping {
+                remaining,
+                survivors,
+            } => (remaining, survivors),
+            _ => unreachable!("Not sweeping"),
+        };
 
-        // Now free all unmarked objects
-        let mut i = 0;
-        loop {
-            let mut obj_ptr = match self.mem.obj_list.get(i) {
-                Some(obj) => *obj,
-                None => break,
-            };
+        let mut work = 0;
+        while remaining > 0 {
+            let i = self.mem.obj_list.len() - survivors - 1;
+            let mut obj_ptr = self.mem.obj_list[i];
+            remaining -= 1;
 
             if obj_ptr.as_ref().is_marked {
                 obj_ptr.as_mut().is_marked = false;
-                i += 1;
-                continue;
+                survivors += 1;
+            } else {
+                self.mem.obj_list.remove(i);
+                // Its shapes go with it, and a new one could take the
+                // address of one that's cached
+                if obj_ptr.kind == ObjKind::Class {
+                    self.cache_epoch = self.cache_epoch.wrapping_add(1);
+                }
+                self.gc_stats.freed[obj_ptr.kind as usize] += 1;
+                self.mem.free_obj(obj_ptr)
             }
 
-            self.mem.obj_list.remove(i);
-            Obj::free(obj_ptr.as_non_null_ptr())
+            work += 1;
+            if work % GC_DEADLINE_CHECK_INTERVAL == 0 && past(deadline) {
+                break;
+            }
         }
+
+        self.gc_phase = GcPhase::Sweeping {
+            remaining,
+            survivors,
+        };
+        remaining == 0
     }
 
     fn mark_roots(&mut self, greystack: &mut Greystack) {
@@ -274,23 +506,33 @@ impl VM {
         }
 
         self.mem.globals.mark(greystack);
+        self.pins.mark(greystack);
+        if let Some(debugger) = self.debugger.as_ref() {
+            debugger.mark(greystack);
+        }
 
         Obj::mark(self.init_string.as_ptr().cast(), greystack);
     }
 
-    fn collect_garbage(&mut self) {
+    /// Do up to `budget` worth of garbage collection work, picking up where
+    /// the last call left off. This lets an embedder spread collections over
+    /// the idle time between its own frames instead of pausing for a whole
+    /// one when an allocation crosses the threshold.
+    ///
+    /// Always makes some progress, even with a zero budget. Returns true if
+    /// this call finished a collection cycle.
+    pub fn collect_garbage(&mut self, budget: Duration) -> bool {
+        self.gc_step(Some(Instant::now() + budget))
+    }
+
+    /// Finish the collection cycle in progress, or run a whole new one
+    pub(crate) fn collect_all_garbage(&mut self) {
         #[cfg(feature = "debug_gc")]
         println!("-- gc begin");
         #[cfg(feature = "debug_gc")]
         let before = self.mem.bytes_allocated();
 
-        let mut greystack = std::mem::take(&mut self.grey_stack);
-
-        self.mark_roots(&mut greystack);
-        self.trace_references(&mut greystack);
-        self.sweep();
-
-        self.mem.next_gc = self.mem.bytes_allocated() * GC_HEAP_GROW_FACTOR;
+        self.gc_step(None);
 
         #[cfg(feature = "debug_gc")]
         {
@@ -305,14 +547,120 @@ impl VM {
         }
     }
 
-    fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
+    fn gc_step(&mut self, deadline: Option<Instant>) -> bool {
+        let start = Instant::now();
+        let finished = self.gc_work(deadline);
+        self.gc_stats.duration += start.elapsed();
+        if finished {
+            self.gc_stats.bytes_after = self.mem.live_bytes;
+            self.events.gc_end(&self.gc_stats);
+        }
+        finished
+    }
+
+    fn gc_work(&mut self, deadline: Option<Instant>) -> bool {
+        if self.gc_phase == GcPhase::Idle {
+            self.events.gc_start(self.mem.live_bytes);
+            self.gc_stats = GcStats::new(self.mem.live_bytes);
+            let mut greystack = std::mem::take(&mut self.grey_stack);
+            self.mark_roots(&mut greystack);
+            self.grey_stack = greystack;
+            self.gc_phase = GcPhase::Marking;
+        }
+
+        if self.gc_phase == GcPhase::Marking {
+            if !self.trace_references(deadline) {
+                return false;
+            }
+
+            // The roots may have changed since the cycle started, and they
+            // aren't covered by the write barrier, so mark them again. This
+            // part can't be interrupted, otherwise it might never finish.
+            let mut greystack = std::mem::take(&mut self.grey_stack);
+            self.mark_roots(&mut greystack);
+            self.grey_stack = greystack;
+            self.trace_references(None);
+
+            // Clear references to unmarked strings
+            self.mem.interned_strings.remove_white();
+            self.mem.clear_weak_refs();
+
+            self.gc_phase = GcPhase::Sweeping {
+                remaining: self.mem.obj_list.len(),
+                survivors: 0,
+            };
+        }
+
+        if !self.sweep(deadline) {
+            return false;
+        }
+
+        self.gc_phase = GcPhase::Idle;
+        self.mem.next_gc = self.mem.bytes_allocated() * GC_HEAP_GROW_FACTOR;
+        if let Some(trace) = self.mem.trace.as_mut() {
+            trace.collection();
+        }
+        true
+    }
+
+    /// Give up on the collection cycle in progress, unmarking every object
+    /// so the next one starts from scratch, for when a panic may have left
+    /// it half way through a step
+    fn abandon_collection(&mut self) {
+        self.grey_stack.clear();
+        for &obj in self.mem.obj_list.iter() {
+            unsafe { (*obj.as_ptr()).is_marked = false };
+        }
+        self.gc_phase = GcPhase::Idle;
+    }
+
+    /// Mark `value` if an incremental collection is marking, since it's
+    /// about to be stored in an object that may already have been traced
+    #[inline]
+    pub(crate) fn write_barrier(&mut self, value: Value) {
+        if self.gc_phase == GcPhase::Marking {
+            value.mark(&mut self.grey_stack);
+        }
+    }
+
+    pub(crate) fn alloc_obj<T: ObjPunnable>(&mut self, obj: T) -> Gc<T> {
         if self.mem.should_run_gc::<T>() {
             #[cfg(feature = "debug_gc")]
             println!("Allocated a {:?}, now collecting garbage", obj.kind());
-            self.collect_garbage();
+            self.collect_all_garbage();
+        }
+
+        if self.allocations.is_some() && self.call_frame_count > 0 {
+            self.record_allocation(obj.kind(), std::mem::size_of::<T>());
         }
 
-        self.mem.alloc_obj(obj)
+        let obj = self.mem.alloc_obj(obj);
+        // Objects allocated while marking start out grey, they might be
+        // stored somewhere that has already been traced
+        if self.gc_phase == GcPhase::Marking {
+            Obj::mark(obj.as_ptr().cast(), &mut self.grey_stack);
+        }
+        obj
+    }
+
+    fn record_allocation(&mut self, kind: ObjKind, bytes: usize) {
+        let frame = self.top_call_frame();
+        let function = frame.closure.function;
+        // The instruction pointer is already past the byte that allocated
+        let offset = frame.instr_offset.saturating_sub(1);
+        if let Some(allocations) = self.allocations.as_mut() {
+            allocations.record(function.as_ref(), offset, kind, bytes);
+        }
+    }
+
+    /// What [`VM::allocations`] recorded, if it's set, busiest site first
+    pub fn allocation_report(&self) -> Option<String> {
+        let allocations = self.allocations.as_ref()?;
+        let mut out = vec![];
+        allocations
+            .write_report(&mut out)
+            .expect("Writing to a Vec can't fail.");
+        Some(String::from_utf8(out).unwrap())
     }
 
     fn alloc_obj_string(&mut self, obj_string: ObjString) -> Gc<ObjString> {
@@ -336,6 +684,8 @@ impl VM {
             hash,
         ) {
             Some(interned) => {
+                // We own `chars`, and the interned string has its own
+                self.mem.free_chars(chars, len as usize);
                 return interned;
             }
             None => (),
@@ -345,6 +695,39 @@ impl VM {
         self.alloc_obj_string(obj_string)
     }
 
+    /// Intern a copy of `string`. Unlike `Mem::copy_string` this can trigger
+    /// a collection, so it's the one to use once the program is running.
+    pub fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
+        let hash = ObjHash::hash_string(string);
+        if let Some(interned) = self.mem.interned_strings.find_string(string, hash) {
+            return interned;
+        }
+
+        // Allocating layout for zero length data is not allowed
+        let chars = if string.is_empty() {
+            NonNull::dangling()
+        } else {
+            let chars = self.mem.alloc_chars(string.len());
+            unsafe { ptr::copy_nonoverlapping(string.as_ptr(), chars.as_ptr(), string.len()) };
+            chars
+        };
+
+        self.alloc_obj_string(ObjString::new(chars, string.len() as u32, hash))
+    }
+
+    /// The interned string with the same characters as `value`, if it's a
+    /// string. Tables are keyed by interned strings, so a view has to go
+    /// through this to be used as a key, which copies its characters unless
+    /// they're interned already.
+    pub fn intern(&mut self, value: Value) -> Option<Gc<ObjString>> {
+        if let Some(string) = value.as_obj_str() {
+            return Some(string);
+        }
+        let view = value.as_str_view()?;
+        // The characters are copied before anything can be collected
+        Some(self.copy_string(view.as_str()))
+    }
+
     #[cfg(debug_assertions)]
     /// Only to be used for debugging purposes
     pub fn get_string(&mut self, string: &str) -> Gc<ObjString> {
@@ -352,77 +735,276 @@ impl VM {
     }
 
     #[inline]
-    fn push(&mut self, val: Value) {
-        // unsafe {
-        //     *self.stack.as_mut_ptr().add(self.stack_top as usize) = MaybeUninit::new(val);
-        // }
-        unsafe {
-            *(self.stack.top) = val;
-            self.stack.offset(1);
-        }
+    pub(crate) fn push(&mut self, val: Value) {
+        self.stack.push(val)
     }
 
     #[inline]
-    fn pop(&mut self) -> Value {
-        self.stack.sub(1);
-        unsafe { *self.stack.top }
+    pub(crate) fn pop(&mut self) -> Value {
+        self.stack.pop()
     }
 
     #[inline]
     fn binary_op<F: FnOnce(Value, Value) -> Value>(&mut self, f: F) -> InterpretResult<()> {
         if !matches!(self.peek(0), Value::Number(_)) || !matches!(self.peek(1), Value::Number(_)) {
-            self.runtime_error("Operands must be two numbers or two strings.".into());
+            self.runtime_error(ErrorCode::OperandsMustBeNumbersOrStrings, &[]);
             return Err(InterpretError::RuntimeError);
         }
 
         let b = self.pop();
         let a = self.pop();
-        self.push(f(a, b));
+        let result = f(a, b);
+        if let Value::Number(n) = result {
+            if !n.is_finite() && self.in_strict_code() {
+                self.runtime_error(ErrorCode::NotFinite, &[&n]);
+                return Err(InterpretError::RuntimeError);
+            }
+        }
+        self.push(result);
 
         Ok(())
     }
 
+    /// Whether the code running was compiled in strict mode, see
+    /// [`Parser::strict`]
+    fn in_strict_code(&self) -> bool {
+        self.top_call_frame().function().chunk.strict
+    }
+
     #[inline]
-    fn reset_stack(&mut self) {
-        self.stack.top = self.stack.stack;
+    pub(crate) fn reset_stack(&mut self) {
+        self.stack.set_top(self.stack.base());
         self.call_frame_count = 0;
         self.open_upvalues = null_mut();
     }
 
-    fn runtime_error<'a>(&mut self, err: Cow<'a, str>) {
-        eprintln!("{err}");
+    fn runtime_error(&mut self, code: ErrorCode, args: &[&dyn Display]) {
+        self.raise(code.error(args))
+    }
 
-        let frame = self.top_call_frame();
-        let instr_idx = frame.instr_offset;
-        let line = frame.function().chunk.lines[instr_idx as usize];
+    /// Report `err` with a stack trace and throw away the failed script
+    fn raise(&mut self, mut err: Error) {
+        err.trace = self.stack_trace();
+        if self.verbose_errors {
+            err.notes.extend(self.frame_locals());
+        }
+        err.notes.extend(self.budget_offenders(err.code));
 
-        eprintln!("[line {line}] in script");
+        self.report(&err);
+        self.last_error = Some(err);
+        self.error_reported = true;
+        self.unwind();
+    }
 
-        for frame in self
-            .call_frames
-            .iter()
-            .take(self.call_frame_count as usize - 1)
-            .rev()
-        {
-            unsafe {
-                let frame = frame.assume_init();
-                let function = frame.function();
-                let instruction = frame.instr_offset;
-                eprintln!(
-                    "[line {}] in {}",
-                    function.chunk.lines[instruction as usize],
-                    match function.name.as_ref() {
-                        Some(name) => name.as_str(),
-                        None => "script",
-                    }
+    /// Write `err` to stderr in the [`error_format`](VM::error_format)
+    fn report(&self, err: &Error) {
+        match self.error_format {
+            ErrorFormat::Human => {
+                eprintln!("{}", paint(self.color, Style::Error, &err.to_string()));
+                for line in &err.trace {
+                    eprintln!("{}", self.paint_trace_line(line));
+                }
+                for note in &err.notes {
+                    eprintln!("{note}");
+                }
+            }
+            ErrorFormat::Json => eprintln!("{}", self.error_json(err)),
+        }
+    }
+
+    /// `err` as a line of JSON, see [`ErrorFormat::Json`], at the
+    /// instruction the innermost frame was running
+    fn error_json(&self, err: &Error) -> String {
+        let (file, line, column) = match self.iter_frames().next_back() {
+            Some(frame) => {
+                let chunk = &frame.function().chunk;
+                let offset = frame.instr_offset as usize;
+                (
+                    chunk
+                        .file
+                        .as_deref()
+                        .map_or("null".to_string(), json_string),
+                    chunk.lines[offset],
+                    chunk.columns.get(offset).copied().unwrap_or(0),
                 )
             }
+            None => ("null".to_string(), 0, 0),
+        };
+        let list = |lines: &[String]| {
+            let lines: Vec<_> = lines.iter().map(|line| json_string(line)).collect();
+            format!("[{}]", lines.join(","))
+        };
+        format!(
+            "{{\"severity\":\"error\",\"code\":\"{}\",\"message\":{},\"file\":{file},\"line\":{line},\"column\":{column},\"span\":null,\"trace\":{},\"notes\":{}}}",
+            err.code.code(),
+            json_string(&err.message),
+            list(&err.trace),
+            list(&err.notes)
+        )
+    }
+
+    /// Report the innermost frame's call, or with its `result`, its return,
+    /// to the [`events`](VM::events), unless it's the script's
+    fn trace_call(&mut self, result: Option<Value>) {
+        let frame = self.top_call_frame();
+        let function = frame.function();
+        if function.name.is_null() {
+            return;
+        }
+        let depth = self.call_frame_count.saturating_sub(2) as usize;
+        let name = function.name_str().to_string();
+        match result {
+            None => {
+                let args = Self::frame_args(frame);
+                self.events.function_entered(depth, &name, &args)
+            }
+            Some(result) => {
+                let result = native_fn::inspect(result, 0);
+                self.events.function_returned(depth, &name, &result)
+            }
+        }
+    }
+
+    /// A line of [`stack_trace`](VM::stack_trace) as it's printed, with its
+    /// location highlighted
+    fn paint_trace_line(&self, line: &str) -> String {
+        match line.split_once("] ") {
+            Some((location, rest)) if line.starts_with('[') => format!(
+                "{} {rest}",
+                paint(self.color, Style::Location, &format!("{location}]"))
+            ),
+            _ => paint(self.color, Style::Dim, line),
+        }
+    }
+
+    /// A line per frame, innermost first, with the arguments of the
+    /// innermost few. A run of the same line, like a recursion that
+    /// overflowed the stack, is shown once and then counted.
+    fn stack_trace(&self) -> Vec<String> {
+        let mut trace = vec![];
+        let mut previous: Option<String> = None;
+        let mut repeated = 0;
+        for (depth, frame) in self.iter_frames().rev().enumerate() {
+            let function = frame.function();
+            let mut line = format!(
+                "{} in {}",
+                function.chunk.location(frame.instr_offset as usize),
+                function.name_str()
+            );
+            if depth < TRACE_ARGS_FRAMES && !function.name.is_null() {
+                line.push_str(&format!("({})", Self::frame_args(&frame)));
+            }
+
+            if previous.as_ref() == Some(&line) {
+                repeated += 1;
+                continue;
+            }
+            Self::push_repeated(&mut trace, previous.take(), repeated);
+            trace.push(line.clone());
+            previous = Some(line);
+            repeated = 0;
+        }
+        Self::push_repeated(&mut trace, previous, repeated);
+        trace
+    }
+
+    fn push_repeated(trace: &mut Vec<String>, line: Option<String>, repeated: u32) {
+        match repeated {
+            0 => (),
+            1 => trace.extend(line),
+            _ => trace.push(format!("... previous frame repeated {repeated} times")),
         }
+    }
+
+    /// The arguments `frame` was called with, named from the debug table
+    /// when it has the parameters
+    fn frame_args(frame: &CallFrame) -> String {
+        let function = frame.function();
+        let args: Vec<_> = (1..=function.arity as usize)
+            .map(|slot| {
+                let value = native_fn::inspect(frame.index(slot), 0);
+                let name = function
+                    .chunk
+                    .locals
+                    .iter()
+                    .find(|local| local.start == 0 && local.slot as usize == slot);
+                match name {
+                    Some(local) => format!("{}={value}", local.name),
+                    None => value,
+                }
+            })
+            .collect();
+        args.join(", ")
+    }
+
+    /// A line naming the innermost frame, then a line per local in scope
+    /// where it stopped, or nothing if the debug table has none
+    fn frame_locals(&self) -> Vec<String> {
+        let frame = self.top_call_frame();
+        let function = frame.function();
+        let locals = function.chunk.locals_at(frame.instr_offset);
+        if locals.is_empty() {
+            return vec![];
+        }
+        let mut lines = vec![format!("Locals of {}:", function.name_str())];
+        for local in locals {
+            let value = native_fn::inspect(frame.index(local.slot as usize), 0);
+            lines.push(format!("  {} = {value}", local.name));
+        }
+        lines
+    }
+
+    fn count_step(&mut self) {
+        let function = self.top_call_frame().closure.function;
+        let (_, count) = self
+            .steps_by_function
+            .entry(function.as_ptr())
+            .or_insert_with(|| (function.name_str().to_owned(), 0));
+        *count += 1;
+    }
+
+    /// What to blame for a stack overflow or for running out of steps: the
+    /// functions with the most frames on the stack, or that ran the most
+    /// instructions, most first. `None` for other errors.
+    fn budget_offenders(&self, code: ErrorCode) -> Option<String> {
+        let (what, mut counts) = match code {
+            ErrorCode::StackOverflow => {
+                let mut counts: Vec<(String, u64)> = vec![];
+                for frame in self.iter_frames() {
+                    let name = frame.function().name_str();
+                    match counts.iter_mut().find(|(n, _)| n == name) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((name.to_owned(), 1)),
+                    }
+                }
+                ("frames", counts)
+            }
+            ErrorCode::StepLimitExceeded => {
+                let counts = self.steps_by_function.values().cloned().collect();
+                ("instructions", counts)
+            }
+            _ => return None,
+        };
+        counts.sort_by(|(a, a_count), (b, b_count)| {
+            (Reverse(a_count), a).cmp(&(Reverse(b_count), b))
+        });
+        let counts: Vec<_> = counts
+            .iter()
+            .take(MAX_OFFENDERS)
+            .map(|(name, count)| format!("{name} ({count})"))
+            .collect();
+        Some(format!("Most {what}: {}", counts.join(", ")))
+    }
 
-        self.reset_stack();
+    /// Throw away the frames of a script that failed
+    fn unwind(&mut self) {
+        // Leave a paused script, or the native that called in, intact
+        if self.base_frame == 0 {
+            self.reset_stack();
+        }
     }
 
-    fn peek(&self, distance: u32) -> Value {
+    pub(crate) fn peek(&self, distance: u32) -> Value {
         self.stack.peek(distance)
     }
 
@@ -430,10 +1012,11 @@ impl VM {
         let b = self.pop();
         let a = self.pop();
 
-        let b = b.as_obj_str().unwrap();
-        let a = a.as_obj_str().unwrap();
+        // Either might be a view
+        let b = b.as_str().unwrap();
+        let a = a.as_str().unwrap();
 
-        let new_len = a.len + b.len;
+        let new_len = (a.len() + b.len()) as u32;
 
         let obj_str = if new_len == 0 {
             self.alloc_obj_string(ObjString::new(
@@ -442,21 +1025,11 @@ impl VM {
                 ObjHash::EMPTY_STR_HASH,
             ))
         } else {
-            let layout = Layout::array::<u8>(new_len as usize).unwrap();
-            let chars = unsafe {
-                match NonNull::new(alloc::alloc(layout)) {
-                    Some(ptr) => ptr,
-                    None => handle_alloc_error(layout),
-                }
-            };
+            let chars = self.mem.alloc_chars(new_len as usize);
 
             unsafe {
-                ptr::copy_nonoverlapping(a.chars.as_ptr(), chars.as_ptr(), a.len as usize);
-                ptr::copy_nonoverlapping(
-                    b.chars.as_ptr(),
-                    chars.as_ptr().offset(a.len as isize),
-                    b.len as usize,
-                );
+                ptr::copy_nonoverlapping(a.as_ptr(), chars.as_ptr(), a.len());
+                ptr::copy_nonoverlapping(b.as_ptr(), chars.as_ptr().add(a.len()), b.len());
             }
 
             self.take_string(chars, new_len)
@@ -465,15 +1038,47 @@ impl VM {
         self.push(Value::Obj(obj_str.cast()))
     }
 
+    /// `"ab" * 3` or `[0] * 10`, replacing the operands on the stack with a
+    /// new string or list of `sequence` repeated `count` times
+    fn repeat(&mut self, sequence: Value, count: Value) -> Result<(), Error> {
+        let count = match count {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => n,
+            _ => return Err(ErrorCode::InvalidRepeatCount.error(&[])),
+        };
+        let (kind, len) = match sequence.as_list() {
+            Some(list) => ("list", list.items.len()),
+            None => ("string", sequence.as_str().unwrap().len()),
+        };
+        let new_len = len as f64 * count;
+        if new_len > MAX_REPEAT_LEN as f64 {
+            return Err(ErrorCode::RepeatTooLong.error(&[&kind, &new_len, &MAX_REPEAT_LEN]));
+        }
+
+        // The operands stay on the stack until the result is made
+        let repeated = match sequence.as_list() {
+            Some(list) => {
+                let items = list.items.repeat(count as usize);
+                Value::Obj(self.alloc_obj(ObjList::new(items)).cast())
+            }
+            None => {
+                let string = sequence.as_str().unwrap().repeat(count as usize);
+                Value::Obj(self.copy_string(&string).cast())
+            }
+        };
+        self.stack.sub(2);
+        self.push(repeated);
+        Ok(())
+    }
+
     fn call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> bool {
         let arity = closure.as_ref().function.as_ref().arity;
         if arg_count != arity {
-            self.runtime_error(format!("Expected {arg_count} arguments but got {arity}.").into());
+            self.runtime_error(ErrorCode::WrongArity, &[&arity, &arg_count]);
             return false;
         }
 
-        if self.call_frame_count as usize == FRAMES_MAX {
-            self.runtime_error("Stack overflow.".into());
+        if !self.has_room_for_frame() {
+            self.runtime_error(ErrorCode::StackOverflow, &[]);
             return false;
         }
 
@@ -482,30 +1087,224 @@ impl VM {
         true
     }
 
+    /// Call `callee` with `args` and run it to completion, for natives that
+    /// take Lox functions. It runs in a nested [`VM::run`], on top of the
+    /// native's own call.
+    ///
+    /// If it fails the native should fail with the same error, which won't
+    /// be reported twice. The result isn't rooted anywhere, so
+    /// [`pin`](VM::pin) it before allocating.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Result<Value, String> {
+        let arg_count: u8 = args
+            .len()
+            .try_into()
+            .map_err(|_| ErrorCode::TooManyArguments.message(&[]))?;
+
+        let base = self.stack.top();
+        let used = self.stack.len();
+        if used + args.len() + 1 > self.stack_size {
+            return Err(ErrorCode::StackOverflow.message(&[]));
+        }
+        self.push(callee);
+        for &arg in args {
+            self.push(arg);
+        }
+
+        let frames = self.call_frame_count;
+        if !self.call_value(callee, arg_count) {
+            self.stack.set_top(base);
+            return Err("Call failed.".into());
+        }
+
+        // Natives, and classes without an initializer, are done already
+        if self.call_frame_count == frames {
+            let result = self.pop();
+            self.stack.set_top(base);
+            return Ok(result);
+        }
+
+        // Anything the callback does is part of the native call as far as
+        // the debugger is concerned
+        let suspended = self
+            .debugger
+            .as_mut()
+            .map(|debugger| std::mem::replace(&mut debugger.suspended, true));
+
+        let base_frame = std::mem::replace(&mut self.base_frame, frames);
+        self.settle_budget();
+        let bound = self.yield_countdown.take();
+        let result = self.run();
+        self.yield_countdown = bound;
+        self.call_frame_count = frames;
+        self.base_frame = base_frame;
+        self.stack.set_top(base);
+
+        if let (Some(debugger), Some(suspended)) = (self.debugger.as_mut(), suspended) {
+            debugger.suspended = suspended;
+        }
+
+        result.map_err(|_| "Called function failed.".into())
+    }
+
+    /// Whether another frame can be pushed, with room on the stack for all
+    /// the locals it could have
+    pub(crate) fn has_room_for_frame(&self) -> bool {
+        let used = self.stack.len();
+        (self.call_frame_count as usize) < self.call_frames.len()
+            && used + U8_COUNT <= self.stack_size
+    }
+
+    /// Compile and run a single statement (or a few) on top of everything run
+    /// so far, as a REPL or notebook cell would. Top-level state carries over
+    /// between calls through globals.
+    ///
+    /// If the last statement is an expression statement, its value is
+    /// returned, and for convenience its trailing `;` can be left out.
+    /// Anything printed is captured and returned too, rather than written to
+    /// `output`.
+    pub fn execute_statement(&mut self, src: &str) -> InterpretResult<Executed> {
+        let function = {
+            let mut parser = Parser::new(src, &mut self.mem);
+            parser.repl = true;
+            parser.max_expression_depth = self.max_expression_depth;
+            parser.warn_undeclared = self.warn_undeclared;
+            parser.asi = self.asi;
+            parser.strict = self.strict;
+            parser.passes = self.passes.clone();
+            if !parser.compile() {
+                return Err(InterpretError::CompileError);
+            }
+            parser.compiler.function
+        };
+
+        let previous = self.captured_output.replace(vec![]);
+        self.init(function);
+        let result = self.catch_panics(VM::run);
+        let output = std::mem::replace(&mut self.captured_output, previous).unwrap_or_default();
+
+        Ok(Executed {
+            value: result?,
+            output: String::from_utf8_lossy(&output).into_owned(),
+        })
+    }
+
+    /// Make `function` callable from scripts as the global `name`. See
+    /// [`NativeCtx`](crate::native_fn::NativeCtx) for what natives must do to
+    /// stay safe around the GC.
+    pub fn register_native(&mut self, name: &str, function: NativeFn) {
+        self.define_native(name, NativeFnKind::Custom(function));
+    }
+
     fn define_native(&mut self, name: &str, native_fn_kind: NativeFnKind) {
         // We don't want/need to trigger GC here so directly call allocation
-        // functions on `self.mem`
+        // functions on `self.mem`, which also means this works before the
+        // stack is set up by `init`
+
+        let name = self.mem.copy_string(name);
+        let native_fn = self.mem.alloc_obj(ObjNative::new(native_fn_kind));
+
+        self.mem
+            .globals
+            .set(name.as_non_null_ptr(), Value::Obj(native_fn.cast()));
+    }
 
-        let name = Value::Obj(self.mem.copy_string(name).cast());
+    /// Define the global `math`, whose fields are modules of natives, like
+    /// `math.matrix.multiply`
+    fn define_math(&mut self) {
+        let matrix = self.native_module("matrix", native_fn::MATRIX_NATIVES);
+        let mut math = self.native_module(native_fn::MATH_MODULE, &[]);
+        let key = self.mem.copy_string("matrix");
+        math.fields
+            .set(key.as_non_null_ptr(), Value::Obj(matrix.cast()));
+
+        let name = self.mem.copy_string(native_fn::MATH_MODULE);
+        self.mem
+            .globals
+            .set(name.as_non_null_ptr(), Value::Obj(math.cast()));
+    }
 
-        let native_fn = Value::Obj(self.mem.alloc_obj(ObjNative::new(native_fn_kind)).cast());
+    /// An instance of a class called `name`, with `natives` as its fields.
+    /// Allocates without triggering GC, like `define_native`.
+    fn native_module(&mut self, name: &str, natives: &[(&str, NativeFnKind)]) -> Gc<ObjInstance> {
+        let name = self.mem.copy_string(name);
+        let class = self.mem.alloc_obj(ObjClass::new(name.as_non_null_ptr()));
+        let mut module = self.mem.alloc_obj(ObjInstance::new(class));
+        for &(name, kind) in natives {
+            let name = self.mem.copy_string(name);
+            let native = self.mem.alloc_obj(ObjNative::new(kind));
+            module
+                .fields
+                .set(name.as_non_null_ptr(), Value::Obj(native.cast()));
+        }
+        module
+    }
 
-        self.push(name);
-        self.push(native_fn);
+    /// Write program output, to `captured_output` if it's being captured
+    pub(crate) fn write_output(&mut self, args: std::fmt::Arguments) {
+        match self.captured_output.as_mut() {
+            Some(captured) => captured.write_fmt(args),
+            None => self.output.write_fmt(args),
+        }
+        .expect("Failed to write output.");
+    }
 
-        self.mem.globals.set(
-            // self.stack[self.stack_top as usize - 2]
-            //     .assume_init()
-            //     .as_obj_str()
-            //     .unwrap()
-            //     .as_non_null_ptr(),
-            // self.stack[self.stack_top as usize - 1].assume_init(),
-            self.stack.peek(1).as_obj_str().unwrap().as_non_null_ptr(),
-            self.stack.peek(0),
-        );
+    /// Define the global `ARGS` as a list of `args`, the arguments the script
+    /// was started with
+    pub fn set_args(&mut self, args: &[String]) {
+        // Nothing here is reachable until it's in the globals table, so
+        // allocate without triggering GC like `define_native`
+        let items = args
+            .iter()
+            .map(|arg| Value::Obj(self.mem.copy_string(arg).cast()))
+            .collect();
+        let list = self.mem.alloc_obj(ObjList::new(items));
+        let name = self.mem.copy_string("ARGS");
+
+        self.mem
+            .globals
+            .set(name.as_non_null_ptr(), Value::Obj(list.cast()));
+    }
 
-        self.pop();
-        self.pop();
+    /// The value of the global `name`, if it's defined. The value isn't
+    /// rooted anywhere but the globals table.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self
+            .mem
+            .interned_strings
+            .find_string(name, ObjHash::hash_string(name))?;
+        self.mem.globals.get(name.as_non_null_ptr())
+    }
+
+    /// Every live object of type `T`, like every instance to audit which
+    /// ones hold a resource. Borrowing the VM means nothing can run or
+    /// allocate while this iterates, so call it between
+    /// [`run_bounded`](VM::run_bounded) steps, while paused in the debugger,
+    /// or from a native. Objects the sweep in progress is about to free are
+    /// skipped. Like [`VM::get_global`]'s, the objects aren't rooted, pin
+    /// them to keep them past anything that allocates.
+    pub fn live_objects<T: ObjType>(&self) -> impl Iterator<Item = Gc<T>> + '_ {
+        let unswept = match self.gc_phase {
+            GcPhase::Sweeping {
+                remaining,
+                survivors,
+            } => {
+                let end = self.mem.obj_list.len() - survivors;
+                end - remaining..end
+            }
+            _ => 0..0,
+        };
+        self.mem
+            .obj_list
+            .iter()
+            .enumerate()
+            .filter(move |(i, obj)| obj.is_marked || !unswept.contains(i))
+            .filter_map(|(_, obj)| obj.downcast())
+    }
+
+    /// Keep `value` alive until the returned handle is dropped, for host code
+    /// holding on to it across anything that might allocate
+    pub fn pin(&mut self, value: Value) -> Handle {
+        self.pins.pin(value)
     }
 
     fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
@@ -514,7 +1313,7 @@ impl VM {
                 let kind = obj.as_ref().kind;
                 match kind {
                     ObjKind::Class => {
-                        let class: Gc<ObjClass> = obj.cast();
+                        let class: Gc<ObjClass> = obj.downcast_unchecked();
                         let instance = self.alloc_obj(ObjInstance::new(class));
                         self.stack
                             .set(arg_count as u32, Value::Obj(instance.cast()));
@@ -528,25 +1327,37 @@ impl VM {
                         }
 
                         if arg_count != 0 {
-                            self.runtime_error(
-                                format!("Expected 0 arguments but got {:?}", arg_count).into(),
-                            );
+                            self.runtime_error(ErrorCode::WrongArity, &[&0, &arg_count]);
                             return false;
                         }
 
                         return true;
                     }
-                    ObjKind::Closure => return self.call(obj.cast(), arg_count),
+                    ObjKind::Closure => return self.call(obj.downcast_unchecked(), arg_count),
                     ObjKind::Native => {
-                        let native: Gc<ObjNative> = obj.cast();
+                        let native: Gc<ObjNative> = obj.downcast_unchecked();
                         let values = unsafe {
                             std::slice::from_raw_parts(
-                                self.stack.top.sub(arg_count as usize),
+                                self.stack.top().sub(arg_count as usize),
                                 arg_count as usize,
                             )
                         };
-                        let result =
-                            unsafe { native.as_ref().function.call(std::mem::transmute(values)) };
+                        let function = native.as_ref().function;
+                        if self.deterministic && !function.is_deterministic(values) {
+                            self.runtime_error(ErrorCode::Nondeterministic, &[&function.name()]);
+                            return false;
+                        }
+                        if let Some(debugger) = self.debugger.as_mut() {
+                            debugger.called_native(&function, values);
+                        }
+                        self.error_reported = false;
+                        let result = match native.as_ref().function.call(self, values) {
+                            Ok(result) => result,
+                            Err(err) => {
+                                self.native_failed(err);
+                                return false;
+                            }
+                        };
 
                         self.stack.sub(arg_count as u32 + 1);
 
@@ -554,9 +1365,8 @@ impl VM {
                         return true;
                     }
                     ObjKind::BoundMethod => {
-                        let bound: Gc<ObjBoundMethod> = obj.cast();
-                        self.stack
-                            .set(arg_count as u32 + 1, bound.as_ref().receiver);
+                        let bound: Gc<ObjBoundMethod> = obj.downcast_unchecked();
+                        self.stack.set(arg_count as u32, bound.as_ref().receiver);
                         return self.call(bound.method, arg_count);
                     }
                     _ => (),
@@ -565,7 +1375,7 @@ impl VM {
             _ => {}
         }
 
-        self.runtime_error("Can only call functions and classes.".into());
+        self.runtime_error(ErrorCode::NotCallable, &[]);
         false
     }
 
@@ -656,6 +1466,7 @@ impl VM {
                 // (*upvalue_ptr).closed = *((*upvalue_ptr).location.as_ptr() as *const _);
 
                 (*upvalue_ptr).closed = *(*upvalue_ptr).location.as_ptr();
+                self.write_barrier((*upvalue_ptr).closed);
 
                 (*upvalue_ptr).location =
                     NonNull::new(addr_of_mut!((*upvalue_ptr).closed)).unwrap();
@@ -668,16 +1479,74 @@ impl VM {
         let method = self.peek(0);
         let class_value = self.peek(1);
         let mut class = class_value.as_class().unwrap();
+        self.write_barrier(method);
         let class = class.as_mut();
         class.methods.set(name.as_non_null_ptr(), method);
+        self.cache_epoch = self.cache_epoch.wrapping_add(1);
+        self.pop();
+    }
+
+    /// Look `name` up on `instance` for the `GetProperty` or `Invoke` at
+    /// `site` in the current function, through its inline cache
+    #[inline]
+    fn property(
+        &mut self,
+        instance: Gc<ObjInstance>,
+        name: Gc<ObjString>,
+        site: usize,
+    ) -> Option<Property> {
+        let mut function = self.top_call_frame().closure.function;
+        let shape = instance.fields.shape();
+        if let Some(shape) = shape {
+            match function.caches.get(site, shape, self.cache_epoch) {
+                Some(CacheHit::Slot(slot)) => {
+                    return Some(Property::Field(instance.fields.slot(slot)))
+                }
+                Some(CacheHit::Method(method)) => return Some(Property::Method(method)),
+                None => (),
+            }
+        }
+
+        let name = name.as_non_null_ptr();
+        let (property, hit) = match instance.fields.get(name) {
+            Some(value) => {
+                let slot = shape.and_then(|shape| unsafe { shape.as_ref() }.slot(name));
+                (Property::Field(value), slot.map(CacheHit::Slot))
+            }
+            None => {
+                let method = instance.class.methods.get(name)?;
+                // A full shape might have the name in its overflow table later
+                let cacheable =
+                    shape.map_or(false, |shape| unsafe { shape.as_ref() }.len() < MAX_SLOTS);
+                (
+                    Property::Method(method),
+                    cacheable.then_some(CacheHit::Method(method)),
+                )
+            }
+        };
+        if let (Some(shape), Some(hit)) = (shape, hit) {
+            let code_len = function.chunk.code.len();
+            function
+                .caches
+                .set(code_len, site, shape, self.cache_epoch, hit);
+        }
+        Some(property)
+    }
+
+    /// Replace the receiver on top of the stack with `method` bound to it
+    fn bind(&mut self, method: Value) {
+        let bound = ObjBoundMethod::new(self.peek(0), method.as_obj_closure().unwrap());
+        let bound = self.alloc_obj(bound);
+
         self.pop();
+        self.push(Value::Obj(bound.cast()));
     }
 
     fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> bool {
         let method = match class.methods.get(name.as_non_null_ptr()) {
             Some(method) => method,
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
+                self.runtime_error(ErrorCode::UndefinedProperty, &[&name.as_str()]);
 
                 return false;
             }
@@ -693,22 +1562,56 @@ impl VM {
         true
     }
 
-    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8) -> bool {
+    /// Raise the error a native call failed with, unless it came from a
+    /// callback that reported it already
+    fn native_failed(&mut self, err: String) {
+        match self.error_reported {
+            true => self.unwind(),
+            false => self.raise(ErrorCode::Native.error(&[&err])),
+        }
+    }
+
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8, site: usize) -> bool {
         let receiver = self.peek(arg_count as u32);
         let instance = match receiver.as_instance_fn() {
             Some(inst) => inst,
+            None => return self.invoke_builtin(receiver, name, arg_count),
+        };
+
+        match self.property(instance, name, site) {
+            Some(Property::Field(field)) => {
+                self.stack.set(arg_count as u32, field);
+                self.call_value(field, arg_count)
+            }
+            Some(Property::Method(method)) => {
+                self.call(method.as_obj_closure().unwrap(), arg_count);
+                true
+            }
             None => {
-                self.runtime_error("Only instances have methods.".into());
+                self.runtime_error(ErrorCode::UndefinedProperty, &[&name.as_str()]);
+                false
+            }
+        }
+    }
+
+    /// Call a method of a value that isn't an instance, see
+    /// [`call_method`](native_fn::call_method)
+    fn invoke_builtin(&mut self, receiver: Value, name: Gc<ObjString>, arg_count: u8) -> bool {
+        let values = unsafe {
+            std::slice::from_raw_parts(self.stack.top().sub(arg_count as usize), arg_count as usize)
+        };
+        self.error_reported = false;
+        let result = match native_fn::call_method(self, receiver, name.as_str(), values) {
+            Ok(result) => result,
+            Err(err) => {
+                self.native_failed(err);
                 return false;
             }
         };
 
-        if let Some(field) = instance.fields.get(name.as_non_null_ptr()) {
-            self.stack.set(arg_count as u32, field);
-            return self.call_value(field, arg_count);
-        }
-
-        self.invoke_from_class(instance.class, name, arg_count)
+        self.stack.sub(arg_count as u32 + 1);
+        self.push(result);
+        true
     }
 
     fn invoke_from_class(
@@ -724,45 +1627,273 @@ impl VM {
                 true
             }
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
+                self.runtime_error(ErrorCode::UndefinedProperty, &[&name.as_str()]);
                 false
             }
         }
     }
 
-    pub fn run(&mut self) -> InterpretResult<()> {
+    /// A handle that stops this VM's `run` from anywhere
+    pub fn interrupt_handle(&self) -> VmInterruptHandle {
+        VmInterruptHandle(self.interrupt.clone())
+    }
+
+    /// Run until the script returns, producing whatever it returned
+    pub fn run(&mut self) -> InterpretResult<Value> {
+        self.interrupted = false;
+        // What ran before counts against the limits, and the loop works out
+        // a new budget from them as they are now
+        self.settle_budget();
+        let result = self.run_instructions();
+        self.settle_budget();
+        match result {
+            // The native whose callback was interrupted failed because of it
+            Err(InterpretError::RuntimeError) if self.interrupted => {
+                Err(InterpretError::Interrupted)
+            }
+            result => result,
+        }
+    }
+
+    /// Run at most `max_instructions` more instructions of the script
+    /// [`init`](VM::init) set up, then hand control back, so a host can keep
+    /// its event loop responsive while a long script runs. After
+    /// [`StepResult::Yielded`] call it again to carry on where it stopped.
+    ///
+    /// A callback from a native runs to the end within the instruction that
+    /// called the native, and its instructions don't count.
+    pub fn run_bounded(&mut self, max_instructions: u64) -> StepResult {
+        self.yield_countdown = Some(max_instructions);
+        let result = self.catch_panics(VM::run);
+        self.yield_countdown = None;
+
+        match result {
+            Ok(value) => StepResult::Done(value),
+            Err(InterpretError::Yielded) => StepResult::Yielded,
+            Err(err) => StepResult::Error(err),
+        }
+    }
+
+    /// Take the instructions run since the budget was last granted off the
+    /// bounded run and the step limit, and leave no budget, so the run loop
+    /// works out a new one before its next instruction
+    fn settle_budget(&mut self) {
+        let ran = (self.budget_granted - self.budget) as u64;
+        if let Some(left) = self.yield_countdown.as_mut() {
+            *left -= ran;
+        }
+        if let Some(steps) = self.step_limit.as_mut() {
+            *steps = steps.saturating_sub(ran);
+        }
+        self.budget = 0;
+        self.budget_granted = 0;
+    }
+
+    /// Give back the budget for an instruction that didn't run after all,
+    /// since it runs again on resuming. Code the debugger ran to decide may
+    /// have settled the budget already.
+    fn refund_step(&mut self) {
+        self.settle_budget();
+        if let Some(left) = self.yield_countdown.as_mut() {
+            *left += 1;
+        }
+        if let Some(steps) = self.step_limit.as_mut() {
+            *steps += 1;
+        }
+    }
+
+    /// The run loop's slow path, taken when the budget runs out: yield,
+    /// stop for an interrupt or the step limit, or grant a new budget
+    #[cold]
+    #[inline(never)]
+    fn budget_spent(&mut self) -> InterpretResult<()> {
+        self.settle_budget();
+        if self.yield_countdown == Some(0) {
+            return Err(InterpretError::Yielded);
+        }
+
+        if self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed) {
+            self.interrupted = true;
+            // Shows where it was, and makes natives this was called back
+            // from fail quietly
+            self.runtime_error(ErrorCode::Interrupted, &[]);
+            return Err(InterpretError::Interrupted);
+        }
+
+        let mut grant = INTERRUPT_CHECK_INTERVAL as u64;
+        if let Some(left) = self.yield_countdown {
+            grant = grant.min(left);
+        }
+        match self.step_limit {
+            Some(0) => {
+                self.runtime_error(ErrorCode::StepLimitExceeded, &[]);
+                return Err(InterpretError::RuntimeError);
+            }
+            // Every step is counted against its function
+            Some(_) => {
+                self.count_step();
+                grant = 1;
+            }
+            None => (),
+        }
+
+        self.budget = grant as u32;
+        self.budget_granted = grant as u32;
+        Ok(())
+    }
+
+    /// Traces, debugs and profiles the instruction about to run. Returns
+    /// whether the debugger wants to pause before it.
+    #[inline(never)]
+    fn observe_instruction(&mut self) -> bool {
+        if self.trace_execution {
+            // Debug frame window
+            let slot_addr = self.top_call_frame().slots_ptr as usize;
+            println!("          Frame slot addr: {}", slot_addr);
+            // Debug stack
+            let take_amount =
+                (self.stack.top() as usize - slot_addr) / std::mem::size_of::<Value>();
+            for (i, slot) in self.stack.values().iter().take(take_amount).enumerate() {
+                let value = slot;
+                println!("          {i}: {value:?}");
+            }
+
+            // Debug instruction
+            let frame = self.top_call_frame();
+            let mut duplicate_instruction_index = frame.instr_offset as usize;
+            let line = frame.function().chunk.lines[duplicate_instruction_index];
+            let inner = frame
+                .function()
+                .chunk
+                .disassemble_instruction(&mut duplicate_instruction_index);
+            println!("{:?}", inner.map(|inner| InstructionDebug { line, inner }));
+        }
+
+        if self.debugger.is_some() && self.debug_hook() {
+            return true;
+        }
+
+        if self.coverage.is_some() {
+            let frame = self.top_call_frame();
+            let line = frame.function().chunk.lines[frame.instr_offset as usize];
+            if let Some(coverage) = self.coverage.as_mut() {
+                coverage.record(line);
+            }
+        }
+
+        if self.line_profile.is_some() {
+            let frame = self.top_call_frame();
+            let line = frame.function().chunk.lines[frame.instr_offset as usize];
+            let depth = self.call_frame_count;
+            if let Some(profile) = self.line_profile.as_mut() {
+                profile.record(line, depth);
+            }
+        }
+
+        if self.opcode_histogram.is_some() {
+            let frame = self.top_call_frame();
+            let byte = frame.function().chunk.code[frame.instr_offset as usize];
+            if let Some(histogram) = self.opcode_histogram.as_mut() {
+                histogram.record(byte);
+            }
+        }
+
+        false
+    }
+
+    fn run_instructions(&mut self) -> InterpretResult<Value> {
+        // Tracing, the debugger and the profilers are only set up between
+        // runs, so one test here keeps them all off the plain path
+        let instrumented = self.trace_execution
+            || self.debugger.is_some()
+            || self.coverage.is_some()
+            || self.line_profile.is_some()
+            || self.opcode_histogram.is_some();
+
         loop {
-            #[cfg(debug_assertions)]
-            {
-                // Debug frame window
-                let slot_addr = self.top_call_frame().slots_ptr as usize;
-                println!("          Frame slot addr: {}", slot_addr);
-                // Debug stack
-                let take_amount =
-                    (self.stack.top as usize - slot_addr) / std::mem::size_of::<Value>();
-                for (i, slot) in self.stack.iter().take(take_amount).enumerate() {
-                    let value = slot;
-                    println!("          {i}: {value:?}");
-                }
+            // First, so nothing below happens twice for the instruction it
+            // resumes at
+            if self.budget == 0 {
+                self.budget_spent()?;
+            }
+            self.budget -= 1;
 
-                // Debug instruction
-                let frame = self.top_call_frame();
-                let mut duplicate_instruction_index = frame.instr_offset as usize;
-                let line = frame.function().chunk.lines[duplicate_instruction_index];
-                let inner = frame
-                    .function()
-                    .chunk
-                    .disassemble_instruction(&mut duplicate_instruction_index);
-                println!("{:?}", inner.map(|inner| InstructionDebug { line, inner }));
+            if instrumented && self.observe_instruction() {
+                self.refund_step();
+                return Err(InterpretError::Paused);
             }
 
             let byte = self.read_byte();
 
-            match Opcode::from_u8(byte) {
+            match Opcode::try_from(byte).ok() {
+                Some(Opcode::BuildList) => {
+                    let count = self.read_byte() as u32;
+                    // The items stay on the stack until the list has them,
+                    // so allocating can't collect them
+                    let mut list = self.alloc_obj(ObjList::new(vec![]));
+                    list.items = (0..count).rev().map(|dist| self.peek(dist)).collect();
+
+                    self.stack.sub(count);
+                    self.push(Value::Obj(list.cast()));
+                }
+                Some(Opcode::Unpack) => {
+                    let count = self.read_byte() as usize;
+                    let list = match self.peek(0).as_list() {
+                        Some(list) => list,
+                        None => {
+                            self.runtime_error(ErrorCode::DestructureNonList, &[]);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    };
+                    if list.items.len() != count {
+                        let len = list.items.len();
+                        self.runtime_error(ErrorCode::DestructureCount, &[&count, &len]);
+                        return Err(InterpretError::RuntimeError);
+                    }
+
+                    self.pop();
+                    for &item in &list.items {
+                        self.push(item);
+                    }
+                }
+                Some(Opcode::IndexGet) => {
+                    let value = match self.index_get(self.peek(1), self.peek(0)) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            self.raise(err);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    };
+
+                    self.stack.sub(2);
+                    self.push(value);
+                }
+                Some(Opcode::Slice) => {
+                    let value = match self.slice(self.peek(2), self.peek(1), self.peek(0)) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            self.raise(err);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    };
+
+                    self.stack.sub(3);
+                    self.push(value);
+                }
+                Some(Opcode::IndexSet) => {
+                    let value = self.peek(0);
+                    if let Err(err) = self.index_set(self.peek(2), self.peek(1), value) {
+                        self.raise(err);
+                        return Err(InterpretError::RuntimeError);
+                    }
+
+                    self.stack.sub(3);
+                    self.push(value);
+                }
                 Some(Opcode::SuperInvoke) => {
-                    let method = self.read_constant().as_obj_str().unwrap();
+                    let method = self.read_string_constant()?;
                     let arg_count = self.read_byte();
-                    let superclass = self.pop().as_class().unwrap();
+                    let superclass = self.pop_class()?;
 
                     if !self.invoke_from_class(superclass, method, arg_count) {
                         return Err(InterpretError::RuntimeError);
@@ -770,9 +1901,9 @@ impl VM {
                 }
                 Some(Opcode::GetSuper) => {
                     // The name of the class
-                    let name = self.read_constant().as_obj_str().unwrap();
+                    let name = self.read_string_constant()?;
 
-                    let superclass = self.pop().as_class().unwrap();
+                    let superclass = self.pop_class()?;
 
                     if !self.bind_method(superclass, name) {
                         return Err(InterpretError::RuntimeError);
@@ -783,53 +1914,64 @@ impl VM {
                     let superclass = match superclass.as_class() {
                         Some(class) => class,
                         None => {
-                            self.runtime_error("Superclass must be a class.".into());
+                            self.runtime_error(ErrorCode::SuperclassNotClass, &[]);
                             return Err(InterpretError::RuntimeError);
                         }
                     };
 
-                    let mut subclass = self.peek(0);
-                    let mut subclass = subclass.as_class().unwrap();
+                    let mut subclass = self
+                        .peek(0)
+                        .try_as_class()
+                        .map_err(|err| self.invalid_bytecode(&err.to_string()))?;
+
+                    // The subclass gets the same methods the superclass has
+                    self.write_barrier(Value::Obj(superclass.cast()));
 
                     superclass.methods.add_all(&mut subclass.methods);
+                    subclass.superclass = Some(superclass);
+                    self.cache_epoch = self.cache_epoch.wrapping_add(1);
 
                     self.pop();
                 }
                 Some(Opcode::Invoke) => {
-                    let method = self.read_constant().as_obj_str().unwrap();
+                    let site = self.top_call_frame().instr_offset as usize - 1;
+                    let method = self.read_string_constant()?;
                     let arg_count = self.read_byte();
-                    if !self.invoke(method, arg_count) {
+                    if !self.invoke(method, arg_count, site) {
                         return Err(InterpretError::RuntimeError);
                     }
                 }
                 Some(Opcode::Method) => {
-                    let obj_str = self.read_constant().as_obj_str().unwrap();
+                    let obj_str = self.read_string_constant()?;
+                    // Only a class extension can get here without a class
+                    if self.peek(1).as_class().is_none() {
+                        self.runtime_error(ErrorCode::ExtendNonClass, &[]);
+                        return Err(InterpretError::RuntimeError);
+                    }
                     self.define_method(obj_str)
                 }
                 Some(Opcode::GetProperty) => {
+                    let site = self.top_call_frame().instr_offset as usize - 1;
                     let top = self.peek(0);
                     let instance = match top.as_instance_fn() {
                         Some(instance) => instance,
                         None => {
-                            self.runtime_error("Only instances have properties.".into());
+                            self.runtime_error(ErrorCode::PropertyOfNonInstance, &[]);
                             return Err(InterpretError::RuntimeError);
                         }
                     };
 
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect to read a string constant.");
+                    let name = self.read_string_constant()?;
 
-                    match instance.fields.get(name.as_non_null_ptr()) {
-                        Some(val) => {
+                    match self.property(instance, name, site) {
+                        Some(Property::Field(val)) => {
                             self.pop();
                             self.push(val);
                         }
+                        Some(Property::Method(method)) => self.bind(method),
                         None => {
-                            if !self.bind_method(unsafe { &*instance.as_ptr() }.class, name) {
-                                return Err(InterpretError::RuntimeError);
-                            }
+                            self.runtime_error(ErrorCode::UndefinedProperty, &[&name.as_str()]);
+                            return Err(InterpretError::RuntimeError);
                         }
                     }
                 }
@@ -838,29 +1980,33 @@ impl VM {
                     let mut instance = match top.as_instance_fn() {
                         Some(instance) => instance,
                         None => {
-                            self.runtime_error("Only instances have fields.".into());
+                            self.runtime_error(ErrorCode::FieldOfNonInstance, &[]);
                             return Err(InterpretError::RuntimeError);
                         }
                     };
 
-                    let field_name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect to string constant");
+                    let field_name = self.read_string_constant()?;
+
+                    let old_val = match self.debugger {
+                        Some(_) => instance.fields.get(field_name.as_non_null_ptr()),
+                        None => None,
+                    };
 
+                    self.write_barrier(self.peek(0));
                     instance
                         .fields
                         .set(field_name.as_non_null_ptr(), self.peek(0));
 
+                    if self.debugger.is_some() {
+                        self.check_watch(false, field_name.as_ref(), old_val, self.peek(0));
+                    }
+
                     let value = self.pop();
                     self.pop();
                     self.push(value);
                 }
                 Some(Opcode::Class) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Opcode::Class instruction should be followed by string constant");
+                    let name = self.read_string_constant()?;
 
                     let class = ObjClass::new(name.as_non_null_ptr());
                     let class = self.alloc_obj(class);
@@ -868,43 +2014,42 @@ impl VM {
                     self.push(Value::Obj(class.cast()));
                 }
                 Some(Opcode::CloseUpvalue) => {
-                    self.close_upvalues(unsafe { self.stack.top.sub(1) });
+                    self.close_upvalues(self.stack.top().wrapping_sub(1));
                     self.pop();
                 }
                 Some(Opcode::GetUpvalue) => {
                     let slot = self.read_byte();
-                    let val = unsafe {
-                        *(self
-                            .top_call_frame()
-                            .closure
-                            .as_ref()
-                            .upvalue_at_slot(slot as usize)
-                            .unwrap()
-                            .as_ref()
-                            .location
-                            .as_ptr())
+                    let upvalue = match self.top_call_frame().closure.upvalue_at_slot(slot as usize)
+                    {
+                        Some(upvalue) => upvalue,
+                        None => return Err(self.invalid_bytecode("No such upvalue.")),
                     };
+                    let val = unsafe { *upvalue.as_ref().location.as_ptr() };
 
                     self.push(val);
                 }
                 Some(Opcode::SetUpvalue) => {
                     let slot = self.read_byte();
                     let val = self.peek(0);
+                    self.write_barrier(val);
+                    let mut upvalue = match self
+                        .top_call_frame()
+                        .closure()
+                        .upvalue_at_slot(slot as usize)
+                    {
+                        Some(upvalue) => upvalue,
+                        None => return Err(self.invalid_bytecode("No such upvalue.")),
+                    };
                     unsafe {
-                        let loc_ptr = self
-                            .top_call_frame()
-                            .closure()
-                            .upvalue_at_slot(slot as usize)
-                            .unwrap()
-                            .as_mut()
-                            .location
-                            .as_ptr();
-
+                        let loc_ptr = upvalue.as_mut().location.as_ptr();
                         (*loc_ptr) = val;
                     }
                 }
                 Some(Opcode::Closure) => {
-                    let function = self.read_constant().as_fn().unwrap();
+                    let function = self
+                        .read_constant()
+                        .try_as_fn()
+                        .map_err(|err| self.invalid_bytecode(&err.to_string()))?;
                     self.new_closure(function);
                     // TODO: investigate
                     // let closure = self.alloc_obj();
@@ -932,45 +2077,53 @@ impl VM {
                         self.top_call_frame_mut().instr_offset += offset as u32;
                     }
                 }
+                Some(Opcode::JumpIfNil) => {
+                    let offset = self.read_u16();
+                    if self.peek(0) == Value::Nil {
+                        self.top_call_frame_mut().instr_offset += offset as u32;
+                    }
+                }
                 Some(Opcode::GetLocal) => {
                     let slot = self.read_byte();
-                    let val = self.top_call_frame().index(slot as usize);
+                    let slots = self.top_call_frame().slots_ptr;
+                    let val = unsafe { self.stack.slot(slots, slot as usize) };
 
                     self.push(val);
                 }
                 Some(Opcode::SetLocal) => {
                     let slot = self.read_byte();
                     let val = self.peek(0);
-                    self.top_call_frame_mut().set(slot as usize, val);
+                    let slots = self.top_call_frame().slots_ptr;
+                    unsafe { self.stack.set_slot(slots, slot as usize, val) };
                 }
                 Some(Opcode::SetGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
+                    let name = self.read_string_constant()?;
 
                     let new_val = self.peek(0);
                     // println!("{} = {:?}", unsafe { name.as_ref() }.as_str(), self.peek(0));
+                    let old_val = match self.debugger {
+                        Some(_) => self.mem.globals.get(name.as_non_null_ptr()),
+                        None => None,
+                    };
 
                     if self.mem.globals.set(name.as_non_null_ptr(), new_val) {
                         self.mem.globals.delete(name.as_non_null_ptr());
-                        self.runtime_error(format!("Undefined variable: {}", name.as_str()).into());
+                        self.runtime_error(ErrorCode::UndefinedVariable, &[&name.as_str()]);
 
                         return Err(InterpretError::RuntimeError);
                     }
+
+                    if self.debugger.is_some() {
+                        self.check_watch(true, name.as_ref(), old_val, new_val);
+                    }
                 }
                 Some(Opcode::GetGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
+                    let name = self.read_string_constant()?;
 
                     let val = match self.mem.globals.get(name.as_non_null_ptr()) {
                         Some(global) => global,
                         None => {
-                            self.runtime_error(
-                                format!("Undefined variable: {}", name.as_str()).into(),
-                            );
+                            self.runtime_error(ErrorCode::UndefinedVariable, &[&name.as_str()]);
 
                             return Err(InterpretError::RuntimeError);
                         }
@@ -979,10 +2132,7 @@ impl VM {
                     self.push(val);
                 }
                 Some(Opcode::DefineGlobal) => {
-                    let name = self
-                        .read_constant()
-                        .as_obj_str()
-                        .expect("Expect string constant for global variable name.");
+                    let name = self.read_string_constant()?;
 
                     self.mem.globals.set(name.as_non_null_ptr(), self.peek(0));
                     self.pop();
@@ -1001,11 +2151,21 @@ impl VM {
                 }
                 Some(Opcode::Print) => {
                     let value = self.pop();
-                    println!("{value:?}");
+                    self.write_output(format_args!("{value:?}\n"));
                 }
                 Some(Opcode::Equal) => {
                     let b = self.pop();
                     let a = self.pop();
+                    if (self.strict_equality || self.in_strict_code())
+                        && a != Value::Nil
+                        && b != Value::Nil
+                    {
+                        let (a, b) = (a.kind().name(), b.kind().name());
+                        if a != b {
+                            self.runtime_error(ErrorCode::StrictEquality, &[&a, &b]);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
 
                     self.push(Value::Bool(a == b))
                 }
@@ -1015,23 +2175,28 @@ impl VM {
                 }
                 Some(Opcode::Negate) => {
                     if !matches!(self.peek(0), Value::Bool(_) | Value::Number(_)) {
-                        self.runtime_error("Operand must be a number.".into());
+                        self.runtime_error(ErrorCode::OperandMustBeNumber, &[]);
                         return Err(InterpretError::RuntimeError);
                     }
 
                     let negated = -self.pop();
                     self.push(negated)
                 }
-                Some(Opcode::Return) => {
-                    if self.call_frame_count == 1 {
-                        self.pop();
-                        return Ok(());
+                Some(Opcode::TraceEnter) => self.trace_call(None),
+                Some(op @ (Opcode::Return | Opcode::TraceReturn)) => {
+                    if op == Opcode::TraceReturn {
+                        self.trace_call(Some(self.peek(0)));
                     }
-
                     let result = self.pop();
                     self.close_upvalues(self.top_call_frame().slots_ptr);
 
-                    self.stack.top = self.top_call_frame().slots_ptr;
+                    // Can be a `return` from inside a block at the top
+                    // level, so the upvalues above have to be closed first
+                    if self.call_frame_count == self.base_frame + 1 {
+                        return Ok(result);
+                    }
+
+                    self.stack.set_top(self.top_call_frame().slots_ptr);
                     self.call_frame_count -= 1;
                     self.push(result);
                 }
@@ -1039,8 +2204,39 @@ impl VM {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
+                Some(Opcode::Number) => {
+                    let operand = self.read_byte();
+                    let number =
+                        self.top_call_frame().function().chunk.number_values[operand as usize];
+                    self.push(Value::Number(number));
+                }
+                Some(Opcode::Is) => {
+                    let class = match self.pop().as_class() {
+                        Some(class) => class,
+                        None => {
+                            self.runtime_error(ErrorCode::IsNotClass, &[]);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    };
+                    let value = self.pop();
+                    let is = value
+                        .as_instance_fn()
+                        .map_or(false, |instance| instance.class.is_a(class));
+                    self.push(Value::Bool(is));
+                }
                 Some(Opcode::Subtract) => self.binary_op(std::ops::Sub::sub)?,
-                Some(Opcode::Multiply) => self.binary_op(std::ops::Mul::mul)?,
+                Some(Opcode::Multiply) => match (self.peek(1), self.peek(0)) {
+                    (Value::Number(_), Value::Number(_)) => self.binary_op(std::ops::Mul::mul)?,
+                    (sequence, count @ Value::Number(_)) | (count @ Value::Number(_), sequence)
+                        if sequence.is_str() || sequence.as_list().is_some() =>
+                    {
+                        if let Err(err) = self.repeat(sequence, count) {
+                            self.raise(err);
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
+                    _ => self.binary_op(std::ops::Mul::mul)?,
+                },
                 Some(Opcode::Divide) => self.binary_op(std::ops::Div::div)?,
                 Some(Opcode::Greater) => {
                     self.binary_op(Value::gt_owned)?;
@@ -1053,9 +2249,107 @@ impl VM {
                         self.binary_op(std::ops::Add::add)?
                     }
                 }
-                otherwise => panic!("Unknown opcode {otherwise:?}"),
+                None => return Err(self.invalid_bytecode(&format!("Unknown opcode {byte}."))),
+            }
+        }
+    }
+
+    /// The list and item that `list[index]` refers to
+    fn index_get(&mut self, target: Value, index: Value) -> Result<Value, Error> {
+        if let Some(list) = target.as_list() {
+            let index = Self::check_index("list", index, list.items.len())?;
+            Ok(list.items[index])
+        } else if let Some(array) = target.as_float_array() {
+            let index = Self::check_index("float array", index, array.values.len())?;
+            Ok(Value::Number(array.values[index]))
+        } else if let Some(string) = target.as_str() {
+            // The character starting at that byte, as a view of the string
+            let start = Self::check_index("string", index, string.len())?;
+            let char_len = match string.get(start..).and_then(|rest| rest.chars().next()) {
+                Some(c) => c.len_utf8(),
+                None => return Err(ErrorCode::IndexSplitsCharacter.error(&[&index])),
+            };
+            Ok(StringMethod::view(self, target, start, start + char_len))
+        } else {
+            Err(ErrorCode::NotIndexable.error(&[]))
+        }
+    }
+
+    /// `target[start:end]`, a new list or a view of the string. Either bound
+    /// can be nil for the start or end, and negative ones count back from
+    /// the end.
+    fn slice(&mut self, target: Value, start: Value, end: Value) -> Result<Value, Error> {
+        let (kind, len) = if let Some(list) = target.as_list() {
+            ("list", list.items.len())
+        } else if let Some(string) = target.as_str() {
+            ("string", string.len())
+        } else {
+            return Err(ErrorCode::NotSliceable.error(&[]));
+        };
+
+        let bound = |value: Value, default: usize| match value {
+            Value::Nil => Ok(Some(default)),
+            Value::Number(n) if n.fract() == 0.0 => {
+                let n = if n < 0.0 { len as f64 + n } else { n };
+                Ok((0.0..=len as f64).contains(&n).then_some(n as usize))
             }
+            _ => Err(ErrorCode::InvalidSliceBound.error(&[])),
+        };
+        // Written as they were, leaving out a missing one
+        let shown = |value: Value| match value {
+            Value::Nil => String::new(),
+            value => value.to_string(),
+        };
+        let range = match (bound(start, 0)?, bound(end, len)?) {
+            (Some(from), Some(to)) if from <= to => from..to,
+            _ => {
+                let (start, end) = (shown(start), shown(end));
+                return Err(ErrorCode::SliceOutOfRange.error(&[&start, &end, &kind, &len]));
+            }
+        };
+
+        // The target stays on the stack while the slice is made
+        if let Some(list) = target.as_list() {
+            let items = list.items[range].to_vec();
+            return Ok(Value::Obj(self.alloc_obj(ObjList::new(items)).cast()));
+        }
+        if target.as_str().unwrap().get(range.clone()).is_none() {
+            return Err(ErrorCode::SliceSplitsCharacter.error(&[&shown(start), &shown(end)]));
         }
+        Ok(StringMethod::view(self, target, range.start, range.end))
+    }
+
+    fn index_set(&mut self, target: Value, index: Value, value: Value) -> Result<(), Error> {
+        if let Some(mut list) = target.as_list() {
+            let index = Self::check_index("list", index, list.items.len())?;
+            self.write_barrier(value);
+            list.items[index] = value;
+        } else if let Some(mut array) = target.as_float_array() {
+            let index = Self::check_index("float array", index, array.values.len())?;
+            match value {
+                Value::Number(n) => array.values[index] = n,
+                _ => return Err(ErrorCode::FloatArrayItemNotNumber.error(&[])),
+            }
+        } else if target.is_str() {
+            return Err(ErrorCode::StringNotMutable.error(&[]));
+        } else {
+            return Err(ErrorCode::NotIndexable.error(&[]));
+        }
+        Ok(())
+    }
+
+    /// Where `index` is in something `len` long, counting back from the end
+    /// if it's negative
+    fn check_index(kind: &str, index: Value, len: usize) -> Result<usize, Error> {
+        let n = match index {
+            Value::Number(n) if n.fract() == 0.0 => n,
+            _ => return Err(ErrorCode::InvalidIndex.error(&[])),
+        };
+        let from_start = if n < 0.0 { len as f64 + n } else { n };
+        if from_start < 0.0 || from_start >= len as f64 {
+            return Err(ErrorCode::IndexOutOfRange.error(&[&index, &kind, &len]));
+        }
+        Ok(from_start as usize)
     }
 
     #[inline]
@@ -1075,7 +2369,7 @@ impl VM {
         self.call_frame_count += 1;
         unsafe {
             (*call_frame).instr_offset = 0;
-            (*call_frame).slots_ptr = self.stack.top.offset(-(arg_count as isize) - 1);
+            (*call_frame).slots_ptr = self.stack.top().sub(arg_count as usize + 1);
             (*call_frame).closure = closure;
         }
     }
@@ -1093,7 +2387,7 @@ impl VM {
         unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_mut() }
     }
     #[inline]
-    fn top_call_frame(&self) -> &CallFrame {
+    pub(crate) fn top_call_frame(&self) -> &CallFrame {
         unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_ref() }
     }
 
@@ -1116,4 +2410,90 @@ impl VM {
         let idx = self.read_byte();
         self.top_call_frame().function().chunk.constants[idx as usize]
     }
+
+    fn read_string_constant(&mut self) -> InterpretResult<Gc<ObjString>> {
+        self.read_constant()
+            .try_as_obj_str()
+            .map_err(|err| self.invalid_bytecode(&err.to_string()))
+    }
+
+    /// The superclass `super` refers to
+    fn pop_class(&mut self) -> InterpretResult<Gc<ObjClass>> {
+        self.pop()
+            .try_as_class()
+            .map_err(|err| self.invalid_bytecode(&err.to_string()))
+    }
+
+    /// Raise a runtime error for bytecode the compiler never emits, which
+    /// can still come from a snapshot or a host that built it by hand
+    fn invalid_bytecode(&mut self, what: &str) -> InterpretError {
+        self.runtime_error(ErrorCode::InvalidBytecode, &[&what]);
+        InterpretError::RuntimeError
+    }
+
+    /// Run `f`, turning a panic into a runtime error so it doesn't unwind
+    /// into the host. The VM is reset, since it may have been left half way
+    /// through an instruction.
+    pub(crate) fn catch_panics<T>(
+        &mut self,
+        f: impl FnOnce(&mut VM) -> InterpretResult<T>,
+    ) -> InterpretResult<T> {
+        install_quiet_panic_hook();
+        let catching = CATCHING_PANICS.with(|catching| catching.replace(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        CATCHING_PANICS.with(|flag| flag.set(catching));
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = match (
+                    payload.downcast_ref::<&str>(),
+                    payload.downcast_ref::<String>(),
+                ) {
+                    (Some(message), _) => message,
+                    (_, Some(message)) => message.as_str(),
+                    _ => "unknown panic",
+                };
+                let err = ErrorCode::Internal.error(&[&message]);
+
+                // The frames may be half way through an instruction, so
+                // they're gone before the error says where it happened
+                self.base_frame = 0;
+                self.error_reported = true;
+                self.reset_stack();
+                self.abandon_collection();
+                self.report(&err);
+                self.last_error = Some(err);
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Whether this thread is in [`VM::catch_panics`], whose panics are
+    /// reported as runtime errors instead
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Wrap the panic hook so it stays quiet about the panics
+/// [`VM::catch_panics`] catches. The hook is shared by every thread, so
+/// swapping it out around each call would silence other threads' panics.
+fn install_quiet_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING_PANICS.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+}
+
+#[inline]
+fn past(deadline: Option<Instant>) -> bool {
+    match deadline {
+        Some(deadline) => Instant::now() >= deadline,
+        None => false,
+    }
 }
@@ -0,0 +1,361 @@
+//! Runs every script under `tests/lox`, checking what it prints against its
+//! `// expect: ` comments, in order. A script that has to fail says so with
+//! `// expect runtime error` or `// expect compile error`. They run a second
+//! time from `.loxb` bytecode.
+//!
+//! The scripts under `tests/loxb` are for sharing bytecode with zlox: each
+//! leaves the number its `// result: ` comment gives in `result`, and comes
+//! with the `.loxb` file loxide compiles it to, which zlox's tests load too.
+//! With `ZLOX` set to a zlox binary, it compiles them for loxide to run, and
+//! runs loxide's.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use loxide::{
+    compile_loxb, interpret, load_loxb, run as run_loaded,
+    value::Value,
+    vm::{InterpretError, VM},
+};
+
+struct Expectations {
+    output: String,
+    error: Option<InterpretError>,
+}
+
+impl Expectations {
+    fn parse(src: &str) -> Self {
+        let mut output = String::new();
+        let mut error = None;
+        for line in src.lines() {
+            let comment = match line.find("// expect") {
+                Some(start) => &line[start + "// ".len()..],
+                None => continue,
+            };
+            if let Some(expected) = comment.strip_prefix("expect: ") {
+                output.push_str(expected);
+                output.push('\n');
+            } else if comment.trim_end() == "expect runtime error" {
+                error = Some(InterpretError::RuntimeError);
+            } else if comment.trim_end() == "expect compile error" {
+                error = Some(InterpretError::CompileError);
+            }
+        }
+        Self { output, error }
+    }
+}
+
+/// Every file under `dir` with the extension `ext`, in a stable order
+fn scripts(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            found.extend(scripts(&path, ext));
+        } else if path.extension().map_or(false, |found| found == ext) {
+            found.push(path);
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Why the script at `path` failed, if it did. With `from_loxb` it's
+/// compiled to `.loxb` and loaded from that.
+fn run(path: &Path, from_loxb: bool) -> Result<(), String> {
+    let src = std::fs::read_to_string(path).unwrap();
+    let expected = Expectations::parse(&src);
+
+    let mut vm = VM::new();
+    vm.captured_output = Some(vec![]);
+    let result = match from_loxb {
+        false => interpret(&mut vm, &src),
+        true => match compile_loxb(&src) {
+            Ok(bytes) => {
+                load_loxb(&mut vm, &bytes).map_err(|err| format!("loading .loxb: {err}"))?;
+                run_loaded(&mut vm)
+            }
+            Err(_) => Err(InterpretError::CompileError),
+        },
+    };
+    let output = String::from_utf8(vm.captured_output.take().unwrap()).unwrap();
+
+    match (result, expected.error) {
+        (Ok(_), None) => (),
+        (Err(err), Some(expected)) if err == expected => (),
+        (result, expected) => {
+            return Err(format!("expected {expected:?}, finished with {result:?}"));
+        }
+    }
+    if output != expected.output {
+        return Err(format!(
+            "expected output:\n{}got:\n{output}",
+            expected.output
+        ));
+    }
+    Ok(())
+}
+
+fn run_all(from_loxb: bool) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let scripts = scripts(&root, "lox");
+    assert!(!scripts.is_empty(), "No scripts in {}", root.display());
+
+    let failures: Vec<_> = scripts
+        .iter()
+        .filter_map(|path| {
+            run(path, from_loxb)
+                .err()
+                .map(|err| format!("{}: {err}", path.strip_prefix(&root).unwrap().display()))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed:\n\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n\n")
+    );
+}
+
+#[test]
+fn lox_scripts() {
+    run_all(false)
+}
+
+#[test]
+fn lox_scripts_from_loxb() {
+    run_all(true)
+}
+
+/// Runs the scripts `loxide bench` times, in the repository's `benchmarks`,
+/// once each to check they still work. They take seconds each even in a
+/// release build, so only with `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn benchmark_scripts() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../benchmarks");
+    let scripts = scripts(&root, "lox");
+    assert!(!scripts.is_empty(), "No scripts in {}", root.display());
+
+    for path in scripts {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let mut vm = VM::new();
+        vm.captured_output = Some(vec![]);
+        assert_eq!(
+            interpret(&mut vm, &src),
+            Ok(Value::Nil),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+/// The number a `tests/loxb` script says it leaves in `result`
+fn expected_result(src: &str) -> f64 {
+    src.lines()
+        .find_map(|line| line.strip_prefix("// result: "))
+        .expect("No `// result: ` comment")
+        .parse()
+        .unwrap()
+}
+
+/// Load and run a `.loxb` file, returning what it left in `result`
+fn run_loxb(bytes: &[u8]) -> Result<f64, String> {
+    let mut vm = VM::new();
+    load_loxb(&mut vm, bytes).map_err(|err| err.to_string())?;
+    run_loaded(&mut vm).map_err(|err| format!("{err:?}"))?;
+    match vm.get_global("result") {
+        Some(Value::Number(n)) => Ok(n),
+        other => Err(format!("result is {other:?}")),
+    }
+}
+
+#[test]
+fn loxb_interop() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/loxb");
+    let scripts = scripts(&root, "lox");
+    assert!(!scripts.is_empty(), "No scripts in {}", root.display());
+    let zlox = std::env::var_os("ZLOX");
+
+    for path in scripts {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let expected = expected_result(&src);
+        let golden_path = path.with_extension("loxb");
+        let golden = std::fs::read(&golden_path).unwrap();
+
+        let bytes = compile_loxb(&src).unwrap();
+        assert!(
+            bytes == golden,
+            "{} isn't what loxide compiles {} to, if the format changed on purpose run `loxide --emit-loxb` again",
+            golden_path.display(),
+            path.display()
+        );
+        assert_eq!(run_loxb(&golden), Ok(expected), "{}", golden_path.display());
+
+        let Some(zlox) = zlox.as_ref() else {
+            continue;
+        };
+        let out = std::env::temp_dir().join(format!(
+            "zlox-{}-{}",
+            std::process::id(),
+            golden_path.file_name().unwrap().to_string_lossy()
+        ));
+        let status = Command::new(zlox)
+            .arg("--emit-loxb")
+            .arg(&out)
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "zlox failed to compile {}",
+            path.display()
+        );
+        let from_zlox = std::fs::read(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(
+            run_loxb(&from_zlox),
+            Ok(expected),
+            "{} compiled by zlox",
+            path.display()
+        );
+
+        let status = Command::new(zlox).arg(&golden_path).status().unwrap();
+        assert!(
+            status.success(),
+            "zlox failed to run {}",
+            golden_path.display()
+        );
+    }
+}
+
+#[test]
+fn exit_status() {
+    for (i, (returned, status)) in [
+        ("", 0),
+        ("return 3;", 3),
+        ("return 255;", 255),
+        ("return 256;", 70),
+        ("return -1;", 70),
+        ("return 1.5;", 70),
+        ("return \"1\";", 70),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let output = run_cli(&format!("exit-{i}"), returned, &[]);
+        assert_eq!(output.status.code(), Some(status), "{returned}");
+    }
+}
+
+#[test]
+fn nested_callback_errors() {
+    // Each `map` fails because its callback did, which is one error
+    let src = "fun f(x) { return [1].map(f); } f(1);";
+    let output = run_cli("nested", src, &["--error-format=json"]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 1, "{stderr}");
+    assert!(stderr.contains("Stack overflow."), "{stderr}");
+}
+
+#[test]
+fn repl_history() {
+    let path = std::env::temp_dir().join(format!("loxide-{}-history", std::process::id()));
+    let repl = |input: &str| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_loxide"))
+            .env("LOXIDE_HISTORY", &path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    repl("var a = 1;\n\nprint a;\n");
+    // The next session starts with what the last one entered
+    let listed = repl(":history\n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        listed,
+        "    1  var a = 1;\n    2  print a;\n    3  :history\n"
+    );
+}
+
+/// Run `src` with the loxide binary, from a file named after `name`
+fn run_cli(name: &str, src: &str, args: &[&str]) -> Output {
+    let path = std::env::temp_dir().join(format!("loxide-{}-{name}.lox", std::process::id()));
+    std::fs::write(&path, src).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn loxb_rejects_bad_files() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/loxb/fib.loxb");
+    let golden = std::fs::read(golden).unwrap();
+
+    let mut newer = golden.clone();
+    newer[4] = 2;
+    let mut unknown_flag = golden.clone();
+    unknown_flag[6] = 0x80;
+    let truncated = &golden[..golden.len() - 1];
+
+    for (what, bytes) in [
+        ("wrong magic", &b"LOXC"[..]),
+        ("newer version", &newer),
+        ("unknown flag", &unknown_flag),
+        ("truncated", truncated),
+    ] {
+        let mut vm = VM::new();
+        assert!(
+            load_loxb(&mut vm, bytes).is_err(),
+            "loaded a file with {what}"
+        );
+    }
+
+    let mut vm = VM::new();
+    assert!(load_loxb(&mut vm, &script_loxb(&[7, 0])).is_ok());
+    for (what, code) in [
+        ("a stack underflow", &[15, 15, 15, 0][..]),
+        ("a local out of range", &[19, 255, 0]),
+        ("a jump into an operand", &[22, 0, 1, 19, 0, 0]),
+        ("code running past its end", &[7]),
+        ("paths meeting at different heights", &[8, 21, 0, 1, 7, 0]),
+    ] {
+        let mut vm = VM::new();
+        assert!(
+            load_loxb(&mut vm, &script_loxb(code)).is_err(),
+            "loaded a file with {what}"
+        );
+    }
+}
+
+/// A `.loxb` file whose script is `code`, with no constants
+fn script_loxb(code: &[u8]) -> Vec<u8> {
+    let mut bytes = b"LOXB\x01\x00\x00\x00".to_vec();
+    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(code);
+    // Lines and columns
+    bytes.resize(bytes.len() + 8 * code.len(), 0);
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes
+}
@@ -28,6 +28,8 @@ gray_stack: ArrayList(*Obj),
 call_frames: ?*CallFrameStack = null,
 stack: ?*ValueStack = null,
 bytes_allocated: usize = 0,
+/// The most `bytes_allocated` has been, for the heap stats `loxide bench` reads
+peak_bytes_allocated: usize = 0,
 next_gc: usize = 1024 * 1024,
 
 pub fn init(gc: *GC, allocator: Allocator, comptime run_gc: bool) !void {
@@ -36,6 +38,9 @@ pub fn init(gc: *GC, allocator: Allocator, comptime run_gc: bool) !void {
     gc.open_upvalues = null;
     gc.call_frames = null;
     gc.stack = null;
+    gc.bytes_allocated = 0;
+    gc.peak_bytes_allocated = 0;
+    gc.next_gc = 1024 * 1024;
     gc.interned_strings = Table.init();
     gc.globals = Table.init();
     gc.gray_stack = try ArrayList(*Obj).initCapacity(allocator, 64);
@@ -66,13 +71,15 @@ pub fn patch_allocator(self: *GC) void {
 fn alloc(self: *GC, len: usize, ptr_align: u29, len_align: u29, ret_addr: usize) ![]u8 {
     const bytes = try self.inner_allocator.rawAlloc(len, ptr_align, len_align, ret_addr);
     self.bytes_allocated += bytes.len;
+    self.peak_bytes_allocated = std.math.max(self.peak_bytes_allocated, self.bytes_allocated);
     try self.maybe_collect();
     return bytes;
 }
 
 fn resize(self: *GC, buf: []u8, buf_align: u29, new_len: usize, len_align: u29, ret_addr: usize) ?usize {
     const calculated_new_len = self.inner_allocator.rawResize(buf, buf_align, new_len, len_align, ret_addr) orelse return null;
-    self.bytes_allocated += calculated_new_len - buf.len;
+    self.bytes_allocated = self.bytes_allocated + calculated_new_len - buf.len;
+    self.peak_bytes_allocated = std.math.max(self.peak_bytes_allocated, self.bytes_allocated);
     if (calculated_new_len > buf.len) {
         self.maybe_collect() catch @panic("GC failed to collect");
     }
@@ -0,0 +1,211 @@
+//! Reading and writing `.loxb` files, bytecode that zlox and loxide can both
+//! run. The format is laid out in loxide/src/chunk.rs, next to `LOXB_MAGIC`:
+//! little-endian throughout, a versioned header, then the script with every
+//! function it declares nested in its constants.
+const std = @import("std");
+const mem = std.mem;
+const Allocator = mem.Allocator;
+
+const ArrayList = std.ArrayListUnmanaged;
+
+const _chunk = @import("chunk.zig");
+const Chunk = _chunk.Chunk;
+const Opcode = _chunk.Opcode;
+const Value = @import("value.zig").Value;
+const Obj = @import("obj.zig");
+const GC = @import("gc.zig");
+
+pub const MAGIC = "LOXB";
+pub const VERSION: u16 = 1;
+/// Set by loxide when the code uses its list opcodes, which zlox doesn't have
+pub const FLAG_LISTS: u16 = 1 << 0;
+
+/// The name length written for a function with no name, like the script
+const NO_NAME: u32 = std.math.maxInt(u32);
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+pub const Error = error{
+    NotLoxb,
+    UnsupportedVersion,
+    UnsupportedFlags,
+    Truncated,
+    InvalidConstant,
+    InvalidCode,
+};
+
+/// Write `script` and every function declared in it to `out`
+pub fn write(allocator: Allocator, script: *Obj.Function, out: *ArrayList(u8)) !void {
+    try out.appendSlice(allocator, MAGIC);
+    try put_int(allocator, out, u16, VERSION);
+    try put_int(allocator, out, u16, 0);
+    try write_function(allocator, script, out);
+}
+
+fn write_function(allocator: Allocator, function: *Obj.Function, out: *ArrayList(u8)) !void {
+    if (function.name) |name| {
+        try put_bytes(allocator, out, name.chars[0..name.len]);
+    } else {
+        try put_int(allocator, out, u32, NO_NAME);
+    }
+    try out.append(allocator, function.arity);
+    try out.append(allocator, @intCast(u8, function.upvalue_count));
+
+    const chunk = &function.chunk;
+    try put_bytes(allocator, out, chunk.code.items);
+    for (chunk.lines.items) |line| {
+        try put_int(allocator, out, u32, line);
+    }
+    // zlox doesn't keep columns, 0 is "not known"
+    for (chunk.code.items) |_| {
+        try put_int(allocator, out, u32, 0);
+    }
+
+    try put_int(allocator, out, u32, @intCast(u32, chunk.constants.items.len));
+    for (chunk.constants.items) |constant| {
+        switch (constant) {
+            .Nil => try out.append(allocator, TAG_NIL),
+            .Bool => |val| try out.append(allocator, if (val) TAG_TRUE else TAG_FALSE),
+            .Number => |val| {
+                try out.append(allocator, TAG_NUMBER);
+                try put_int(allocator, out, u64, @bitCast(u64, val));
+            },
+            .Obj => |obj| switch (obj.type) {
+                .String => {
+                    const string = obj.narrow(Obj.String);
+                    try out.append(allocator, TAG_STRING);
+                    try put_bytes(allocator, out, string.chars[0..string.len]);
+                },
+                .Function => {
+                    try out.append(allocator, TAG_FUNCTION);
+                    try write_function(allocator, obj.narrow(Obj.Function), out);
+                },
+                else => return Error.InvalidConstant,
+            },
+        }
+    }
+}
+
+fn put_int(allocator: Allocator, out: *ArrayList(u8), comptime T: type, val: T) !void {
+    var bytes: [@sizeOf(T)]u8 = undefined;
+    mem.writeIntLittle(T, &bytes, val);
+    try out.appendSlice(allocator, &bytes);
+}
+
+fn put_bytes(allocator: Allocator, out: *ArrayList(u8), bytes: []const u8) !void {
+    try put_int(allocator, out, u32, @intCast(u32, bytes.len));
+    try out.appendSlice(allocator, bytes);
+}
+
+/// Load a `.loxb` file written by `write` or by loxide, returning the script.
+/// The GC mustn't collect while this runs, nothing is rooted until the
+/// script is handed to the VM.
+pub fn read(gc: *GC, bytes: []const u8) !*Obj.Function {
+    var reader = Reader{ .bytes = bytes };
+    if (!mem.eql(u8, try reader.take(MAGIC.len), MAGIC)) return Error.NotLoxb;
+    if ((try reader.int(u16)) != VERSION) return Error.UnsupportedVersion;
+    // Lists, or anything a later flag stands for, can't run here
+    if ((try reader.int(u16)) != 0) return Error.UnsupportedFlags;
+
+    const script = try reader.function(gc);
+    if (reader.pos != bytes.len) return Error.InvalidCode;
+    return script;
+}
+
+const Reader = struct {
+    bytes: []const u8,
+    pos: usize = 0,
+
+    fn take(self: *Reader, len: usize) Error![]const u8 {
+        if (len > self.bytes.len - self.pos) return Error.Truncated;
+        const slice = self.bytes[self.pos .. self.pos + len];
+        self.pos += len;
+        return slice;
+    }
+
+    fn int(self: *Reader, comptime T: type) Error!T {
+        const bytes = try self.take(@sizeOf(T));
+        return mem.readIntLittle(T, bytes[0..@sizeOf(T)]);
+    }
+
+    fn function(self: *Reader, gc: *GC) anyerror!*Obj.Function {
+        const allocator = gc.as_allocator();
+        var function = try gc.alloc_obj(Obj.Function);
+        try function.init(allocator);
+
+        const name_len = try self.int(u32);
+        if (name_len != NO_NAME) {
+            const name = try self.take(name_len);
+            function.name = try gc.copy_string(name.ptr, name_len);
+        }
+        function.arity = try self.int(u8);
+        function.upvalue_count = try self.int(u8);
+
+        const code = try self.take(try self.int(u32));
+        for (code) |byte| {
+            const line = try self.int(u32);
+            try function.chunk.write_byte(allocator, byte, @intCast(u16, std.math.min(line, std.math.maxInt(u16))));
+        }
+        // Columns, which zlox doesn't keep
+        _ = try self.take(code.len * @sizeOf(u32));
+
+        const constant_count = try self.int(u32);
+        var i: u32 = 0;
+        while (i < constant_count) : (i += 1) {
+            const constant = switch (try self.int(u8)) {
+                TAG_NIL => Value.nil(),
+                TAG_FALSE => Value.boolean(false),
+                TAG_TRUE => Value.boolean(true),
+                TAG_NUMBER => Value.number(@bitCast(f64, try self.int(u64))),
+                TAG_STRING => blk: {
+                    const len = try self.int(u32);
+                    const chars = try self.take(len);
+                    break :blk Value.obj((try gc.copy_string(chars.ptr, len)).widen());
+                },
+                TAG_FUNCTION => Value.obj((try self.function(gc)).widen()),
+                else => return Error.InvalidConstant,
+            };
+            try function.chunk.constants.append(allocator, constant);
+        }
+
+        try check_code(&function.chunk);
+        return function;
+    }
+};
+
+/// Make sure every instruction is one zlox has, with all of its operands,
+/// and that the constants they refer to exist
+fn check_code(chunk: *const Chunk) Error!void {
+    const code = chunk.code.items;
+    const constants = chunk.constants.items;
+    var offset: usize = 0;
+    while (offset < code.len) {
+        const op = std.meta.intToEnum(Opcode, code[offset]) catch return Error.InvalidCode;
+        const len: usize = switch (op) {
+            .Return, .Negate, .Add, .Subtract, .Multiply, .Divide, .Nil, .True, .False, .Not, .Equal, .Greater, .Less, .Print, .Pop, .CloseUpvalue, .Inherit => 1,
+            .GetLocal, .SetLocal, .Call, .GetUpvalue, .SetUpvalue => 2,
+            .Constant, .DefineGlobal, .GetGlobal, .SetGlobal, .Class, .GetProperty, .SetProperty, .Method, .GetSuper => blk: {
+                if (offset + 1 >= code.len or code[offset + 1] >= constants.len) return Error.InvalidCode;
+                break :blk 2;
+            },
+            .Invoke, .InvokeSuper => blk: {
+                if (offset + 1 >= code.len or code[offset + 1] >= constants.len) return Error.InvalidCode;
+                break :blk 3;
+            },
+            .Jump, .JumpIfFalse, .Loop => 3,
+            .Closure => blk: {
+                if (offset + 1 >= code.len or code[offset + 1] >= constants.len) return Error.InvalidCode;
+                const obj = constants[code[offset + 1]].as_obj() orelse return Error.InvalidCode;
+                if (!obj.is(Obj.Function)) return Error.InvalidCode;
+                break :blk 2 + 2 * @intCast(usize, obj.narrow(Obj.Function).upvalue_count);
+            },
+        };
+        if (offset + len > code.len) return Error.InvalidCode;
+        offset += len;
+    }
+}
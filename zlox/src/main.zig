@@ -19,6 +19,7 @@ const GC = @import("gc.zig");
 const Value = @import("value.zig").Value;
 const Obj = @import("obj.zig");
 const Conf = @import("conf.zig");
+const loxb = @import("loxb.zig");
 
 // const alloc = if (Conf.DEBUG_LOG_GC) blk: {
 //     var general_purpose_allocator = std.heap.GeneralPurposeAllocator(.{
@@ -37,6 +38,7 @@ pub fn main() !void {
     const params = comptime clap.parseParamsComptime(
         \\-h, --help             Display this help and exit.
         \\-r, --repl             Start a REPL..
+        \\--emit-loxb <FILE>     Compile the script to a .loxb file instead of running it.
         \\<FILE>...
         \\
     );
@@ -66,6 +68,14 @@ pub fn main() !void {
     }
 
     const file = res.positionals[0];
+    if (res.args.@"emit-loxb") |out| {
+        try emit_loxb(alloc, file, out);
+        return;
+    }
+    if (std.mem.endsWith(u8, file, ".loxb")) {
+        try run_loxb_file(alloc, file);
+        return;
+    }
     try run_file(alloc, file);
 
     // var args = std.process.args();
@@ -95,6 +105,41 @@ pub fn run_file(allocator: Allocator, path: []const u8) !void {
     allocator.free(source);
 }
 
+/// Compile the script at `path` and write it to `out_path` as `.loxb`, for
+/// loxide or zlox to run later
+pub fn emit_loxb(allocator: Allocator, path: []const u8, out_path: []const u8) !void {
+    const source = try std.fs.cwd().readFileAlloc(allocator, path, std.math.maxInt(usize));
+    defer allocator.free(source);
+
+    var gc: *GC = try allocator.create(GC);
+    try gc.init(allocator, false);
+    defer gc.free_objects() catch {};
+
+    var parser = Compiler.init_parser();
+    var scanner = Scanner.init(source);
+    var compiler = try Compiler.init(gc, errw, null, &scanner, &parser, null, FunctionType.Script);
+    const function = try compiler.compile() orelse return InterpretError.CompileError;
+
+    var bytes = std.ArrayListUnmanaged(u8){};
+    defer bytes.deinit(allocator);
+    try loxb.write(allocator, function, &bytes);
+    try std.fs.cwd().writeFile(out_path, bytes.items);
+}
+
+pub fn run_loxb_file(allocator: Allocator, path: []const u8) !void {
+    const bytes = try std.fs.cwd().readFileAlloc(allocator, path, std.math.maxInt(usize));
+    _ = try interpret_loxb(allocator, bytes, true);
+    allocator.free(bytes);
+}
+
+fn interpret_loxb(allocator: Allocator, bytes: []const u8, comptime do_teardown: bool) !*VMType {
+    var gc: *GC = try allocator.create(GC);
+    try gc.init(allocator, false);
+
+    const function = try loxb.read(gc, bytes);
+    return run_function(gc, function, do_teardown);
+}
+
 pub fn interpret(allocator: Allocator, source: []const u8) !*VMType {
     return interpret_impl(allocator, source, true);
 }
@@ -112,6 +157,10 @@ fn interpret_impl(allocator: Allocator, source: []const u8, comptime do_teardown
     var compiler = try Compiler.init(gc, errw, null, &scanner, &parser, null, FunctionType.Script);
     // note that this may get freed by gc depending on how we choose to implement it later
     const function = try compiler.compile() orelse return InterpretError.CompileError;
+    return run_function(gc, function, do_teardown);
+}
+
+fn run_function(gc: *GC, function: *Obj.Function, comptime do_teardown: bool) !*VMType {
     const closure = try Obj.Closure.init(gc, function);
 
     try VM.init(gc, closure);
@@ -123,10 +172,55 @@ fn interpret_impl(allocator: Allocator, source: []const u8, comptime do_teardown
     }
 
     try VM.run();
+    try write_heap_stats(gc);
 
     return VM;
 }
 
+/// Write the most the GC had allocated at once to the file in
+/// `LOX_HEAP_STATS`, if it's set, for `loxide bench` to compare
+fn write_heap_stats(gc: *GC) !void {
+    const path = std.process.getEnvVarOwned(alloc, "LOX_HEAP_STATS") catch |err| switch (err) {
+        error.EnvironmentVariableNotFound => return,
+        else => return err,
+    };
+    defer alloc.free(path);
+
+    const file = try std.fs.cwd().createFile(path, .{});
+    defer file.close();
+    try file.writer().print("peak_heap_bytes {d}\n", .{gc.peak_bytes_allocated});
+}
+
+test "loading loxide's .loxb files" {
+    const cases = .{ .{ "fib", 610 }, .{ "closures", 13 }, .{ "classes", 42 } };
+    inline for (cases) |case| {
+        const bytes = try std.fs.cwd().readFileAlloc(alloc, "../loxide/tests/loxb/" ++ case[0] ++ ".loxb", std.math.maxInt(usize));
+        defer alloc.free(bytes);
+
+        const vm = try interpret_loxb(alloc, bytes, false);
+        defer {
+            _ = vm.free() catch {};
+        }
+
+        const result_str = try vm.gc.copy_string(@ptrCast([*]const u8, "result"), "result".len);
+        const value = vm.gc.globals.get(result_str) orelse @panic("result not found");
+
+        try std.testing.expect(Value.eq(value, Value.number(case[1])));
+    }
+}
+
+test "loxb with lists is rejected" {
+    const bytes = try std.fs.cwd().readFileAlloc(alloc, "../loxide/tests/loxb/fib.loxb", std.math.maxInt(usize));
+    defer alloc.free(bytes);
+    bytes[6] = loxb.FLAG_LISTS;
+
+    var gc: *GC = try alloc.create(GC);
+    try gc.init(alloc, false);
+    defer gc.free_objects() catch {};
+
+    try std.testing.expectError(loxb.Error.UnsupportedFlags, loxb.read(gc, bytes));
+}
+
 test "fib" {
     const source =
         \\ fun fib(x) {